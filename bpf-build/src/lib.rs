@@ -0,0 +1,460 @@
+//! Compiles BPF programs written in C from a build script, the way `cc`
+//! compiles C libraries.
+//!
+//! Sources are compiled with `clang -target bpf -g -O2` against the
+//! headers of the kernel found by [`bpf_sys::headers`], and the DWARF `-g`
+//! adds is stripped with `llvm-strip -g`, which keeps the `.BTF` and
+//! `.BTF.ext` sections maps and CO-RE relocations need. The tools are
+//! looked up in `PATH` unless `CLANG` and `LLVM_STRIP` name them.
+//!
+//! ```ignore
+//! // build.rs
+//! fn main() {
+//!     let object = bpf_build::Build::new()
+//!         .file("src/bpf/probe.bpf.c")
+//!         .include("src/bpf")
+//!         .compile("probe");
+//!     let out = object.with_file_name("probe.skel.rs");
+//!     rsops::module::skeleton::write(&object, &out).unwrap();
+//! }
+//! ```
+//!
+//! puts `probe.o` in `OUT_DIR`, where the program can load it with
+//! `include_bytes!(concat!(env!("OUT_DIR"), "/probe.o"))` or through the
+//! skeleton generated next to it.
+
+use bpf_sys::headers;
+use std::{
+    env,
+    error::Error,
+    ffi::OsString,
+    fmt::{self, Display},
+    io,
+    path::{Path, PathBuf},
+    process::{Command, ExitStatus, Stdio},
+};
+
+/// Kernel headers BPF programs are compiled against, in the order the
+/// kernel's own build uses.
+const KERNEL_HEADERS: [&str; 7] = [
+    "arch/{arch}/include",
+    "arch/{arch}/include/generated",
+    "include",
+    "arch/{arch}/include/uapi",
+    "arch/{arch}/include/generated/uapi",
+    "include/uapi",
+    "include/generated/uapi",
+];
+
+const KCONFIG: &str = "include/linux/kconfig.h";
+
+const CLANG_HINT: &str = "BPF programs are compiled with clang, install it";
+const LLVM_STRIP_HINT: &str = "it removes the DWARF -g adds and keeps the BTF, install llvm \
+                               or turn stripping off with Build::strip(false)";
+
+/// Warnings kernel headers trigger with clang.
+const KERNEL_FLAGS: [&str; 8] = [
+    "-Wno-unused-value",
+    "-Wno-pointer-sign",
+    "-Wno-compare-distinct-pointer-types",
+    "-Wno-gnu-variable-sized-type-not-at-end",
+    "-Wno-address-of-packed-member",
+    "-Wno-tautological-compare",
+    "-Wno-unknown-warning-option",
+    "-fno-stack-protector",
+];
+
+#[derive(Debug)]
+pub enum BuildError {
+    /// No source was given with [`Build::file`]
+    NoSource,
+    /// `OUT_DIR` isn't set, i.e. not running from a build script
+    NoOutDir,
+    /// A tool isn't installed, or `var` doesn't point to it
+    ToolNotFound {
+        tool: &'static str,
+        var: &'static str,
+        path: PathBuf,
+        hint: &'static str,
+    },
+    /// No kernel headers were found for the target
+    NoKernelHeaders,
+    /// A tool failed on `file`; its diagnostics went to stderr
+    Failed {
+        tool: PathBuf,
+        file: PathBuf,
+        status: ExitStatus,
+    },
+    Io(PathBuf, io::Error),
+}
+
+impl Display for BuildError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use BuildError::*;
+        match self {
+            NoSource => write!(f, "No BPF source to compile, add one with Build::file"),
+            NoOutDir => write!(
+                f,
+                "OUT_DIR is not set, run from a build script or set Build::out_dir"
+            ),
+            ToolNotFound {
+                tool,
+                var,
+                path,
+                hint,
+            } => write!(
+                f,
+                "{} not found (tried {}): {}, or set {} to its path",
+                tool,
+                path.display(),
+                hint,
+                var
+            ),
+            NoKernelHeaders => write!(
+                f,
+                "No kernel headers found, install them or set KERNEL_SOURCE to a kernel \
+                 source or headers directory"
+            ),
+            Failed { tool, file, status } => {
+                write!(
+                    f,
+                    "{} failed on {}: {}",
+                    tool.display(),
+                    file.display(),
+                    status
+                )
+            }
+            Io(path, e) => write!(f, "Failed to run {}: {}", path.display(), e),
+        }
+    }
+}
+
+impl Error for BuildError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            BuildError::Io(_, e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/// The configuration of a BPF object to compile.
+#[derive(Debug, Clone)]
+pub struct Build {
+    file: Option<PathBuf>,
+    includes: Vec<PathBuf>,
+    defines: Vec<(String, Option<String>)>,
+    flags: Vec<String>,
+    out_dir: Option<PathBuf>,
+    kernel_headers: bool,
+    strip: bool,
+}
+
+impl Default for Build {
+    fn default() -> Build {
+        Build::new()
+    }
+}
+
+impl Build {
+    pub fn new() -> Build {
+        Build {
+            file: None,
+            includes: Vec::new(),
+            defines: Vec::new(),
+            flags: Vec::new(),
+            out_dir: None,
+            kernel_headers: true,
+            strip: true,
+        }
+    }
+
+    /// The C source of the object. An object is a single translation
+    /// unit, so a later call replaces the file.
+    pub fn file<P: AsRef<Path>>(&mut self, file: P) -> &mut Build {
+        self.file = Some(file.as_ref().to_path_buf());
+        self
+    }
+
+    /// Adds a directory to search for headers before the kernel's.
+    pub fn include<P: AsRef<Path>>(&mut self, dir: P) -> &mut Build {
+        self.includes.push(dir.as_ref().to_path_buf());
+        self
+    }
+
+    /// Defines `var`, to `value` if given.
+    pub fn define(&mut self, var: &str, value: Option<&str>) -> &mut Build {
+        self.defines
+            .push((var.to_string(), value.map(str::to_string)));
+        self
+    }
+
+    /// Passes `flag` to clang after the default ones, e.g. `-O1` to
+    /// override the optimization level.
+    pub fn flag(&mut self, flag: &str) -> &mut Build {
+        self.flags.push(flag.to_string());
+        self
+    }
+
+    /// Where to put the object instead of `OUT_DIR`.
+    pub fn out_dir<P: AsRef<Path>>(&mut self, dir: P) -> &mut Build {
+        self.out_dir = Some(dir.as_ref().to_path_buf());
+        self
+    }
+
+    /// Whether to compile against the kernel headers, on by default.
+    /// Programs that include a `vmlinux.h` instead don't need them.
+    pub fn kernel_headers(&mut self, kernel_headers: bool) -> &mut Build {
+        self.kernel_headers = kernel_headers;
+        self
+    }
+
+    /// Whether to strip DWARF from the object, on by default.
+    pub fn strip(&mut self, strip: bool) -> &mut Build {
+        self.strip = strip;
+        self
+    }
+
+    /// Compiles the source to `<out dir>/<name>.o` and returns its path,
+    /// panicking with the diagnostics on failure like `cc` does.
+    pub fn compile(&self, name: &str) -> PathBuf {
+        match self.try_compile(name) {
+            Ok(object) => object,
+            Err(e) => panic!("\n\nerror compiling BPF object {}: {}\n\n", name, e),
+        }
+    }
+
+    /// Compiles the source to `<out dir>/<name>.o` and returns its path.
+    pub fn try_compile(&self, name: &str) -> Result<PathBuf, BuildError> {
+        let file = self.file.as_ref().ok_or(BuildError::NoSource)?;
+        let out_dir = match &self.out_dir {
+            Some(dir) => dir.clone(),
+            None => env::var_os("OUT_DIR")
+                .map(PathBuf::from)
+                .ok_or(BuildError::NoOutDir)?,
+        };
+        let object = out_dir.join(format!("{}.o", name));
+
+        println!("cargo:rerun-if-changed={}", file.display());
+        for dir in &self.includes {
+            println!("cargo:rerun-if-changed={}", dir.display());
+        }
+        for var in &["CLANG", "LLVM_STRIP", "KERNEL_SOURCE", "KERNEL_VERSION"] {
+            println!("cargo:rerun-if-env-changed={}", var);
+        }
+
+        let clang = tool("clang", "CLANG", CLANG_HINT)?;
+        let strip = if self.strip {
+            Some(tool("llvm-strip", "LLVM_STRIP", LLVM_STRIP_HINT)?)
+        } else {
+            None
+        };
+        let kernel = if self.kernel_headers {
+            Some(kernel_headers()?)
+        } else {
+            None
+        };
+
+        let mut cmd = self.clang_command(&clang, kernel.as_ref(), file, &object);
+        run(&mut cmd, &clang, file)?;
+        if let Some(strip) = strip {
+            run(Command::new(&strip).arg("-g").arg(&object), &strip, &object)?;
+        }
+        Ok(object)
+    }
+
+    fn clang_command(
+        &self,
+        clang: &Path,
+        kernel: Option<&KernelHeaders>,
+        file: &Path,
+        object: &Path,
+    ) -> Command {
+        let mut cmd = Command::new(clang);
+        cmd.args(["-target", "bpf", "-g", "-O2", "-D__BPF_TRACING__"]);
+        for dir in &self.includes {
+            cmd.arg("-I").arg(dir);
+        }
+        if let Some(kernel) = kernel {
+            cmd.arg("-D__KERNEL__");
+            if let Some(arch) = kernel.arch {
+                cmd.arg(format!("-D__TARGET_ARCH_{}", arch));
+            }
+            cmd.args(KERNEL_FLAGS);
+            for dir in &kernel.includes {
+                cmd.arg("-I").arg(dir);
+            }
+            cmd.arg("-include").arg(&kernel.kconfig);
+        }
+        for (var, value) in &self.defines {
+            match value {
+                Some(value) => cmd.arg(format!("-D{}={}", var, value)),
+                None => cmd.arg(format!("-D{}", var)),
+            };
+        }
+        cmd.args(&self.flags);
+        cmd.arg("-c").arg(file).arg("-o").arg(object);
+        cmd
+    }
+}
+
+#[derive(Debug)]
+struct KernelHeaders {
+    includes: Vec<String>,
+    kconfig: String,
+    arch: Option<&'static str>,
+}
+
+fn kernel_headers() -> Result<KernelHeaders, BuildError> {
+    let includes =
+        headers::prefix_kernel_headers(&KERNEL_HEADERS).ok_or(BuildError::NoKernelHeaders)?;
+    let kconfig = headers::prefix_kernel_headers(&[KCONFIG])
+        .and_then(|mut kconfig| kconfig.pop())
+        .ok_or(BuildError::NoKernelHeaders)?;
+    let arch = env::var("TARGET")
+        .ok()
+        .and_then(|target| headers::kernel_arch(&target));
+    Ok(KernelHeaders {
+        includes,
+        kconfig,
+        arch,
+    })
+}
+
+/// The path of `tool`, from `var` or `PATH`, checking it runs.
+fn tool(tool: &'static str, var: &'static str, hint: &'static str) -> Result<PathBuf, BuildError> {
+    let path = PathBuf::from(env::var_os(var).unwrap_or_else(|| OsString::from(tool)));
+    check_tool(tool, var, path, hint)
+}
+
+fn check_tool(
+    tool: &'static str,
+    var: &'static str,
+    path: PathBuf,
+    hint: &'static str,
+) -> Result<PathBuf, BuildError> {
+    let status = Command::new(&path)
+        .arg("--version")
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status();
+    match status {
+        Ok(_) => Ok(path),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Err(BuildError::ToolNotFound {
+            tool,
+            var,
+            path,
+            hint,
+        }),
+        Err(e) => Err(BuildError::Io(path, e)),
+    }
+}
+
+fn run(cmd: &mut Command, tool: &Path, file: &Path) -> Result<(), BuildError> {
+    let status = cmd
+        .status()
+        .map_err(|e| BuildError::Io(tool.to_path_buf(), e))?;
+    if !status.success() {
+        return Err(BuildError::Failed {
+            tool: tool.to_path_buf(),
+            file: file.to_path_buf(),
+            status,
+        });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_clang_command() {
+        let kernel = KernelHeaders {
+            includes: vec!["/src/include".to_string(), "/src/include/uapi".to_string()],
+            kconfig: "/src/include/linux/kconfig.h".to_string(),
+            arch: Some("x86"),
+        };
+        let mut build = Build::new();
+        build
+            .include("bpf")
+            .define("DEBUG", None)
+            .define("MAX", Some("4"))
+            .flag("-O1");
+        let cmd = build.clang_command(
+            Path::new("clang"),
+            Some(&kernel),
+            Path::new("p.c"),
+            Path::new("/out/p.o"),
+        );
+        let args: Vec<_> = cmd.get_args().map(|a| a.to_str().unwrap()).collect();
+        assert_eq!(
+            args[..5],
+            ["-target", "bpf", "-g", "-O2", "-D__BPF_TRACING__"]
+        );
+        assert_eq!(
+            args[5..9],
+            ["-I", "bpf", "-D__KERNEL__", "-D__TARGET_ARCH_x86"]
+        );
+        assert_eq!(
+            args[9 + KERNEL_FLAGS.len()..],
+            [
+                "-I",
+                "/src/include",
+                "-I",
+                "/src/include/uapi",
+                "-include",
+                "/src/include/linux/kconfig.h",
+                "-DDEBUG",
+                "-DMAX=4",
+                "-O1",
+                "-c",
+                "p.c",
+                "-o",
+                "/out/p.o"
+            ]
+        );
+
+        let cmd = Build::new().clang_command(
+            Path::new("clang"),
+            None,
+            Path::new("p.c"),
+            Path::new("p.o"),
+        );
+        let args: Vec<_> = cmd.get_args().map(|a| a.to_str().unwrap()).collect();
+        assert_eq!(
+            args,
+            [
+                "-target",
+                "bpf",
+                "-g",
+                "-O2",
+                "-D__BPF_TRACING__",
+                "-c",
+                "p.c",
+                "-o",
+                "p.o"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_tool_not_found() {
+        let e = check_tool(
+            "clang",
+            "CLANG",
+            PathBuf::from("/nonexistent/clang"),
+            CLANG_HINT,
+        )
+        .unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "clang not found (tried /nonexistent/clang): BPF programs are compiled with clang, \
+             install it, or set CLANG to its path"
+        );
+        assert!(matches!(
+            Build::new().try_compile("p"),
+            Err(BuildError::NoSource)
+        ));
+    }
+}
@@ -10,9 +10,9 @@ use std::env;
 use std::path::PathBuf;
 
 const KERNEL_HEADERS: [&str; 6] = [
-    "arch/x86/include/generated/uapi",
-    "arch/x86/include/uapi",
-    "arch/x86/include/",
+    "arch/{arch}/include/generated/uapi",
+    "arch/{arch}/include/uapi",
+    "arch/{arch}/include/",
     "include/generated/uapi",
     "include/uapi",
     "include",
@@ -63,7 +63,9 @@ fn main() {
         .include(".");
     if target.contains("musl") {
 
-        for include in headers::prefix_kernel_headers(&KERNEL_HEADERS).expect("couldn't find kernel headers") {
+        let includes = headers::prefix_kernel_headers(&KERNEL_HEADERS)
+            .unwrap_or_else(|| panic!("couldn't find kernel headers for {}", target));
+        for include in includes {
             libbpf.include(include);
         }
         libbpf
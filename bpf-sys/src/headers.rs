@@ -6,51 +6,126 @@
 // copied, modified, or distributed except according to those terms.
 
 use crate::uname;
+pub use crate::uname::KernelVersion;
 
 use std::{env,
 	  fmt::{Display, self},
 	  error::Error,
+	  fs, io, process,
 	  path::{Path, PathBuf},
-	  str::FromStr,
 	  process::Command};
 
 #[derive(Debug)]
 pub enum HeadersError {
-    NotFound,
+    /// The kernel release couldn't be determined
+    NoRelease,
+    /// None of the directories tried has kernel headers
+    NotFound(Vec<PathBuf>),
+    /// `/sys/kernel/kheaders.tar.xz` couldn't be extracted and none of the
+    /// directories tried has kernel headers
+    Kheaders {
+        archive: PathBuf,
+        cache: PathBuf,
+        reason: String,
+        tried: Vec<PathBuf>,
+    },
+    /// None of the version headers exist under the build directory
+    NoVersionHeader(PathBuf),
+    /// A version header that doesn't define a usable version
+    InvalidVersion(PathBuf),
+    Io(PathBuf, io::Error),
 }
 impl Display for HeadersError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "No headers found")
+        use HeadersError::*;
+        match self {
+            NoRelease => write!(f, "Unknown kernel release, set KERNEL_VERSION or KERNEL_SOURCE"),
+            NotFound(tried) => {
+                write!(f, "No headers found, tried:")?;
+                tried.iter().try_for_each(|path| write!(f, "\n  {}", path.display()))
+            }
+            Kheaders { archive, cache, reason, tried } => {
+                write!(f, "Failed to extract {} into {}: {}", archive.display(), cache.display(), reason)?;
+                write!(f, "\nNo other headers found, tried:")?;
+                tried.iter().try_for_each(|path| write!(f, "\n  {}", path.display()))
+            }
+            NoVersionHeader(build) => write!(
+                f,
+                "No version.h or utsrelease.h found in {}",
+                build.display()
+            ),
+            InvalidVersion(path) => write!(f, "No kernel version defined in {}", path.display()),
+            Io(path, e) => write!(f, "Failed to read {}: {}", path.display(), e),
+        }
+    }
+}
+impl Error for HeadersError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            HeadersError::Io(_, e) => Some(e),
+            _ => None,
+        }
     }
 }
-impl Error for HeadersError {}
 
+#[derive(Debug, PartialEq)]
 struct KernelHeaders {
     source: PathBuf,
     build: PathBuf
 }
 
-pub struct KernelVersion {
-    pub version: u8,
-    pub patchlevel: u8,
-    pub sublevel: u8
-}
-
+/// Prefixes `headers` with the kernel headers directory, `include/generated`
+/// paths with the build directory and the rest with the source directory.
+///
+/// `{arch}` in a header is replaced by the kernel architecture of the
+/// cargo `TARGET` being built, or of the host outside of build scripts, so
+/// `arch/{arch}/include/uapi` works when cross compiling.
 pub fn prefix_kernel_headers(headers: &[&str]) -> Option<Vec<String>> {
     let KernelHeaders { source, build } = kernel_headers_path().ok()?;
+    let arch = match env::var("TARGET") {
+        Ok(target) => kernel_arch(&target),
+        Err(_) => kernel_arch(env::consts::ARCH),
+    };
+    if arch.is_none() && headers.iter().any(|h| h.contains("{arch}")) {
+        return None;
+    }
+    Some(prefix_headers(&source, &build, arch.unwrap_or_default(), headers))
+}
+
+fn prefix_headers(source: &Path, build: &Path, arch: &str, headers: &[&str]) -> Vec<String> {
     let mut ret: Vec<String> = Vec::new();
     for header in headers {
+        let header = header.replace("{arch}", arch);
         if header.contains("generated") {
-            let path = build.join(header);
+            let path = build.join(&header);
             ret.push(path.to_string_lossy().into());
             if header.ends_with("generated") {
                 ret.push(path.parent().unwrap().to_string_lossy().into());
             }
         } else {
-            ret.push(source.join(header).to_string_lossy().into());
+            ret.push(source.join(&header).to_string_lossy().into());
         }
     }
-    Some(ret)
+    ret
+}
+
+/// The kernel's name for the architecture of a target triple such as
+/// `aarch64-unknown-linux-musl`, i.e. its directory under `arch/`.
+pub fn kernel_arch(target: &str) -> Option<&'static str> {
+    let arch = target.split('-').next()?;
+    let kernel_arch = match arch {
+        "x86_64" | "i386" | "i586" | "i686" | "x86" => "x86",
+        "aarch64" | "aarch64_be" => "arm64",
+        "s390x" => "s390",
+        "loongarch64" => "loongarch",
+        "sparc" | "sparc64" | "sparcv9" => "sparc",
+        _ if arch.starts_with("arm") || arch.starts_with("thumb") => "arm",
+        _ if arch.starts_with("riscv") => "riscv",
+        _ if arch.starts_with("powerpc") => "powerpc",
+        _ if arch.starts_with("mips") => "mips",
+        _ => return None,
+    };
+    Some(kernel_arch)
 }
 
 pub fn running_kernel_version() -> Option<String> {
@@ -61,74 +136,332 @@ pub fn running_kernel_version() -> Option<String> {
     })
 }
 
-pub fn build_kernel_version() -> Result<KernelVersion, Box<dyn Error>> {
+/// The version of the kernel whose headers the build uses.
+pub fn build_kernel_version() -> Result<KernelVersion, HeadersError> {
     let KernelHeaders { source: _, build } = kernel_headers_path()?;
-    let make_db = Command::new("make")
-                          .arg("-qp")
-                          .arg("-f")
-                          .arg(build.join("Makefile"))
-                          .output()?;
-    let reader = String::from_utf8(make_db.stdout)?;
-
-    let mut version = None::<u8>;
-    let mut patchlevel = None::<u8>;
-    let mut sublevel = None::<u8>;
-	
-    for line in reader.lines() {
-	let mut var = line.split(" = ");
-	match var.next() {
-	    Some("VERSION") => version = var.next().map(u8::from_str).transpose()?,
-	    Some("PATCHLEVEL") => patchlevel = var.next().map(u8::from_str).transpose()?,
-	    Some("SUBLEVEL") => sublevel = var.next().map(u8::from_str).transpose()?,
-	    _ => continue
-	}
-
-	if version.is_some() && patchlevel.is_some() && sublevel.is_some() {
-	    break;
-	}
-    }
-
-    Ok(KernelVersion {
-	version: version.unwrap(),
-	patchlevel: patchlevel.unwrap(),
-	sublevel: sublevel.unwrap()
-    })
+    headers_kernel_version(&build)
+}
+
+/// Files that record the kernel version, relative to the build directory.
+/// Kernels before 3.7 generate `version.h` outside of `uapi`.
+const VERSION_HEADERS: [&str; 3] = [
+    "include/generated/uapi/linux/version.h",
+    "include/linux/version.h",
+    "include/generated/utsrelease.h",
+];
+
+/// Reads the kernel version from the generated headers under `build`.
+///
+/// `LINUX_VERSION_MAJOR`, `LINUX_VERSION_PATCHLEVEL` and
+/// `LINUX_VERSION_SUBLEVEL` (5.14+) are used when defined, then
+/// `LINUX_VERSION_CODE`. The code's sublevel saturates at 255, so for
+/// 4.9.337 `UTS_RELEASE` is used instead, unless it's a distribution ABI
+/// name like `4.9.0-19-amd64` that doesn't carry the sublevel at all.
+pub fn headers_kernel_version(build: &Path) -> Result<KernelVersion, HeadersError> {
+    let mut found = None;
+    let mut code = None;
+    let mut release = None;
+    for header in VERSION_HEADERS.iter() {
+        let path = build.join(header);
+        let contents = match fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(HeadersError::Io(path, e)),
+        };
+        let define = |name: &str| {
+            contents.lines().find_map(|line| {
+                let mut words = line.split_whitespace();
+                match (words.next(), words.next(), words.next()) {
+                    (Some("#define"), Some(n), Some(value)) if n == name => Some(value),
+                    _ => None,
+                }
+            })
+        };
+        let number = |name: &str| define(name).and_then(|v| v.parse::<u32>().ok());
+
+        if let (Some(version), Some(patchlevel), Some(sublevel)) = (
+            number("LINUX_VERSION_MAJOR"),
+            number("LINUX_VERSION_PATCHLEVEL"),
+            number("LINUX_VERSION_SUBLEVEL"),
+        ) {
+            return Ok(KernelVersion::new(version, patchlevel, sublevel));
+        }
+        code = code.or_else(|| number("LINUX_VERSION_CODE").map(KernelVersion::from_code));
+        release = release.or_else(|| define("UTS_RELEASE").and_then(|r| r.trim_matches('"').parse().ok()));
+        found = found.or(Some(path));
+    }
+
+    match (code, release) {
+        (Some(code), Some(release))
+            if code.sublevel == 0xff
+                && (release.version, release.patchlevel) == (code.version, code.patchlevel)
+                && release.sublevel > 0xff =>
+        {
+            Ok(release)
+        }
+        (Some(version), _) | (None, Some(version)) => Ok(version),
+        (None, None) => Err(match found {
+            Some(path) => HeadersError::InvalidVersion(path),
+            None => HeadersError::NoVersionHeader(build.to_path_buf()),
+        }),
+    }
 }
 
 fn kernel_headers_path() -> Result<KernelHeaders, HeadersError> {
-    env::var("KERNEL_SOURCE")
-    .ok()
-    .map(|s| {
-        let path = PathBuf::from(s);
-        KernelHeaders {
+    if let Ok(source) = env::var("KERNEL_SOURCE") {
+        let path = PathBuf::from(source);
+        return Ok(KernelHeaders {
             source: path.clone(),
             build: path
+        });
+    }
+    let release = running_kernel_version().ok_or(HeadersError::NoRelease)?;
+    let running = uname::uname().is_ok_and(|u| uname::to_str(&u.release) == release);
+    find_kernel_headers(Path::new("/"), &release, running)
+}
+
+const KCONFIG: &str = "include/linux/kconfig.h";
+const KHEADERS: &str = "sys/kernel/kheaders.tar.xz";
+
+/// Looks for the headers of kernel `release` under `root`, in order:
+///
+/// - `/lib/modules/<release>/{source,build}`
+/// - `/usr/src/linux-headers-<release>` (Debian, Ubuntu, Alpine), with the
+///   sources in the `-common` package on Debian
+/// - `/usr/src/kernels/<release>` (Fedora, RHEL)
+/// - `/sys/kernel/kheaders.tar.xz` of the running kernel (`CONFIG_IKHEADERS`),
+///   extracted into a cache directory
+/// - any other `/usr/src/linux-headers-*` or `/usr/src/kernels/*`, newest
+///   first: containers see the host's release but have the image's headers
+fn find_kernel_headers(root: &Path, release: &str, running: bool) -> Result<KernelHeaders, HeadersError> {
+    let lib_modules = root.join("lib/modules").join(release);
+    let usr_src = root.join("usr/src");
+    let mut candidates = vec![
+        (lib_modules.join("source"), lib_modules.join("build")),
+        debian_headers(usr_src.join(format!("linux-headers-{}", release))),
+        (usr_src.join("kernels").join(release), usr_src.join("kernels").join(release)),
+    ];
+    let mut tried = Vec::new();
+    if let Some(headers) = first_kernel_headers(&candidates, &mut tried) {
+        return Ok(headers);
+    }
+
+    let mut kheaders_error = None;
+    let archive = root.join(KHEADERS);
+    if running && archive.is_file() {
+        let cache = kheaders_cache_dir().join(format!("kheaders-{}", release));
+        match extract_kheaders(&archive, &cache) {
+            Ok(()) => return Ok(KernelHeaders { source: cache.clone(), build: cache }),
+            Err(reason) => kheaders_error = Some((cache, reason)),
         }
+    }
+    tried.push(archive.clone());
+
+    let mut others: Vec<PathBuf> = [usr_src.clone(), usr_src.join("kernels")]
+        .iter()
+        .filter_map(|dir| fs::read_dir(dir).ok())
+        .flatten()
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| {
+            let name = path.file_name().unwrap_or_default().to_string_lossy();
+            path.parent() == Some(&usr_src.join("kernels"))
+                || (name.starts_with("linux-headers-") && !name.ends_with("-common"))
+        })
+        .filter(|path| candidates.iter().all(|(_, build)| build != path))
+        .collect();
+    others.sort_by_key(|path| {
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        name.trim_start_matches("linux-headers-").parse::<KernelVersion>().ok()
+    });
+    candidates = others.into_iter().rev().map(debian_headers).collect();
+    if let Some(headers) = first_kernel_headers(&candidates, &mut tried) {
+        return Ok(headers);
+    }
+
+    Err(match kheaders_error {
+        Some((cache, reason)) => HeadersError::Kheaders { archive, cache, reason, tried },
+        None => HeadersError::NotFound(tried),
     })
-    .or_else(lib_modules_kernel_headers)
-    .ok_or(HeadersError::NotFound)
 }
 
-fn lib_modules_kernel_headers() -> Option<KernelHeaders> {
-    if let Some(version) = running_kernel_version() {
-        let path = Path::new("/lib/modules").join(version);
-        let mut build = path.join("build");
-        let source = path.join("source");
-        let kconfig = "include/linux/kconfig.h";
-        let source = match (source.join(kconfig).is_file(), build.join(kconfig).is_file()) {
+/// Debian splits the headers of `linux-headers-6.1.0-17-amd64` into the
+/// generated, per flavour files and `linux-headers-6.1.0-17-common`.
+fn debian_headers(build: PathBuf) -> (PathBuf, PathBuf) {
+    let name = build.file_name().unwrap_or_default().to_string_lossy();
+    let common = name
+        .rfind('-')
+        .map(|flavour| build.with_file_name(format!("{}-common", &name[..flavour])));
+    match common {
+        Some(common) if common.join(KCONFIG).is_file() => (common, build),
+        _ => (build.clone(), build),
+    }
+}
+
+/// The first `(source, build)` candidate with headers. The source directory
+/// falls back to the build directory and the other way around.
+fn first_kernel_headers(candidates: &[(PathBuf, PathBuf)], tried: &mut Vec<PathBuf>) -> Option<KernelHeaders> {
+    for (source, build) in candidates {
+        let source = match (source.join(KCONFIG).is_file(), build.join(KCONFIG).is_file()) {
             (true, _) => source,
-            (false, true) => build.clone(),
-            _ => return None
-        };
-        if !build.join("include/generated/uapi/linux/version.h").is_file() {
-            build = source.clone()
+            (false, true) => build,
+            _ => {
+                tried.push(build.clone());
+                if source != build {
+                    tried.push(source.clone());
+                }
+                continue
+            }
         };
-
+        let build = if build.join(VERSION_HEADERS[0]).is_file() { build } else { source };
         return Some(KernelHeaders {
-            source,
-            build
+            source: source.clone(),
+            build: build.clone()
         });
     }
 
     None
 }
+
+/// `$XDG_CACHE_HOME/bpf-sys`, `~/.cache/bpf-sys` or a temporary directory.
+fn kheaders_cache_dir() -> PathBuf {
+    env::var_os("XDG_CACHE_HOME")
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".cache")))
+        .unwrap_or_else(env::temp_dir)
+        .join("bpf-sys")
+}
+
+/// Extracts `archive` into `cache` unless an earlier build already did.
+/// Extracts into a temporary directory first so that concurrent builds
+/// never see a partial tree.
+fn extract_kheaders(archive: &Path, cache: &Path) -> Result<(), String> {
+    if cache.join(KCONFIG).is_file() {
+        return Ok(());
+    }
+    let name = cache.file_name().unwrap_or_default().to_string_lossy();
+    let tmp = cache.with_file_name(format!("{}.tmp{}", name, process::id()));
+    fs::create_dir_all(&tmp).map_err(|e| format!("{}: {}", tmp.display(), e))?;
+    let output = Command::new("tar")
+        .arg("-xJf")
+        .arg(archive)
+        .arg("-C")
+        .arg(&tmp)
+        .output()
+        .map_err(|e| format!("failed to run tar: {}", e))?;
+    if !output.status.success() {
+        let _ = fs::remove_dir_all(&tmp);
+        return Err(format!("tar: {}", String::from_utf8_lossy(&output.stderr).trim()));
+    }
+    if fs::rename(&tmp, cache).is_err() {
+        let _ = fs::remove_dir_all(&tmp);
+        if !cache.join(KCONFIG).is_file() {
+            return Err(format!("failed to move headers into {}", cache.display()));
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn fixture(layout: &str) -> Result<KernelVersion, HeadersError> {
+        headers_kernel_version(&Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/headers").join(layout))
+    }
+
+    #[test]
+    fn test_headers_kernel_version() {
+        assert_eq!(fixture("debian-bookworm").unwrap(), KernelVersion::new(6, 1, 71));
+        assert_eq!(fixture("vanilla-4.9").unwrap(), KernelVersion::new(4, 9, 337));
+        assert_eq!(fixture("debian-stretch").unwrap(), KernelVersion::new(4, 9, 255));
+        assert_eq!(fixture("centos-7").unwrap(), KernelVersion::new(3, 10, 0));
+        assert_eq!(fixture("legacy-3.2").unwrap(), KernelVersion::new(3, 2, 0));
+        assert!(matches!(fixture("invalid"), Err(HeadersError::InvalidVersion(_))));
+        assert!(matches!(fixture("missing"), Err(HeadersError::NoVersionHeader(_))));
+    }
+
+    #[test]
+    fn test_kernel_arch() {
+        assert_eq!(kernel_arch("x86_64-unknown-linux-musl"), Some("x86"));
+        assert_eq!(kernel_arch("i686-unknown-linux-gnu"), Some("x86"));
+        assert_eq!(kernel_arch("aarch64-unknown-linux-musl"), Some("arm64"));
+        assert_eq!(kernel_arch("armv7-unknown-linux-musleabihf"), Some("arm"));
+        assert_eq!(kernel_arch("arm-unknown-linux-musleabi"), Some("arm"));
+        assert_eq!(kernel_arch("riscv64gc-unknown-linux-musl"), Some("riscv"));
+        assert_eq!(kernel_arch("s390x-unknown-linux-gnu"), Some("s390"));
+        assert_eq!(kernel_arch("powerpc64le-unknown-linux-musl"), Some("powerpc"));
+        assert_eq!(kernel_arch("mips64el-unknown-linux-muslabi64"), Some("mips"));
+        assert_eq!(kernel_arch("aarch64"), Some("arm64"));
+        assert_eq!(kernel_arch("wasm32-unknown-unknown"), None);
+    }
+
+    #[test]
+    fn test_prefix_headers() {
+        let headers = prefix_headers(
+            Path::new("/src"),
+            Path::new("/build"),
+            "arm64",
+            &["arch/{arch}/include/generated", "arch/{arch}/include/uapi", "include"],
+        );
+        assert_eq!(
+            headers,
+            [
+                "/build/arch/arm64/include/generated",
+                "/build/arch/arm64/include",
+                "/src/arch/arm64/include/uapi",
+                "/src/include"
+            ]
+        );
+    }
+
+    fn root(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/roots").join(name)
+    }
+
+    #[test]
+    fn test_find_kernel_headers() {
+        let debian = root("debian");
+        let usr_src = debian.join("usr/src");
+        assert_eq!(
+            find_kernel_headers(&debian, "6.1.0-17-amd64", false).unwrap(),
+            KernelHeaders {
+                source: usr_src.join("linux-headers-6.1.0-17-common"),
+                build: usr_src.join("linux-headers-6.1.0-17-amd64"),
+            }
+        );
+
+        let fedora = root("fedora").join("usr/src/kernels/6.5.6-300.fc39.x86_64");
+        assert_eq!(
+            find_kernel_headers(&root("fedora"), "6.5.6-300.fc39.x86_64", false).unwrap(),
+            KernelHeaders { source: fedora.clone(), build: fedora }
+        );
+
+        // a container on a host with another kernel: the newest headers win
+        let newest = root("container").join("usr/src/linux-headers-5.15.0-91-generic");
+        assert_eq!(
+            find_kernel_headers(&root("container"), "6.8.0-1-aws", false).unwrap(),
+            KernelHeaders { source: newest.clone(), build: newest }
+        );
+    }
+
+    #[test]
+    fn test_headers_not_found() {
+        let missing = root("missing");
+        let tried = match find_kernel_headers(&missing, "5.10.0", true) {
+            Err(HeadersError::NotFound(tried)) => tried,
+            other => panic!("unexpected {:?}", other),
+        };
+        assert_eq!(
+            tried,
+            [
+                missing.join("lib/modules/5.10.0/build"),
+                missing.join("lib/modules/5.10.0/source"),
+                missing.join("usr/src/linux-headers-5.10.0"),
+                missing.join("usr/src/kernels/5.10.0"),
+                missing.join("sys/kernel/kheaders.tar.xz"),
+            ]
+        );
+    }
+}
@@ -0,0 +1 @@
+#define UTS_RELEASE "3.10.0-1160.105.1.el7.x86_64"
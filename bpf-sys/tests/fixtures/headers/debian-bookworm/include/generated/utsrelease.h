@@ -0,0 +1 @@
+#define UTS_RELEASE "6.1.0-17-amd64"
@@ -0,0 +1 @@
+#define UTS_RELEASE "4.9.0-19-amd64"
@@ -0,0 +1,6 @@
+#ifndef _LINUX_KCONFIG_H
+#define _LINUX_KCONFIG_H
+
+#include <generated/autoconf.h>
+
+#endif /* _LINUX_KCONFIG_H */
@@ -0,0 +1,5 @@
+#define LINUX_VERSION_CODE 394502
+#define KERNEL_VERSION(a,b,c) (((a) << 16) + ((b) << 8) + ((c) > 255 ? 255 : (c)))
+#define LINUX_VERSION_MAJOR 6
+#define LINUX_VERSION_PATCHLEVEL 5
+#define LINUX_VERSION_SUBLEVEL 6
@@ -0,0 +1,154 @@
+// Copyright 2019 Authors of Red Sift
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Kernel versions as the kernel itself numbers them, shared by `bpf-sys`,
+//! which matches them against kernel headers, and `rsops`, which checks
+//! objects and probes features against the running kernel.
+
+use std::error::Error;
+use std::ffi::CStr;
+use std::fmt::{self, Display};
+use std::fs;
+use std::mem;
+use std::str::FromStr;
+
+/// A kernel version such as 4.15.18. Orders the way releases do.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct KernelVersion {
+    pub version: u32,
+    pub patchlevel: u32,
+    pub sublevel: u32,
+}
+
+impl KernelVersion {
+    pub const fn new(version: u32, patchlevel: u32, sublevel: u32) -> KernelVersion {
+        KernelVersion { version, patchlevel, sublevel }
+    }
+
+    /// The version of the running kernel. On Ubuntu the release names the
+    /// ABI (4.15.0-55-generic) and `/proc/version_signature` has the
+    /// upstream version it's based on, which is what `LINUX_VERSION_CODE`
+    /// is built from. Falls back to the release when the signature is
+    /// missing or can't be parsed.
+    pub fn running() -> Option<KernelVersion> {
+        fs::read_to_string("/proc/version_signature")
+            .ok()
+            .and_then(|signature| parse_version_signature(signature.trim()))
+            .and_then(|version| version.parse().ok())
+            .or_else(|| release()?.parse().ok())
+    }
+
+    /// Decodes a `LINUX_VERSION_CODE`.
+    pub fn from_code(code: u32) -> KernelVersion {
+        KernelVersion::new(code >> 16, (code >> 8) & 0xff, code & 0xff)
+    }
+
+    /// `LINUX_VERSION_CODE`, with the sublevel saturated at 255 the way
+    /// `KERNEL_VERSION()` does since 4.9.256.
+    pub fn code(&self) -> u32 {
+        self.version << 16 | self.patchlevel.min(0xff) << 8 | self.sublevel.min(0xff)
+    }
+}
+
+impl Display for KernelVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.version, self.patchlevel, self.sublevel)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseKernelVersionError(String);
+
+impl Display for ParseKernelVersionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid kernel version: {:?}", self.0)
+    }
+}
+
+impl Error for ParseKernelVersionError {}
+
+impl FromStr for KernelVersion {
+    type Err = ParseKernelVersionError;
+
+    /// Parses a kernel release, ignoring local suffixes such as
+    /// `-generic`, `+` or `-1.el7.elrepo.x86_64`.
+    fn from_str(release: &str) -> Result<KernelVersion, ParseKernelVersionError> {
+        parse_version(release)
+            .map(|(version, patchlevel, sublevel)| KernelVersion::new(version, patchlevel, sublevel))
+            .ok_or_else(|| ParseKernelVersionError(release.to_string()))
+    }
+}
+
+/// The release of the running kernel, as `uname -r` prints it.
+fn release() -> Option<String> {
+    let mut uts: libc::utsname = unsafe { mem::zeroed() };
+    if unsafe { libc::uname(&mut uts) } != 0 {
+        return None;
+    }
+    Some(unsafe { CStr::from_ptr(uts.release.as_ptr()) }.to_string_lossy().into_owned())
+}
+
+fn parse_version_signature(signature: &str) -> Option<String> {
+    let parts: Vec<_> = signature.split(' ').collect();
+    if parts.len() != 3 {
+        return None;
+    }
+
+    parts.last().map(|v| <&str>::clone(v).into())
+}
+
+fn parse_version(version: &str) -> Option<(u32, u32, u32)> {
+    let end = version
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(version.len());
+    let parts: Vec<_> = version[..end].split('.').map(u32::from_str).collect::<Result<_, _>>().ok()?;
+    match parts[..] {
+        [major, minor, patch] => Some((major, minor, patch)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_version() {
+        assert_eq!(parse_version("4.15.18"), Some((4, 15, 18)));
+        assert_eq!(parse_version("4.15.1-generic"), Some((4, 15, 1)));
+        assert_eq!(parse_version("4.15.1-generic-foo"), Some((4, 15, 1)));
+        assert_eq!(parse_version("4.14.138+"), Some((4, 14, 138)));
+        assert_eq!(parse_version("5.11.6-1.el7.elrepo.x86_64"), Some((5, 11, 6)));
+        assert_eq!(parse_version("3.10.0.el7"), None);
+        assert_eq!(parse_version("4.3.2.1"), None);
+        assert_eq!(parse_version("4.2.foo"), None);
+        assert_eq!(parse_version("4.2."), None);
+        assert_eq!(parse_version("4.2"), None);
+        assert_eq!(parse_version("foo"), None);
+        assert_eq!(parse_version(""), None);
+    }
+
+    #[test]
+    fn test_parse_version_signature() {
+        assert_eq!(parse_version_signature("Ubuntu 4.15.0-55.60-generic 4.15.18"), Some("4.15.18".into()));
+        assert_eq!(parse_version_signature("Ubuntu 4.15.0-55.60-generic 4.15.18 foo"), None);
+        assert_eq!(parse_version_signature("Ubuntu 4.15.0-55.60-generic"), None);
+    }
+
+    #[test]
+    fn test_kernel_version() {
+        let version: KernelVersion = "4.9.337-generic".parse().unwrap();
+        assert_eq!(version, KernelVersion::new(4, 9, 337));
+        assert_eq!(version.to_string(), "4.9.337");
+        assert_eq!(version.code(), 0x0409ff);
+        assert_eq!(KernelVersion::from_code(0x050a00), KernelVersion::new(5, 10, 0));
+        assert!(KernelVersion::new(4, 9, 337) < KernelVersion::new(4, 10, 0));
+        assert!(KernelVersion::new(5, 4, 0) > "4.19.0+".parse().unwrap());
+        assert!("4.2".parse::<KernelVersion>().is_err());
+        assert!(KernelVersion::running().is_some());
+    }
+}
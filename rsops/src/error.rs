@@ -0,0 +1,78 @@
+use std::{
+    error,
+    fmt::{self, Display},
+    io, result,
+};
+
+pub type Result<T> = result::Result<T, Error>;
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    Syscall(&'static str, io::Error),
+    Elf(String),
+    NotFound(String),
+    Invalid(String),
+    Load {
+        program: String,
+        error: io::Error,
+        log: String,
+        /// `log` explained against the program's instructions and source
+        /// lines, for programs loaded from an object file
+        report: Option<String>,
+    },
+    /// A program run by the userspace vm faulted at `insn`.
+    Vm {
+        insn: usize,
+        reason: String,
+    },
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "{}", e),
+            Error::Syscall(op, e) => write!(f, "{} failed: {}", op, e),
+            Error::Elf(msg) => write!(f, "invalid ELF object: {}", msg),
+            Error::NotFound(what) => write!(f, "{} not found", what),
+            Error::Invalid(msg) => write!(f, "{}", msg),
+            Error::Load {
+                program,
+                error,
+                log,
+                report,
+            } => {
+                write!(f, "loading program {} failed: {}", program, error)?;
+                match report {
+                    Some(report) => write!(f, "\n{}", report.trim_end())?,
+                    None if !log.is_empty() => write!(f, "\n{}", log.trim_end())?,
+                    None => {}
+                }
+                Ok(())
+            }
+            Error::Vm { insn, reason } => write!(f, "vm: insn {}: {}", insn, reason),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            Error::Io(e) | Error::Syscall(_, e) => Some(e),
+            Error::Load { error, .. } => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Error {
+        Error::Io(e)
+    }
+}
+
+impl From<goblin::error::Error> for Error {
+    fn from(e: goblin::error::Error) -> Error {
+        Error::Elf(e.to_string())
+    }
+}
@@ -0,0 +1,5 @@
+pub mod error;
+pub mod module;
+pub mod sys;
+
+pub use error::{Error, Result};
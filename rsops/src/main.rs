@@ -1,11 +1,344 @@
-mod lib;
-mod module;
-use lib::libbpf;
-use module::bpf;
+use rsops::module::cfg::Limits;
+use rsops::module::compat::{self, Report};
+use rsops::module::object::ObjectFile;
+use rsops::module::btf::{self, Btf};
+use rsops::module::{
+    bpf, btf_dump, btf_rust, core_reloc, dump, features, inventory, skeleton, tag,
+};
+use rsops::sys::libbpf;
+use kernel_version::KernelVersion;
+use std::env;
+use std::fs;
+use std::process;
+
+fn show_inventory(what: Option<&str>) -> rsops::Result<()> {
+    if what != Some("map") {
+        for prog in inventory::programs()? {
+            println!("{}", prog);
+        }
+    }
+    if what != Some("prog") {
+        for map in inventory::maps()? {
+            println!("{}", map);
+        }
+    }
+    Ok(())
+}
+
+fn show_dump(id: Option<&str>, jited: bool) -> rsops::Result<()> {
+    let id = id
+        .and_then(|id| id.parse().ok())
+        .ok_or_else(|| rsops::Error::Invalid("usage: rsops dump <prog id> [jited]".to_string()))?;
+    let dump = dump::dump_by_id(id)?;
+    if jited {
+        print!("{}", dump.jited_hex());
+    } else {
+        print!("{}", dump);
+    }
+    Ok(())
+}
+
+fn show_disasm(path: Option<&str>, section: Option<&str>) -> rsops::Result<()> {
+    let path = path
+        .ok_or_else(|| rsops::Error::Invalid("usage: rsops disasm <elf> [section]".to_string()))?;
+    let object = ObjectFile::open(path)?;
+    let programs: Vec<_> = object
+        .programs
+        .iter()
+        .filter(|p| section.is_none_or(|s| p.section == s))
+        .collect();
+    if programs.is_empty() {
+        return Err(rsops::Error::NotFound(format!(
+            "section {}",
+            section.unwrap_or("with programs")
+        )));
+    }
+    for program in programs {
+        println!("{}: {}", program.section, program.name);
+        print!("{}", program.disassemble());
+    }
+    Ok(())
+}
+
+fn show_cfg(target: Option<&str>, arg: Option<&str>) -> rsops::Result<()> {
+    let usage = || {
+        rsops::Error::Invalid("usage: rsops cfg <elf> [section] | rsops cfg prog <id>".to_string())
+    };
+    match target.ok_or_else(usage)? {
+        "prog" => {
+            let id = arg.and_then(|id| id.parse().ok()).ok_or_else(usage)?;
+            print!("{}", dump::dump_by_id(id)?.to_dot());
+        }
+        path => {
+            let object = ObjectFile::open(path)?;
+            let mut found = false;
+            for program in &object.programs {
+                if arg.is_none_or(|s| program.section == s) {
+                    print!("{}", program.to_dot());
+                    found = true;
+                }
+            }
+            if !found {
+                return Err(rsops::Error::NotFound(format!(
+                    "section {}",
+                    arg.unwrap_or("with programs")
+                )));
+            }
+        }
+    }
+    Ok(())
+}
+
+fn show_tags(path: Option<&str>, section: Option<&str>) -> rsops::Result<()> {
+    let path =
+        path.ok_or_else(|| rsops::Error::Invalid("usage: rsops tag <elf> [section]".to_string()))?;
+    let object = ObjectFile::open(path)?;
+    for program in &object.programs {
+        if section.is_some_and(|s| program.section != s) {
+            continue;
+        }
+        let insns = object.relocated_insns(program)?;
+        let tag = tag::prog_tag(&insns, tag::TagHash::running());
+        let loaded: Vec<_> = tag::find_loaded(&insns)?
+            .iter()
+            .map(|p| p.id.to_string())
+            .collect();
+        print!("{}: {}  tag {}", program.section, program.name, tag::tag_to_string(&tag));
+        if loaded.is_empty() {
+            println!("  not loaded");
+        } else {
+            println!("  loaded as {}", loaded.join(","));
+        }
+    }
+    Ok(())
+}
+
+fn check(path: Option<&str>, kernel: Option<&str>) -> rsops::Result<()> {
+    let usage = || rsops::Error::Invalid("usage: rsops check <elf> [kernel release]".to_string());
+    let object = ObjectFile::open(path.ok_or_else(usage)?)?;
+    let limits = match kernel {
+        Some(release) => Limits::for_kernel(release.parse().map_err(|_| usage())?),
+        None => Limits::running(),
+    };
+    let mut count = 0;
+    for program in &object.programs {
+        for problem in program.check(&limits) {
+            println!("{}: {}: {}", program.section, program.name, problem);
+            count += 1;
+        }
+    }
+    if count > 0 {
+        return Err(rsops::Error::Invalid(format!("{} problems found", count)));
+    }
+    Ok(())
+}
+
+fn show_core(path: Option<&str>, btf: Option<&str>) -> rsops::Result<()> {
+    let path =
+        path.ok_or_else(|| rsops::Error::Invalid("usage: rsops core <elf> [btf]".to_string()))?;
+    let object = ObjectFile::open(path)?;
+    let local = match &object.btf {
+        Some(local) => local,
+        None => return Err(rsops::Error::NotFound(format!("BTF in {}", path))),
+    };
+    let target = match btf {
+        Some(btf) => Btf::parse(&fs::read(btf)?)?,
+        None => Btf::vmlinux()?,
+    };
+    for program in object.programs.iter().filter(|p| !p.core_relocs.is_empty()) {
+        println!("{}: {}", program.section, program.name);
+        for reloc in &program.core_relocs {
+            let ty = local.type_by_id(reloc.type_id)?;
+            let resolved = match core_reloc::resolve(local, &target, reloc) {
+                Ok(resolution) => match resolution.target {
+                    Some(value) => format!("{} -> {}", resolution.local, value),
+                    None => format!("{} -> poisoned", resolution.local),
+                },
+                Err(e) => format!("error: {}", e),
+            };
+            println!(
+                "  insn {}: {} of {} {} {}: {}",
+                reloc.insn_off,
+                reloc.kind.name(),
+                ty.kind_name(),
+                ty.name,
+                reloc.access,
+                resolved
+            );
+        }
+    }
+    Ok(())
+}
+
+fn show_compat(path: Option<&str>, fleet: Option<&str>) -> rsops::Result<()> {
+    let path = path
+        .ok_or_else(|| rsops::Error::Invalid("usage: rsops compat <elf> [fleet]".to_string()))?;
+    let report = Report::for_object(&ObjectFile::open(path)?);
+    let kernel = report.min_kernel();
+    println!("needs kernel {}.{}", kernel.version, kernel.patchlevel);
+    for requirement in &report.requirements {
+        println!("  {}", requirement);
+    }
+    let hosts = match fleet {
+        Some(fleet) => compat::parse_fleet(&std::fs::read_to_string(fleet)?)?,
+        None => {
+            let kernel = KernelVersion::running()
+                .ok_or_else(|| rsops::Error::Invalid("unknown kernel release".to_string()))?;
+            vec![compat::Host {
+                name: "localhost".to_string(),
+                release: kernel.to_string(),
+                kernel,
+            }]
+        }
+    };
+    for host in hosts {
+        match report.missing(host.kernel).first() {
+            None => println!("{} ({}): ok", host.name, host.release),
+            Some(missing) => println!(
+                "{} ({}): needs {}.{} for {}",
+                host.name,
+                host.release,
+                missing.since.version,
+                missing.since.patchlevel,
+                missing.what
+            ),
+        }
+    }
+    Ok(())
+}
+
+fn show_features(format: Option<&str>) -> rsops::Result<()> {
+    let features = features::probe()?;
+    match format {
+        None => print!("{}", features),
+        Some("json") => println!("{}", features.to_json()),
+        Some(_) => {
+            return Err(rsops::Error::Invalid(
+                "usage: rsops features [json]".to_string(),
+            ))
+        }
+    }
+    Ok(())
+}
+
+fn show_btf(what: Option<&str>, arg: Option<&str>, module: Option<&str>) -> rsops::Result<()> {
+    let usage = || {
+        rsops::Error::Invalid(
+            "usage: rsops btf header [module] | find <name> [module] | modules".to_string(),
+        )
+    };
+    let load = |module: Option<&str>| -> rsops::Result<Btf> {
+        let vmlinux = Btf::vmlinux()?;
+        match module {
+            Some(module) => Btf::kernel_module(module, &vmlinux),
+            None => Ok(vmlinux),
+        }
+    };
+    match what {
+        Some("header") => {
+            let btf = load(arg)?;
+            print!("{}", btf_dump::c_header(&btf, "__VMLINUX_H__")?);
+        }
+        Some("find") => {
+            let btf = load(module)?;
+            let name = arg.ok_or_else(usage)?;
+            let mut ids = btf.find_all_by_name(name).peekable();
+            if ids.peek().is_none() {
+                return Err(rsops::Error::NotFound(format!("BTF type {}", name)));
+            }
+            for id in ids {
+                let ty = btf.type_by_id(id)?;
+                match btf.type_size(id) {
+                    Ok(size) => println!("[{}] {} {} size {}", id, ty.kind_name(), name, size),
+                    Err(_) => println!("[{}] {} {}", id, ty.kind_name(), name),
+                }
+            }
+        }
+        Some("modules") => {
+            for module in btf::kernel_modules()? {
+                println!("{}", module);
+            }
+        }
+        _ => return Err(usage()),
+    }
+    Ok(())
+}
+
+fn show_bindings(path: Option<&str>, types: &[String]) -> rsops::Result<()> {
+    let path = path.ok_or_else(|| {
+        rsops::Error::Invalid("usage: rsops bindings <elf> [type...]".to_string())
+    })?;
+    let object = ObjectFile::open(path)?;
+    let btf = match &object.btf {
+        Some(btf) => btf,
+        None => return Err(rsops::Error::NotFound(format!("BTF in {}", path))),
+    };
+    let types: Vec<&str> = types.iter().map(String::as_str).collect();
+    print!("{}", btf_rust::rust_types(btf, &types)?);
+    Ok(())
+}
+
+fn show_skeleton(path: Option<&str>) -> rsops::Result<()> {
+    let path =
+        path.ok_or_else(|| rsops::Error::Invalid("usage: rsops skeleton <elf>".to_string()))?;
+    print!("{}", skeleton::generate(path)?);
+    Ok(())
+}
+
 fn main() {
-    libbpf::new_bpf("/lib/modules/5.11.6-1.el7.elrepo.x86_64/source/main.elf");
-    println!("------");
-    bpf::parse("/lib/modules/5.11.6-1.el7.elrepo.x86_64/source/main.elf");
-    libbpf::bpf_attach_kprobe();
-    println!("Hello, world!");
+    let args: Vec<String> = env::args().collect();
+    let res = match args.get(1).map(String::as_str) {
+        Some("inventory") => show_inventory(args.get(2).map(String::as_str)),
+        Some("disasm") => show_disasm(
+            args.get(2).map(String::as_str),
+            args.get(3).map(String::as_str),
+        ),
+        Some("tag") => show_tags(
+            args.get(2).map(String::as_str),
+            args.get(3).map(String::as_str),
+        ),
+        Some("cfg") => show_cfg(
+            args.get(2).map(String::as_str),
+            args.get(3).map(String::as_str),
+        ),
+        Some("compat") => show_compat(
+            args.get(2).map(String::as_str),
+            args.get(3).map(String::as_str),
+        ),
+        Some("core") => show_core(
+            args.get(2).map(String::as_str),
+            args.get(3).map(String::as_str),
+        ),
+        Some("check") => check(
+            args.get(2).map(String::as_str),
+            args.get(3).map(String::as_str),
+        ),
+        Some("features") => show_features(args.get(2).map(String::as_str)),
+        Some("btf") => show_btf(
+            args.get(2).map(String::as_str),
+            args.get(3).map(String::as_str),
+            args.get(4).map(String::as_str),
+        ),
+        Some("bindings") => show_bindings(
+            args.get(2).map(String::as_str),
+            args.get(3..).unwrap_or_default(),
+        ),
+        Some("skeleton") => show_skeleton(args.get(2).map(String::as_str)),
+        Some("dump") => show_dump(
+            args.get(2).map(String::as_str),
+            args.get(3).map(String::as_str) == Some("jited"),
+        ),
+        _ => {
+            libbpf::new_bpf("/lib/modules/5.11.6-1.el7.elrepo.x86_64/source/main.elf");
+            println!("------");
+            bpf::parse("/lib/modules/5.11.6-1.el7.elrepo.x86_64/source/main.elf");
+            libbpf::bpf_attach_kprobe();
+            println!("Hello, world!");
+            Ok(())
+        }
+    };
+    if let Err(e) = res {
+        eprintln!("rsops: {}", e);
+        process::exit(1);
+    }
 }
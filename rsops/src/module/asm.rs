@@ -0,0 +1,822 @@
+//! Writing programs by hand: an instruction [`Builder`] and a text
+//! [`assemble`]r that accepts the syntax printed by [`disasm`].
+//!
+//! ```no_run
+//! use rsops::module::asm::*;
+//! use rsops::module::helpers::Helper;
+//! use rsops::module::map::{Map, MapDef, MapType};
+//! use rsops::module::program::{Program, ProgramType};
+//! # fn main() -> rsops::Result<()> {
+//! let map = Map::create(
+//!     "counts",
+//!     &MapDef {
+//!         map_type: MapType::Array,
+//!         key_size: 4,
+//!         value_size: 8,
+//!         max_entries: 1,
+//!         map_flags: 0,
+//!     },
+//! )?;
+//! let mut b = Builder::new();
+//! b.mov64_imm(R0, 0)
+//!     .st_mem(BPF_W, R10, -4, 0)
+//!     .mov64_reg(R2, R10)
+//!     .alu64_imm(BPF_ADD, R2, -4)
+//!     .ld_map_fd(R1, &map)
+//!     .call(Helper::MapLookupElem)
+//!     .jeq_imm(R0, 0, "out")
+//!     .mov64_imm(R1, 1)
+//!     .atomic_add(BPF_DW, R0, 0, R1)
+//!     .label("out")
+//!     .mov64_imm(R0, 2)
+//!     .exit();
+//! let program = Program::load("count", ProgramType::Xdp, &b.build()?, "GPL", 0)?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! [`disasm`]: crate::module::disasm
+
+use crate::error::{Error, Result};
+use crate::module::helpers::Helper;
+pub use crate::module::insn::*;
+use crate::module::map::Map;
+use crate::module::object::{MapReloc, ProgramSpec};
+use crate::module::program::ProgramType;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::os::unix::io::AsRawFd;
+
+pub const R0: u8 = 0;
+pub const R1: u8 = 1;
+pub const R2: u8 = 2;
+pub const R3: u8 = 3;
+pub const R4: u8 = 4;
+pub const R5: u8 = 5;
+pub const R6: u8 = 6;
+pub const R7: u8 = 7;
+pub const R8: u8 = 8;
+pub const R9: u8 = 9;
+/// the read only frame pointer
+pub const R10: u8 = 10;
+
+/// Jump target: a label or an offset relative to the next instruction.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Target {
+    Label(String),
+    Offset(i16),
+}
+
+impl From<&str> for Target {
+    fn from(label: &str) -> Target {
+        Target::Label(label.to_string())
+    }
+}
+
+impl From<i16> for Target {
+    fn from(off: i16) -> Target {
+        Target::Offset(off)
+    }
+}
+
+/// Accumulates instructions, resolving label jumps in [`Builder::build`].
+#[derive(Debug, Clone, Default)]
+pub struct Builder {
+    insns: Vec<Insn>,
+    labels: HashMap<String, usize>,
+    jumps: Vec<(usize, String)>,
+    map_relocs: Vec<MapReloc>,
+}
+
+impl Builder {
+    pub fn new() -> Builder {
+        Builder::default()
+    }
+
+    /// Appends a raw instruction.
+    pub fn insn(&mut self, insn: Insn) -> &mut Builder {
+        self.insns.push(insn);
+        self
+    }
+
+    /// Number of instructions emitted so far, `ld_imm64` counting twice.
+    pub fn len(&self) -> usize {
+        self.insns.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.insns.is_empty()
+    }
+
+    /// Marks the position of the next instruction as `name`.
+    pub fn label(&mut self, name: &str) -> &mut Builder {
+        self.labels.insert(name.to_string(), self.insns.len());
+        self
+    }
+
+    pub fn alu64_imm(&mut self, op: u8, dst: u8, imm: i32) -> &mut Builder {
+        self.insn(Insn::new(BPF_ALU64 | op | BPF_K, dst, 0, 0, imm))
+    }
+
+    pub fn alu64_reg(&mut self, op: u8, dst: u8, src: u8) -> &mut Builder {
+        self.insn(Insn::new(BPF_ALU64 | op | BPF_X, dst, src, 0, 0))
+    }
+
+    pub fn alu32_imm(&mut self, op: u8, dst: u8, imm: i32) -> &mut Builder {
+        self.insn(Insn::new(BPF_ALU | op | BPF_K, dst, 0, 0, imm))
+    }
+
+    pub fn alu32_reg(&mut self, op: u8, dst: u8, src: u8) -> &mut Builder {
+        self.insn(Insn::new(BPF_ALU | op | BPF_X, dst, src, 0, 0))
+    }
+
+    pub fn mov64_imm(&mut self, dst: u8, imm: i32) -> &mut Builder {
+        self.alu64_imm(BPF_MOV, dst, imm)
+    }
+
+    pub fn mov64_reg(&mut self, dst: u8, src: u8) -> &mut Builder {
+        self.alu64_reg(BPF_MOV, dst, src)
+    }
+
+    pub fn mov32_imm(&mut self, dst: u8, imm: i32) -> &mut Builder {
+        self.alu32_imm(BPF_MOV, dst, imm)
+    }
+
+    pub fn mov32_reg(&mut self, dst: u8, src: u8) -> &mut Builder {
+        self.alu32_reg(BPF_MOV, dst, src)
+    }
+
+    /// Loads a 64 bit constant, taking two instruction slots.
+    pub fn ld_imm64(&mut self, dst: u8, imm: u64) -> &mut Builder {
+        self.ld_imm64_raw(dst, 0, imm as i32, (imm >> 32) as i32)
+    }
+
+    fn ld_imm64_raw(&mut self, dst: u8, src: u8, lo: i32, hi: i32) -> &mut Builder {
+        self.insn(Insn::new(BPF_LD | BPF_IMM | BPF_DW, dst, src, 0, lo))
+            .insn(Insn::new(0, 0, 0, 0, hi))
+    }
+
+    /// Loads a reference to `map`, which must stay open until the program
+    /// is loaded.
+    pub fn ld_map_fd(&mut self, dst: u8, map: &Map) -> &mut Builder {
+        self.ld_imm64_raw(dst, BPF_PSEUDO_MAP_FD, map.as_raw_fd(), 0)
+    }
+
+    /// Loads a reference to the map called `name`, resolved when the
+    /// program is loaded as part of an [`ObjectFile`].
+    ///
+    /// [`ObjectFile`]: crate::module::object::ObjectFile
+    pub fn ld_map(&mut self, dst: u8, name: &str) -> &mut Builder {
+        self.map_relocs.push(MapReloc {
+            insn: self.insns.len(),
+            map: name.to_string(),
+        });
+        self.ld_imm64_raw(dst, 0, 0, 0)
+    }
+
+    /// `dst = *(size *)(src + off)`
+    pub fn ldx_mem(&mut self, size: u8, dst: u8, src: u8, off: i16) -> &mut Builder {
+        self.insn(Insn::new(BPF_LDX | BPF_MEM | size, dst, src, off, 0))
+    }
+
+    /// `*(size *)(dst + off) = src`
+    pub fn stx_mem(&mut self, size: u8, dst: u8, off: i16, src: u8) -> &mut Builder {
+        self.insn(Insn::new(BPF_STX | BPF_MEM | size, dst, src, off, 0))
+    }
+
+    /// `*(size *)(dst + off) = imm`
+    pub fn st_mem(&mut self, size: u8, dst: u8, off: i16, imm: i32) -> &mut Builder {
+        self.insn(Insn::new(BPF_ST | BPF_MEM | size, dst, 0, off, imm))
+    }
+
+    /// `lock *(size *)(dst + off) += src`
+    pub fn atomic_add(&mut self, size: u8, dst: u8, off: i16, src: u8) -> &mut Builder {
+        self.insn(Insn::new(BPF_STX | BPF_ATOMIC | size, dst, src, off, 0))
+    }
+
+    fn jump(&mut self, mut insn: Insn, target: Target) -> &mut Builder {
+        match target {
+            Target::Offset(off) => insn.off = off,
+            Target::Label(label) => self.jumps.push((self.insns.len(), label)),
+        }
+        self.insn(insn)
+    }
+
+    pub fn ja<T: Into<Target>>(&mut self, target: T) -> &mut Builder {
+        self.jump(Insn::new(BPF_JMP | BPF_JA, 0, 0, 0, 0), target.into())
+    }
+
+    /// `if dst <op> imm goto target`, 64 bit compare.
+    pub fn jmp_imm<T: Into<Target>>(
+        &mut self,
+        op: u8,
+        dst: u8,
+        imm: i32,
+        target: T,
+    ) -> &mut Builder {
+        self.jump(
+            Insn::new(BPF_JMP | op | BPF_K, dst, 0, 0, imm),
+            target.into(),
+        )
+    }
+
+    /// `if dst <op> src goto target`, 64 bit compare.
+    pub fn jmp_reg<T: Into<Target>>(
+        &mut self,
+        op: u8,
+        dst: u8,
+        src: u8,
+        target: T,
+    ) -> &mut Builder {
+        self.jump(
+            Insn::new(BPF_JMP | op | BPF_X, dst, src, 0, 0),
+            target.into(),
+        )
+    }
+
+    /// `if wdst <op> imm goto target`, 32 bit compare.
+    pub fn jmp32_imm<T: Into<Target>>(
+        &mut self,
+        op: u8,
+        dst: u8,
+        imm: i32,
+        target: T,
+    ) -> &mut Builder {
+        self.jump(
+            Insn::new(BPF_JMP32 | op | BPF_K, dst, 0, 0, imm),
+            target.into(),
+        )
+    }
+
+    /// `if wdst <op> wsrc goto target`, 32 bit compare.
+    pub fn jmp32_reg<T: Into<Target>>(
+        &mut self,
+        op: u8,
+        dst: u8,
+        src: u8,
+        target: T,
+    ) -> &mut Builder {
+        self.jump(
+            Insn::new(BPF_JMP32 | op | BPF_X, dst, src, 0, 0),
+            target.into(),
+        )
+    }
+
+    pub fn jeq_imm<T: Into<Target>>(&mut self, dst: u8, imm: i32, target: T) -> &mut Builder {
+        self.jmp_imm(BPF_JEQ, dst, imm, target)
+    }
+
+    pub fn jne_imm<T: Into<Target>>(&mut self, dst: u8, imm: i32, target: T) -> &mut Builder {
+        self.jmp_imm(BPF_JNE, dst, imm, target)
+    }
+
+    pub fn call(&mut self, helper: Helper) -> &mut Builder {
+        self.insn(Insn::new(BPF_JMP | BPF_CALL, 0, 0, 0, helper as i32))
+    }
+
+    pub fn exit(&mut self) -> &mut Builder {
+        self.insn(Insn::new(BPF_JMP | BPF_EXIT, 0, 0, 0, 0))
+    }
+
+    fn resolve(&self) -> Result<Vec<Insn>> {
+        let mut insns = self.insns.clone();
+        for (idx, label) in &self.jumps {
+            let target = *self
+                .labels
+                .get(label)
+                .ok_or_else(|| Error::NotFound(format!("label {}", label)))?;
+            let off = target as i64 - *idx as i64 - 1;
+            let insn = &mut insns[*idx];
+            if insn.class() == BPF_JMP32 && insn.op() == BPF_JA {
+                insn.imm = off as i32;
+            } else {
+                insn.off = i16::try_from(off).map_err(|_| {
+                    Error::Invalid(format!("jump to {} at {} out of range", label, idx))
+                })?;
+            }
+        }
+        Ok(insns)
+    }
+
+    /// Resolves labels into the final instructions. Fails if a map is
+    /// referenced by name, use [`Builder::into_spec`] for those.
+    pub fn build(&self) -> Result<Vec<Insn>> {
+        if let Some(reloc) = self.map_relocs.first() {
+            return Err(Error::Invalid(format!(
+                "map {} is referenced by name, load the program through an object",
+                reloc.map
+            )));
+        }
+        self.resolve()
+    }
+
+    /// Wraps the program into a spec that can be added to an
+    /// [`ObjectFile`](crate::module::object::ObjectFile).
+    pub fn into_spec(&self, name: &str, prog_type: ProgramType) -> Result<ProgramSpec> {
+        Ok(ProgramSpec {
+            name: name.to_string(),
+            section: prog_type.name().to_string(),
+            offset: 0,
+            prog_type,
+            insns: self.resolve()?,
+            map_relocs: self.map_relocs.clone(),
+            line_info: Vec::new(),
+            core_relocs: Vec::new(),
+            problems: Vec::new(),
+        })
+    }
+
+    pub fn map_relocs(&self) -> &[MapReloc] {
+        &self.map_relocs
+    }
+}
+
+fn syntax(line: &str) -> Error {
+    Error::Invalid(format!("cannot assemble `{}`", line))
+}
+
+fn parse_imm(s: &str) -> Option<i64> {
+    let s = s.trim();
+    let (neg, digits) = match s.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, s),
+    };
+    let value = match digits.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16).ok()? as i64,
+        None => digits.parse::<u64>().ok()? as i64,
+    };
+    Some(if neg { value.wrapping_neg() } else { value })
+}
+
+/// Parses a 32 bit immediate, accepting both signed and unsigned spellings.
+fn parse_imm32(s: &str) -> Option<i32> {
+    let value = parse_imm(s)?;
+    if value >= i64::from(i32::MIN) && value <= i64::from(u32::MAX) {
+        Some(value as i32)
+    } else {
+        None
+    }
+}
+
+/// Parses `rN` or `wN`, returning the register and whether it is 64 bit.
+fn parse_reg(s: &str) -> Option<(u8, bool)> {
+    let s = s.trim();
+    let wide = match s.chars().next()? {
+        'r' => true,
+        'w' => false,
+        _ => return None,
+    };
+    let reg = s[1..].parse().ok()?;
+    if reg <= 10 {
+        Some((reg, wide))
+    } else {
+        None
+    }
+}
+
+fn parse_size(s: &str) -> Option<(u8, bool)> {
+    let signed = s.starts_with('s');
+    let size = match &s[1..] {
+        "8" => BPF_B,
+        "16" => BPF_H,
+        "32" => BPF_W,
+        "64" => BPF_DW,
+        _ => return None,
+    };
+    Some((size, signed))
+}
+
+/// Parses `(u32 *)(r10 -4)` into size, signedness, base register and
+/// offset.
+fn parse_ptr(s: &str) -> Option<(u8, bool, u8, i16)> {
+    let s = s.trim().strip_prefix('(')?;
+    let (size, rest) = s.split_once(" *)(")?;
+    let (size, signed) = parse_size(size)?;
+    let rest = rest.strip_suffix(')')?;
+    let (base, off) = rest.split_once(' ')?;
+    let (base, true) = parse_reg(base)? else {
+        return None;
+    };
+    let off = parse_imm(off.trim_start_matches('+'))?;
+    Some((size, signed, base, i16::try_from(off).ok()?))
+}
+
+fn parse_target(s: &str) -> Option<Target> {
+    let s = s.trim();
+    match s.strip_prefix("pc") {
+        Some(off) => Some(Target::Offset(
+            i16::try_from(parse_imm(off.trim_start_matches('+'))?).ok()?,
+        )),
+        None if !s.is_empty() && s.chars().all(|c| c.is_alphanumeric() || c == '_') => {
+            Some(Target::Label(s.to_string()))
+        }
+        None => None,
+    }
+}
+
+fn pc_offset(s: &str) -> Option<i32> {
+    parse_imm32(s.trim().strip_prefix("pc")?.trim_start_matches('+'))
+}
+
+const ALU_OPS: [(&str, u8); 14] = [
+    ("s>>=", BPF_ARSH),
+    ("<<=", BPF_LSH),
+    (">>=", BPF_RSH),
+    ("s/=", BPF_DIV),
+    ("s%=", BPF_MOD),
+    ("+=", BPF_ADD),
+    ("-=", BPF_SUB),
+    ("*=", BPF_MUL),
+    ("/=", BPF_DIV),
+    ("|=", BPF_OR),
+    ("&=", BPF_AND),
+    ("%=", BPF_MOD),
+    ("^=", BPF_XOR),
+    ("=", BPF_MOV),
+];
+
+const JMP_OPS: [(&str, u8); 11] = [
+    ("==", BPF_JEQ),
+    (">", BPF_JGT),
+    (">=", BPF_JGE),
+    ("&", BPF_JSET),
+    ("!=", BPF_JNE),
+    ("s>", BPF_JSGT),
+    ("s>=", BPF_JSGE),
+    ("<", BPF_JLT),
+    ("<=", BPF_JLE),
+    ("s<", BPF_JSLT),
+    ("s<=", BPF_JSLE),
+];
+
+const ATOMIC_OPS: [(&str, i32); 4] = [("add", 0x00), ("or", 0x40), ("and", 0x50), ("xor", 0xa0)];
+
+fn assemble_call(b: &mut Builder, operand: &str) -> Option<()> {
+    let insn = if let Some(off) = pc_offset(operand) {
+        Insn::new(BPF_JMP | BPF_CALL, 0, BPF_PSEUDO_CALL, 0, off)
+    } else if let Some(id) = operand.strip_prefix("kfunc#") {
+        Insn::new(
+            BPF_JMP | BPF_CALL,
+            0,
+            BPF_PSEUDO_KFUNC_CALL,
+            0,
+            parse_imm32(id)?,
+        )
+    } else {
+        let id = match operand.split_once('#') {
+            Some((_, id)) => parse_imm32(id)?,
+            None => Helper::from_name(operand)? as i32,
+        };
+        Insn::new(BPF_JMP | BPF_CALL, 0, 0, 0, id)
+    };
+    b.insn(insn);
+    Some(())
+}
+
+fn assemble_jmp(b: &mut Builder, cond: &str) -> Option<()> {
+    // `if r1 == 0x0 goto pc+3`
+    let (cond, target) = cond.split_once(" goto ")?;
+    let mut parts = cond.split_whitespace();
+    let (dst, wide) = parse_reg(parts.next()?)?;
+    let op = parts.next()?;
+    let src = parts.next()?;
+    let op = JMP_OPS.iter().find(|(name, _)| *name == op)?.1;
+    let class = if wide { BPF_JMP } else { BPF_JMP32 };
+    let insn = match parse_reg(src) {
+        Some((src, _)) => Insn::new(class | op | BPF_X, dst, src, 0, 0),
+        None => Insn::new(class | op | BPF_K, dst, 0, 0, parse_imm32(src)?),
+    };
+    b.jump(insn, parse_target(target)?);
+    Some(())
+}
+
+/// `lock *(u64 *)(r1 +0) += r2`
+fn assemble_lock(b: &mut Builder, rest: &str) -> Option<()> {
+    let (ptr, rest) = rest.strip_prefix('*')?.split_once(") ")?;
+    let (size, false, dst, off) = parse_ptr(&format!("{})", ptr))? else {
+        return None;
+    };
+    let (op, src) = rest.split_once(' ')?;
+    let op = ALU_OPS.iter().find(|(name, _)| *name == op)?.1;
+    let imm = ATOMIC_OPS.iter().find(|(_, o)| *o == i32::from(op))?.1;
+    b.insn(Insn::new(
+        BPF_STX | BPF_ATOMIC | size,
+        dst,
+        parse_reg(src)?.0,
+        off,
+        imm,
+    ));
+    Some(())
+}
+
+/// `r2 = atomic64_fetch_add((u64 *)(r1 +0), r2)` and friends.
+fn assemble_atomic_call(b: &mut Builder, rhs: &str) -> Option<()> {
+    let rhs = rhs
+        .strip_prefix("atomic64_")
+        .or_else(|| rhs.strip_prefix("atomic_"))?;
+    let (name, args) = rhs.split_once('(')?;
+    let args = args.strip_suffix(')')?;
+    let (ptr, regs) = args.split_once("), ")?;
+    let (size, false, dst, off) = parse_ptr(&format!("{})", ptr))? else {
+        return None;
+    };
+    let src = parse_reg(regs.rsplit(", ").next()?)?.0;
+    let imm = match name {
+        "xchg" => BPF_XCHG,
+        "cmpxchg" => BPF_CMPXCHG,
+        _ => {
+            let name = name.strip_prefix("fetch_")?;
+            ATOMIC_OPS.iter().find(|(n, _)| *n == name)?.1 | BPF_FETCH
+        }
+    };
+    b.insn(Insn::new(BPF_STX | BPF_ATOMIC | size, dst, src, off, imm));
+    Some(())
+}
+
+/// `rN = map[...]` and the other `ld_imm64` forms.
+fn assemble_ld_imm64(b: &mut Builder, dst: u8, rhs: &str) -> Option<()> {
+    if let Some(value) = rhs.strip_suffix(" ll") {
+        b.ld_imm64(dst, parse_imm(value)? as u64);
+    } else if let Some(map) = rhs.strip_prefix("map[") {
+        let (map, value_off) = match map.split_once("][0]+") {
+            Some((map, off)) => (map, Some(parse_imm32(off)?)),
+            None => (map.strip_suffix(']')?, None),
+        };
+        let (src, imm) = match (map.split_once(':'), value_off) {
+            (Some(("id", id)), None) => (BPF_PSEUDO_MAP_FD, parse_imm32(id)?),
+            (Some(("id", id)), Some(_)) => (BPF_PSEUDO_MAP_VALUE, parse_imm32(id)?),
+            (Some(("idx", idx)), None) => (BPF_PSEUDO_MAP_IDX, parse_imm32(idx)?),
+            (Some(("idx", idx)), Some(_)) => (BPF_PSEUDO_MAP_IDX_VALUE, parse_imm32(idx)?),
+            (None, None) => {
+                b.ld_map(dst, map);
+                return Some(());
+            }
+            _ => return None,
+        };
+        b.ld_imm64_raw(dst, src, imm, value_off.unwrap_or(0));
+    } else if let Some(id) = rhs.strip_prefix("btf_id[") {
+        b.ld_imm64_raw(
+            dst,
+            BPF_PSEUDO_BTF_ID,
+            parse_imm32(id.strip_suffix(']')?)?,
+            0,
+        );
+    } else if let Some(target) = rhs.strip_prefix("func[") {
+        b.ld_imm64_raw(
+            dst,
+            BPF_PSEUDO_FUNC,
+            pc_offset(target.strip_suffix(']')?)?,
+            0,
+        );
+    } else {
+        return None;
+    }
+    Some(())
+}
+
+fn assemble_assign(b: &mut Builder, lhs: &str, op: &str, rhs: &str) -> Option<()> {
+    // stores: `*(u32 *)(r10 -4) = r1` or `= 0`
+    if let Some(ptr) = lhs.strip_prefix('*') {
+        let (size, false, dst, off) = parse_ptr(ptr)? else {
+            return None;
+        };
+        match parse_reg(rhs) {
+            Some((src, true)) => b.stx_mem(size, dst, off, src),
+            _ => b.st_mem(size, dst, off, parse_imm32(rhs)?),
+        };
+        return Some(());
+    }
+    let (dst, wide) = parse_reg(lhs)?;
+    let op = ALU_OPS.iter().find(|(name, _)| *name == op)?;
+    let signed_op = op.0.starts_with("s/") || op.0.starts_with("s%");
+    let class = if wide { BPF_ALU64 } else { BPF_ALU };
+    if op.1 == BPF_MOV {
+        if let Some(ptr) = rhs.strip_prefix('*') {
+            if let Some(skb) = ptr.strip_prefix('(').and_then(|p| p.split_once(" *)skb[")) {
+                let (size, _) = parse_size(skb.0)?;
+                let index = skb.1.strip_suffix(']')?;
+                let insn = match index.split_once(" + ") {
+                    Some((reg, imm)) => Insn::new(
+                        BPF_LD | BPF_IND | size,
+                        0,
+                        parse_reg(reg)?.0,
+                        0,
+                        parse_imm32(imm)?,
+                    ),
+                    None => Insn::new(BPF_LD | BPF_ABS | size, 0, 0, 0, parse_imm32(index)?),
+                };
+                b.insn(insn);
+                return Some(());
+            }
+            let (size, signed, src, off) = parse_ptr(ptr)?;
+            let mode = if signed { BPF_MEMSX } else { BPF_MEM };
+            b.insn(Insn::new(BPF_LDX | mode | size, dst, src, off, 0));
+            return Some(());
+        }
+        if rhs.starts_with("atomic") {
+            return assemble_atomic_call(b, rhs);
+        }
+        if wide && assemble_ld_imm64(b, dst, rhs).is_some() {
+            return Some(());
+        }
+        if let Some(neg) = rhs.strip_prefix('-') {
+            if parse_reg(neg) == Some((dst, wide)) {
+                b.insn(Insn::new(class | BPF_NEG, dst, 0, 0, 0));
+                return Some(());
+            }
+        }
+        for (kind, source) in [("be", BPF_TO_BE), ("le", BPF_TO_LE), ("bswap", BPF_TO_LE)] {
+            if let Some(rest) = rhs.strip_prefix(kind) {
+                if let Some((bits, reg)) = rest.split_once(' ') {
+                    if let (Ok(bits), Some((reg, _))) = (bits.parse(), parse_reg(reg)) {
+                        if reg == dst {
+                            b.insn(Insn::new(class | BPF_END | source, dst, 0, 0, bits));
+                            return Some(());
+                        }
+                    }
+                }
+            }
+        }
+        if let Some(rest) = rhs.strip_prefix("(s") {
+            let (bits, src) = rest.split_once(')')?;
+            let (src, _) = parse_reg(src)?;
+            b.insn(Insn::new(
+                class | BPF_MOV | BPF_X,
+                dst,
+                src,
+                bits.parse().ok()?,
+                0,
+            ));
+            return Some(());
+        }
+    }
+    let off = if signed_op { 1 } else { 0 };
+    let insn = match parse_reg(rhs) {
+        Some((src, _)) => Insn::new(class | op.1 | BPF_X, dst, src, off, 0),
+        None => Insn::new(class | op.1 | BPF_K, dst, 0, off, parse_imm32(rhs)?),
+    };
+    b.insn(insn);
+    Some(())
+}
+
+fn assemble_line(b: &mut Builder, line: &str) -> Option<()> {
+    if line == "exit" {
+        b.exit();
+        return Some(());
+    }
+    if let Some(target) = line.strip_prefix("goto ") {
+        b.ja(parse_target(target)?);
+        return Some(());
+    }
+    if let Some(target) = line.strip_prefix("gotol ") {
+        b.insn(Insn::new(BPF_JMP32 | BPF_JA, 0, 0, 0, pc_offset(target)?));
+        return Some(());
+    }
+    if let Some(operand) = line.strip_prefix("call ") {
+        return assemble_call(b, operand.trim());
+    }
+    if let Some(cond) = line.strip_prefix("if ") {
+        return assemble_jmp(b, cond);
+    }
+    if let Some(rest) = line.strip_prefix("lock ") {
+        return assemble_lock(b, rest);
+    }
+    // `lhs op rhs`, the lhs may contain spaces in a memory operand
+    let split = match line.rfind(") ") {
+        Some(pos) if line.starts_with('*') => pos + 1,
+        _ => line.find(' ')?,
+    };
+    let (lhs, rest) = line.split_at(split);
+    let (op, rhs) = rest.trim_start().split_once(' ')?;
+    assemble_assign(b, lhs.trim(), op, rhs.trim())
+}
+
+/// Assembles a program written in the disassembler's syntax. Lines may
+/// carry the disassembler's `  12:` index prefix, `label:` lines name jump
+/// targets and `;`, `//` and `#` start comments.
+pub fn assemble(text: &str) -> Result<Builder> {
+    let mut b = Builder::new();
+    for raw in text.lines() {
+        let mut line = raw;
+        for comment in [";", "//", "#"] {
+            // `#` also separates a helper's name from its id
+            if comment == "#" && line.contains("call ") {
+                continue;
+            }
+            if let Some(pos) = line.find(comment) {
+                line = &line[..pos];
+            }
+        }
+        let mut line = line.trim().trim_start_matches('>').trim();
+        if let Some((prefix, rest)) = line.split_once(':') {
+            let prefix = prefix.trim();
+            if prefix.chars().all(|c| c.is_ascii_digit()) && !prefix.is_empty() {
+                line = rest.trim();
+            } else if rest.trim().is_empty()
+                && prefix.chars().all(|c| c.is_alphanumeric() || c == '_')
+            {
+                b.label(prefix);
+                continue;
+            }
+        }
+        if line.is_empty() {
+            continue;
+        }
+        assemble_line(&mut b, line).ok_or_else(|| syntax(raw.trim()))?;
+    }
+    Ok(b)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::module::disasm::disassemble;
+
+    #[test]
+    fn test_builder_labels() {
+        let mut b = Builder::new();
+        b.mov64_imm(R0, 0)
+            .jeq_imm(R1, 0, "out")
+            .ld_imm64(R0, 1 << 40)
+            .label("out")
+            .exit();
+        let insns = b.build().unwrap();
+        assert_eq!(insns.len(), 5);
+        assert_eq!(insns[1].off, 2);
+
+        let mut b = Builder::new();
+        b.ja("nowhere");
+        assert!(b.build().is_err());
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let mut b = Builder::new();
+        b.mov64_reg(R6, R1)
+            .st_mem(BPF_W, R10, -4, 0)
+            .alu64_imm(BPF_ADD, R2, -4)
+            .alu32_reg(BPF_XOR, R3, R4)
+            .insn(Insn::new(BPF_ALU64 | BPF_DIV | BPF_K, 3, 0, 1, 7))
+            .insn(Insn::new(BPF_ALU64 | BPF_NEG, 5, 0, 0, 0))
+            .insn(Insn::new(BPF_ALU | BPF_END | BPF_TO_BE, 1, 0, 0, 32))
+            .insn(Insn::new(BPF_ALU64 | BPF_MOV | BPF_X, 1, 2, 16, 0))
+            .ld_map(R1, "counts")
+            .insn(Insn::new(
+                BPF_LD | BPF_IMM | BPF_DW,
+                2,
+                BPF_PSEUDO_MAP_VALUE,
+                0,
+                7,
+            ))
+            .insn(Insn::new(0, 0, 0, 0, 16))
+            .ld_imm64(R3, 0xdead_beef_0000_0001)
+            .call(Helper::MapLookupElem)
+            .insn(Insn::new(BPF_JMP | BPF_CALL, 0, BPF_PSEUDO_CALL, 0, 3))
+            .jmp_imm(BPF_JSGT, R0, -1, 2i16)
+            .jmp32_reg(BPF_JLE, R1, R2, -3i16)
+            .ldx_mem(BPF_DW, R1, R0, 8)
+            .insn(Insn::new(BPF_LDX | BPF_MEMSX | BPF_H, 2, 0, -2, 0))
+            .stx_mem(BPF_B, R10, -1, R1)
+            .atomic_add(BPF_DW, R0, 0, R1)
+            .insn(Insn::new(
+                BPF_STX | BPF_ATOMIC | BPF_W,
+                0,
+                1,
+                4,
+                0x50 | BPF_FETCH,
+            ))
+            .insn(Insn::new(
+                BPF_STX | BPF_ATOMIC | BPF_DW,
+                0,
+                2,
+                0,
+                BPF_CMPXCHG,
+            ))
+            .insn(Insn::new(BPF_LD | BPF_ABS | BPF_B, 0, 0, 0, 23))
+            .insn(Insn::new(BPF_LD | BPF_IND | BPF_W, 0, 7, 0, 4))
+            .ja(-5i16)
+            .exit();
+        let text = disassemble(&b.resolve().unwrap(), b.map_relocs());
+        let back = assemble(&text).unwrap();
+        assert_eq!(back.resolve().unwrap(), b.resolve().unwrap(), "{}", text);
+        assert_eq!(back.map_relocs(), b.map_relocs());
+    }
+
+    #[test]
+    fn test_assemble_text() {
+        let b = assemble(
+            "; count packets\n\
+             start:\n\
+             \x20   r0 = 0\n\
+             \x20   if r1 != 0x0 goto out  // skip\n\
+             \x20   call bpf_ktime_get_ns\n\
+             out:\n\
+             \x20   exit\n",
+        )
+        .unwrap();
+        let insns = b.build().unwrap();
+        assert_eq!(insns[1].off, 1);
+        assert_eq!(insns[2].imm, Helper::KtimeGetNs as i32);
+        assert!(assemble("r0 = frobnicate").is_err());
+    }
+}
@@ -0,0 +1,762 @@
+//! Parser for the BPF Type Format.
+//!
+//! Type ids are indices into [`Btf::types`]; id 0 is always `void`.
+//! Kernel module BTF is split BTF: its ids and string offsets continue
+//! where the vmlinux BTF it's based on ends, so it's parsed into a copy of
+//! the base with the module's types appended.
+
+use crate::error::{Error, Result};
+use crate::sys::syscall;
+use std::convert::TryInto;
+use std::fs;
+use std::path::Path;
+
+/// Where the kernel exposes its own BTF and that of loaded modules.
+pub const SYSFS_BTF: &str = "/sys/kernel/btf";
+
+pub const BTF_MAGIC: u16 = 0xeb9f;
+
+const BTF_KIND_INT: u32 = 1;
+const BTF_KIND_PTR: u32 = 2;
+const BTF_KIND_ARRAY: u32 = 3;
+const BTF_KIND_STRUCT: u32 = 4;
+const BTF_KIND_UNION: u32 = 5;
+const BTF_KIND_ENUM: u32 = 6;
+const BTF_KIND_FWD: u32 = 7;
+const BTF_KIND_TYPEDEF: u32 = 8;
+const BTF_KIND_VOLATILE: u32 = 9;
+const BTF_KIND_CONST: u32 = 10;
+const BTF_KIND_RESTRICT: u32 = 11;
+const BTF_KIND_FUNC: u32 = 12;
+const BTF_KIND_FUNC_PROTO: u32 = 13;
+const BTF_KIND_VAR: u32 = 14;
+const BTF_KIND_DATASEC: u32 = 15;
+const BTF_KIND_FLOAT: u32 = 16;
+const BTF_KIND_DECL_TAG: u32 = 17;
+const BTF_KIND_TYPE_TAG: u32 = 18;
+const BTF_KIND_ENUM64: u32 = 19;
+
+// encoding bits of BTF_KIND_INT
+pub const BTF_INT_SIGNED: u8 = 1 << 0;
+pub const BTF_INT_CHAR: u8 = 1 << 1;
+pub const BTF_INT_BOOL: u8 = 1 << 2;
+
+// linkage of BTF_KIND_VAR
+pub const BTF_VAR_STATIC: u32 = 0;
+pub const BTF_VAR_GLOBAL_ALLOCATED: u32 = 1;
+
+/// Longest chain of modifiers, typedefs and arrays followed to resolve a
+/// type, as in libbpf. Anything longer is taken for a loop.
+const MAX_RESOLVE_DEPTH: usize = 32;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Member {
+    pub name: String,
+    pub type_id: u32,
+    /// offset in bits from the start of the struct
+    pub bit_offset: u32,
+    /// width of a bitfield member, 0 for regular members
+    pub bitfield_size: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EnumValue {
+    pub name: String,
+    pub value: i64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Param {
+    pub name: String,
+    pub type_id: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VarSecinfo {
+    pub type_id: u32,
+    pub offset: u32,
+    pub size: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BtfKind {
+    Void,
+    Int {
+        size: u32,
+        encoding: u8,
+        offset: u8,
+        bits: u8,
+    },
+    Ptr(u32),
+    Array {
+        elem: u32,
+        index: u32,
+        nelems: u32,
+    },
+    Struct {
+        size: u32,
+        members: Vec<Member>,
+    },
+    Union {
+        size: u32,
+        members: Vec<Member>,
+    },
+    Enum {
+        size: u32,
+        signed: bool,
+        values: Vec<EnumValue>,
+    },
+    Fwd {
+        union: bool,
+    },
+    Typedef(u32),
+    Volatile(u32),
+    Const(u32),
+    Restrict(u32),
+    Func {
+        proto: u32,
+        linkage: u32,
+    },
+    FuncProto {
+        ret: u32,
+        params: Vec<Param>,
+    },
+    Var {
+        type_id: u32,
+        linkage: u32,
+    },
+    Datasec {
+        size: u32,
+        vars: Vec<VarSecinfo>,
+    },
+    Float {
+        size: u32,
+    },
+    DeclTag {
+        type_id: u32,
+        component_idx: i32,
+    },
+    TypeTag(u32),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BtfType {
+    pub name: String,
+    pub kind: BtfKind,
+}
+
+impl BtfType {
+    /// The type this one refers to for pointers, modifiers, typedefs,
+    /// variables and functions.
+    pub fn referenced(&self) -> Option<u32> {
+        match self.kind {
+            BtfKind::Ptr(t)
+            | BtfKind::Typedef(t)
+            | BtfKind::Volatile(t)
+            | BtfKind::Const(t)
+            | BtfKind::Restrict(t)
+            | BtfKind::TypeTag(t)
+            | BtfKind::Func { proto: t, .. }
+            | BtfKind::Var { type_id: t, .. }
+            | BtfKind::DeclTag { type_id: t, .. } => Some(t),
+            _ => None,
+        }
+    }
+
+    pub fn kind_name(&self) -> &'static str {
+        match self.kind {
+            BtfKind::Void => "void",
+            BtfKind::Int { .. } => "int",
+            BtfKind::Ptr(_) => "ptr",
+            BtfKind::Array { .. } => "array",
+            BtfKind::Struct { .. } => "struct",
+            BtfKind::Union { .. } => "union",
+            BtfKind::Enum { .. } => "enum",
+            BtfKind::Fwd { .. } => "fwd",
+            BtfKind::Typedef(_) => "typedef",
+            BtfKind::Volatile(_) => "volatile",
+            BtfKind::Const(_) => "const",
+            BtfKind::Restrict(_) => "restrict",
+            BtfKind::Func { .. } => "func",
+            BtfKind::FuncProto { .. } => "func_proto",
+            BtfKind::Var { .. } => "var",
+            BtfKind::Datasec { .. } => "datasec",
+            BtfKind::Float { .. } => "float",
+            BtfKind::DeclTag { .. } => "decl_tag",
+            BtfKind::TypeTag(_) => "type_tag",
+        }
+    }
+}
+
+/// Little endian cursor over the raw type section.
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl Reader<'_> {
+    fn u32(&mut self) -> Result<u32> {
+        let bytes = self
+            .data
+            .get(self.pos..self.pos + 4)
+            .ok_or_else(|| Error::Invalid("BTF type section truncated".to_string()))?;
+        self.pos += 4;
+        Ok(u32::from_le_bytes(bytes.try_into().unwrap()))
+    }
+}
+
+/// C alignment in bytes of a type, and whether a struct or union needs
+/// to be packed to get the offsets and size BTF records.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Layout {
+    pub align: u32,
+    pub packed: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct Btf {
+    pub types: Vec<BtfType>,
+    strings: Vec<u8>,
+}
+
+impl Btf {
+    pub fn parse(data: &[u8]) -> Result<Btf> {
+        Btf::parse_with_base(data, None)
+    }
+
+    /// Parses split BTF, such as a kernel module's, on top of `base`.
+    pub fn parse_split(data: &[u8], base: &Btf) -> Result<Btf> {
+        Btf::parse_with_base(data, Some(base))
+    }
+
+    /// A BTF made of `types`, `types[0]` being `void`, without a string
+    /// section.
+    pub fn from_types(types: Vec<BtfType>) -> Btf {
+        Btf {
+            types,
+            strings: vec![0],
+        }
+    }
+
+    /// The running kernel's BTF (`CONFIG_DEBUG_INFO_BTF`).
+    pub fn vmlinux() -> Result<Btf> {
+        Btf::parse(&read_sysfs_btf("vmlinux")?)
+    }
+
+    /// The BTF of loaded kernel module `name` on top of `vmlinux`.
+    pub fn kernel_module(name: &str, vmlinux: &Btf) -> Result<Btf> {
+        Btf::parse_split(&read_sysfs_btf(name)?, vmlinux)
+    }
+
+    fn parse_with_base(data: &[u8], base: Option<&Btf>) -> Result<Btf> {
+        let invalid = |msg: &str| Error::Invalid(format!("invalid BTF: {}", msg));
+        let field = |off: usize| -> Result<u32> {
+            data.get(off..off + 4)
+                .map(|b| u32::from_le_bytes(b.try_into().unwrap()))
+                .ok_or_else(|| invalid("header truncated"))
+        };
+        if data.len() < 24 || u16::from_le_bytes([data[0], data[1]]) != BTF_MAGIC {
+            return Err(invalid("bad magic"));
+        }
+        let hdr_len = field(4)? as usize;
+        let (type_off, type_len) = (field(8)? as usize, field(12)? as usize);
+        let (str_off, str_len) = (field(16)? as usize, field(20)? as usize);
+        let section = |off: usize, len: usize| {
+            data.get(hdr_len + off..hdr_len + off + len)
+                .ok_or_else(|| invalid("section out of bounds"))
+        };
+        let strings = section(str_off, str_len)?;
+        let mut btf = match base {
+            Some(base) => {
+                let mut btf = base.clone();
+                btf.strings.extend_from_slice(strings);
+                btf
+            }
+            None => Btf {
+                types: vec![BtfType {
+                    name: String::new(),
+                    kind: BtfKind::Void,
+                }],
+                strings: strings.to_vec(),
+            },
+        };
+        btf.parse_types(section(type_off, type_len)?)?;
+        Ok(btf)
+    }
+
+    /// Fetches and parses the BTF object with kernel id `id`, such as the
+    /// one referenced by a loaded program's `btf_id`.
+    pub fn from_id(id: u32) -> Result<Btf> {
+        let fd = syscall::obj_get_fd_by_id(syscall::BPF_BTF_GET_FD_BY_ID, id)
+            .map_err(|e| Error::Syscall("BPF_BTF_GET_FD_BY_ID", e))?;
+        let mut info = syscall::BtfInfo::default();
+        let mut res = syscall::obj_get_info(fd, &mut info);
+        let mut data = vec![0u8; info.btf_size as usize];
+        if res.is_ok() {
+            info = syscall::BtfInfo {
+                btf: data.as_mut_ptr() as u64,
+                btf_size: data.len() as u32,
+                ..Default::default()
+            };
+            res = syscall::obj_get_info(fd, &mut info);
+        }
+        unsafe {
+            libc::close(fd);
+        }
+        res.map_err(|e| Error::Syscall("BPF_OBJ_GET_INFO_BY_FD", e))?;
+        Btf::parse(&data)
+    }
+
+    /// Returns the NUL terminated string at `offset` of the string section.
+    pub fn string(&self, offset: u32) -> Result<&str> {
+        let rest = self
+            .strings
+            .get(offset as usize..)
+            .ok_or_else(|| Error::Invalid(format!("BTF string offset {} out of bounds", offset)))?;
+        let len = rest.iter().position(|&b| b == 0).unwrap_or(rest.len());
+        std::str::from_utf8(&rest[..len])
+            .map_err(|_| Error::Invalid(format!("BTF string at {} is not UTF-8", offset)))
+    }
+
+    fn parse_types(&mut self, data: &[u8]) -> Result<()> {
+        let mut r = Reader { data, pos: 0 };
+        while r.pos < data.len() {
+            let name = self.string(r.u32()?)?.to_string();
+            let info = r.u32()?;
+            let size_or_type = r.u32()?;
+            let vlen = info & 0xffff;
+            let kind_flag = info >> 31 == 1;
+            let members = |r: &mut Reader<'_>, btf: &Btf| -> Result<Vec<Member>> {
+                (0..vlen)
+                    .map(|_| {
+                        let name = btf.string(r.u32()?)?.to_string();
+                        let type_id = r.u32()?;
+                        let offset = r.u32()?;
+                        let (bit_offset, bitfield_size) = if kind_flag {
+                            (offset & 0xff_ffff, offset >> 24)
+                        } else {
+                            (offset, 0)
+                        };
+                        Ok(Member {
+                            name,
+                            type_id,
+                            bit_offset,
+                            bitfield_size,
+                        })
+                    })
+                    .collect()
+            };
+            let kind = match (info >> 24) & 0x1f {
+                BTF_KIND_INT => {
+                    let int = r.u32()?;
+                    BtfKind::Int {
+                        size: size_or_type,
+                        encoding: (int >> 24) as u8 & 0x0f,
+                        offset: (int >> 16) as u8,
+                        bits: int as u8,
+                    }
+                }
+                BTF_KIND_PTR => BtfKind::Ptr(size_or_type),
+                BTF_KIND_ARRAY => BtfKind::Array {
+                    elem: r.u32()?,
+                    index: r.u32()?,
+                    nelems: r.u32()?,
+                },
+                BTF_KIND_STRUCT => BtfKind::Struct {
+                    size: size_or_type,
+                    members: members(&mut r, self)?,
+                },
+                BTF_KIND_UNION => BtfKind::Union {
+                    size: size_or_type,
+                    members: members(&mut r, self)?,
+                },
+                BTF_KIND_ENUM => BtfKind::Enum {
+                    size: size_or_type,
+                    signed: kind_flag,
+                    values: (0..vlen)
+                        .map(|_| {
+                            let name = self.string(r.u32()?)?.to_string();
+                            let value = r.u32()?;
+                            let value = if kind_flag {
+                                i64::from(value as i32)
+                            } else {
+                                i64::from(value)
+                            };
+                            Ok(EnumValue { name, value })
+                        })
+                        .collect::<Result<_>>()?,
+                },
+                BTF_KIND_ENUM64 => BtfKind::Enum {
+                    size: size_or_type,
+                    signed: kind_flag,
+                    values: (0..vlen)
+                        .map(|_| {
+                            let name = self.string(r.u32()?)?.to_string();
+                            let lo = u64::from(r.u32()?);
+                            let hi = u64::from(r.u32()?);
+                            Ok(EnumValue {
+                                name,
+                                value: (hi << 32 | lo) as i64,
+                            })
+                        })
+                        .collect::<Result<_>>()?,
+                },
+                BTF_KIND_FWD => BtfKind::Fwd { union: kind_flag },
+                BTF_KIND_TYPEDEF => BtfKind::Typedef(size_or_type),
+                BTF_KIND_VOLATILE => BtfKind::Volatile(size_or_type),
+                BTF_KIND_CONST => BtfKind::Const(size_or_type),
+                BTF_KIND_RESTRICT => BtfKind::Restrict(size_or_type),
+                BTF_KIND_FUNC => BtfKind::Func {
+                    proto: size_or_type,
+                    linkage: vlen,
+                },
+                BTF_KIND_FUNC_PROTO => BtfKind::FuncProto {
+                    ret: size_or_type,
+                    params: (0..vlen)
+                        .map(|_| {
+                            Ok(Param {
+                                name: self.string(r.u32()?)?.to_string(),
+                                type_id: r.u32()?,
+                            })
+                        })
+                        .collect::<Result<_>>()?,
+                },
+                BTF_KIND_VAR => BtfKind::Var {
+                    type_id: size_or_type,
+                    linkage: r.u32()?,
+                },
+                BTF_KIND_DATASEC => BtfKind::Datasec {
+                    size: size_or_type,
+                    vars: (0..vlen)
+                        .map(|_| {
+                            Ok(VarSecinfo {
+                                type_id: r.u32()?,
+                                offset: r.u32()?,
+                                size: r.u32()?,
+                            })
+                        })
+                        .collect::<Result<_>>()?,
+                },
+                BTF_KIND_FLOAT => BtfKind::Float { size: size_or_type },
+                BTF_KIND_DECL_TAG => BtfKind::DeclTag {
+                    type_id: size_or_type,
+                    component_idx: r.u32()? as i32,
+                },
+                BTF_KIND_TYPE_TAG => BtfKind::TypeTag(size_or_type),
+                kind => {
+                    return Err(Error::Invalid(format!(
+                        "invalid BTF: unknown kind {} for type {}",
+                        kind,
+                        self.types.len()
+                    )))
+                }
+            };
+            self.types.push(BtfType { name, kind });
+        }
+        Ok(())
+    }
+
+    pub fn type_by_id(&self, id: u32) -> Result<&BtfType> {
+        self.types
+            .get(id as usize)
+            .ok_or_else(|| Error::NotFound(format!("BTF type {}", id)))
+    }
+
+    /// Ids of all types called `name`, forward declarations included.
+    pub fn find_all_by_name<'a>(&'a self, name: &'a str) -> impl Iterator<Item = u32> + 'a {
+        self.types
+            .iter()
+            .enumerate()
+            .filter(move |(_, t)| t.name == name)
+            .map(|(id, _)| id as u32)
+    }
+
+    /// Finds the first type called `name`, skipping forward declarations.
+    pub fn find_by_name(&self, name: &str) -> Option<u32> {
+        self.types
+            .iter()
+            .position(|t| t.name == name && !matches!(t.kind, BtfKind::Fwd { .. }))
+            .map(|id| id as u32)
+    }
+
+    /// Follows typedefs and const/volatile/restrict modifiers to the
+    /// underlying type.
+    pub fn skip_mods_and_typedefs(&self, id: u32) -> Result<u32> {
+        let mut resolved = id;
+        for _ in 0..MAX_RESOLVE_DEPTH {
+            match self.type_by_id(resolved)?.kind {
+                BtfKind::Typedef(t)
+                | BtfKind::Volatile(t)
+                | BtfKind::Const(t)
+                | BtfKind::Restrict(t)
+                | BtfKind::TypeTag(t) => resolved = t,
+                _ => return Ok(resolved),
+            }
+        }
+        Err(Error::Invalid(format!("BTF type {} nests too deep", id)))
+    }
+
+    /// Size in bytes of an instance of type `id`.
+    pub fn type_size(&self, id: u32) -> Result<u32> {
+        self.type_size_at(id, 0)
+    }
+
+    fn type_size_at(&self, id: u32, depth: usize) -> Result<u32> {
+        if depth == MAX_RESOLVE_DEPTH {
+            return Err(Error::Invalid(format!("BTF type {} nests too deep", id)));
+        }
+        let id = self.skip_mods_and_typedefs(id)?;
+        match &self.type_by_id(id)?.kind {
+            BtfKind::Int { size, .. }
+            | BtfKind::Struct { size, .. }
+            | BtfKind::Union { size, .. }
+            | BtfKind::Enum { size, .. }
+            | BtfKind::Datasec { size, .. }
+            | BtfKind::Float { size } => Ok(*size),
+            BtfKind::Ptr(_) => Ok(8),
+            BtfKind::Array { elem, nelems, .. } => self
+                .type_size_at(*elem, depth + 1)?
+                .checked_mul(*nelems)
+                .ok_or_else(|| Error::Invalid(format!("size of BTF type {} overflows", id))),
+            BtfKind::Var { type_id, .. } => self.type_size_at(*type_id, depth + 1),
+            _ => Err(Error::Invalid(format!(
+                "BTF type {} of kind {} has no size",
+                id,
+                self.type_by_id(id)?.kind_name()
+            ))),
+        }
+    }
+
+    /// The layout of every type, by id. BTF records no alignment, so it is
+    /// derived from the members the way the compiler lays them out, which
+    /// misses explicit `aligned` attributes.
+    pub fn layouts(&self) -> Result<Vec<Layout>> {
+        let mut layouts = vec![None; self.types.len()];
+        for id in 0..self.types.len() as u32 {
+            layout(self, id, &mut layouts)?;
+        }
+        Ok(layouts.into_iter().map(Option::unwrap).collect())
+    }
+}
+
+/// Computes the layout of `id` and of the types it contains.
+fn layout(btf: &Btf, id: u32, layouts: &mut Vec<Option<Layout>>) -> Result<Layout> {
+    if let Some(layout) = layouts[id as usize] {
+        return Ok(layout);
+    }
+    let natural = |align| Layout {
+        align,
+        packed: false,
+    };
+    let layout = match &btf.type_by_id(id)?.kind {
+        BtfKind::Int { size, .. } | BtfKind::Float { size } | BtfKind::Enum { size, .. } => {
+            natural((*size).clamp(1, 16))
+        }
+        BtfKind::Ptr(_) => natural(8),
+        BtfKind::Array { elem, .. } => natural(layout(btf, *elem, layouts)?.align),
+        BtfKind::Typedef(t)
+        | BtfKind::Const(t)
+        | BtfKind::Volatile(t)
+        | BtfKind::Restrict(t)
+        | BtfKind::TypeTag(t) => layout(btf, *t, layouts)?,
+        BtfKind::Struct { size, members } | BtfKind::Union { size, members } => {
+            let mut align = 1;
+            let mut packed = false;
+            for m in members {
+                let member = layout(btf, m.type_id, layouts)?.align;
+                align = align.max(member);
+                packed |= m.bitfield_size == 0 && m.bit_offset % (member * 8) != 0;
+            }
+            // unions can't be packed by their members' offsets, but their
+            // size can still be off
+            packed |= size % align != 0;
+            Layout {
+                align: if packed { 1 } else { align },
+                packed,
+            }
+        }
+        _ => natural(1),
+    };
+    layouts[id as usize] = Some(layout);
+    Ok(layout)
+}
+
+fn read_sysfs_btf(name: &str) -> Result<Vec<u8>> {
+    let path = Path::new(SYSFS_BTF).join(name);
+    fs::read(&path).map_err(|e| match e.kind() {
+        std::io::ErrorKind::NotFound => Error::NotFound(path.display().to_string()),
+        _ => Error::Io(e),
+    })
+}
+
+/// Names of the kernel modules that have BTF.
+pub fn kernel_modules() -> Result<Vec<String>> {
+    let mut modules = Vec::new();
+    for entry in fs::read_dir(SYSFS_BTF)? {
+        let name = entry?.file_name().to_string_lossy().into_owned();
+        if name != "vmlinux" {
+            modules.push(name);
+        }
+    }
+    modules.sort();
+    Ok(modules)
+}
+
+/// Factories for the BTF fixtures of unit tests.
+#[cfg(test)]
+pub mod fixture {
+    use super::*;
+
+    pub fn ty(name: &str, kind: BtfKind) -> BtfType {
+        BtfType {
+            name: name.to_string(),
+            kind,
+        }
+    }
+
+    pub fn member(name: &str, type_id: u32, bit_offset: u32) -> Member {
+        Member {
+            name: name.to_string(),
+            type_id,
+            bit_offset,
+            bitfield_size: 0,
+        }
+    }
+
+    pub fn int(name: &str, size: u32, signed: bool) -> BtfType {
+        ty(
+            name,
+            BtfKind::Int {
+                size,
+                encoding: if signed { BTF_INT_SIGNED } else { 0 },
+                offset: 0,
+                bits: (size * 8) as u8,
+            },
+        )
+    }
+
+    pub fn array(elem: u32, nelems: u32) -> BtfType {
+        ty(
+            "",
+            BtfKind::Array {
+                elem,
+                index: elem,
+                nelems,
+            },
+        )
+    }
+
+    pub fn enumeration(name: &str, signed: bool, values: &[(&str, i64)]) -> BtfType {
+        let values = values
+            .iter()
+            .map(|&(name, value)| EnumValue {
+                name: name.to_string(),
+                value,
+            })
+            .collect();
+        ty(
+            name,
+            BtfKind::Enum {
+                size: 4,
+                signed,
+                values,
+            },
+        )
+    }
+
+    /// Raw BTF with a 24 byte header, the type section `types` and the
+    /// string section `strings`.
+    pub fn raw(types: &[u32], strings: &[u8]) -> Vec<u8> {
+        let type_len = (types.len() * 4) as u32;
+        let mut data = BTF_MAGIC.to_le_bytes().to_vec();
+        data.extend_from_slice(&[1, 0]);
+        for field in [24, 0, type_len, type_len, strings.len() as u32] {
+            data.extend_from_slice(&field.to_le_bytes());
+        }
+        for word in types {
+            data.extend_from_slice(&word.to_le_bytes());
+        }
+        data.extend_from_slice(strings);
+        data
+    }
+
+    /// A BTF of `types`, numbered from 1 after `void`.
+    pub fn btf(types: Vec<BtfType>) -> Btf {
+        let mut all = vec![ty("", BtfKind::Void)];
+        all.extend(types);
+        Btf::from_types(all)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::fixture::*;
+    use super::*;
+
+    /// `int`, a signed 32 bit integer named by string offset 1.
+    const INT: [u32; 4] = [1, BTF_KIND_INT << 24, 4, (BTF_INT_SIGNED as u32) << 24 | 32];
+
+    #[test]
+    fn test_parse() {
+        let btf = Btf::parse(&raw(&INT, b"\0int\0")).unwrap();
+        assert_eq!(btf.types, vec![ty("", BtfKind::Void), int("int", 4, true)]);
+        assert_eq!(btf.find_by_name("int"), Some(1));
+    }
+
+    #[test]
+    fn test_parse_malformed() {
+        let data = raw(&INT, b"\0int\0");
+        // header
+        assert!(Btf::parse(&data[..20]).is_err());
+        assert!(Btf::parse(&[0; 32]).is_err());
+        // a record cut short, and the sections running past the end
+        let mut truncated = raw(&INT[..3], b"\0int\0");
+        assert!(Btf::parse(&truncated).is_err());
+        truncated.truncate(truncated.len() - 2);
+        assert!(Btf::parse(&truncated).is_err());
+        // a name past the string section, an unknown kind
+        assert!(Btf::parse(&raw(&[9, BTF_KIND_PTR << 24, 0], b"\0")).is_err());
+        assert!(Btf::parse(&raw(&[0, 31 << 24, 0], b"\0")).is_err());
+        // a struct claiming more members than it has
+        assert!(Btf::parse(&raw(&[0, BTF_KIND_STRUCT << 24 | 2, 8, 0, 0, 0], b"\0")).is_err());
+    }
+
+    #[test]
+    fn test_parse_split() {
+        let base = Btf::parse(&raw(&INT, b"\0int\0")).unwrap();
+        // split string offsets continue after the base's
+        let split = Btf::parse_split(&raw(&[5, BTF_KIND_PTR << 24, 1], b"p\0"), &base).unwrap();
+        assert_eq!(split.types.len(), 3);
+        assert_eq!(split.types[2], ty("p", BtfKind::Ptr(1)));
+        assert_eq!(split.type_size(2).unwrap(), 8);
+
+        assert!(Btf::parse_split(&raw(&[5, BTF_KIND_PTR << 24], b"p\0"), &base).is_err());
+        assert!(Btf::parse_split(&raw(&[8, BTF_KIND_PTR << 24, 1], b"p\0"), &base).is_err());
+    }
+
+    #[test]
+    fn test_resolve_loops() {
+        // 1: typedef a -> 2: typedef b -> 1
+        let typedefs = btf(vec![
+            ty("a", BtfKind::Typedef(2)),
+            ty("b", BtfKind::Typedef(1)),
+        ]);
+        assert!(typedefs.skip_mods_and_typedefs(1).is_err());
+        assert!(typedefs.type_size(1).is_err());
+        // 1: an array of itself
+        assert!(btf(vec![array(1, 2)]).type_size(1).is_err());
+    }
+
+    #[test]
+    fn test_type_size() {
+        let btf = btf(vec![
+            int("int", 4, true),
+            ty("", BtfKind::Const(1)),
+            array(2, 16),
+            array(3, 1 << 28),
+        ]);
+        assert_eq!(btf.type_size(2).unwrap(), 4);
+        assert_eq!(btf.type_size(3).unwrap(), 64);
+        assert!(btf.type_size(4).is_err());
+    }
+}
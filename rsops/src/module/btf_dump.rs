@@ -0,0 +1,618 @@
+//! Renders BTF as a C header, like `bpftool btf dump file ... format c`.
+//!
+//! Every named struct, union, enum and typedef is emitted, ordered so that
+//! a type is defined before anything embeds it by value; types only
+//! pointed to get a forward declaration. BTF doesn't record alignment
+//! attributes, so gaps the natural layout wouldn't produce are filled with
+//! unnamed bitfields and misaligned structs are marked packed, which keeps
+//! every offset and size identical to the kernel's.
+
+use crate::error::{Error, Result};
+use crate::module::btf::{Btf, BtfKind, Layout, Member};
+use std::collections::HashMap;
+use std::fmt::Write;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    None,
+    Defining,
+    Defined,
+}
+
+struct Dumper<'a> {
+    btf: &'a Btf,
+    /// unique C name of each type, empty for anonymous ones
+    names: Vec<String>,
+    /// unique name of each enumerator, by enum id and index
+    enumerators: HashMap<(u32, usize), String>,
+    layouts: Vec<Layout>,
+    /// whether another type refers to it, for anonymous enums
+    referenced: Vec<bool>,
+    state: Vec<State>,
+    /// whether `struct name;` was emitted
+    declared: Vec<bool>,
+    out: String,
+}
+
+/// Renders all types of `btf` as a header guarded by `guard`, e.g.
+/// `__VMLINUX_H__`.
+///
+/// Records get `preserve_access_index` so that field accesses compiled
+/// against the header are CO-RE relocatable; define
+/// `BPF_NO_PRESERVE_ACCESS_INDEX` to opt out.
+pub fn c_header(btf: &Btf, guard: &str) -> Result<String> {
+    let mut dumper = Dumper::new(btf)?;
+    writeln!(dumper.out, "#ifndef {}\n#define {}\n", guard, guard).unwrap();
+    dumper.out.push_str(
+        "#ifndef BPF_NO_PRESERVE_ACCESS_INDEX\n\
+         #pragma clang attribute push (__attribute__((preserve_access_index)), apply_to = record)\n\
+         #endif\n\n",
+    );
+    for id in 1..btf.types.len() as u32 {
+        dumper.emit_top_level(id)?;
+    }
+    dumper.out.push_str(
+        "#ifndef BPF_NO_PRESERVE_ACCESS_INDEX\n\
+         #pragma clang attribute pop\n\
+         #endif\n\n",
+    );
+    writeln!(dumper.out, "#endif /* {} */", guard).unwrap();
+    Ok(dumper.out)
+}
+
+fn round_up(value: u32, align: u32) -> u32 {
+    value.div_ceil(align) * align
+}
+
+fn tabs(indent: usize) -> String {
+    "\t".repeat(indent)
+}
+
+impl<'a> Dumper<'a> {
+    fn new(btf: &'a Btf) -> Result<Dumper<'a>> {
+        // struct, union and enum tags share a namespace, typedefs and
+        // enumerators share the ordinary one; later duplicates get a
+        // ___N suffix the way libbpf does
+        let mut seen: HashMap<(bool, &str), usize> = HashMap::new();
+        let mut unique = |tag: bool, name: &'a str| {
+            let count = seen.entry((tag, name)).or_insert(0);
+            *count += 1;
+            match *count {
+                1 => name.to_string(),
+                n => format!("{}___{}", name, n),
+            }
+        };
+        let count = btf.types.len();
+        let mut names = Vec::with_capacity(count);
+        let mut enumerators = HashMap::new();
+        let mut referenced = vec![false; count];
+        for (id, ty) in btf.types.iter().enumerate() {
+            let name = match &ty.kind {
+                _ if ty.name.is_empty() => String::new(),
+                BtfKind::Struct { .. } | BtfKind::Union { .. } | BtfKind::Enum { .. } => {
+                    unique(true, &ty.name)
+                }
+                BtfKind::Typedef(_) => unique(false, &ty.name),
+                _ => ty.name.clone(),
+            };
+            match &ty.kind {
+                BtfKind::Enum { values, .. } => {
+                    for (idx, value) in values.iter().enumerate() {
+                        enumerators.insert((id as u32, idx), unique(false, &value.name));
+                    }
+                }
+                BtfKind::Struct { members, .. } | BtfKind::Union { members, .. } => {
+                    for m in members {
+                        referenced[m.type_id as usize] = true;
+                    }
+                }
+                BtfKind::Array { elem, .. } => referenced[*elem as usize] = true,
+                BtfKind::FuncProto { ret, params } => {
+                    referenced[*ret as usize] = true;
+                    for p in params {
+                        referenced[p.type_id as usize] = true;
+                    }
+                }
+                _ => {
+                    if let Some(t) = ty.referenced() {
+                        referenced[t as usize] = true;
+                    }
+                }
+            }
+            names.push(name);
+        }
+        Ok(Dumper {
+            btf,
+            names,
+            enumerators,
+            layouts: btf.layouts()?,
+            referenced,
+            state: vec![State::None; count],
+            declared: vec![false; count],
+            out: String::new(),
+        })
+    }
+
+    fn kind(&self, id: u32) -> Result<&'a BtfKind> {
+        Ok(&self.btf.type_by_id(id)?.kind)
+    }
+
+    fn name(&self, id: u32) -> &str {
+        &self.names[id as usize]
+    }
+
+    fn emit_top_level(&mut self, id: u32) -> Result<()> {
+        let anonymous = self.name(id).is_empty();
+        match self.kind(id)? {
+            BtfKind::Struct { .. }
+            | BtfKind::Union { .. }
+            | BtfKind::Enum { .. }
+            | BtfKind::Fwd { .. }
+            | BtfKind::Typedef(_)
+                if !anonymous =>
+            {
+                self.need(id, true)
+            }
+            // anonymous enums that nothing uses define constants
+            BtfKind::Enum { .. } if !self.referenced[id as usize] => {
+                let body = self.body(id, 0)?;
+                writeln!(self.out, "{};\n", body).unwrap();
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Emits whatever `id` depends on, and `id` itself if it's named.
+    /// `strong` uses need the complete type, others only a declaration.
+    fn need(&mut self, id: u32, strong: bool) -> Result<()> {
+        let state = self.state[id as usize];
+        let anonymous = self.name(id).is_empty();
+        match self.kind(id)? {
+            BtfKind::Void
+            | BtfKind::Int { .. }
+            | BtfKind::Float { .. }
+            | BtfKind::Func { .. }
+            | BtfKind::Var { .. }
+            | BtfKind::Datasec { .. }
+            | BtfKind::DeclTag { .. } => Ok(()),
+            BtfKind::Ptr(t) => self.need(*t, false),
+            BtfKind::Const(t)
+            | BtfKind::Volatile(t)
+            | BtfKind::Restrict(t)
+            | BtfKind::TypeTag(t) => self.need(*t, strong),
+            // C has no arrays of incomplete types, even behind pointers
+            BtfKind::Array { elem, .. } => self.need(*elem, true),
+            BtfKind::FuncProto { ret, params } => {
+                self.need(*ret, false)?;
+                params.iter().try_for_each(|p| self.need(p.type_id, false))
+            }
+            // defined inline wherever it's used
+            BtfKind::Struct { members, .. } | BtfKind::Union { members, .. } if anonymous => {
+                members.iter().try_for_each(|m| self.need(m.type_id, true))
+            }
+            BtfKind::Struct { members, .. } | BtfKind::Union { members, .. } => match state {
+                State::Defined => Ok(()),
+                State::Defining if strong => Err(Error::Invalid(format!(
+                    "BTF type {} ({}) contains itself",
+                    id,
+                    self.name(id)
+                ))),
+                _ if !strong => {
+                    self.declare(id);
+                    Ok(())
+                }
+                _ => {
+                    self.state[id as usize] = State::Defining;
+                    for m in members {
+                        self.need(m.type_id, true)?;
+                    }
+                    let body = self.body(id, 0)?;
+                    writeln!(self.out, "{};\n", body).unwrap();
+                    self.state[id as usize] = State::Defined;
+                    Ok(())
+                }
+            },
+            BtfKind::Enum { .. } if anonymous => Ok(()),
+            BtfKind::Enum { .. } => {
+                if state != State::Defined {
+                    let body = self.body(id, 0)?;
+                    writeln!(self.out, "{};\n", body).unwrap();
+                    self.state[id as usize] = State::Defined;
+                }
+                Ok(())
+            }
+            BtfKind::Fwd { .. } => {
+                self.declare(id);
+                Ok(())
+            }
+            BtfKind::Typedef(t) => {
+                // the typedef only needs its target declared, so it can
+                // come before the definition that refers to it
+                if state == State::None {
+                    self.state[id as usize] = State::Defining;
+                    self.need(*t, false)?;
+                    let name = self.name(id).to_string();
+                    let decl = self.decl(*t, &name, 0)?;
+                    writeln!(self.out, "typedef {};\n", decl).unwrap();
+                    self.state[id as usize] = State::Defined;
+                }
+                if strong {
+                    self.need(*t, true)?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Emits `struct name;` unless the struct is declared or defined
+    /// already. Structs being defined get one too: pointers to them can
+    /// show up in typedefs emitted before their definition.
+    fn declare(&mut self, id: u32) {
+        if self.declared[id as usize] || self.state[id as usize] == State::Defined {
+            return;
+        }
+        let keyword = match self.kind(id) {
+            Ok(BtfKind::Union { .. }) | Ok(BtfKind::Fwd { union: true }) => "union",
+            _ => "struct",
+        };
+        let decl = format!("{} {};\n", keyword, self.name(id));
+        writeln!(self.out, "{}", decl).unwrap();
+        self.declared[id as usize] = true;
+    }
+
+    fn align(&self, id: u32) -> u32 {
+        self.layouts[id as usize].align
+    }
+
+    /// Unnamed bitfields covering bits `from..to` of a struct.
+    fn padding(&self, mut from: u32, to: u32, indent: usize) -> String {
+        let mut out = String::new();
+        while from < to {
+            let left = to - from;
+            let (ty, bits) = if !from.is_multiple_of(8) {
+                ("char", left.min(8 - from % 8))
+            } else {
+                [("long", 64), ("int", 32), ("short", 16), ("char", 8)]
+                    .iter()
+                    .copied()
+                    .find(|(_, bits)| from.is_multiple_of(*bits) && left >= *bits)
+                    .unwrap_or(("char", left))
+            };
+            writeln!(out, "{}{}: {};", tabs(indent), ty, bits).unwrap();
+            from += bits;
+        }
+        out
+    }
+
+    fn members(&self, id: u32, members: &[Member], size: u32, indent: usize) -> Result<String> {
+        let is_struct = matches!(self.kind(id)?, BtfKind::Struct { .. });
+        let Layout { align, packed } = self.layouts[id as usize];
+        let mut out = String::new();
+        let mut end = 0;
+        for m in members {
+            let decl = self.decl(m.type_id, &m.name, indent)?;
+            let type_bits = self.btf.type_size(m.type_id)? * 8;
+            if is_struct {
+                let natural = if m.bitfield_size > 0 {
+                    let unit = type_bits.max(8);
+                    if end % unit + m.bitfield_size > unit {
+                        round_up(end, unit)
+                    } else {
+                        end
+                    }
+                } else if packed {
+                    end
+                } else {
+                    round_up(end, self.align(m.type_id) * 8)
+                };
+                if m.bit_offset > natural {
+                    out.push_str(&self.padding(end, m.bit_offset, indent));
+                }
+            }
+            match m.bitfield_size {
+                0 => writeln!(out, "{}{};", tabs(indent), decl).unwrap(),
+                bits => writeln!(out, "{}{}: {};", tabs(indent), decl, bits).unwrap(),
+            }
+            let bits = if m.bitfield_size > 0 {
+                m.bitfield_size
+            } else {
+                type_bits
+            };
+            end = end.max(m.bit_offset + bits);
+        }
+        if is_struct && size * 8 > round_up(end, align * 8) {
+            out.push_str(&self.padding(end, size * 8, indent));
+        }
+        Ok(out)
+    }
+
+    /// The definition of struct, union or enum `id`, its name included
+    /// unless it's anonymous, closing brace at `indent`.
+    fn body(&self, id: u32, indent: usize) -> Result<String> {
+        let ty = self.btf.type_by_id(id)?;
+        let name = match self.name(id) {
+            "" => String::new(),
+            name => format!(" {}", name),
+        };
+        let packed = match ty.kind {
+            BtfKind::Enum { size, .. } => size < 4,
+            _ => self.layouts[id as usize].packed,
+        };
+        let attribute = if packed {
+            " __attribute__((packed))"
+        } else {
+            ""
+        };
+        let body = match &ty.kind {
+            BtfKind::Struct { size, members } | BtfKind::Union { size, members } => {
+                let keyword = match ty.kind {
+                    BtfKind::Struct { .. } => "struct",
+                    _ => "union",
+                };
+                format!(
+                    "{}{} {{\n{}{}}}",
+                    keyword,
+                    name,
+                    self.members(id, members, *size, indent + 1)?,
+                    tabs(indent)
+                )
+            }
+            BtfKind::Enum { signed, values, .. } => {
+                let mut body = String::new();
+                for (idx, value) in values.iter().enumerate() {
+                    let name = &self.enumerators[&(id, idx)];
+                    if *signed || value.value >= 0 {
+                        writeln!(body, "{}{} = {},", tabs(indent + 1), name, value.value)
+                    } else {
+                        writeln!(
+                            body,
+                            "{}{} = {}ULL,",
+                            tabs(indent + 1),
+                            name,
+                            value.value as u64
+                        )
+                    }
+                    .unwrap();
+                }
+                format!("enum{} {{\n{}{}}}", name, body, tabs(indent))
+            }
+            _ => unreachable!("body of BTF type {} of kind {}", id, ty.kind_name()),
+        };
+        Ok(body + attribute)
+    }
+
+    /// The C declaration of `inner` with type `id`, e.g. `int (*fn)(void)`
+    /// for a pointer to a function prototype. Anonymous records and enums
+    /// are spelled out at `indent`.
+    fn decl(&self, id: u32, inner: &str, indent: usize) -> Result<String> {
+        let space = |inner: &str| {
+            if inner.is_empty() || inner.starts_with('[') {
+                inner.to_string()
+            } else {
+                format!(" {}", inner)
+            }
+        };
+        let parens = |inner: &str| {
+            if inner.starts_with('*') {
+                format!("({})", inner)
+            } else {
+                inner.to_string()
+            }
+        };
+        let ty = self.btf.type_by_id(id)?;
+        Ok(match &ty.kind {
+            BtfKind::Void => format!("void{}", space(inner)),
+            BtfKind::Int { .. } | BtfKind::Float { .. } => format!("{}{}", ty.name, space(inner)),
+            BtfKind::Ptr(t) => self.decl(*t, &format!("*{}", inner), indent)?,
+            BtfKind::Const(t) | BtfKind::Volatile(t) | BtfKind::Restrict(t) => {
+                let qualifier = match ty.kind {
+                    BtfKind::Const(_) => "const",
+                    BtfKind::Volatile(_) => "volatile",
+                    _ => "restrict",
+                };
+                // qualifiers of a pointer go after the `*`
+                match self.kind(*t)? {
+                    BtfKind::Ptr(_) => {
+                        self.decl(*t, &format!("{} {}", qualifier, inner), indent)?
+                    }
+                    _ => format!("{} {}", qualifier, self.decl(*t, inner, indent)?),
+                }
+            }
+            BtfKind::TypeTag(t) => self.decl(*t, inner, indent)?,
+            BtfKind::Array { elem, nelems, .. } => {
+                self.decl(*elem, &format!("{}[{}]", parens(inner), nelems), indent)?
+            }
+            BtfKind::FuncProto { ret, params } => {
+                let mut args = Vec::new();
+                for (idx, p) in params.iter().enumerate() {
+                    if p.type_id == 0 && idx == params.len() - 1 {
+                        args.push("...".to_string());
+                    } else {
+                        args.push(self.decl(p.type_id, &p.name, indent)?);
+                    }
+                }
+                if args.is_empty() {
+                    args.push("void".to_string());
+                }
+                self.decl(
+                    *ret,
+                    &format!("{}({})", parens(inner), args.join(", ")),
+                    indent,
+                )?
+            }
+            BtfKind::Struct { .. } | BtfKind::Union { .. } | BtfKind::Enum { .. }
+                if self.name(id).is_empty() =>
+            {
+                format!("{}{}", self.body(id, indent)?, space(inner))
+            }
+            BtfKind::Struct { .. } => format!("struct {}{}", self.name(id), space(inner)),
+            BtfKind::Union { .. } => format!("union {}{}", self.name(id), space(inner)),
+            BtfKind::Enum { .. } => format!("enum {}{}", self.name(id), space(inner)),
+            BtfKind::Fwd { union } => {
+                let keyword = if *union { "union" } else { "struct" };
+                format!("{} {}{}", keyword, self.name(id), space(inner))
+            }
+            BtfKind::Typedef(_) => format!("{}{}", self.name(id), space(inner)),
+            BtfKind::Func { .. }
+            | BtfKind::Var { .. }
+            | BtfKind::Datasec { .. }
+            | BtfKind::DeclTag { .. } => {
+                return Err(Error::Invalid(format!(
+                    "BTF type {} of kind {} has no C type",
+                    id,
+                    ty.kind_name()
+                )))
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::module::btf::fixture::{btf, int, member, ty};
+    use crate::module::btf::{BtfType, EnumValue, Param};
+
+    fn header(types: Vec<BtfType>) -> String {
+        c_header(&btf(types), "__TEST_H__").unwrap()
+    }
+
+    #[test]
+    fn test_order() {
+        let out = header(vec![
+            // 1: typedef int (*cb_t)(struct node *)
+            ty("cb_t", BtfKind::Typedef(2)),
+            ty("", BtfKind::Ptr(3)),
+            ty(
+                "",
+                BtfKind::FuncProto {
+                    ret: 4,
+                    params: vec![Param {
+                        name: String::new(),
+                        type_id: 5,
+                    }],
+                },
+            ),
+            int("int", 4, true),
+            ty("", BtfKind::Ptr(6)),
+            // 6
+            ty(
+                "node",
+                BtfKind::Struct {
+                    size: 16,
+                    members: vec![member("next", 5, 0), member("cb", 1, 64)],
+                },
+            ),
+        ]);
+        let declared = out.find("struct node;\n").unwrap();
+        let typedef = out.find("typedef int (*cb_t)(struct node *);\n").unwrap();
+        let defined = out
+            .find("struct node {\n\tstruct node *next;\n\tcb_t cb;\n};\n")
+            .unwrap();
+        assert!(declared < typedef && typedef < defined);
+        assert!(out.starts_with("#ifndef __TEST_H__\n#define __TEST_H__\n"));
+        assert!(out.ends_with("#endif /* __TEST_H__ */\n"));
+    }
+
+    #[test]
+    fn test_layout() {
+        let out = header(vec![
+            int("char", 1, true),
+            int("int", 4, true),
+            // 3: a gap after `a` that natural alignment doesn't explain
+            ty(
+                "gap",
+                BtfKind::Struct {
+                    size: 16,
+                    members: vec![member("a", 1, 0), member("b", 2, 64)],
+                },
+            ),
+            ty(
+                "misaligned",
+                BtfKind::Struct {
+                    size: 5,
+                    members: vec![member("a", 1, 0), member("b", 2, 8)],
+                },
+            ),
+            ty(
+                "bits",
+                BtfKind::Struct {
+                    size: 4,
+                    members: vec![
+                        Member {
+                            bitfield_size: 3,
+                            ..member("lo", 2, 0)
+                        },
+                        Member {
+                            bitfield_size: 4,
+                            ..member("hi", 2, 8)
+                        },
+                    ],
+                },
+            ),
+        ]);
+        assert!(out.contains(
+            "struct gap {\n\tchar a;\n\tchar: 8;\n\tshort: 16;\n\tint: 32;\n\tint b;\n\tint: 32;\n};\n"
+        ));
+        assert!(
+            out.contains("struct misaligned {\n\tchar a;\n\tint b;\n} __attribute__((packed));\n")
+        );
+        assert!(out.contains("struct bits {\n\tint lo: 3;\n\tchar: 5;\n\tint hi: 4;\n};\n"));
+    }
+
+    #[test]
+    fn test_enums() {
+        let values = |names: &[&str]| {
+            names
+                .iter()
+                .enumerate()
+                .map(|(value, name)| EnumValue {
+                    name: name.to_string(),
+                    value: value as i64,
+                })
+                .collect()
+        };
+        let out = header(vec![
+            // 1: only used by `struct state`
+            ty(
+                "",
+                BtfKind::Enum {
+                    size: 4,
+                    signed: false,
+                    values: values(&["IDLE", "BUSY"]),
+                },
+            ),
+            ty(
+                "state",
+                BtfKind::Struct {
+                    size: 4,
+                    members: vec![member("s", 1, 0)],
+                },
+            ),
+            ty(
+                "",
+                BtfKind::Enum {
+                    size: 4,
+                    signed: false,
+                    values: values(&["MAX_STATES"]),
+                },
+            ),
+            // 4: same names as the first ones
+            ty(
+                "state",
+                BtfKind::Enum {
+                    size: 1,
+                    signed: false,
+                    values: values(&["IDLE"]),
+                },
+            ),
+        ]);
+        assert!(
+            out.contains("struct state {\n\tenum {\n\t\tIDLE = 0,\n\t\tBUSY = 1,\n\t} s;\n};\n")
+        );
+        assert_eq!(out.matches("BUSY").count(), 1);
+        assert!(out.contains("enum {\n\tMAX_STATES = 0,\n};\n"));
+        assert!(out.contains("enum state___2 {\n\tIDLE___2 = 0,\n} __attribute__((packed));\n"));
+    }
+}
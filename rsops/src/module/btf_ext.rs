@@ -0,0 +1,387 @@
+//! Parser for the `.BTF.ext` section, which maps the instructions of each
+//! program section back to functions and C source lines, and lists the
+//! CO-RE relocations applied by [`core_reloc`](crate::module::core_reloc).
+
+use crate::error::{Error, Result};
+use crate::module::btf::{Btf, BTF_MAGIC};
+use std::convert::TryInto;
+use std::fmt::{self, Display};
+
+/// A `bpf_line_info` record resolved against the BTF string section.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SourceLine {
+    /// first instruction generated for this line: a byte offset within
+    /// the section as parsed into [`SectionInfo`], an instruction index
+    /// within the program in [`ProgramSpec::line_info`]
+    ///
+    /// [`ProgramSpec::line_info`]: crate::module::object::ProgramSpec::line_info
+    pub insn_off: u32,
+    pub file: String,
+    pub line: u32,
+    pub col: u32,
+    /// text of the source line, if the compiler recorded it
+    pub source: String,
+}
+
+impl SourceLine {
+    /// Decodes the `line_col` field packing the line number in the upper
+    /// 22 bits and the column in the lower 10.
+    pub fn resolve(
+        btf: &Btf,
+        insn_off: u32,
+        file_name_off: u32,
+        line_off: u32,
+        line_col: u32,
+    ) -> Result<SourceLine> {
+        Ok(SourceLine {
+            insn_off,
+            file: btf.string(file_name_off)?.to_string(),
+            line: line_col >> 10,
+            col: line_col & 0x3ff,
+            source: btf.string(line_off)?.trim().to_string(),
+        })
+    }
+}
+
+impl Display for SourceLine {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "; {} [{}:{}:{}]",
+            self.source, self.file, self.line, self.col
+        )
+    }
+}
+
+/// A `bpf_func_info` record: the function of type `type_id` starts at
+/// `insn_off`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FuncInfo {
+    /// byte offset within the section
+    pub insn_off: u32,
+    pub type_id: u32,
+}
+
+/// What a CO-RE relocation computes, `enum bpf_core_relo_kind`.
+#[repr(u32)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum CoreRelocKind {
+    FieldByteOffset = 0,
+    FieldByteSize,
+    FieldExists,
+    FieldSigned,
+    FieldLshiftU64,
+    FieldRshiftU64,
+    TypeIdLocal,
+    TypeIdTarget,
+    TypeExists,
+    TypeSize,
+    EnumvalExists,
+    EnumvalValue,
+    TypeMatches,
+}
+
+const CORE_RELOC_KINDS: [(CoreRelocKind, &str); 13] = [
+    (CoreRelocKind::FieldByteOffset, "byte_off"),
+    (CoreRelocKind::FieldByteSize, "byte_sz"),
+    (CoreRelocKind::FieldExists, "field_exists"),
+    (CoreRelocKind::FieldSigned, "signed"),
+    (CoreRelocKind::FieldLshiftU64, "lshift_u64"),
+    (CoreRelocKind::FieldRshiftU64, "rshift_u64"),
+    (CoreRelocKind::TypeIdLocal, "local_type_id"),
+    (CoreRelocKind::TypeIdTarget, "target_type_id"),
+    (CoreRelocKind::TypeExists, "type_exists"),
+    (CoreRelocKind::TypeSize, "type_size"),
+    (CoreRelocKind::EnumvalExists, "enumval_exists"),
+    (CoreRelocKind::EnumvalValue, "enumval_value"),
+    (CoreRelocKind::TypeMatches, "type_matches"),
+];
+
+impl CoreRelocKind {
+    pub fn from_u32(value: u32) -> Option<CoreRelocKind> {
+        CORE_RELOC_KINDS.get(value as usize).map(|(k, _)| *k)
+    }
+
+    pub fn name(self) -> &'static str {
+        CORE_RELOC_KINDS[self as usize].1
+    }
+
+    /// Relocations of a struct, union or array member.
+    pub fn is_field(self) -> bool {
+        (self as u32) <= CoreRelocKind::FieldRshiftU64 as u32
+    }
+
+    /// Relocations of an enumerator.
+    pub fn is_enumval(self) -> bool {
+        self == CoreRelocKind::EnumvalExists || self == CoreRelocKind::EnumvalValue
+    }
+}
+
+/// A `bpf_core_relo` record: the instruction at `insn_off` holds a value
+/// computed by `kind` from local type `type_id`, e.g. a field offset.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CoreReloc {
+    /// relocated instruction, in bytes or as an index like
+    /// [`SourceLine::insn_off`]
+    pub insn_off: u32,
+    pub type_id: u32,
+    /// accessor string such as `0:1:2`: an index into an array of
+    /// `type_id`, then member or element indices
+    pub access: String,
+    pub kind: CoreRelocKind,
+}
+
+/// The records of one program section.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SectionInfo {
+    pub func_info: Vec<FuncInfo>,
+    /// line records, `insn_off` in bytes within the section
+    pub line_info: Vec<SourceLine>,
+    /// CO-RE relocations, `insn_off` in bytes within the section
+    pub core_relocs: Vec<CoreReloc>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct BtfExt {
+    /// section name and its records, in file order
+    pub sections: Vec<(String, SectionInfo)>,
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl Reader<'_> {
+    fn u32(&mut self) -> Result<u32> {
+        let bytes = self
+            .data
+            .get(self.pos..self.pos + 4)
+            .ok_or_else(|| Error::Invalid("BTF.ext section truncated".to_string()))?;
+        self.pos += 4;
+        Ok(u32::from_le_bytes(bytes.try_into().unwrap()))
+    }
+}
+
+/// Walks one info subsection: a record size followed by blocks of
+/// `{ sec_name_off, num_info, records[num_info] }`. `record` reads the
+/// leading fields it knows, the rest of each record is skipped.
+fn parse_info<F>(btf: &Btf, data: &[u8], ext: &mut BtfExt, mut record: F) -> Result<()>
+where
+    F: FnMut(&mut Reader<'_>, &mut SectionInfo) -> Result<()>,
+{
+    if data.is_empty() {
+        return Ok(());
+    }
+    let mut r = Reader { data, pos: 0 };
+    let rec_size = r.u32()? as usize;
+    while r.pos < data.len() {
+        let section = btf.string(r.u32()?)?.to_string();
+        let num_info = r.u32()?;
+        let idx = match ext.sections.iter().position(|(name, _)| *name == section) {
+            Some(idx) => idx,
+            None => {
+                ext.sections.push((section, SectionInfo::default()));
+                ext.sections.len() - 1
+            }
+        };
+        for _ in 0..num_info {
+            let start = r.pos;
+            record(&mut r, &mut ext.sections[idx].1)?;
+            if r.pos - start > rec_size {
+                return Err(Error::Invalid(format!(
+                    "BTF.ext record size {} too small",
+                    rec_size
+                )));
+            }
+            r.pos = start + rec_size;
+        }
+    }
+    Ok(())
+}
+
+impl BtfExt {
+    /// Parses `.BTF.ext`, resolving strings against the object's `.BTF`.
+    pub fn parse(data: &[u8], btf: &Btf) -> Result<BtfExt> {
+        let invalid = |msg: &str| Error::Invalid(format!("invalid BTF.ext: {}", msg));
+        let field = |off: usize| -> Result<u32> {
+            data.get(off..off + 4)
+                .map(|b| u32::from_le_bytes(b.try_into().unwrap()))
+                .ok_or_else(|| invalid("header truncated"))
+        };
+        if data.len() < 24 || u16::from_le_bytes([data[0], data[1]]) != BTF_MAGIC {
+            return Err(invalid("bad magic"));
+        }
+        let hdr_len = field(4)? as usize;
+        let subsection = |off: usize, len: usize| {
+            data.get(hdr_len + off..hdr_len + off + len)
+                .ok_or_else(|| invalid("subsection out of bounds"))
+        };
+        let mut ext = BtfExt::default();
+        parse_info(
+            btf,
+            subsection(field(8)? as usize, field(12)? as usize)?,
+            &mut ext,
+            |r, sec| {
+                sec.func_info.push(FuncInfo {
+                    insn_off: r.u32()?,
+                    type_id: r.u32()?,
+                });
+                Ok(())
+            },
+        )?;
+        parse_info(
+            btf,
+            subsection(field(16)? as usize, field(20)? as usize)?,
+            &mut ext,
+            |r, sec| {
+                let (insn_off, file, line, line_col) = (r.u32()?, r.u32()?, r.u32()?, r.u32()?);
+                sec.line_info
+                    .push(SourceLine::resolve(btf, insn_off, file, line, line_col)?);
+                Ok(())
+            },
+        )?;
+        // the CO-RE subsection came later and extends the header
+        if hdr_len >= 32 {
+            parse_info(
+                btf,
+                subsection(field(24)? as usize, field(28)? as usize)?,
+                &mut ext,
+                |r, sec| {
+                    let (insn_off, type_id, access, kind) =
+                        (r.u32()?, r.u32()?, r.u32()?, r.u32()?);
+                    sec.core_relocs.push(CoreReloc {
+                        insn_off,
+                        type_id,
+                        access: btf.string(access)?.to_string(),
+                        kind: CoreRelocKind::from_u32(kind).ok_or_else(|| {
+                            Error::Invalid(format!("unknown CO-RE relocation kind {}", kind))
+                        })?,
+                    });
+                    Ok(())
+                },
+            )?;
+        }
+        Ok(ext)
+    }
+
+    pub fn section(&self, name: &str) -> Option<&SectionInfo> {
+        self.sections
+            .iter()
+            .find(|(section, _)| section == name)
+            .map(|(_, info)| info)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::module::btf::fixture::raw;
+
+    /// Section `xdp` at 1, file `prog.c` at 5, line `x = 1;` at 12 and
+    /// accessor `0:1` at 19.
+    const STRINGS: &[u8] = b"\0xdp\0prog.c\0x = 1;\x000:1\0";
+
+    const FUNC_INFO: [u32; 5] = [8, 1, 1, 0, 1];
+    const LINE_INFO: [u32; 11] = [16, 1, 2, 0, 5, 12, 3 << 10 | 2, 16, 5, 12, 4 << 10 | 1];
+    const CORE_RELOCS: [u32; 7] = [16, 1, 1, 8, 1, 19, 0];
+
+    /// Raw `.BTF.ext` with a `hdr_len` byte header followed by the given
+    /// subsections, in words.
+    fn raw_ext(hdr_len: u32, subsections: &[&[u32]]) -> Vec<u8> {
+        let mut data = BTF_MAGIC.to_le_bytes().to_vec();
+        data.extend_from_slice(&[1, 0]);
+        data.extend_from_slice(&hdr_len.to_le_bytes());
+        let mut off = 0u32;
+        for sub in subsections {
+            let len = (sub.len() * 4) as u32;
+            data.extend_from_slice(&off.to_le_bytes());
+            data.extend_from_slice(&len.to_le_bytes());
+            off += len;
+        }
+        data.resize(hdr_len as usize, 0);
+        for word in subsections.iter().flat_map(|sub| sub.iter()) {
+            data.extend_from_slice(&word.to_le_bytes());
+        }
+        data
+    }
+
+    fn btf() -> Btf {
+        Btf::parse(&raw(&[], STRINGS)).unwrap()
+    }
+
+    #[test]
+    fn test_parse() {
+        let data = raw_ext(32, &[&FUNC_INFO, &LINE_INFO, &CORE_RELOCS]);
+        let ext = BtfExt::parse(&data, &btf()).unwrap();
+        let xdp = ext.section("xdp").unwrap();
+        assert_eq!(
+            xdp.func_info,
+            vec![FuncInfo {
+                insn_off: 0,
+                type_id: 1
+            }]
+        );
+        let lines: Vec<_> = xdp
+            .line_info
+            .iter()
+            .map(|l| (l.insn_off, l.line, l.col))
+            .collect();
+        assert_eq!(lines, vec![(0, 3, 2), (16, 4, 1)]);
+        assert_eq!(xdp.line_info[1].file, "prog.c");
+        assert_eq!(xdp.line_info[1].source, "x = 1;");
+        assert_eq!(
+            xdp.core_relocs,
+            vec![CoreReloc {
+                insn_off: 8,
+                type_id: 1,
+                access: "0:1".to_string(),
+                kind: CoreRelocKind::FieldByteOffset,
+            }]
+        );
+    }
+
+    #[test]
+    fn test_parse_without_core() {
+        // a 24 byte header predates the CO-RE subsection
+        let data = raw_ext(24, &[&FUNC_INFO, &LINE_INFO]);
+        let ext = BtfExt::parse(&data, &btf()).unwrap();
+        let xdp = ext.section("xdp").unwrap();
+        assert_eq!(xdp.line_info.len(), 2);
+        assert!(xdp.core_relocs.is_empty());
+    }
+
+    #[test]
+    fn test_parse_truncated() {
+        let btf = btf();
+        let data = raw_ext(32, &[&FUNC_INFO, &LINE_INFO, &CORE_RELOCS]);
+        assert!(BtfExt::parse(&data[..20], &btf).is_err());
+        assert!(BtfExt::parse(&data[..28], &btf).is_err());
+        assert!(BtfExt::parse(&data[..data.len() - 4], &btf).is_err());
+        assert!(BtfExt::parse(&[0; 32], &btf).is_err());
+        // two line records announced, one present
+        let data = raw_ext(24, &[&FUNC_INFO, &LINE_INFO[..7]]);
+        assert!(BtfExt::parse(&data, &btf).is_err());
+    }
+
+    #[test]
+    fn test_parse_record_size() {
+        let btf = btf();
+        // records smaller than the fields the parser reads
+        let mut lines = LINE_INFO;
+        lines[0] = 12;
+        assert!(BtfExt::parse(&raw_ext(24, &[&FUNC_INFO, &lines]), &btf).is_err());
+        // larger records come from newer compilers, the tail is skipped
+        let funcs = [12, 1, 1, 0, 1, 7];
+        let ext = BtfExt::parse(&raw_ext(24, &[&funcs, &LINE_INFO]), &btf).unwrap();
+        assert_eq!(ext.section("xdp").unwrap().func_info[0].type_id, 1);
+    }
+
+    #[test]
+    fn test_parse_unknown_kind() {
+        let mut relocs = CORE_RELOCS;
+        relocs[6] = 99;
+        let data = raw_ext(32, &[&FUNC_INFO, &LINE_INFO, &relocs]);
+        assert!(BtfExt::parse(&data, &btf()).is_err());
+    }
+}
@@ -0,0 +1,1017 @@
+//! Generates Rust bindings from BTF, so that map values, ring buffer
+//! events and other structs shared with BPF programs are declared once in
+//! C and used from Rust with the same layout.
+//!
+//! Structs and unions become `#[repr(C)]` with every padding byte spelled
+//! out, which makes them [`Pod`](crate::module::map::Pod), and a const
+//! assertion checks their size and alignment. Bitfields are stored as
+//! bytes behind getters and setters, enums become newtypes with associated
+//! constants since the kernel may hand back values the enum doesn't name,
+//! `bool` becomes `u8`, and pointers become `u64` because BPF pointers are
+//! 64-bit whatever the host. The bindings assume a little-endian target.
+
+use crate::error::{Error, Result};
+use crate::module::btf::{Btf, BtfKind, Layout, Member, BTF_INT_BOOL, BTF_INT_SIGNED};
+use crate::module::object::ObjectFile;
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::fs;
+use std::path::Path;
+
+const KEYWORDS: [&str; 48] = [
+    "abstract", "as", "async", "await", "become", "box", "break", "const", "continue", "do", "dyn",
+    "else", "enum", "extern", "false", "final", "fn", "for", "gen", "if", "impl", "in", "let",
+    "loop", "macro", "match", "mod", "move", "mut", "override", "priv", "pub", "ref", "return",
+    "static", "struct", "trait", "true", "try", "type", "typeof", "unsafe", "unsized", "use",
+    "virtual", "where", "while", "yield",
+];
+
+const PRIMITIVES: [&str; 16] = [
+    "u8", "u16", "u32", "u64", "u128", "usize", "i8", "i16", "i32", "i64", "i128", "isize", "f32",
+    "f64", "bool", "char",
+];
+
+/// A bitfield member and where it lives in its struct's byte storage.
+struct Bitfield<'a> {
+    member: &'a Member,
+    storage: String,
+    offset: u32,
+    bits: u32,
+}
+
+struct Generator<'a> {
+    btf: &'a Btf,
+    layouts: Vec<Layout>,
+    /// unique C-derived name of each record, enum and typedef, empty for
+    /// anonymous enums that only provide constants
+    names: Vec<String>,
+    /// typedefs spelled as the type they name
+    transparent: Vec<bool>,
+    /// whether a type contains a union by value, and so can't derive Debug
+    unions: Vec<Option<bool>>,
+    /// how many constants have each name, enum newtypes counting as their
+    /// constructor
+    constants: HashMap<String, usize>,
+    out: String,
+}
+
+/// Renders the types called `names`, and the types they contain by value,
+/// as Rust items; all named types if `names` is empty. A name may also be
+/// an enumerator of an anonymous enum, which is rendered as constants.
+///
+/// The output refers to this crate as `::rsops` and is meant to be
+/// `include!`d, e.g. from a file written by [`write_object_types`].
+pub fn rust_types(btf: &Btf, names: &[&str]) -> Result<String> {
+    let mut gen = Generator::new(btf)?;
+    let count = btf.types.len();
+    let mut stack = Vec::new();
+    if names.is_empty() {
+        for id in 1..count as u32 {
+            let ty = &btf.types[id as usize];
+            let root = match ty.kind {
+                BtfKind::Struct { .. } | BtfKind::Union { .. } | BtfKind::Typedef(_) => {
+                    !ty.name.is_empty()
+                }
+                BtfKind::Enum { .. } => !ty.name.is_empty() || gen.names[id as usize].is_empty(),
+                _ => false,
+            };
+            if root {
+                stack.push(id);
+            }
+        }
+    }
+    for name in names {
+        let before = stack.len();
+        for (id, ty) in btf.types.iter().enumerate() {
+            let found = match &ty.kind {
+                BtfKind::Struct { .. }
+                | BtfKind::Union { .. }
+                | BtfKind::Enum { .. }
+                | BtfKind::Typedef(_) => ty.name == *name,
+                _ => false,
+            } || match &ty.kind {
+                BtfKind::Enum { values, .. } if gen.names[id].is_empty() => {
+                    values.iter().any(|v| v.name == *name)
+                }
+                _ => false,
+            };
+            if found {
+                stack.push(id as u32);
+            }
+        }
+        if stack.len() == before {
+            return Err(Error::NotFound(format!("BTF type {}", name)));
+        }
+    }
+
+    let mut wanted = vec![false; count];
+    while let Some(id) = stack.pop() {
+        if std::mem::replace(&mut wanted[id as usize], true) {
+            continue;
+        }
+        match &btf.type_by_id(id)?.kind {
+            BtfKind::Struct { members, .. } | BtfKind::Union { members, .. } => {
+                stack.extend(members.iter().map(|m| m.type_id));
+            }
+            BtfKind::Array { elem, .. } => stack.push(*elem),
+            BtfKind::Typedef(t)
+            | BtfKind::Const(t)
+            | BtfKind::Volatile(t)
+            | BtfKind::Restrict(t)
+            | BtfKind::TypeTag(t) => stack.push(*t),
+            _ => {}
+        }
+    }
+
+    gen.out
+        .push_str("// Generated by rsops from BTF, do not edit.\n");
+    for id in 1..count as u32 {
+        if wanted[id as usize] {
+            gen.item(id)?;
+        }
+    }
+    Ok(gen.out)
+}
+
+/// Writes bindings for the types called `names` in the `.BTF` section of
+/// the object file at `object` to `out`, e.g. from a build script:
+///
+/// ```ignore
+/// let out = Path::new(&env::var("OUT_DIR")?).join("types.rs");
+/// btf_rust::write_object_types("probe.o", &["event"], &out)?;
+/// // and in the crate: include!(concat!(env!("OUT_DIR"), "/types.rs"));
+/// ```
+pub fn write_object_types<P: AsRef<Path>, Q: AsRef<Path>>(
+    object: P,
+    names: &[&str],
+    out: Q,
+) -> Result<()> {
+    let path = object.as_ref();
+    let object = ObjectFile::open(path)?;
+    let btf = object
+        .btf
+        .as_ref()
+        .ok_or_else(|| Error::NotFound(format!("BTF in {}", path.display())))?;
+    fs::write(out, rust_types(btf, names)?)?;
+    Ok(())
+}
+
+/// Reads the `bits` wide bitfield at bit `offset` of `storage`, for
+/// generated getters.
+pub fn get_bits(storage: &[u8], offset: u32, bits: u32) -> u64 {
+    (0..bits).fold(0, |value, i| {
+        let bit = (offset + i) as usize;
+        value | (u64::from((storage[bit / 8] >> (bit % 8)) & 1) << i)
+    })
+}
+
+/// Writes the low `bits` of `value` to the bitfield at bit `offset` of
+/// `storage`, for generated setters.
+pub fn set_bits(storage: &mut [u8], offset: u32, bits: u32, value: u64) {
+    for i in 0..bits {
+        let bit = (offset + i) as usize;
+        let mask = 1 << (bit % 8);
+        if (value >> i) & 1 != 0 {
+            storage[bit / 8] |= mask;
+        } else {
+            storage[bit / 8] &= !mask;
+        }
+    }
+}
+
+/// A C identifier as a Rust one.
+pub fn ident(name: &str) -> String {
+    match name {
+        "self" | "Self" | "super" | "crate" | "_" => format!("{}_", name),
+        _ if KEYWORDS.contains(&name) => format!("r#{}", name),
+        _ => name.to_string(),
+    }
+}
+
+/// A C type name as a Rust one, which may not shadow a primitive.
+pub fn type_ident(name: &str) -> String {
+    if PRIMITIVES.contains(&name) {
+        format!("{}_", name)
+    } else {
+        ident(name)
+    }
+}
+
+fn int_type(size: u32, signed: bool) -> Option<&'static str> {
+    Some(match (size, signed) {
+        (1, false) => "u8",
+        (2, false) => "u16",
+        (4, false) => "u32",
+        (8, false) => "u64",
+        (16, false) => "u128",
+        (1, true) => "i8",
+        (2, true) => "i16",
+        (4, true) => "i32",
+        (8, true) => "i64",
+        (16, true) => "i128",
+        _ => return None,
+    })
+}
+
+/// The representation of an enum of `size` bytes.
+fn enum_repr(name: &str, size: u32, signed: bool) -> Result<&'static str> {
+    int_type(size, signed)
+        .ok_or_else(|| Error::Invalid(format!("enum {} has unsupported size {}", name, size)))
+}
+
+/// Whether `id` contains a union by value.
+fn has_union(btf: &Btf, id: u32, unions: &mut Vec<Option<bool>>) -> Result<bool> {
+    if let Some(found) = unions[id as usize] {
+        return Ok(found);
+    }
+    let found = match &btf.type_by_id(id)?.kind {
+        BtfKind::Union { .. } => true,
+        BtfKind::Struct { members, .. } => {
+            let mut found = false;
+            for m in members {
+                found |= has_union(btf, m.type_id, unions)?;
+            }
+            found
+        }
+        BtfKind::Array { elem: t, .. }
+        | BtfKind::Typedef(t)
+        | BtfKind::Const(t)
+        | BtfKind::Volatile(t)
+        | BtfKind::Restrict(t)
+        | BtfKind::TypeTag(t) => has_union(btf, *t, unions)?,
+        _ => false,
+    };
+    unions[id as usize] = Some(found);
+    Ok(found)
+}
+
+impl<'a> Generator<'a> {
+    fn new(btf: &'a Btf) -> Result<Generator<'a>> {
+        let count = btf.types.len();
+        let mut gen = Generator {
+            btf,
+            layouts: btf.layouts()?,
+            names: vec![String::new(); count],
+            transparent: vec![false; count],
+            unions: vec![None; count],
+            constants: HashMap::new(),
+            out: String::new(),
+        };
+        // Rust has a single namespace for all of these, later duplicates
+        // get a ___N suffix the way libbpf does
+        let mut seen = HashMap::new();
+        let mut unique = |name: String| {
+            let count = seen.entry(name.clone()).or_insert(0);
+            *count += 1;
+            match *count {
+                1 => name,
+                n => format!("{}___{}", name, n),
+            }
+        };
+        let mut referenced = vec![false; count];
+        for ty in &btf.types {
+            match &ty.kind {
+                BtfKind::Struct { members, .. } | BtfKind::Union { members, .. } => {
+                    for m in members {
+                        referenced[m.type_id as usize] = true;
+                    }
+                }
+                BtfKind::Array { elem, .. } => referenced[*elem as usize] = true,
+                BtfKind::FuncProto { ret, params } => {
+                    referenced[*ret as usize] = true;
+                    for p in params {
+                        referenced[p.type_id as usize] = true;
+                    }
+                }
+                _ => {
+                    if let Some(t) = ty.referenced() {
+                        referenced[t as usize] = true;
+                    }
+                }
+            }
+        }
+
+        for (id, ty) in btf.types.iter().enumerate() {
+            match &ty.kind {
+                BtfKind::Struct { .. } | BtfKind::Union { .. } | BtfKind::Enum { .. }
+                    if !ty.name.is_empty() =>
+                {
+                    gen.names[id] = unique(ty.name.clone());
+                }
+                BtfKind::Typedef(t) => {
+                    // typedefs named like a primitive or like the record
+                    // they alias, and those naming an anonymous record or
+                    // enum, add no type of their own
+                    let target = gen.skip_mods(*t)?;
+                    let tagged = matches!(
+                        btf.types[target as usize].kind,
+                        BtfKind::Struct { .. } | BtfKind::Union { .. } | BtfKind::Enum { .. }
+                    );
+                    let target_name = &btf.types[target as usize].name;
+                    if tagged && target_name.is_empty() && gen.names[target as usize].is_empty() {
+                        gen.names[target as usize] = unique(ty.name.clone());
+                        gen.transparent[id] = true;
+                    } else if (tagged && *target_name == ty.name)
+                        || PRIMITIVES.contains(&ty.name.as_str())
+                    {
+                        gen.transparent[id] = true;
+                    } else {
+                        gen.names[id] = unique(ty.name.clone());
+                    }
+                }
+                _ => {}
+            }
+        }
+        for id in 0..count as u32 {
+            if !gen.names[id as usize].is_empty() {
+                gen.name_members(id, &mut unique)?;
+            }
+        }
+        for (id, ty) in btf.types.iter().enumerate() {
+            let anonymous = match ty.kind {
+                BtfKind::Struct { .. } | BtfKind::Union { .. } => true,
+                BtfKind::Enum { .. } => referenced[id],
+                _ => false,
+            };
+            if anonymous && gen.names[id].is_empty() {
+                gen.names[id] = unique(format!("__anon_{}", id));
+            }
+        }
+        for (id, ty) in btf.types.iter().enumerate() {
+            if let BtfKind::Enum { .. } = ty.kind {
+                if !gen.names[id].is_empty() {
+                    gen.constants.insert(type_ident(&gen.names[id]), 1);
+                }
+            }
+        }
+        Ok(gen)
+    }
+
+    /// Names the anonymous records and enums the members of `id` are
+    /// declared with after the member, e.g. `dentry__d_u` or `sk_buff__anon_0`.
+    fn name_members(&mut self, id: u32, unique: &mut impl FnMut(String) -> String) -> Result<()> {
+        let members = match &self.btf.type_by_id(id)?.kind {
+            BtfKind::Struct { members, .. } | BtfKind::Union { members, .. } => members,
+            _ => return Ok(()),
+        };
+        for (idx, m) in members.iter().enumerate() {
+            let mut t = self.skip_mods(m.type_id)?;
+            while let BtfKind::Array { elem, .. } = self.btf.type_by_id(t)?.kind {
+                t = self.skip_mods(elem)?;
+            }
+            let anonymous = match self.btf.type_by_id(t)?.kind {
+                BtfKind::Struct { .. } | BtfKind::Union { .. } | BtfKind::Enum { .. } => {
+                    self.names[t as usize].is_empty()
+                }
+                _ => false,
+            };
+            if anonymous {
+                let field = if m.name.is_empty() {
+                    format!("anon_{}", idx)
+                } else {
+                    m.name.clone()
+                };
+                self.names[t as usize] = unique(format!("{}__{}", self.names[id as usize], field));
+                self.name_members(t, unique)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Follows const/volatile/restrict modifiers, but not typedefs.
+    fn skip_mods(&self, mut id: u32) -> Result<u32> {
+        loop {
+            match self.btf.type_by_id(id)?.kind {
+                BtfKind::Const(t)
+                | BtfKind::Volatile(t)
+                | BtfKind::Restrict(t)
+                | BtfKind::TypeTag(t) => id = t,
+                _ => return Ok(id),
+            }
+        }
+    }
+
+    /// The Rust type of a value of type `id`.
+    fn spell(&self, id: u32) -> Result<String> {
+        let ty = self.btf.type_by_id(id)?;
+        Ok(match &ty.kind {
+            BtfKind::Int { size, encoding, .. } => {
+                let signed = encoding & BTF_INT_SIGNED != 0 && encoding & BTF_INT_BOOL == 0;
+                match int_type(*size, signed) {
+                    Some(name) => name.to_string(),
+                    None => format!("[u8; {}]", size),
+                }
+            }
+            BtfKind::Float { size: 4 } => "f32".to_string(),
+            BtfKind::Float { size: 8 } => "f64".to_string(),
+            BtfKind::Float { size } => format!("[u8; {}]", size),
+            BtfKind::Ptr(_) => "u64".to_string(),
+            BtfKind::Array { elem, nelems, .. } => format!("[{}; {}]", self.spell(*elem)?, nelems),
+            BtfKind::Typedef(t) if self.transparent[id as usize] => self.spell(*t)?,
+            BtfKind::Struct { .. }
+            | BtfKind::Union { .. }
+            | BtfKind::Enum { .. }
+            | BtfKind::Typedef(_) => type_ident(&self.names[id as usize]),
+            BtfKind::Const(t)
+            | BtfKind::Volatile(t)
+            | BtfKind::Restrict(t)
+            | BtfKind::TypeTag(t) => self.spell(*t)?,
+            _ => {
+                return Err(Error::Invalid(format!(
+                    "BTF {} {} can't be used by value",
+                    ty.kind_name(),
+                    if ty.name.is_empty() {
+                        id.to_string()
+                    } else {
+                        ty.name.clone()
+                    }
+                )))
+            }
+        })
+    }
+
+    /// The alignment Rust gives the type [`spell`](Generator::spell)
+    /// returns for `id`.
+    fn rust_align(&self, id: u32) -> Result<u32> {
+        Ok(match &self.btf.type_by_id(id)?.kind {
+            BtfKind::Int { size, .. } if int_type(*size, false).is_some() => *size,
+            BtfKind::Float {
+                size: size @ (4 | 8),
+            } => *size,
+            BtfKind::Ptr(_) => 8,
+            BtfKind::Array { elem: t, .. }
+            | BtfKind::Typedef(t)
+            | BtfKind::Const(t)
+            | BtfKind::Volatile(t)
+            | BtfKind::Restrict(t)
+            | BtfKind::TypeTag(t) => self.rust_align(*t)?,
+            BtfKind::Struct { .. } | BtfKind::Union { .. } | BtfKind::Enum { .. } => {
+                self.layouts[id as usize].align
+            }
+            _ => 1,
+        })
+    }
+
+    /// The bit offset and width of `m` if it is a bitfield, either
+    /// explicitly or as an int type narrower than its size.
+    fn bitfield(&self, m: &Member) -> Result<Option<(u32, u32)>> {
+        if m.bitfield_size != 0 {
+            return Ok(Some((m.bit_offset, m.bitfield_size)));
+        }
+        let t = self.btf.skip_mods_and_typedefs(m.type_id)?;
+        match self.btf.type_by_id(t)?.kind {
+            BtfKind::Int {
+                size, offset, bits, ..
+            } if offset != 0 || u32::from(bits) != size * 8 => {
+                Ok(Some((m.bit_offset + u32::from(offset), u32::from(bits))))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    fn item(&mut self, id: u32) -> Result<()> {
+        let ty = self.btf.type_by_id(id)?;
+        match &ty.kind {
+            BtfKind::Struct { .. } | BtfKind::Union { .. } => self.record(id),
+            BtfKind::Enum { .. } => self.enumeration(id),
+            BtfKind::Typedef(t) if !self.transparent[id as usize] => {
+                // typedefs of functions and incomplete types can only be
+                // pointed to, which needs no alias
+                if let Ok(target) = self.spell(*t) {
+                    writeln!(
+                        self.out,
+                        "\n#[allow(non_camel_case_types, dead_code)]\npub type {} = {};",
+                        type_ident(&self.names[id as usize]),
+                        target
+                    )
+                    .unwrap();
+                }
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn enumeration(&mut self, id: u32) -> Result<()> {
+        let ty = self.btf.type_by_id(id)?;
+        let (size, signed, values) = match &ty.kind {
+            BtfKind::Enum {
+                size,
+                signed,
+                values,
+            } => (*size, *signed, values),
+            _ => unreachable!(),
+        };
+        let repr = enum_repr(&ty.name, size, signed)?;
+        let literal = |value: i64| {
+            let shift = 64 - size * 8;
+            if signed {
+                ((value << shift) >> shift).to_string()
+            } else {
+                (((value as u64) << shift) >> shift).to_string()
+            }
+        };
+        if self.names[id as usize].is_empty() {
+            // an anonymous enum nothing refers to only defines constants
+            for v in values {
+                let count = self.constants.entry(ident(&v.name)).or_insert(0);
+                *count += 1;
+                let name = match *count {
+                    1 => ident(&v.name),
+                    n => format!("{}___{}", v.name, n),
+                };
+                writeln!(
+                    self.out,
+                    "\n#[allow(non_upper_case_globals, dead_code)]\npub const {}: {} = {};",
+                    name,
+                    repr,
+                    literal(v.value)
+                )
+                .unwrap();
+            }
+            return Ok(());
+        }
+        let name = type_ident(&self.names[id as usize]);
+        writeln!(
+            self.out,
+            "\n#[repr(transparent)]\n\
+             #[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]\n\
+             #[allow(non_camel_case_types, dead_code)]\n\
+             pub struct {}(pub {});",
+            name, repr
+        )
+        .unwrap();
+        if !values.is_empty() {
+            writeln!(
+                self.out,
+                "\n#[allow(non_upper_case_globals, dead_code)]\nimpl {} {{",
+                name
+            )
+            .unwrap();
+            for v in values {
+                writeln!(
+                    self.out,
+                    "    pub const {}: {} = {}({});",
+                    ident(&v.name),
+                    name,
+                    name,
+                    literal(v.value)
+                )
+                .unwrap();
+            }
+            self.out.push_str("}\n");
+        }
+        writeln!(
+            self.out,
+            "\nunsafe impl ::rsops::module::map::Pod for {} {{}}",
+            name
+        )
+        .unwrap();
+        Ok(())
+    }
+
+    fn record(&mut self, id: u32) -> Result<()> {
+        let btf = self.btf;
+        let ty = btf.type_by_id(id)?;
+        let (size, members, union) = match &ty.kind {
+            BtfKind::Struct { size, members } => (*size, members, false),
+            BtfKind::Union { size, members } => (*size, members, true),
+            _ => unreachable!(),
+        };
+        let name = type_ident(&self.names[id as usize]);
+        let Layout { align, packed } = self.layouts[id as usize];
+        let invalid =
+            |what: String| Error::Invalid(format!("{} {}: {}", ty.kind_name(), name, what));
+        let field_name = |idx: usize, m: &Member| {
+            if m.name.is_empty() {
+                format!("__anon_{}", idx)
+            } else {
+                ident(&m.name)
+            }
+        };
+
+        let mut fields = Vec::new();
+        let mut bitfields = Vec::new();
+        // the alignment Rust derives from the fields, and the end of the
+        // last one
+        let mut natural = 1;
+        let mut end = 0;
+        let mut pads = 0;
+        let mut idx = 0;
+        while idx < members.len() {
+            let m = &members[idx];
+            let (start, stop, field) = match self.bitfield(m)? {
+                Some((bit, bits)) if union => {
+                    let storage = format!("_bitfield_{}", bitfields.len());
+                    bitfields.push(Bitfield {
+                        member: m,
+                        storage: storage.clone(),
+                        offset: bit,
+                        bits,
+                    });
+                    idx += 1;
+                    let stop = (bit + bits).div_ceil(8);
+                    (0, stop, format!("{}: [u8; {}]", storage, stop))
+                }
+                Some((bit, _)) => {
+                    // consecutive bitfields share one storage
+                    let start = bit / 8;
+                    let storage = format!("_bitfield_{}", bitfields.len());
+                    let mut stop = start;
+                    while let Some(m) = members.get(idx) {
+                        let (bit, bits) = match self.bitfield(m)? {
+                            Some(bitfield) => bitfield,
+                            None => break,
+                        };
+                        stop = stop.max((bit + bits).div_ceil(8));
+                        bitfields.push(Bitfield {
+                            member: m,
+                            storage: storage.clone(),
+                            offset: bit - start * 8,
+                            bits,
+                        });
+                        idx += 1;
+                    }
+                    (start, stop, format!("{}: [u8; {}]", storage, stop - start))
+                }
+                None => {
+                    if m.bit_offset % 8 != 0 || (union && m.bit_offset != 0) {
+                        return Err(invalid(format!(
+                            "member {} is at bit offset {}",
+                            m.name, m.bit_offset
+                        )));
+                    }
+                    let start = m.bit_offset / 8;
+                    natural = natural.max(self.rust_align(m.type_id)?);
+                    let field = format!("pub {}: {}", field_name(idx, m), self.spell(m.type_id)?);
+                    idx += 1;
+                    (start, start + btf.type_size(m.type_id)?, field)
+                }
+            };
+            if union {
+                end = end.max(stop);
+            } else {
+                if start < end {
+                    return Err(invalid(format!("members overlap at offset {}", start)));
+                }
+                if start > end {
+                    fields.push(format!("__pad_{}: [u8; {}]", pads, start - end));
+                    pads += 1;
+                }
+                end = stop;
+            }
+            fields.push(field);
+        }
+        if end > size {
+            return Err(invalid(format!(
+                "members end at {} past its size {}",
+                end, size
+            )));
+        }
+        if end < size {
+            if union {
+                fields.push(format!("__pad: [u8; {}]", size));
+            } else {
+                fields.push(format!("__pad_{}: [u8; {}]", pads, size - end));
+            }
+        }
+        // a zero-sized array gives the record its C alignment where
+        // bitfield storage lowered it, without taking space
+        if !packed && natural < align {
+            if let Some(marker) = int_type(align, false) {
+                fields.insert(0, format!("_align: [{}; 0]", marker));
+            }
+        }
+
+        let debug = if has_union(btf, id, &mut self.unions)? {
+            ""
+        } else {
+            "Debug, "
+        };
+        writeln!(
+            self.out,
+            "\n#[repr(C{})]\n#[derive({}Clone, Copy)]\n\
+             #[allow(non_camel_case_types, non_snake_case, dead_code)]\n\
+             pub {} {} {{",
+            if packed { ", packed" } else { "" },
+            debug,
+            if union { "union" } else { "struct" },
+            name
+        )
+        .unwrap();
+        for field in fields {
+            writeln!(self.out, "    {},", field).unwrap();
+        }
+        self.out.push_str("}\n");
+        writeln!(
+            self.out,
+            "\nimpl ::std::default::Default for {} {{\n    \
+             fn default() -> Self {{\n        \
+             unsafe {{ ::std::mem::zeroed() }}\n    \
+             }}\n}}",
+            name
+        )
+        .unwrap();
+        if !bitfields.is_empty() {
+            writeln!(
+                self.out,
+                "\n#[allow(non_snake_case, dead_code)]\nimpl {} {{",
+                name
+            )
+            .unwrap();
+            // BTF leaves out unnamed bitfields, which only pad
+            for (i, bitfield) in bitfields
+                .iter()
+                .filter(|b| !b.member.name.is_empty())
+                .enumerate()
+            {
+                if i > 0 {
+                    self.out.push('\n');
+                }
+                let accessors = self
+                    .accessors(bitfield)
+                    .map_err(|e| invalid(e.to_string()))?;
+                self.out.push_str(&accessors);
+            }
+            self.out.push_str("}\n");
+        }
+        writeln!(
+            self.out,
+            "\nunsafe impl ::rsops::module::map::Pod for {} {{}}\n\n\
+             const _: () = assert!(\n    \
+             ::std::mem::size_of::<{}>() == {} && ::std::mem::align_of::<{}>() == {}\n);",
+            name, name, size, name, align
+        )
+        .unwrap();
+        Ok(())
+    }
+
+    /// The getter and setter of a bitfield.
+    fn accessors(&self, bitfield: &Bitfield) -> Result<String> {
+        let Bitfield {
+            member,
+            storage,
+            offset,
+            bits,
+        } = bitfield;
+        let t = self.btf.skip_mods_and_typedefs(member.type_id)?;
+        let (repr, signed, newtype) = match &self.btf.type_by_id(t)?.kind {
+            BtfKind::Int { size, encoding, .. } => {
+                let signed = encoding & BTF_INT_SIGNED != 0 && encoding & BTF_INT_BOOL == 0;
+                (int_type(*size, signed), signed, false)
+            }
+            BtfKind::Enum { size, signed, .. } => (int_type(*size, *signed), *signed, true),
+            _ => (None, false, false),
+        };
+        let repr = match repr {
+            Some(repr) if *bits <= 64 => repr,
+            _ => {
+                return Err(Error::Invalid(format!(
+                    "unsupported bitfield {}",
+                    member.name
+                )))
+            }
+        };
+        let ty = self.spell(member.type_id)?;
+        let raw = format!(
+            "::rsops::module::btf_rust::get_bits(&self.{}, {}, {})",
+            storage, offset, bits
+        );
+        let mut value = if signed && *bits < 64 {
+            format!(
+                "((({} << {}) as i64) >> {}){}",
+                raw,
+                64 - bits,
+                64 - bits,
+                if repr == "i64" {
+                    String::new()
+                } else {
+                    format!(" as {}", repr)
+                }
+            )
+        } else if repr == "u64" {
+            raw
+        } else if repr == "i64" {
+            format!("{} as i64", raw)
+        } else {
+            format!("{} as {}", raw, repr)
+        };
+        let mut input = if newtype { "value.0" } else { "value" }.to_string();
+        if repr != "u64" {
+            input.push_str(" as u64");
+        }
+        if newtype {
+            value = format!("{}({})", ty, value);
+        }
+        let mut out = String::new();
+        writeln!(
+            out,
+            "    pub fn {}(&self) -> {} {{\n        {}\n    }}\n\n    \
+             pub fn set_{}(&mut self, value: {}) {{\n        \
+             ::rsops::module::btf_rust::set_bits(&mut self.{}, {}, {}, {});\n    }}",
+            ident(&member.name),
+            ty,
+            value,
+            member.name,
+            ty,
+            storage,
+            offset,
+            bits,
+            input
+        )
+        .unwrap();
+        Ok(out)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::module::btf::fixture::{btf, enumeration, int, member, ty};
+
+    #[test]
+    fn test_records() {
+        let out = rust_types(
+            &btf(vec![
+                int("char", 1, true),
+                int("int", 4, true),
+                int("long", 8, true),
+                ty(
+                    "event",
+                    BtfKind::Struct {
+                        size: 24,
+                        members: vec![
+                            member("a", 1, 0),
+                            Member {
+                                bitfield_size: 3,
+                                ..member("lo", 2, 8)
+                            },
+                            Member {
+                                bitfield_size: 4,
+                                ..member("hi", 2, 11)
+                            },
+                            member("d", 3, 64),
+                            member("", 5, 128),
+                        ],
+                    },
+                ),
+                ty(
+                    "",
+                    BtfKind::Union {
+                        size: 8,
+                        members: vec![member("x", 2, 0), member("y", 3, 0)],
+                    },
+                ),
+                ty(
+                    "misaligned",
+                    BtfKind::Struct {
+                        size: 6,
+                        members: vec![member("a", 1, 0), member("b", 2, 8)],
+                    },
+                ),
+            ]),
+            &[],
+        )
+        .unwrap();
+        assert!(out.contains(
+            "#[repr(C)]\n#[derive(Clone, Copy)]\n\
+             #[allow(non_camel_case_types, non_snake_case, dead_code)]\n\
+             pub struct event {\n    pub a: i8,\n    _bitfield_0: [u8; 1],\n    \
+             __pad_0: [u8; 6],\n    pub d: i64,\n    pub __anon_4: event__anon_4,\n}\n"
+        ));
+        assert!(out.contains(
+            "    pub fn lo(&self) -> i32 {\n        \
+             (((::rsops::module::btf_rust::get_bits(&self._bitfield_0, 0, 3) << 61) as i64) \
+             >> 61) as i32\n    }\n\n    \
+             pub fn set_lo(&mut self, value: i32) {\n        \
+             ::rsops::module::btf_rust::set_bits(&mut self._bitfield_0, 0, 3, value as u64);\n    \
+             }\n"
+        ));
+        assert!(out.contains("get_bits(&self._bitfield_0, 3, 4)"));
+        assert!(out.contains(
+            "const _: () = assert!(\n    \
+             ::std::mem::size_of::<event>() == 24 && ::std::mem::align_of::<event>() == 8\n);"
+        ));
+        assert!(out.contains("pub union event__anon_4 {\n    pub x: i32,\n    pub y: i64,\n}\n"));
+        assert!(out.contains(
+            "#[repr(C, packed)]\n#[derive(Debug, Clone, Copy)]\n\
+             #[allow(non_camel_case_types, non_snake_case, dead_code)]\n\
+             pub struct misaligned {\n    pub a: i8,\n    pub b: i32,\n    __pad_0: [u8; 1],\n}\n"
+        ));
+        assert!(out.contains("unsafe impl ::rsops::module::map::Pod for misaligned {}"));
+    }
+
+    #[test]
+    fn test_names() {
+        let btf = btf(vec![
+            int("int", 4, true),
+            ty(
+                "",
+                BtfKind::Struct {
+                    size: 4,
+                    members: vec![member("v", 1, 0)],
+                },
+            ),
+            // 3: names the anonymous struct
+            ty("foo_t", BtfKind::Typedef(2)),
+            ty("u32", BtfKind::Typedef(1)),
+            ty(
+                "type",
+                BtfKind::Struct {
+                    size: 4,
+                    members: vec![member("match", 4, 0)],
+                },
+            ),
+            ty("type", BtfKind::Typedef(5)),
+            enumeration("", true, &[("A", 0), ("B", -1)]),
+            ty(
+                "holder",
+                BtfKind::Struct {
+                    size: 4,
+                    members: vec![member("inner", 9, 0)],
+                },
+            ),
+            ty(
+                "",
+                BtfKind::Struct {
+                    size: 4,
+                    members: vec![member("self", 1, 0)],
+                },
+            ),
+        ]);
+        let out = rust_types(&btf, &[]).unwrap();
+        assert!(out.contains("pub struct foo_t {\n    pub v: i32,\n}\n"));
+        assert!(out.contains("pub struct r#type {\n    pub r#match: i32,\n}\n"));
+        assert!(!out.contains("pub type"));
+        assert!(out.contains("pub const A: i32 = 0;"));
+        assert!(out.contains("pub const B: i32 = -1;"));
+        assert!(out.contains("pub struct holder {\n    pub inner: holder__inner,\n}\n"));
+        assert!(out.contains("pub struct holder__inner {\n    pub self_: i32,\n}\n"));
+
+        let out = rust_types(&btf, &["holder"]).unwrap();
+        assert!(out.contains("pub struct holder__inner"));
+        assert!(!out.contains("foo_t"));
+        assert!(!out.contains("pub const"));
+        let out = rust_types(&btf, &["B"]).unwrap();
+        assert!(out.contains("pub const A: i32 = 0;"));
+        assert!(!out.contains("pub struct"));
+        assert!(matches!(
+            rust_types(&btf, &["nope"]),
+            Err(Error::NotFound(_))
+        ));
+    }
+
+    #[test]
+    fn test_enums() {
+        let out = rust_types(
+            &btf(vec![
+                enumeration("state", true, &[("IDLE", 0), ("BUSY", 1)]),
+                ty(
+                    "flags",
+                    BtfKind::Struct {
+                        size: 4,
+                        members: vec![Member {
+                            bitfield_size: 2,
+                            ..member("s", 1, 0)
+                        }],
+                    },
+                ),
+                // 3: the same name as the enum's constructor
+                enumeration("", true, &[("state", 2)]),
+            ]),
+            &[],
+        )
+        .unwrap();
+        assert!(out.contains(
+            "#[repr(transparent)]\n\
+             #[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]\n\
+             #[allow(non_camel_case_types, dead_code)]\n\
+             pub struct state(pub i32);\n\n\
+             #[allow(non_upper_case_globals, dead_code)]\n\
+             impl state {\n    pub const IDLE: state = state(0);\n    \
+             pub const BUSY: state = state(1);\n}\n"
+        ));
+        assert!(out.contains(
+            "pub struct flags {\n    _align: [u32; 0],\n    _bitfield_0: [u8; 1],\n    \
+             __pad_0: [u8; 3],\n}\n"
+        ));
+        assert!(out.contains(
+            "state((((::rsops::module::btf_rust::get_bits(&self._bitfield_0, 0, 2) << 62) as i64) \
+             >> 62) as i32)"
+        ));
+        assert!(out.contains("set_bits(&mut self._bitfield_0, 0, 2, value.0 as u64)"));
+        assert!(out.contains("pub const state___2: i32 = 2;"));
+    }
+
+    #[test]
+    fn test_bits() {
+        let mut storage = [0u8; 2];
+        set_bits(&mut storage, 3, 7, 0x55);
+        assert_eq!(storage, [0xa8, 0x02]);
+        assert_eq!(get_bits(&storage, 3, 7), 0x55);
+        let mut storage = [0xff; 2];
+        set_bits(&mut storage, 3, 7, 0);
+        assert_eq!(storage, [0x07, 0xfc]);
+        assert_eq!(get_bits(&storage, 0, 16), 0xfc07);
+    }
+}
@@ -0,0 +1,596 @@
+//! Control-flow graph of a program, its rendering as Graphviz DOT and
+//! verifier-like checks run before loading it.
+//!
+//! [`check`] reports jumps out of the program or into the middle of an
+//! `ld_imm64`, a last instruction that falls off the end, unreachable
+//! instructions, registers read before they are written, and, depending
+//! on the target kernel's [`Limits`], back-edges and programs with too
+//! many instructions. It is far less precise than the verifier, which also
+//! prunes branches it can prove dead and checks helper arguments.
+
+use crate::module::disasm;
+use crate::module::insn::*;
+use crate::module::object::MapReloc;
+use kernel_version::KernelVersion;
+use std::fmt::{self, Display};
+
+/// How control reaches a block from the end of another.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EdgeKind {
+    /// the next instruction, including a conditional jump not taken
+    Fallthrough,
+    /// a conditional jump taken
+    Taken,
+    /// an unconditional `goto`
+    Jump,
+    /// a bpf-to-bpf call
+    Call,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Edge {
+    /// index of the target block
+    pub to: usize,
+    pub kind: EdgeKind,
+}
+
+/// Instructions `start..end` executed in sequence.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Block {
+    pub start: usize,
+    pub end: usize,
+    pub succs: Vec<Edge>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cfg {
+    /// blocks in instruction order, the first is the entry
+    pub blocks: Vec<Block>,
+}
+
+/// Where control may go after instruction `idx`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Flow {
+    Next,
+    Exit,
+    Jump(i64),
+    Branch(i64),
+    Call(i64),
+}
+
+fn flow(insns: &[Insn], idx: usize) -> Flow {
+    let insn = insns[idx];
+    if insn.class() != BPF_JMP && insn.class() != BPF_JMP32 {
+        return Flow::Next;
+    }
+    let target = |off: i64| idx as i64 + 1 + off;
+    match insn.op() {
+        BPF_EXIT => Flow::Exit,
+        BPF_CALL if insn.src() == BPF_PSEUDO_CALL => Flow::Call(target(i64::from(insn.imm))),
+        BPF_CALL => Flow::Next,
+        BPF_JA if insn.class() == BPF_JMP32 => Flow::Jump(target(i64::from(insn.imm))),
+        BPF_JA => Flow::Jump(target(i64::from(insn.off))),
+        _ => Flow::Branch(target(i64::from(insn.off))),
+    }
+}
+
+/// Marks the instructions that start a new instruction, as opposed to the
+/// second half of an `ld_imm64`.
+pub fn insn_starts(insns: &[Insn]) -> Vec<bool> {
+    let mut starts = vec![false; insns.len()];
+    let mut idx = 0;
+    while idx < insns.len() {
+        starts[idx] = true;
+        idx += if insns[idx].is_ld_imm64() { 2 } else { 1 };
+    }
+    starts
+}
+
+fn next_insn(insns: &[Insn], idx: usize) -> usize {
+    idx + if insns[idx].is_ld_imm64() { 2 } else { 1 }
+}
+
+/// Valid targets reachable from instruction `idx` with their edge kind.
+fn successors(insns: &[Insn], starts: &[bool], idx: usize) -> Vec<(usize, EdgeKind)> {
+    let valid = |t: i64| t >= 0 && (t as usize) < insns.len() && starts[t as usize];
+    let next = next_insn(insns, idx) as i64;
+    let edges = match flow(insns, idx) {
+        Flow::Next => vec![(next, EdgeKind::Fallthrough)],
+        Flow::Exit => vec![],
+        Flow::Jump(t) => vec![(t, EdgeKind::Jump)],
+        Flow::Branch(t) => vec![(next, EdgeKind::Fallthrough), (t, EdgeKind::Taken)],
+        Flow::Call(t) => vec![(next, EdgeKind::Fallthrough), (t, EdgeKind::Call)],
+    };
+    edges
+        .into_iter()
+        .filter(|(t, _)| valid(*t))
+        .map(|(t, kind)| (t as usize, kind))
+        .collect()
+}
+
+impl Cfg {
+    /// Splits `insns` into basic blocks. Jumps to invalid targets get no
+    /// edge, see [`check`].
+    pub fn build(insns: &[Insn]) -> Cfg {
+        let starts = insn_starts(insns);
+        let mut leader = vec![false; insns.len() + 1];
+        leader[0] = true;
+        for idx in (0..insns.len()).filter(|&i| starts[i]) {
+            if flow(insns, idx) != Flow::Next {
+                leader[next_insn(insns, idx).min(insns.len())] = true;
+                for (t, _) in successors(insns, &starts, idx) {
+                    leader[t] = true;
+                }
+            }
+        }
+        let bounds: Vec<usize> = (0..insns.len()).filter(|&i| leader[i]).collect();
+        let block_of = |idx: usize| bounds.binary_search(&idx).ok();
+        let mut blocks = Vec::with_capacity(bounds.len());
+        for (i, &start) in bounds.iter().enumerate() {
+            let end = bounds.get(i + 1).copied().unwrap_or(insns.len());
+            let last = (start..end).rev().find(|&i| starts[i]).unwrap_or(start);
+            let succs = successors(insns, &starts, last)
+                .into_iter()
+                .filter_map(|(t, kind)| {
+                    Some(Edge {
+                        to: block_of(t)?,
+                        kind,
+                    })
+                })
+                .collect();
+            blocks.push(Block { start, end, succs });
+        }
+        Cfg { blocks }
+    }
+
+    /// Index of the block holding instruction `idx`.
+    pub fn block_at(&self, idx: usize) -> Option<usize> {
+        self.blocks
+            .iter()
+            .position(|b| (b.start..b.end).contains(&idx))
+    }
+}
+
+/// Escapes `text` for a double quoted DOT string.
+fn dot_escape(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+impl Cfg {
+    /// Renders the graph as a Graphviz DOT digraph called `name`, one
+    /// node per block listing its instructions as [`disasm`] prints them,
+    /// with maps named after `relocs`.
+    pub fn to_dot(&self, name: &str, insns: &[Insn], relocs: &[MapReloc]) -> String {
+        let mut out = format!("digraph \"{}\" {{\n", dot_escape(name));
+        out.push_str("    node [shape=box fontname=\"monospace\"];\n");
+        for (i, block) in self.blocks.iter().enumerate() {
+            let text = disasm::disassemble_range(insns, relocs, block.start..block.end, None);
+            let label: String = text
+                .lines()
+                .map(|line| format!("{}\\l", dot_escape(line.trim_start())))
+                .collect();
+            out.push_str(&format!("    b{} [label=\"{}\"];\n", i, label));
+        }
+        for (i, block) in self.blocks.iter().enumerate() {
+            let branch = block.succs.iter().any(|e| e.kind == EdgeKind::Taken);
+            for edge in &block.succs {
+                let attrs = match edge.kind {
+                    EdgeKind::Taken => " [label=\"true\"]",
+                    EdgeKind::Fallthrough if branch => " [label=\"false\"]",
+                    EdgeKind::Fallthrough => "",
+                    EdgeKind::Jump => " [label=\"goto\"]",
+                    EdgeKind::Call => " [label=\"call\" style=dashed]",
+                };
+                out.push_str(&format!("    b{} -> b{}{};\n", i, edge.to, attrs));
+            }
+        }
+        out.push_str("}\n");
+        out
+    }
+}
+
+/// Program limits of a kernel version.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    /// whether loops the verifier can bound are accepted, since 5.3
+    pub bounded_loops: bool,
+    /// most instructions a privileged program may have: 4096 before 5.2,
+    /// 1M since
+    pub max_insns: usize,
+}
+
+impl Limits {
+    pub fn for_kernel(kernel: KernelVersion) -> Limits {
+        Limits {
+            bounded_loops: kernel >= KernelVersion::new(5, 3, 0),
+            max_insns: if kernel >= KernelVersion::new(5, 2, 0) {
+                1_000_000
+            } else {
+                4096
+            },
+        }
+    }
+
+    /// The limits of the newest kernels, the most permissive ones.
+    pub fn newest() -> Limits {
+        Limits::for_kernel(KernelVersion::new(u32::MAX, 0, 0))
+    }
+
+    /// The limits of the running kernel, those of the newest kernels if
+    /// its release can't be parsed.
+    pub fn running() -> Limits {
+        match KernelVersion::running() {
+            Some(kernel) => Limits::for_kernel(kernel),
+            None => Limits::newest(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProblemKind {
+    /// a jump outside of the program, or a jump or call into an `ld_imm64`;
+    /// calls past either end are taken to be unlinked subprograms
+    InvalidJump {
+        target: i64,
+    },
+    /// the last instruction is neither `exit` nor `goto`
+    FallsOffEnd,
+    /// instructions up to `end` (exclusive) can never execute
+    Unreachable {
+        end: usize,
+    },
+    UninitRead {
+        reg: u8,
+    },
+    /// a jump backwards, rejected before 5.3
+    BackEdge {
+        target: usize,
+    },
+    TooManyInsns {
+        count: usize,
+        limit: usize,
+    },
+}
+
+/// A problem found by [`check`] at instruction `insn`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Problem {
+    pub insn: usize,
+    pub kind: ProblemKind,
+}
+
+impl Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "insn {}: ", self.insn)?;
+        match self.kind {
+            ProblemKind::InvalidJump { target } => write!(f, "jump to invalid insn {}", target),
+            ProblemKind::FallsOffEnd => write!(f, "last insn is not an exit or jmp"),
+            ProblemKind::Unreachable { end } if end == self.insn + 1 => {
+                write!(f, "unreachable insn")
+            }
+            ProblemKind::Unreachable { end } => write!(f, "unreachable insns up to {}", end - 1),
+            ProblemKind::UninitRead { reg } => write!(f, "r{} is read before it is written", reg),
+            ProblemKind::BackEdge { target } => write!(
+                f,
+                "back-edge to insn {}, loops need kernel 5.3 or later",
+                target
+            ),
+            ProblemKind::TooManyInsns { count, limit } => write!(
+                f,
+                "program has {} insns, more than the kernel's limit of {}",
+                count, limit
+            ),
+        }
+    }
+}
+
+const CALLER_SAVED: u16 = 0b11_1110;
+const R0: u16 = 1;
+const FP: u16 = 1 << 10;
+
+fn reg(r: u8) -> u16 {
+    1 << r
+}
+
+/// Registers instruction `idx` reads, and the registers initialized after
+/// it given those initialized before, `init`. Calls also return the
+/// registers initialized on entry to the callee.
+fn transfer(insn: Insn, init: u16) -> (u16, u16, u16) {
+    let (dst, src) = (reg(insn.dst()), reg(insn.src()));
+    let src_x = if insn.source() == BPF_X { src } else { 0 };
+    let after_call = (init & !CALLER_SAVED) | R0;
+    match insn.class() {
+        BPF_ALU | BPF_ALU64 => match insn.op() {
+            BPF_MOV => (src_x, init | dst, 0),
+            BPF_NEG | BPF_END => (dst, init | dst, 0),
+            _ => (dst | src_x, init | dst, 0),
+        },
+        BPF_JMP | BPF_JMP32 => match insn.op() {
+            BPF_JA => (0, init, 0),
+            BPF_EXIT => (R0, init, 0),
+            BPF_CALL if insn.src() == BPF_PSEUDO_CALL => {
+                (0, after_call, (init & CALLER_SAVED) | FP)
+            }
+            // helper arguments depend on the helper's prototype
+            BPF_CALL => (0, after_call, 0),
+            _ => (dst | src_x, init, 0),
+        },
+        BPF_LDX => (src, init | dst, 0),
+        BPF_ST => (dst, init, 0),
+        BPF_STX if insn.mode() == BPF_ATOMIC => {
+            if insn.imm == BPF_CMPXCHG {
+                (dst | src | R0, init | R0, 0)
+            } else {
+                (dst | src, init, 0)
+            }
+        }
+        BPF_STX => (dst | src, init, 0),
+        BPF_LD if insn.is_ld_imm64() => (0, init | dst, 0),
+        // legacy packet access implicitly reads the skb from r6
+        BPF_LD => {
+            let ind = if insn.mode() == BPF_IND { src } else { 0 };
+            (reg(6) | ind, after_call, 0)
+        }
+        _ => (0, init, 0),
+    }
+}
+
+/// Checks `insns` against the `limits` of the target kernel, returning
+/// the problems in instruction order.
+pub fn check(insns: &[Insn], limits: &Limits) -> Vec<Problem> {
+    let mut problems = Vec::new();
+    if insns.is_empty() {
+        return problems;
+    }
+    if insns.len() > limits.max_insns {
+        problems.push(Problem {
+            insn: limits.max_insns,
+            kind: ProblemKind::TooManyInsns {
+                count: insns.len(),
+                limit: limits.max_insns,
+            },
+        });
+    }
+    let starts = insn_starts(insns);
+    let valid = |t: i64| t >= 0 && (t as usize) < insns.len() && starts[t as usize];
+    let mut last = 0;
+    for idx in (0..insns.len()).filter(|&i| starts[i]) {
+        last = idx;
+        if let Flow::Jump(t) | Flow::Branch(t) | Flow::Call(t) = flow(insns, idx) {
+            let outside = t < 0 || t as usize >= insns.len();
+            if outside && matches!(flow(insns, idx), Flow::Call(_)) {
+                // a subprogram that isn't linked into this slice yet
+                continue;
+            }
+            if !valid(t) {
+                problems.push(Problem {
+                    insn: idx,
+                    kind: ProblemKind::InvalidJump { target: t },
+                });
+            } else if !limits.bounded_loops
+                && t as usize <= idx
+                && !matches!(flow(insns, idx), Flow::Call(_))
+            {
+                problems.push(Problem {
+                    insn: idx,
+                    kind: ProblemKind::BackEdge { target: t as usize },
+                });
+            }
+        }
+    }
+    if !matches!(flow(insns, last), Flow::Exit | Flow::Jump(_)) {
+        problems.push(Problem {
+            insn: last,
+            kind: ProblemKind::FallsOffEnd,
+        });
+    }
+
+    // registers initialized on every path to each instruction, None for
+    // unreachable ones
+    let mut init: Vec<Option<u16>> = vec![None; insns.len()];
+    init[0] = Some(reg(1) | FP);
+    let mut work = vec![0];
+    while let Some(idx) = work.pop() {
+        let (_, out, callee) = transfer(insns[idx], init[idx].unwrap());
+        for (t, kind) in successors(insns, &starts, idx) {
+            let incoming = if kind == EdgeKind::Call { callee } else { out };
+            let merged = init[t].map_or(incoming, |old| old & incoming);
+            if init[t] != Some(merged) {
+                init[t] = Some(merged);
+                work.push(t);
+            }
+        }
+    }
+
+    let mut idx = 0;
+    while idx < insns.len() {
+        match init[idx] {
+            Some(state) => {
+                let (reads, _, _) = transfer(insns[idx], state);
+                let missing = reads & !state;
+                if let Some(r) = (0..=10).find(|r| missing & reg(*r) != 0) {
+                    problems.push(Problem {
+                        insn: idx,
+                        kind: ProblemKind::UninitRead { reg: r },
+                    });
+                }
+                idx = next_insn(insns, idx);
+            }
+            None => {
+                let mut end = idx;
+                while end < insns.len() && init[end].is_none() {
+                    end = next_insn(insns, end);
+                }
+                let end = end.min(insns.len());
+                problems.push(Problem {
+                    insn: idx,
+                    kind: ProblemKind::Unreachable { end },
+                });
+                idx = end;
+            }
+        }
+    }
+    problems.sort_by_key(|p| p.insn);
+    problems
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::module::asm::assemble;
+
+    fn insns(text: &str) -> Vec<Insn> {
+        assemble(text).unwrap().build().unwrap()
+    }
+
+    fn kinds(text: &str, limits: &Limits) -> Vec<(usize, ProblemKind)> {
+        check(&insns(text), limits)
+            .into_iter()
+            .map(|p| (p.insn, p.kind))
+            .collect()
+    }
+
+    const LOOP: &str = "
+        r0 = 0
+    loop:
+        r0 += 1
+        if r0 < 0xa goto loop
+        exit
+    ";
+
+    #[test]
+    fn test_build() {
+        let cfg = Cfg::build(&insns(LOOP));
+        assert_eq!(
+            cfg.blocks,
+            vec![
+                Block {
+                    start: 0,
+                    end: 1,
+                    succs: vec![Edge {
+                        to: 1,
+                        kind: EdgeKind::Fallthrough
+                    }],
+                },
+                Block {
+                    start: 1,
+                    end: 3,
+                    succs: vec![
+                        Edge {
+                            to: 2,
+                            kind: EdgeKind::Fallthrough
+                        },
+                        Edge {
+                            to: 1,
+                            kind: EdgeKind::Taken
+                        },
+                    ],
+                },
+                Block {
+                    start: 3,
+                    end: 4,
+                    succs: vec![],
+                },
+            ]
+        );
+        assert_eq!(cfg.block_at(2), Some(1));
+    }
+
+    #[test]
+    fn test_to_dot() {
+        let insns = insns(LOOP);
+        let dot = Cfg::build(&insns).to_dot("count \"loop\"", &insns, &[]);
+        assert_eq!(
+            dot,
+            "digraph \"count \\\"loop\\\"\" {\n\
+             \x20   node [shape=box fontname=\"monospace\"];\n\
+             \x20   b0 [label=\"0: r0 = 0\\l\"];\n\
+             \x20   b1 [label=\"1: r0 += 1\\l2: if r0 < 0xa goto pc-2\\l\"];\n\
+             \x20   b2 [label=\"3: exit\\l\"];\n\
+             \x20   b0 -> b1;\n\
+             \x20   b1 -> b2 [label=\"false\"];\n\
+             \x20   b1 -> b1 [label=\"true\"];\n\
+             }\n"
+        );
+    }
+
+    #[test]
+    fn test_check_loops_and_limits() {
+        let new = Limits::for_kernel(KernelVersion::new(5, 10, 0));
+        let old = Limits::for_kernel(KernelVersion::new(4, 19, 0));
+        assert!(kinds(LOOP, &new).is_empty());
+        assert_eq!(
+            kinds(LOOP, &old),
+            vec![(2, ProblemKind::BackEdge { target: 1 })]
+        );
+        let big = vec![Insn::new(BPF_ALU64 | BPF_MOV | BPF_K, 0, 0, 0, 0); 5000];
+        assert_eq!(
+            check(&big, &old)[0].kind,
+            ProblemKind::TooManyInsns {
+                count: 5000,
+                limit: 4096
+            }
+        );
+    }
+
+    #[test]
+    fn test_check_problems() {
+        let limits = Limits::for_kernel(KernelVersion::new(6, 1, 0));
+        assert_eq!(
+            kinds("r0 = r2\nexit\nr0 = 1\nr0 = 2\nexit", &limits),
+            vec![
+                (0, ProblemKind::UninitRead { reg: 2 }),
+                (2, ProblemKind::Unreachable { end: 5 }),
+            ]
+        );
+        // r1 is clobbered by the helper call, r6 survives it
+        assert_eq!(
+            kinds(
+                "r6 = r1\ncall bpf_ktime_get_ns\nr0 = *(u32 *)(r6 +0)\nr0 = *(u32 *)(r1 +0)\nexit",
+                &limits
+            ),
+            vec![(3, ProblemKind::UninitRead { reg: 1 })]
+        );
+        // only initialized on one path
+        assert_eq!(
+            kinds("if r1 == 0x0 goto pc+1\nr0 = 0\nexit", &limits),
+            vec![(2, ProblemKind::UninitRead { reg: 0 })]
+        );
+        let mut bad = insns("r0 = 0x100000000 ll\nexit");
+        bad.insert(0, Insn::new(BPF_JMP | BPF_JA, 0, 0, 1, 0));
+        assert_eq!(
+            check(&bad, &limits)
+                .into_iter()
+                .map(|p| p.kind)
+                .collect::<Vec<_>>(),
+            vec![
+                ProblemKind::InvalidJump { target: 2 },
+                ProblemKind::Unreachable { end: 4 },
+            ]
+        );
+        assert_eq!(
+            kinds("r0 = 0", &limits),
+            vec![(0, ProblemKind::FallsOffEnd)]
+        );
+    }
+
+    #[test]
+    fn test_check_calls() {
+        let limits = Limits::for_kernel(KernelVersion::new(6, 1, 0));
+        let call = |imm| Insn::new(BPF_JMP | BPF_CALL, 0, BPF_PSEUDO_CALL, 0, imm);
+        // callees not linked in yet are not reported
+        let mut unlinked = insns("r0 = 0\nexit");
+        unlinked.insert(0, call(7));
+        unlinked.insert(1, call(-3));
+        assert!(check(&unlinked, &limits).is_empty());
+        let mut bad = insns("r0 = 0x100000000 ll\nexit");
+        bad.insert(0, call(1));
+        assert_eq!(
+            check(&bad, &limits)
+                .into_iter()
+                .map(|p| p.kind)
+                .collect::<Vec<_>>(),
+            vec![ProblemKind::InvalidJump { target: 2 }]
+        );
+    }
+}
@@ -0,0 +1,454 @@
+//! Which kernels an object can load on, from the helpers, program types,
+//! map types and instruction features it uses and a built-in table of
+//! the kernel release that introduced each.
+//!
+//! Versions are upstream releases: distribution kernels often backport
+//! features, so a host reported as too old may still load the object.
+
+use crate::error::{Error, Result};
+use crate::module::helpers::Helper;
+use crate::module::insn::*;
+use crate::module::map::MapType;
+use crate::module::object::{ObjectFile, ProgramSpec};
+use crate::module::program::ProgramType;
+use kernel_version::KernelVersion;
+use std::fmt::{self, Display};
+
+/// The first kernel of release `major.minor`.
+const fn release(major: u32, minor: u32) -> KernelVersion {
+    KernelVersion::new(major, minor, 0)
+}
+
+/// Helpers are numbered in the order they were merged: the first helper id
+/// of each release.
+const HELPER_RELEASES: [(i32, KernelVersion); 47] = [
+    (1, release(3, 19)),
+    (4, release(4, 1)),
+    (12, release(4, 2)),
+    (17, release(4, 3)),
+    (23, release(4, 4)),
+    (26, release(4, 5)),
+    (27, release(4, 6)),
+    (31, release(4, 8)),
+    (37, release(4, 9)),
+    (42, release(4, 10)),
+    (45, release(4, 11)),
+    (46, release(4, 12)),
+    (48, release(4, 13)),
+    (51, release(4, 14)),
+    (54, release(4, 15)),
+    (58, release(4, 16)),
+    (60, release(4, 17)),
+    (65, release(4, 18)),
+    (81, release(4, 19)),
+    (84, release(4, 20)),
+    (91, release(5, 0)),
+    (93, release(5, 1)),
+    (99, release(5, 2)),
+    (109, release(5, 3)),
+    (111, release(5, 5)),
+    (119, release(5, 6)),
+    (120, release(5, 7)),
+    (121, release(5, 6)),
+    (122, release(5, 7)),
+    (125, release(5, 8)),
+    (126, release(5, 7)),
+    (128, release(5, 8)),
+    (136, release(5, 9)),
+    (142, release(5, 10)),
+    (156, release(5, 11)),
+    (163, release(5, 12)),
+    (164, release(5, 13)),
+    (166, release(5, 14)),
+    (169, release(5, 15)),
+    (176, release(5, 16)),
+    (180, release(5, 17)),
+    (186, release(5, 18)),
+    (194, release(5, 19)),
+    (204, release(6, 0)),
+    (208, release(6, 1)),
+    (210, release(6, 2)),
+    (212, release(u32::MAX, 0)),
+];
+
+/// Release introducing each program type, indexed by its value.
+const PROGRAM_TYPE_RELEASES: [KernelVersion; 31] = [
+    release(0, 0),
+    release(3, 19),
+    release(4, 1),
+    release(4, 1),
+    release(4, 1),
+    release(4, 7),
+    release(4, 8),
+    release(4, 9),
+    release(4, 10),
+    release(4, 10),
+    release(4, 10),
+    release(4, 10),
+    release(4, 10),
+    release(4, 13),
+    release(4, 14),
+    release(4, 15),
+    release(4, 17),
+    release(4, 17),
+    release(4, 17),
+    release(4, 18),
+    release(4, 18),
+    release(4, 19),
+    release(4, 20),
+    release(5, 2),
+    release(5, 2),
+    release(5, 3),
+    release(5, 5),
+    release(5, 6),
+    release(5, 6),
+    release(5, 7),
+    release(5, 9),
+];
+
+/// Release introducing each map type, indexed by its value.
+const MAP_TYPE_RELEASES: [KernelVersion; 31] = [
+    release(0, 0),
+    release(3, 19),
+    release(3, 19),
+    release(4, 2),
+    release(4, 3),
+    release(4, 6),
+    release(4, 6),
+    release(4, 6),
+    release(4, 8),
+    release(4, 10),
+    release(4, 10),
+    release(4, 11),
+    release(4, 12),
+    release(4, 12),
+    release(4, 14),
+    release(4, 14),
+    release(4, 15),
+    release(4, 18),
+    release(4, 18),
+    release(4, 19),
+    release(4, 19),
+    release(4, 20),
+    release(4, 20),
+    release(4, 20),
+    release(5, 2),
+    release(5, 4),
+    release(5, 6),
+    release(5, 8),
+    release(5, 10),
+    release(5, 11),
+    release(5, 16),
+];
+
+/// Release introducing helper `id`, `None` for unknown helpers.
+pub fn helper_release(id: i32) -> Option<KernelVersion> {
+    HELPER_RELEASES
+        .iter()
+        .take_while(|(first, _)| *first <= id)
+        .last()
+        .map(|(_, version)| *version)
+        .filter(|version| version.version != u32::MAX)
+}
+
+pub fn program_type_release(prog_type: ProgramType) -> KernelVersion {
+    PROGRAM_TYPE_RELEASES[prog_type as usize]
+}
+
+pub fn map_type_release(map_type: MapType) -> KernelVersion {
+    MAP_TYPE_RELEASES[map_type as usize]
+}
+
+/// Something an object needs and the release that introduced it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Requirement {
+    pub since: KernelVersion,
+    pub what: String,
+    /// the first program that needs it, `None` for maps
+    pub program: Option<String>,
+}
+
+impl Display for Requirement {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}.{}  {}",
+            self.since.version, self.since.patchlevel, self.what
+        )?;
+        if let Some(program) = &self.program {
+            write!(f, " (program {})", program)?;
+        }
+        Ok(())
+    }
+}
+
+/// Instruction set features used by `insns` and their releases.
+fn insn_features(insns: &[Insn]) -> Vec<(&'static str, KernelVersion)> {
+    let mut features = Vec::new();
+    if insns.len() > 4096 {
+        features.push(("more than 4096 instructions", release(5, 2)));
+    }
+    let mut idx = 0;
+    while idx < insns.len() {
+        let insn = insns[idx];
+        let (class, op) = (insn.class(), insn.op());
+        let is_jmp = class == BPF_JMP || class == BPF_JMP32;
+        let target = idx as i64 + 1 + i64::from(insn.off);
+        if class == BPF_JMP32 {
+            features.push(("jmp32 instructions", release(5, 1)));
+        }
+        if is_jmp && op != BPF_CALL && op != BPF_EXIT && target <= idx as i64 {
+            features.push(("bounded loops", release(5, 3)));
+        }
+        match (class, op) {
+            (BPF_JMP, BPF_CALL) if insn.src() == BPF_PSEUDO_CALL => {
+                features.push(("bpf-to-bpf calls", release(4, 16)))
+            }
+            (BPF_JMP, BPF_CALL) if insn.src() == BPF_PSEUDO_KFUNC_CALL => {
+                features.push(("kfunc calls", release(5, 13)))
+            }
+            (BPF_STX, _) if insn.mode() == BPF_ATOMIC && insn.imm != i32::from(BPF_ADD) => {
+                let what = "atomic fetch, exchange and logic operations";
+                features.push((what, release(5, 12)))
+            }
+            (BPF_LD, _) if insn.is_ld_imm64() => match insn.src() {
+                BPF_PSEUDO_MAP_VALUE => features.push(("global data", release(5, 2))),
+                BPF_PSEUDO_BTF_ID => features.push(("typed ksyms", release(5, 10))),
+                BPF_PSEUDO_FUNC => features.push(("callbacks", release(5, 13))),
+                _ => {}
+            },
+            _ => {}
+        }
+        let cpu_v4 = match class {
+            BPF_LDX => insn.mode() == BPF_MEMSX,
+            BPF_ALU | BPF_ALU64 => {
+                ((op == BPF_DIV || op == BPF_MOD) && insn.off == 1)
+                    || (op == BPF_MOV && insn.off != 0)
+                    || (op == BPF_END && class == BPF_ALU64)
+            }
+            BPF_JMP32 => op == BPF_JA,
+            _ => false,
+        };
+        if cpu_v4 {
+            features.push(("cpu v4 instructions", release(6, 6)));
+        }
+        idx += if insn.is_ld_imm64() { 2 } else { 1 };
+    }
+    features
+}
+
+/// The requirements of a parsed object.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Report {
+    /// newest first
+    pub requirements: Vec<Requirement>,
+}
+
+impl Report {
+    pub fn for_object(object: &ObjectFile) -> Report {
+        let mut report = Report::default();
+        for map in &object.maps {
+            report.add(
+                map_type_release(map.def.map_type),
+                format!("{} map {}", map.def.map_type.name(), map.name),
+                None,
+            );
+        }
+        for program in &object.programs {
+            report.add_program(program);
+        }
+        report
+            .requirements
+            .sort_by(|a, b| b.since.cmp(&a.since).then_with(|| a.what.cmp(&b.what)));
+        report
+    }
+
+    fn add(&mut self, since: KernelVersion, what: String, program: Option<&str>) {
+        if !self.requirements.iter().any(|r| r.what == what) {
+            self.requirements.push(Requirement {
+                since,
+                what,
+                program: program.map(str::to_string),
+            });
+        }
+    }
+
+    fn add_program(&mut self, program: &ProgramSpec) {
+        let name = Some(program.name.as_str());
+        self.add(
+            program_type_release(program.prog_type),
+            format!("{} programs", program.prog_type.name()),
+            name,
+        );
+        for insn in &program.insns {
+            if insn.code == BPF_JMP | BPF_CALL && insn.src() == 0 {
+                let what = match Helper::from_i32(insn.imm) {
+                    Some(helper) => format!("bpf_{}", helper.name()),
+                    None => format!("unknown helper #{}", insn.imm),
+                };
+                let since = helper_release(insn.imm).unwrap_or(release(u32::MAX, 0));
+                self.add(since, what, name);
+            }
+        }
+        for (what, since) in insn_features(&program.insns) {
+            self.add(since, what.to_string(), name);
+        }
+    }
+
+    /// The oldest release providing every requirement.
+    pub fn min_kernel(&self) -> KernelVersion {
+        self.requirements
+            .iter()
+            .map(|r| r.since)
+            .max()
+            .unwrap_or(release(0, 0))
+    }
+
+    /// Requirements `kernel` doesn't provide, newest first.
+    pub fn missing(&self, kernel: KernelVersion) -> Vec<&Requirement> {
+        self.requirements
+            .iter()
+            .filter(|r| r.since > kernel)
+            .collect()
+    }
+}
+
+/// A machine of the fleet and the release of the kernel it runs.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Host {
+    pub name: String,
+    pub release: String,
+    pub kernel: KernelVersion,
+}
+
+/// Parses a fleet inventory: one `<host> <kernel release>` per line,
+/// blank lines and `#` comments ignored.
+pub fn parse_fleet(text: &str) -> Result<Vec<Host>> {
+    let mut hosts = Vec::new();
+    for (n, line) in text.lines().enumerate() {
+        let line = line.split('#').next().unwrap().trim();
+        if line.is_empty() {
+            continue;
+        }
+        let mut fields = line.split_whitespace();
+        let host = fields
+            .next()
+            .zip(fields.next())
+            .and_then(|(name, release)| {
+                Some(Host {
+                    name: name.to_string(),
+                    release: release.to_string(),
+                    kernel: release.parse().ok()?,
+                })
+            });
+        hosts.push(host.ok_or_else(|| {
+            Error::Invalid(format!(
+                "fleet line {}: expected `<host> <kernel release>`",
+                n + 1
+            ))
+        })?);
+    }
+    Ok(hosts)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::module::asm::assemble;
+    use crate::module::map::MapDef;
+    use crate::module::object::MapSpec;
+    use crate::module::pin::Pinning;
+
+    #[test]
+    fn test_tables() {
+        assert_eq!(
+            helper_release(Helper::MapLookupElem as i32),
+            Some(release(3, 19))
+        );
+        assert_eq!(helper_release(Helper::TailCall as i32), Some(release(4, 2)));
+        assert_eq!(
+            helper_release(Helper::FibLookup as i32),
+            Some(release(4, 18))
+        );
+        assert_eq!(
+            helper_release(Helper::XdpOutput as i32),
+            Some(release(5, 6))
+        );
+        assert_eq!(
+            helper_release(Helper::RingbufOutput as i32),
+            Some(release(5, 8))
+        );
+        assert_eq!(helper_release(Helper::Loop as i32), Some(release(5, 17)));
+        assert_eq!(
+            helper_release(Helper::CgrpStorageDelete as i32),
+            Some(release(6, 2))
+        );
+        assert_eq!(helper_release(9999), None);
+        assert_eq!(program_type_release(ProgramType::Xdp), release(4, 8));
+        assert_eq!(program_type_release(ProgramType::SkLookup), release(5, 9));
+        assert_eq!(map_type_release(MapType::Ringbuf), release(5, 8));
+        assert_eq!(map_type_release(MapType::BloomFilter), release(5, 16));
+    }
+
+    #[test]
+    fn test_report() {
+        let mut object = ObjectFile::default();
+        object.maps.push(MapSpec {
+            name: "events".to_string(),
+            def: MapDef {
+                map_type: MapType::Ringbuf,
+                key_size: 0,
+                value_size: 0,
+                max_entries: 4096,
+                map_flags: 0,
+            },
+            pinning: Pinning::None,
+            inner: None,
+            values: Vec::new(),
+        });
+        let prog = assemble(
+            "r0 = 0
+         l:
+             r0 += 1
+             if w0 < 0xa goto l
+             call bpf_get_current_pid_tgid
+             exit",
+        )
+        .unwrap()
+        .into_spec("count", ProgramType::Kprobe)
+        .unwrap();
+        object.programs.push(prog);
+        let report = Report::for_object(&object);
+        let found: Vec<_> = report
+            .requirements
+            .iter()
+            .map(|r| (r.since, r.what.as_str()))
+            .collect();
+        assert_eq!(
+            found,
+            vec![
+                (release(5, 8), "ringbuf map events"),
+                (release(5, 3), "bounded loops"),
+                (release(5, 1), "jmp32 instructions"),
+                (release(4, 2), "bpf_get_current_pid_tgid"),
+                (release(4, 1), "kprobe programs"),
+            ]
+        );
+        assert_eq!(report.min_kernel(), release(5, 8));
+        assert_eq!(report.missing(release(5, 2)).len(), 2);
+        assert!(report.missing(release(6, 1)).is_empty());
+    }
+
+    #[test]
+    fn test_parse_fleet() {
+        let hosts =
+            parse_fleet("# fleet\nweb-1 5.4.0-150-generic\n\ndb-1  4.19.0-25-amd64 # old\n")
+                .unwrap();
+        assert_eq!(hosts.len(), 2);
+        assert_eq!(hosts[0].kernel, KernelVersion::new(5, 4, 0));
+        assert_eq!(hosts[1].name, "db-1");
+        assert_eq!(hosts[1].kernel, KernelVersion::new(4, 19, 0));
+        assert!(parse_fleet("web-1").is_err());
+        assert!(parse_fleet("web-1 6.8-rc1").is_err());
+    }
+}
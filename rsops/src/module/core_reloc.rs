@@ -0,0 +1,1094 @@
+//! CO-RE ("compile once, run everywhere") relocations.
+//!
+//! clang records a [`CoreReloc`] in `.BTF.ext` for every instruction that
+//! depends on the layout of a type, e.g. the offset of a load from
+//! `task->pid`. The object was compiled against its own (local) `.BTF`;
+//! before loading, every relocation is resolved against the target
+//! kernel's BTF the way libbpf does it and the instruction is patched:
+//!
+//! - the relocated type matches target types of the same kind and name,
+//!   ignoring a `___suffix`, so that `struct task_struct___old` can
+//!   describe the layout of older kernels;
+//! - members match by name, also when the target moved them into an
+//!   anonymous struct or union;
+//! - all matching candidates must agree on the value;
+//! - when nothing matches, existence checks resolve to 0 and other
+//!   instructions are replaced by a call to a helper that doesn't exist,
+//!   so the program only fails to load if that code is reachable.
+
+use crate::error::{Error, Result};
+use crate::module::btf::{Btf, BtfKind, Member, BTF_INT_SIGNED};
+use crate::module::btf_ext::{CoreReloc, CoreRelocKind};
+use crate::module::insn::{
+    Insn, BPF_ALU, BPF_ALU64, BPF_B, BPF_CALL, BPF_DW, BPF_H, BPF_JMP, BPF_K, BPF_LD, BPF_LDX,
+    BPF_ST, BPF_STX, BPF_W,
+};
+use std::mem;
+
+/// Helper id of the call replacing instructions whose relocation failed,
+/// the same as libbpf's so verifier logs read the same.
+pub const POISON_HELPER: i32 = 0xbad2310;
+
+/// Longest accessor string and deepest type nesting followed.
+const MAX_SPEC_LEN: usize = 64;
+
+/// How to patch the instruction of one relocation.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Resolution {
+    /// the value computed from the object's own types, which the compiler
+    /// put into the instruction
+    pub local: u64,
+    /// the value for the target kernel, `None` if the type, field or
+    /// enumerator doesn't exist there and the instruction is poisoned
+    pub target: Option<u64>,
+    /// whether the instruction must hold `local`; the compiler may lay
+    /// out bitfields differently
+    validate: bool,
+    /// sizes of the field, loads and stores of it follow a change
+    local_size: u32,
+    target_size: u32,
+    /// whether loads can follow the size change without changing the
+    /// value read, as for unsigned integers and pointers
+    resizable: bool,
+}
+
+/// One step of an accessor string.
+#[derive(Debug, Clone)]
+struct Accessor<'a> {
+    /// the struct or union of a member, the element type of an array
+    /// element, the enum of an enumerator
+    type_id: u32,
+    idx: u32,
+    /// member or enumerator name, empty for array elements
+    name: &'a str,
+}
+
+/// An accessor string resolved against one BTF. Anonymous members are
+/// left out of `accessors` but counted in `bit_offset`.
+#[derive(Debug, Clone)]
+struct Spec<'a> {
+    btf: &'a Btf,
+    root: u32,
+    accessors: Vec<Accessor<'a>>,
+    bit_offset: u64,
+}
+
+/// The value of a relocation computed from one spec.
+#[derive(Debug, Clone, Copy)]
+struct Value {
+    value: u64,
+    validate: bool,
+    /// size and type of the field loaded, for byte offsets
+    size: u32,
+    type_id: u32,
+}
+
+impl Value {
+    fn new(value: u64) -> Value {
+        Value {
+            value,
+            validate: true,
+            size: 0,
+            type_id: 0,
+        }
+    }
+}
+
+fn invalid<T>(msg: String) -> Result<T> {
+    Err(Error::Invalid(msg))
+}
+
+/// `name` without a `___flavor` suffix.
+pub fn essential_name(name: &str) -> &str {
+    let bytes = name.as_bytes();
+    (0..bytes.len().saturating_sub(4))
+        .rev()
+        .find(|&i| bytes[i] != b'_' && &bytes[i + 1..i + 4] == b"___" && bytes[i + 4] != b'_')
+        .map_or(name, |i| &name[..=i])
+}
+
+fn members(btf: &Btf, id: u32) -> Option<&[Member]> {
+    match &btf.types.get(id as usize)?.kind {
+        BtfKind::Struct { members, .. } | BtfKind::Union { members, .. } => Some(members),
+        _ => None,
+    }
+}
+
+/// Whether an array with `nelems` elements accessed right after `prev` is
+/// a flexible array member, which may be indexed past its end.
+fn is_flex_array(btf: &Btf, prev: Option<&Accessor<'_>>, nelems: u32) -> bool {
+    match prev {
+        Some(prev) if nelems == 0 && !prev.name.is_empty() => {
+            members(btf, prev.type_id).is_some_and(|m| prev.idx as usize + 1 == m.len())
+        }
+        _ => false,
+    }
+}
+
+impl<'a> Spec<'a> {
+    fn parse(btf: &'a Btf, type_id: u32, access: &str, kind: CoreRelocKind) -> Result<Spec<'a>> {
+        let raw = access
+            .split(':')
+            .map(str::parse)
+            .collect::<std::result::Result<Vec<u32>, _>>()
+            .or_else(|_| invalid(format!("invalid accessor string {:?}", access)))?;
+        if raw.len() > MAX_SPEC_LEN {
+            return invalid(format!("accessor string {:?} too long", access));
+        }
+        let mut spec = Spec {
+            btf,
+            root: type_id,
+            accessors: Vec::new(),
+            bit_offset: 0,
+        };
+        if !kind.is_field() && !kind.is_enumval() {
+            if raw != [0] {
+                return invalid(format!("type relocation with accessor {:?}", access));
+            }
+            return Ok(spec);
+        }
+
+        let mut id = btf.skip_mods_and_typedefs(type_id)?;
+        if kind.is_enumval() {
+            let value = match &btf.type_by_id(id)?.kind {
+                BtfKind::Enum { values, .. } if raw.len() == 1 => values.get(raw[0] as usize),
+                _ => None,
+            };
+            let value = match value {
+                Some(value) => value,
+                None => return invalid(format!("no enumerator {:?} in type {}", access, id)),
+            };
+            spec.accessors.push(Accessor {
+                type_id: id,
+                idx: raw[0],
+                name: &value.name,
+            });
+            return Ok(spec);
+        }
+
+        // the first index is into an array of the root type, as in
+        // `(&task)[0].pid`
+        spec.accessors.push(Accessor {
+            type_id: id,
+            idx: raw[0],
+            name: "",
+        });
+        spec.bit_offset = u64::from(raw[0]) * u64::from(btf.type_size(id)?) * 8;
+        for &idx in &raw[1..] {
+            id = btf.skip_mods_and_typedefs(id)?;
+            match &btf.type_by_id(id)?.kind {
+                BtfKind::Struct { members, .. } | BtfKind::Union { members, .. } => {
+                    let member = match members.get(idx as usize) {
+                        Some(member) => member,
+                        None => return invalid(format!("no member {} in type {}", idx, id)),
+                    };
+                    spec.bit_offset += u64::from(member.bit_offset);
+                    if !member.name.is_empty() {
+                        spec.accessors.push(Accessor {
+                            type_id: id,
+                            idx,
+                            name: &member.name,
+                        });
+                    }
+                    id = member.type_id;
+                }
+                BtfKind::Array { elem, nelems, .. } => {
+                    let flex = is_flex_array(btf, spec.accessors.last(), *nelems);
+                    if !flex && idx >= *nelems {
+                        return invalid(format!("index {} out of bounds of array {}", idx, id));
+                    }
+                    id = btf.skip_mods_and_typedefs(*elem)?;
+                    spec.accessors.push(Accessor {
+                        type_id: id,
+                        idx,
+                        name: "",
+                    });
+                    spec.bit_offset += u64::from(idx) * u64::from(btf.type_size(id)?) * 8;
+                }
+                _ => {
+                    return invalid(format!(
+                        "accessor {:?} goes into type {}, which has no members",
+                        access, id
+                    ))
+                }
+            }
+        }
+        Ok(spec)
+    }
+
+    /// Matches this local spec against target type `id`, returning the
+    /// equivalent target spec.
+    fn matching(&self, kind: CoreRelocKind, target: &'a Btf, id: u32) -> Result<Option<Spec<'a>>> {
+        let mut spec = Spec {
+            btf: target,
+            root: id,
+            accessors: Vec::new(),
+            bit_offset: 0,
+        };
+        if !kind.is_field() && !kind.is_enumval() {
+            let compat = types_are_compat(self.btf, self.root, target, id, MAX_SPEC_LEN)?;
+            return Ok(if compat { Some(spec) } else { None });
+        }
+
+        let mut id = target.skip_mods_and_typedefs(id)?;
+        if kind.is_enumval() {
+            let values = match &target.type_by_id(id)?.kind {
+                BtfKind::Enum { values, .. } => values,
+                _ => return Ok(None),
+            };
+            let name = essential_name(self.accessors[0].name);
+            return Ok(values
+                .iter()
+                .position(|v| essential_name(&v.name) == name)
+                .map(|idx| {
+                    spec.accessors.push(Accessor {
+                        type_id: id,
+                        idx: idx as u32,
+                        name: &values[idx].name,
+                    });
+                    spec
+                }));
+        }
+
+        for (i, acc) in self.accessors.iter().enumerate() {
+            if !acc.name.is_empty() {
+                let local_type = members(self.btf, acc.type_id).unwrap()[acc.idx as usize].type_id;
+                let found = match_member(
+                    (self.btf, acc.name, local_type),
+                    target,
+                    id,
+                    &mut spec,
+                    MAX_SPEC_LEN,
+                )?;
+                match found {
+                    Some(next) => id = next,
+                    None => return Ok(None),
+                }
+                continue;
+            }
+            // the first accessor indexes into the root type itself
+            if i > 0 {
+                let (elem, nelems) =
+                    match target.type_by_id(target.skip_mods_and_typedefs(id)?)?.kind {
+                        BtfKind::Array { elem, nelems, .. } => (elem, nelems),
+                        _ => return Ok(None),
+                    };
+                if !is_flex_array(target, spec.accessors.last(), nelems) && acc.idx >= nelems {
+                    return Ok(None);
+                }
+                id = target.skip_mods_and_typedefs(elem)?;
+            }
+            spec.accessors.push(Accessor {
+                type_id: id,
+                idx: acc.idx,
+                name: "",
+            });
+            spec.bit_offset += u64::from(acc.idx) * u64::from(target.type_size(id)?) * 8;
+        }
+        Ok(Some(spec))
+    }
+}
+
+/// Looks for member `local.1` of type `local.2` in struct or union `id`
+/// and the anonymous members within it. Returns the type of the member.
+fn match_member<'a>(
+    local: (&Btf, &str, u32),
+    target: &'a Btf,
+    id: u32,
+    spec: &mut Spec<'a>,
+    depth: usize,
+) -> Result<Option<u32>> {
+    if depth == 0 {
+        return invalid(format!("member {} nested too deep", local.1));
+    }
+    let id = target.skip_mods_and_typedefs(id)?;
+    let members = match members(target, id) {
+        Some(members) => members,
+        None => return Ok(None),
+    };
+    for (idx, member) in members.iter().enumerate() {
+        if member.name.is_empty() {
+            spec.bit_offset += u64::from(member.bit_offset);
+            if let Some(found) = match_member(local, target, member.type_id, spec, depth - 1)? {
+                return Ok(Some(found));
+            }
+            spec.bit_offset -= u64::from(member.bit_offset);
+        } else if member.name == local.1 {
+            if !fields_are_compat(local.0, local.2, target, member.type_id)? {
+                return Ok(None);
+            }
+            spec.bit_offset += u64::from(member.bit_offset);
+            spec.accessors.push(Accessor {
+                type_id: id,
+                idx: idx as u32,
+                name: &member.name,
+            });
+            return Ok(Some(member.type_id));
+        }
+    }
+    Ok(None)
+}
+
+/// Whether a field of type `target_id` can stand in for one of type
+/// `local_id`: records match any record, scalars of the same kind match
+/// each other, enums and forward declarations need the same name.
+fn fields_are_compat(local: &Btf, local_id: u32, target: &Btf, target_id: u32) -> Result<bool> {
+    let (mut local_id, mut target_id) = (local_id, target_id);
+    for _ in 0..MAX_SPEC_LEN {
+        let l = local.type_by_id(local.skip_mods_and_typedefs(local_id)?)?;
+        let t = target.type_by_id(target.skip_mods_and_typedefs(target_id)?)?;
+        return Ok(match (&l.kind, &t.kind) {
+            (BtfKind::Struct { .. }, BtfKind::Struct { .. })
+            | (BtfKind::Struct { .. }, BtfKind::Union { .. })
+            | (BtfKind::Union { .. }, BtfKind::Struct { .. })
+            | (BtfKind::Union { .. }, BtfKind::Union { .. })
+            | (BtfKind::Ptr(_), BtfKind::Ptr(_))
+            | (BtfKind::Float { .. }, BtfKind::Float { .. }) => true,
+            (BtfKind::Enum { .. }, BtfKind::Enum { .. })
+            | (BtfKind::Fwd { .. }, BtfKind::Fwd { .. }) => {
+                essential_name(&l.name) == essential_name(&t.name)
+            }
+            // bitfields encoded in the int type itself are deprecated
+            (BtfKind::Int { offset: lo, .. }, BtfKind::Int { offset: to, .. }) => {
+                *lo == 0 && *to == 0
+            }
+            (BtfKind::Array { elem: le, .. }, BtfKind::Array { elem: te, .. }) => {
+                local_id = *le;
+                target_id = *te;
+                continue;
+            }
+            _ => false,
+        });
+    }
+    invalid(format!("array type {} nested too deep", local_id))
+}
+
+/// Whether type `target_id` has the shape of `local_id`, for type based
+/// relocations. Names only matter for the relocated type itself.
+fn types_are_compat(
+    local: &Btf,
+    local_id: u32,
+    target: &Btf,
+    target_id: u32,
+    depth: usize,
+) -> Result<bool> {
+    if depth == 0 {
+        return invalid(format!("type {} nested too deep", local_id));
+    }
+    let l = local.type_by_id(local.skip_mods_and_typedefs(local_id)?)?;
+    let t = target.type_by_id(target.skip_mods_and_typedefs(target_id)?)?;
+    let compat = |l, t| types_are_compat(local, l, target, t, depth - 1);
+    Ok(match (&l.kind, &t.kind) {
+        (BtfKind::Int { offset: lo, .. }, BtfKind::Int { offset: to, .. }) => *lo == 0 && *to == 0,
+        (BtfKind::Ptr(l), BtfKind::Ptr(t)) => compat(*l, *t)?,
+        (BtfKind::Array { elem: l, .. }, BtfKind::Array { elem: t, .. }) => compat(*l, *t)?,
+        (
+            BtfKind::FuncProto {
+                ret: lr,
+                params: lp,
+            },
+            BtfKind::FuncProto {
+                ret: tr,
+                params: tp,
+            },
+        ) => {
+            if lp.len() != tp.len() {
+                return Ok(false);
+            }
+            for (l, t) in lp.iter().zip(tp) {
+                if !compat(l.type_id, t.type_id)? {
+                    return Ok(false);
+                }
+            }
+            compat(*lr, *tr)?
+        }
+        (l, t) => {
+            mem::discriminant(l) == mem::discriminant(t)
+                && matches!(
+                    l,
+                    BtfKind::Void
+                        | BtfKind::Struct { .. }
+                        | BtfKind::Union { .. }
+                        | BtfKind::Enum { .. }
+                        | BtfKind::Fwd { .. }
+                        | BtfKind::Float { .. }
+                )
+        }
+    })
+}
+
+/// Computes the relocated value from `spec`, `None` asking for the
+/// instruction to be poisoned.
+fn value(spec: Option<&Spec<'_>>, kind: CoreRelocKind) -> Result<Option<Value>> {
+    let spec = match spec {
+        Some(spec) => spec,
+        None => {
+            return Ok(match kind {
+                CoreRelocKind::FieldExists
+                | CoreRelocKind::TypeExists
+                | CoreRelocKind::TypeMatches
+                | CoreRelocKind::TypeIdTarget
+                | CoreRelocKind::TypeSize
+                | CoreRelocKind::EnumvalExists => Some(Value::new(0)),
+                _ => None,
+            })
+        }
+    };
+    let btf = spec.btf;
+    let value = match kind {
+        CoreRelocKind::FieldExists
+        | CoreRelocKind::TypeExists
+        | CoreRelocKind::TypeMatches
+        | CoreRelocKind::EnumvalExists => Value::new(1),
+        CoreRelocKind::TypeIdLocal | CoreRelocKind::TypeIdTarget => Value::new(spec.root.into()),
+        CoreRelocKind::TypeSize => Value::new(btf.type_size(spec.root)?.into()),
+        CoreRelocKind::EnumvalValue => {
+            let acc = &spec.accessors[0];
+            match &btf.type_by_id(acc.type_id)?.kind {
+                BtfKind::Enum { values, .. } => Value::new(values[acc.idx as usize].value as u64),
+                _ => unreachable!("enumerator of a type that isn't an enum"),
+            }
+        }
+        _ => return field_value(spec, kind).map(Some),
+    };
+    Ok(Some(value))
+}
+
+fn field_value(spec: &Spec<'_>, kind: CoreRelocKind) -> Result<Value> {
+    let btf = spec.btf;
+    let acc = spec.accessors.last().unwrap();
+    if acc.name.is_empty() {
+        // an array element, or the root itself
+        let size = btf.type_size(acc.type_id)?;
+        return match kind {
+            CoreRelocKind::FieldByteOffset => Ok(Value {
+                size,
+                type_id: acc.type_id,
+                ..Value::new(spec.bit_offset / 8)
+            }),
+            CoreRelocKind::FieldByteSize => Ok(Value::new(size.into())),
+            _ => invalid(format!("{} relocation of an array element", kind.name())),
+        };
+    }
+
+    let member = &members(btf, acc.type_id).unwrap()[acc.idx as usize];
+    let type_id = btf.skip_mods_and_typedefs(member.type_id)?;
+    let bit_offset = spec.bit_offset;
+    let (byte_offset, byte_size, bit_size) = if member.bitfield_size > 0 {
+        // the smallest aligned load covering all bits
+        let bit_size = u64::from(member.bitfield_size);
+        let mut byte_size = u64::from(btf.type_size(type_id)?);
+        let mut byte_offset = bit_offset / 8 / byte_size * byte_size;
+        while bit_offset + bit_size - byte_offset * 8 > byte_size * 8 {
+            if byte_size >= 8 {
+                return invalid(format!("bitfield {} can't be read with one load", acc.name));
+            }
+            byte_size *= 2;
+            byte_offset = bit_offset / 8 / byte_size * byte_size;
+        }
+        (byte_offset, byte_size, bit_size)
+    } else {
+        let byte_size = u64::from(btf.type_size(type_id)?);
+        (bit_offset / 8, byte_size, byte_size * 8)
+    };
+    let bitfield = member.bitfield_size > 0;
+    Ok(match kind {
+        CoreRelocKind::FieldByteOffset if bitfield => Value {
+            validate: false,
+            ..Value::new(byte_offset)
+        },
+        CoreRelocKind::FieldByteOffset => Value {
+            size: byte_size as u32,
+            type_id,
+            ..Value::new(byte_offset)
+        },
+        CoreRelocKind::FieldByteSize => Value {
+            validate: !bitfield,
+            ..Value::new(byte_size)
+        },
+        CoreRelocKind::FieldSigned => {
+            let signed = match btf.type_by_id(type_id)?.kind {
+                BtfKind::Int { encoding, .. } => encoding & BTF_INT_SIGNED != 0,
+                BtfKind::Enum { signed, .. } => signed,
+                _ => false,
+            };
+            Value::new(signed.into())
+        }
+        CoreRelocKind::FieldLshiftU64 => Value {
+            validate: !bitfield,
+            ..Value::new(64 - (bit_offset + bit_size - byte_offset * 8))
+        },
+        CoreRelocKind::FieldRshiftU64 => Value::new(64 - bit_size),
+        _ => unreachable!("{} is not a field relocation", kind.name()),
+    })
+}
+
+/// Whether loads of a field can switch from type `local_id` to the
+/// differently sized `target_id` and read the same value: pointers and
+/// unsigned integers are zero extended.
+fn is_resizable(local: &Btf, local_id: u32, target: &Btf, target_id: u32) -> Result<bool> {
+    Ok(
+        match (
+            &local.type_by_id(local_id)?.kind,
+            &target.type_by_id(target_id)?.kind,
+        ) {
+            (BtfKind::Ptr(_), BtfKind::Ptr(_)) => true,
+            (BtfKind::Int { encoding: l, .. }, BtfKind::Int { encoding: t, .. }) => {
+                l & BTF_INT_SIGNED == 0 && t & BTF_INT_SIGNED == 0
+            }
+            _ => false,
+        },
+    )
+}
+
+/// Resolves `reloc`, recorded against the object's BTF `local`, for a
+/// kernel described by `target`.
+pub fn resolve(local: &Btf, target: &Btf, reloc: &CoreReloc) -> Result<Resolution> {
+    let local_spec = Spec::parse(local, reloc.type_id, &reloc.access, reloc.kind)?;
+    let local_value = value(Some(&local_spec), reloc.kind)?.unwrap();
+    let resolution = |target_value: Option<Value>, resizable| Resolution {
+        local: local_value.value,
+        target: target_value.map(|v| v.value),
+        validate: local_value.validate,
+        local_size: local_value.size,
+        target_size: target_value.map_or(0, |v| v.size),
+        resizable,
+    };
+    // the type id of the object itself, e.g. for `bpf_core_type_id_local`
+    if reloc.kind == CoreRelocKind::TypeIdLocal {
+        return Ok(resolution(Some(local_value), true));
+    }
+
+    let root = local.type_by_id(reloc.type_id)?;
+    if root.name.is_empty() {
+        return invalid(format!(
+            "{} relocation of anonymous type {}",
+            reloc.kind.name(),
+            reloc.type_id
+        ));
+    }
+    let name = essential_name(&root.name);
+    let mut found: Option<(Spec<'_>, Option<Value>)> = None;
+    for (id, candidate) in target.types.iter().enumerate().skip(1) {
+        if mem::discriminant(&candidate.kind) != mem::discriminant(&root.kind)
+            || essential_name(&candidate.name) != name
+        {
+            continue;
+        }
+        let spec = match local_spec.matching(reloc.kind, target, id as u32)? {
+            Some(spec) => spec,
+            None => continue,
+        };
+        let value = value(Some(&spec), reloc.kind)?;
+        match &found {
+            None => found = Some((spec, value)),
+            Some((first, _)) if reloc.kind.is_field() && first.bit_offset != spec.bit_offset => {
+                return invalid(format!(
+                    "{} matches types {} and {} at different offsets",
+                    root.name, first.root, spec.root
+                ))
+            }
+            Some((first, first_value))
+                if first_value.map(|v| v.value) != value.map(|v| v.value) =>
+            {
+                return invalid(format!(
+                    "{} matches types {} and {} with different values",
+                    root.name, first.root, spec.root
+                ))
+            }
+            _ => {}
+        }
+    }
+    let target_value = match found {
+        Some((_, value)) => value,
+        None => value(None, reloc.kind)?,
+    };
+    let resizable = match target_value {
+        Some(v) if v.size != local_value.size => {
+            is_resizable(local, local_value.type_id, target, v.type_id)?
+        }
+        _ => true,
+    };
+    Ok(resolution(target_value, resizable))
+}
+
+fn poison(insn: &mut Insn) {
+    *insn = Insn::new(BPF_JMP | BPF_CALL, 0, 0, 0, POISON_HELPER);
+}
+
+/// Load and store size codes and their width in bytes.
+const SIZES: [(u8, u32); 4] = [(BPF_B, 1), (BPF_H, 2), (BPF_W, 4), (BPF_DW, 8)];
+
+/// Patches instruction `idx` of `insns` with a resolved relocation: the
+/// immediate of an alu instruction or `ld_imm64`, or the offset of a load
+/// or store, whose size also follows the field's.
+pub fn patch(insns: &mut [Insn], idx: usize, resolution: &Resolution) -> Result<()> {
+    let insn = insns[idx];
+    let target = match resolution.target {
+        Some(target) => target,
+        None => {
+            if insn.is_ld_imm64() && idx + 1 < insns.len() {
+                poison(&mut insns[idx + 1]);
+            }
+            poison(&mut insns[idx]);
+            return Ok(());
+        }
+    };
+    let check = |found: u64| {
+        if resolution.validate && found != resolution.local {
+            return invalid(format!(
+                "insn {} holds {}, expected {}",
+                idx, found as i64, resolution.local
+            ));
+        }
+        Ok(())
+    };
+    match insn.class() {
+        BPF_ALU | BPF_ALU64 if insn.source() == BPF_K => {
+            check(insn.imm as u64)?;
+            insns[idx].imm = target as i32;
+        }
+        BPF_LDX | BPF_ST | BPF_STX => {
+            check(insn.off as u64)?;
+            if target > i16::MAX as u64 {
+                return invalid(format!("offset {} of insn {} too big", target, idx));
+            }
+            if !resolution.resizable {
+                poison(&mut insns[idx]);
+                return Ok(());
+            }
+            insns[idx].off = target as i16;
+            if resolution.local_size != resolution.target_size {
+                let bytes = SIZES
+                    .iter()
+                    .find(|(code, _)| *code == insn.size())
+                    .unwrap()
+                    .1;
+                if bytes != resolution.local_size {
+                    return invalid(format!(
+                        "insn {} accesses {} bytes of a {} byte field",
+                        idx, bytes, resolution.local_size
+                    ));
+                }
+                let size = match SIZES.iter().find(|(_, b)| *b == resolution.target_size) {
+                    Some((size, _)) => *size,
+                    None => {
+                        return invalid(format!(
+                            "insn {} can't access {} bytes",
+                            idx, resolution.target_size
+                        ))
+                    }
+                };
+                insns[idx].code = insn.mode() | size | insn.class();
+            }
+        }
+        BPF_LD if insn.is_ld_imm64() && idx + 1 < insns.len() => {
+            let imm = u64::from(insn.imm as u32) | u64::from(insns[idx + 1].imm as u32) << 32;
+            check(imm)?;
+            insns[idx].imm = target as i32;
+            insns[idx + 1].imm = (target >> 32) as i32;
+        }
+        _ => return invalid(format!("insn {} can't be relocated", idx)),
+    }
+    Ok(())
+}
+
+/// Resolves and applies the `relocs` of a program, their `insn_off` an
+/// instruction index into `insns`.
+pub fn relocate(insns: &mut [Insn], relocs: &[CoreReloc], local: &Btf, target: &Btf) -> Result<()> {
+    for (n, reloc) in relocs.iter().enumerate() {
+        let idx = reloc.insn_off as usize;
+        if idx >= insns.len() {
+            return invalid(format!(
+                "CO-RE relocation #{} of insn {} out of bounds",
+                n, idx
+            ));
+        }
+        resolve(local, target, reloc)
+            .and_then(|resolution| patch(insns, idx, &resolution))
+            .map_err(|e| {
+                let ty = local.type_by_id(reloc.type_id).map_or_else(
+                    |_| String::new(),
+                    |t| format!("{} {}", t.kind_name(), t.name),
+                );
+                Error::Invalid(format!(
+                    "CO-RE relocation #{} ({} of {} {}) at insn {}: {}",
+                    n,
+                    reloc.kind.name(),
+                    ty,
+                    reloc.access,
+                    idx,
+                    e
+                ))
+            })?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::module::btf::fixture::{array, btf, enumeration, int, ty};
+    use crate::module::btf::BtfType;
+    use crate::module::insn::{BPF_EXIT, BPF_IMM, BPF_MEM, BPF_MOV};
+
+    /// Members as `(name, type, bit offset, bitfield size)`.
+    fn record(name: &str, size: u32, members: &[(&str, u32, u32, u32)], union: bool) -> BtfType {
+        let members = members
+            .iter()
+            .map(|&(name, type_id, bit_offset, bitfield_size)| Member {
+                name: name.to_string(),
+                type_id,
+                bit_offset,
+                bitfield_size,
+            })
+            .collect();
+        if union {
+            ty(name, BtfKind::Union { size, members })
+        } else {
+            ty(name, BtfKind::Struct { size, members })
+        }
+    }
+
+    fn fields(name: &str, size: u32, members: &[(&str, u32, u32)]) -> BtfType {
+        let members: Vec<_> = members.iter().map(|&(n, t, o)| (n, t, o, 0)).collect();
+        record(name, size, &members, false)
+    }
+
+    fn reloc(type_id: u32, access: &str, kind: CoreRelocKind) -> CoreReloc {
+        CoreReloc {
+            insn_off: 0,
+            type_id,
+            access: access.to_string(),
+            kind,
+        }
+    }
+
+    use CoreRelocKind::*;
+
+    /// A local and a target BTF, and relocations of local types as
+    /// `(type, accessor, kind, local value, target value)`.
+    struct Case {
+        name: &'static str,
+        local: Vec<BtfType>,
+        target: Vec<BtfType>,
+        relocs: Vec<(u32, &'static str, CoreRelocKind, u64, Option<u64>)>,
+    }
+
+    fn corpus() -> Vec<Case> {
+        vec![
+            Case {
+                name: "fields moved",
+                local: vec![
+                    int("int", 4, true),
+                    fields("task_struct", 8, &[("pid", 1, 0), ("tgid", 1, 32)]),
+                    ty("pid_t", BtfKind::Typedef(1)),
+                ],
+                target: vec![
+                    int("long", 8, true),
+                    int("int", 4, true),
+                    fields(
+                        "task_struct",
+                        16,
+                        &[("state", 1, 0), ("pid", 2, 64), ("tgid", 2, 96)],
+                    ),
+                    ty("pid_t", BtfKind::Typedef(2)),
+                ],
+                relocs: vec![
+                    (2, "0:1", FieldByteOffset, 4, Some(12)),
+                    (2, "0:0", FieldByteSize, 4, Some(4)),
+                    (2, "0:1", FieldExists, 1, Some(1)),
+                    (2, "0:0", FieldSigned, 1, Some(1)),
+                    (2, "1:0", FieldByteOffset, 8, Some(24)),
+                    (2, "0", TypeSize, 8, Some(16)),
+                    (2, "0", TypeExists, 1, Some(1)),
+                    (2, "0", TypeIdLocal, 2, Some(2)),
+                    (2, "0", TypeIdTarget, 2, Some(3)),
+                    (3, "0", TypeSize, 4, Some(4)),
+                    (3, "0", TypeIdTarget, 3, Some(4)),
+                ],
+            },
+            Case {
+                name: "flavors and anonymous members",
+                local: vec![
+                    int("int", 4, true),
+                    fields("sock___old", 8, &[("flags", 1, 0), ("state", 1, 32)]),
+                ],
+                target: vec![
+                    int("int", 4, true),
+                    record("", 4, &[("flags", 1, 0, 0)], true),
+                    fields(
+                        "sock",
+                        12,
+                        &[("refcnt", 1, 0), ("", 2, 32), ("state", 1, 64)],
+                    ),
+                ],
+                relocs: vec![
+                    (2, "0:0", FieldByteOffset, 0, Some(4)),
+                    (2, "0:1", FieldByteOffset, 4, Some(8)),
+                    (2, "0", TypeSize, 8, Some(12)),
+                ],
+            },
+            Case {
+                name: "missing and incompatible",
+                local: vec![
+                    int("int", 4, true),
+                    ty("", BtfKind::Ptr(0)),
+                    fields(
+                        "rq",
+                        16,
+                        &[("nr_running", 1, 0), ("gone", 1, 32), ("curr", 2, 64)],
+                    ),
+                    fields("only_local", 4, &[("x", 1, 0)]),
+                    array(1, 4),
+                    // 6
+                    fields("buf", 16, &[("data", 5, 0)]),
+                    ty("handle_t", BtfKind::Typedef(2)),
+                ],
+                target: vec![
+                    int("int", 4, true),
+                    fields("rq", 8, &[("nr_running", 1, 0), ("curr", 1, 32)]),
+                    array(1, 2),
+                    fields("buf", 8, &[("data", 3, 0)]),
+                    ty("handle_t", BtfKind::Typedef(2)),
+                ],
+                relocs: vec![
+                    (3, "0:0", FieldByteOffset, 0, Some(0)),
+                    (3, "0:1", FieldExists, 1, Some(0)),
+                    (3, "0:1", FieldByteOffset, 4, None),
+                    (3, "0:2", FieldByteOffset, 8, None),
+                    (3, "0:2", FieldExists, 1, Some(0)),
+                    (4, "0", TypeExists, 1, Some(0)),
+                    (4, "0", TypeSize, 4, Some(0)),
+                    (4, "0:0", FieldByteOffset, 0, None),
+                    (6, "0:0:1", FieldByteOffset, 4, Some(4)),
+                    (6, "0:0:3", FieldByteOffset, 12, None),
+                    (7, "0", TypeExists, 1, Some(0)),
+                ],
+            },
+            Case {
+                name: "arrays",
+                local: vec![
+                    int("int", 4, true),
+                    array(1, 4),
+                    fields("cpu", 20, &[("id", 1, 0), ("stats", 2, 32)]),
+                    array(1, 0),
+                    fields("msg", 4, &[("len", 1, 0), ("data", 4, 32)]),
+                ],
+                target: vec![
+                    int("long", 8, true),
+                    int("int", 4, true),
+                    array(2, 8),
+                    fields("cpu", 40, &[("id", 1, 0), ("stats", 3, 64)]),
+                    array(2, 0),
+                    // 6
+                    fields(
+                        "msg",
+                        16,
+                        &[("flags", 1, 0), ("len", 2, 64), ("data", 5, 96)],
+                    ),
+                ],
+                relocs: vec![
+                    (3, "0:1:2", FieldByteOffset, 12, Some(16)),
+                    (3, "0:1:2", FieldByteSize, 4, Some(4)),
+                    (3, "0:1", FieldByteSize, 16, Some(32)),
+                    (3, "0:0", FieldByteSize, 4, Some(8)),
+                    (5, "0:1:5", FieldByteOffset, 24, Some(32)),
+                ],
+            },
+            Case {
+                name: "bitfields",
+                local: vec![
+                    int("unsigned int", 4, false),
+                    int("int", 4, true),
+                    record(
+                        "flags",
+                        8,
+                        &[
+                            ("a", 1, 0, 3),
+                            ("b", 1, 3, 5),
+                            ("c", 1, 8, 10),
+                            ("d", 2, 18, 4),
+                            ("e", 1, 28, 10),
+                        ],
+                        false,
+                    ),
+                ],
+                target: vec![
+                    int("unsigned int", 4, false),
+                    int("int", 4, true),
+                    record(
+                        "flags",
+                        16,
+                        &[
+                            ("x", 1, 0, 0),
+                            ("a", 1, 32, 3),
+                            ("b", 1, 35, 5),
+                            ("c", 1, 44, 10),
+                            ("d", 2, 54, 4),
+                            ("e", 1, 92, 10),
+                        ],
+                        false,
+                    ),
+                ],
+                relocs: vec![
+                    (3, "0:2", FieldByteOffset, 0, Some(4)),
+                    (3, "0:2", FieldByteSize, 4, Some(4)),
+                    (3, "0:2", FieldLshiftU64, 46, Some(42)),
+                    (3, "0:2", FieldRshiftU64, 54, Some(54)),
+                    (3, "0:2", FieldSigned, 0, Some(0)),
+                    (3, "0:3", FieldSigned, 1, Some(1)),
+                    (3, "0:3", FieldLshiftU64, 42, Some(38)),
+                    (3, "0:4", FieldByteOffset, 0, Some(8)),
+                    (3, "0:4", FieldByteSize, 8, Some(8)),
+                    (3, "0:4", FieldLshiftU64, 26, Some(26)),
+                ],
+            },
+            Case {
+                name: "enums",
+                local: vec![enumeration(
+                    "state",
+                    false,
+                    &[
+                        ("S_IDLE", 0),
+                        ("S_RUN", 1),
+                        ("S_NEW___v2", 2),
+                        ("S_GONE", 3),
+                        ("S_ERR", 4),
+                    ],
+                )],
+                target: vec![enumeration(
+                    "state",
+                    true,
+                    &[("S_RUN", 4), ("S_IDLE", 7), ("S_NEW", 9), ("S_ERR", -1)],
+                )],
+                relocs: vec![
+                    (1, "0", EnumvalValue, 0, Some(7)),
+                    (1, "1", EnumvalValue, 1, Some(4)),
+                    (1, "2", EnumvalValue, 2, Some(9)),
+                    (1, "3", EnumvalExists, 1, Some(0)),
+                    (1, "3", EnumvalValue, 3, None),
+                    (1, "4", EnumvalValue, 4, Some(u64::MAX)),
+                    (1, "0", TypeSize, 4, Some(4)),
+                ],
+            },
+        ]
+    }
+
+    #[test]
+    fn test_corpus() {
+        for case in corpus() {
+            let local = btf(case.local);
+            let target = btf(case.target);
+            for (type_id, access, kind, local_value, target_value) in case.relocs {
+                let what = format!("{}: {} {} of {}", case.name, kind.name(), access, type_id);
+                let resolution = resolve(&local, &target, &reloc(type_id, access, kind))
+                    .unwrap_or_else(|e| panic!("{}: {}", what, e));
+                assert_eq!(resolution.local, local_value, "{}", what);
+                assert_eq!(resolution.target, target_value, "{}", what);
+            }
+        }
+    }
+
+    #[test]
+    fn test_errors() {
+        let local = btf(vec![
+            int("int", 4, true),
+            fields("ns", 4, &[("id", 1, 0)]),
+            fields("", 4, &[("x", 1, 0)]),
+        ]);
+        let target = btf(vec![
+            int("int", 4, true),
+            fields("ns", 4, &[("id", 1, 0)]),
+            fields("ns___2", 8, &[("pad", 1, 0), ("id", 1, 32)]),
+        ]);
+        let error = |type_id, access: &str, kind| {
+            resolve(&local, &target, &reloc(type_id, access, kind))
+                .unwrap_err()
+                .to_string()
+        };
+        assert!(error(2, "0:0", FieldByteOffset).contains("different offsets"));
+        assert!(error(2, "0:1", FieldByteOffset).contains("no member 1"));
+        assert!(error(2, "0:x", FieldByteOffset).contains("invalid accessor"));
+        assert!(error(2, "0:0", TypeSize).contains("type relocation"));
+        assert!(error(3, "0:0", FieldByteOffset).contains("anonymous"));
+        // both candidates agree that the type exists
+        assert_eq!(
+            resolve(&local, &target, &reloc(2, "0", TypeExists))
+                .unwrap()
+                .target,
+            Some(1)
+        );
+    }
+
+    #[test]
+    fn test_essential_name() {
+        assert_eq!(essential_name("task_struct___old"), "task_struct");
+        assert_eq!(essential_name("a___b___c"), "a___b");
+        assert_eq!(essential_name("task_struct"), "task_struct");
+        assert_eq!(essential_name("foo____bar"), "foo____bar");
+        assert_eq!(essential_name("___x"), "___x");
+    }
+
+    #[test]
+    fn test_relocate() {
+        let local = btf(vec![
+            int("unsigned int", 4, false),
+            int("int", 4, true),
+            fields("rq", 12, &[("a", 1, 0), ("b", 1, 32), ("c", 2, 64)]),
+            enumeration("state", false, &[("RUN", 1)]),
+        ]);
+        let target = btf(vec![
+            int("unsigned long", 8, false),
+            int("long", 8, true),
+            fields("rq", 24, &[("c", 2, 0), ("a", 1, 64), ("b", 1, 128)]),
+            enumeration("other", false, &[]),
+        ]);
+        let ldx = |size, off| Insn::new(BPF_LDX | BPF_MEM | size, 0, 1, off, 0);
+        let mut insns = vec![
+            ldx(BPF_W, 4),
+            Insn::new(BPF_ALU64 | BPF_MOV | BPF_K, 2, 0, 0, 8),
+            ldx(BPF_W, 8),
+            Insn::new(BPF_LD | BPF_IMM | BPF_DW, 3, 0, 0, 1),
+            Insn::new(0, 0, 0, 0, 0),
+            Insn::new(BPF_JMP | BPF_EXIT, 0, 0, 0, 0),
+        ];
+        let mut relocs = vec![
+            CoreReloc {
+                insn_off: 0,
+                ..reloc(3, "0:1", FieldByteOffset)
+            },
+            CoreReloc {
+                insn_off: 1,
+                ..reloc(3, "0:2", FieldByteOffset)
+            },
+            CoreReloc {
+                insn_off: 2,
+                ..reloc(3, "0:2", FieldByteOffset)
+            },
+            CoreReloc {
+                insn_off: 3,
+                ..reloc(4, "0", EnumvalValue)
+            },
+        ];
+        relocate(&mut insns, &relocs, &local, &target).unwrap();
+        // the unsigned field grew, so does the load
+        assert_eq!(insns[0], ldx(BPF_DW, 16));
+        assert_eq!(insns[1].imm, 0);
+        // a signed one can't be read with a wider load
+        assert_eq!(
+            insns[2],
+            Insn::new(BPF_JMP | BPF_CALL, 0, 0, 0, POISON_HELPER)
+        );
+        assert_eq!(insns[3], insns[2]);
+        assert_eq!(insns[4], insns[2]);
+
+        insns[0] = ldx(BPF_W, 0);
+        relocs.truncate(1);
+        let e = relocate(&mut insns, &relocs, &local, &target).unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "CO-RE relocation #0 (byte_off of struct rq 0:1) at insn 0: insn 0 holds 0, expected 4"
+        );
+    }
+}
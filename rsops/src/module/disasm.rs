@@ -0,0 +1,356 @@
+//! Disassembler for eBPF instruction streams.
+//!
+//! The output follows the syntax of the kernel verifier log and
+//! `bpftool prog dump xlated`, e.g. `r1 = *(u32 *)(r10 -4)` or
+//! `if r0 == 0x0 goto pc+3`, except that `ld_imm64` constants carry
+//! llvm's `ll` suffix so they can't be mistaken for a 32 bit move.
+
+use crate::module::cfg;
+use crate::module::helpers::helper_name;
+use crate::module::insn::*;
+use crate::module::object::MapReloc;
+use std::ops::Range;
+
+fn size_name(insn: &Insn) -> &'static str {
+    match insn.size() {
+        BPF_B => "u8",
+        BPF_H => "u16",
+        BPF_W => "u32",
+        _ => "u64",
+    }
+}
+
+fn alu_op(insn: &Insn) -> Option<&'static str> {
+    Some(match insn.op() {
+        BPF_ADD => "+=",
+        BPF_SUB => "-=",
+        BPF_MUL => "*=",
+        BPF_DIV if insn.off == 1 => "s/=",
+        BPF_DIV => "/=",
+        BPF_OR => "|=",
+        BPF_AND => "&=",
+        BPF_LSH => "<<=",
+        BPF_RSH => ">>=",
+        BPF_MOD if insn.off == 1 => "s%=",
+        BPF_MOD => "%=",
+        BPF_XOR => "^=",
+        BPF_MOV => "=",
+        BPF_ARSH => "s>>=",
+        _ => return None,
+    })
+}
+
+fn jmp_op(insn: &Insn) -> Option<&'static str> {
+    Some(match insn.op() {
+        BPF_JEQ => "==",
+        BPF_JGT => ">",
+        BPF_JGE => ">=",
+        BPF_JSET => "&",
+        BPF_JNE => "!=",
+        BPF_JSGT => "s>",
+        BPF_JSGE => "s>=",
+        BPF_JLT => "<",
+        BPF_JLE => "<=",
+        BPF_JSLT => "s<",
+        BPF_JSLE => "s<=",
+        _ => return None,
+    })
+}
+
+/// `r1` for 64 bit operations, `w1` for 32 bit ones.
+fn reg(reg: u8, wide: bool) -> String {
+    format!("{}{}", if wide { 'r' } else { 'w' }, reg)
+}
+
+fn mem(insn: &Insn, base: u8) -> String {
+    format!("*({} *)(r{} {:+})", size_name(insn), base, insn.off)
+}
+
+fn format_alu(insn: &Insn) -> Option<String> {
+    let wide = insn.class() == BPF_ALU64;
+    let dst = reg(insn.dst(), wide);
+    match insn.op() {
+        BPF_NEG => return Some(format!("{} = -{}", dst, dst)),
+        BPF_END => {
+            let kind = if wide {
+                "bswap"
+            } else if insn.source() == BPF_TO_BE {
+                "be"
+            } else {
+                "le"
+            };
+            return Some(format!("{} = {}{} {}", dst, kind, insn.imm, dst));
+        }
+        _ => {}
+    }
+    let op = alu_op(insn)?;
+    if insn.source() == BPF_K {
+        return Some(format!("{} {} {}", dst, op, insn.imm));
+    }
+    let src = reg(insn.src(), wide);
+    if insn.op() == BPF_MOV && insn.off != 0 {
+        // movsx, sign extending the low `off` bits of src
+        return Some(format!("{} = (s{}){}", dst, insn.off, src));
+    }
+    Some(format!("{} {} {}", dst, op, src))
+}
+
+fn format_atomic(insn: &Insn) -> Option<String> {
+    let ptr = format!("({} *)(r{} {:+})", size_name(insn), insn.dst(), insn.off);
+    let wide = insn.size() == BPF_DW;
+    let src = reg(insn.src(), wide);
+    let prefix = if wide { "atomic64" } else { "atomic" };
+    let (op, name) = match insn.imm & !BPF_FETCH {
+        0x00 => ("+=", "add"),
+        0x40 => ("|=", "or"),
+        0x50 => ("&=", "and"),
+        0xa0 => ("^=", "xor"),
+        _ => ("", ""),
+    };
+    Some(match insn.imm {
+        BPF_XCHG => format!("{} = {}_xchg({}, {})", src, prefix, ptr, src),
+        BPF_CMPXCHG => format!(
+            "{} = {}_cmpxchg({}, {}, {})",
+            reg(0, wide),
+            prefix,
+            ptr,
+            reg(0, wide),
+            src
+        ),
+        _ if name.is_empty() => return None,
+        imm if imm & BPF_FETCH != 0 => {
+            format!("{} = {}_fetch_{}({}, {})", src, prefix, name, ptr, src)
+        }
+        _ => format!("lock *{} {} {}", ptr, op, src),
+    })
+}
+
+fn format_ld_imm64(insn: &Insn, next: Option<&Insn>, map: Option<&str>) -> String {
+    let dst = reg(insn.dst(), true);
+    let hi = next.map_or(0, |n| n.imm);
+    // relocated references in an ELF object only get their pseudo src
+    // register at load time
+    let src = match (insn.src(), map) {
+        (0, Some(_)) => BPF_PSEUDO_MAP_FD,
+        (src, _) => src,
+    };
+    let map = |kind: &str| match map {
+        Some(name) => format!("map[{}]", name),
+        None => format!("map[{}:{}]", kind, insn.imm),
+    };
+    match src {
+        BPF_PSEUDO_MAP_FD => format!("{} = {}", dst, map("id")),
+        BPF_PSEUDO_MAP_VALUE => format!("{} = {}[0]+{}", dst, map("id"), hi),
+        BPF_PSEUDO_MAP_IDX => format!("{} = {}", dst, map("idx")),
+        BPF_PSEUDO_MAP_IDX_VALUE => format!("{} = {}[0]+{}", dst, map("idx"), hi),
+        BPF_PSEUDO_BTF_ID => format!("{} = btf_id[{}]", dst, insn.imm),
+        BPF_PSEUDO_FUNC => format!("{} = func[pc{:+}]", dst, insn.imm),
+        _ => {
+            let value = (u64::from(hi as u32) << 32) | u64::from(insn.imm as u32);
+            format!("{} = {:#x} ll", dst, value)
+        }
+    }
+}
+
+fn format_jmp(insn: &Insn) -> Option<String> {
+    let wide = insn.class() == BPF_JMP;
+    match insn.op() {
+        BPF_JA if wide => return Some(format!("goto pc{:+}", insn.off)),
+        // gotol, the 32 bit offset lives in imm
+        BPF_JA => return Some(format!("gotol pc{:+}", insn.imm)),
+        BPF_EXIT if wide => return Some("exit".to_string()),
+        BPF_CALL if wide => {
+            return Some(match insn.src() {
+                BPF_PSEUDO_CALL => format!("call pc{:+}", insn.imm),
+                BPF_PSEUDO_KFUNC_CALL => format!("call kfunc#{}", insn.imm),
+                _ => match helper_name(insn.imm) {
+                    Some(name) => format!("call {}#{}", name, insn.imm),
+                    None => format!("call unknown#{}", insn.imm),
+                },
+            })
+        }
+        _ => {}
+    }
+    let op = jmp_op(insn)?;
+    let src = if insn.source() == BPF_K {
+        format!("{:#x}", insn.imm)
+    } else {
+        reg(insn.src(), wide)
+    };
+    Some(format!(
+        "if {} {} {} goto pc{:+}",
+        reg(insn.dst(), wide),
+        op,
+        src,
+        insn.off
+    ))
+}
+
+/// Renders one instruction. `next` is the second half of an `ld_imm64`
+/// and `map` the name of the map an `ld_imm64` refers to, when known.
+pub fn format_insn(insn: &Insn, next: Option<&Insn>, map: Option<&str>) -> String {
+    let text = match (insn.class(), insn.mode()) {
+        (BPF_ALU, _) | (BPF_ALU64, _) => format_alu(insn),
+        (BPF_JMP, _) | (BPF_JMP32, _) => format_jmp(insn),
+        (BPF_LD, BPF_IMM) if insn.size() == BPF_DW => Some(format_ld_imm64(insn, next, map)),
+        (BPF_LD, BPF_ABS) => Some(format!("r0 = *({} *)skb[{}]", size_name(insn), insn.imm)),
+        (BPF_LD, BPF_IND) => Some(format!(
+            "r0 = *({} *)skb[r{} + {}]",
+            size_name(insn),
+            insn.src(),
+            insn.imm
+        )),
+        (BPF_LDX, BPF_MEM) => Some(format!("r{} = {}", insn.dst(), mem(insn, insn.src()))),
+        (BPF_LDX, BPF_MEMSX) => Some(format!(
+            "r{} = {}",
+            insn.dst(),
+            mem(insn, insn.src()).replacen("*(u", "*(s", 1)
+        )),
+        (BPF_ST, BPF_MEM) => Some(format!("{} = {}", mem(insn, insn.dst()), insn.imm)),
+        (BPF_STX, BPF_MEM) => Some(format!("{} = r{}", mem(insn, insn.dst()), insn.src())),
+        (BPF_STX, BPF_ATOMIC) => format_atomic(insn),
+        _ => None,
+    };
+    text.unwrap_or_else(|| format!("invalid insn code {:#04x}", insn.code))
+}
+
+/// Disassembles `insns[range]`, one numbered line per instruction, naming
+/// maps after `relocs` and prefixing instruction `mark` with `>`. A range
+/// starting on the second half of an `ld_imm64` starts on its first.
+pub fn disassemble_range(
+    insns: &[Insn],
+    relocs: &[MapReloc],
+    range: Range<usize>,
+    mark: Option<usize>,
+) -> String {
+    let mut out = String::new();
+    let end = range.end.min(insns.len());
+    let mut idx = range.start;
+    if idx < end && !cfg::insn_starts(insns)[idx] {
+        idx -= 1;
+    }
+    while idx < end {
+        let insn = &insns[idx];
+        let map = relocs
+            .iter()
+            .find(|r| r.insn == idx)
+            .map(|r| r.map.as_str());
+        let text = format_insn(insn, insns.get(idx + 1), map);
+        let marker = if mark == Some(idx) { '>' } else { ' ' };
+        out.push_str(&format!("{}{:4}: {}\n", marker, idx, text));
+        idx += if insn.is_ld_imm64() { 2 } else { 1 };
+    }
+    out
+}
+
+/// Disassembles a whole program.
+pub fn disassemble(insns: &[Insn], relocs: &[MapReloc]) -> String {
+    disassemble_range(insns, relocs, 0..insns.len(), None)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn fmt(insn: Insn) -> String {
+        format_insn(&insn, None, None)
+    }
+
+    #[test]
+    fn test_format_alu() {
+        assert_eq!(
+            fmt(Insn::new(BPF_ALU64 | BPF_MOV | BPF_K, 0, 0, 0, 2)),
+            "r0 = 2"
+        );
+        assert_eq!(
+            fmt(Insn::new(BPF_ALU64 | BPF_ADD | BPF_X, 1, 2, 0, 0)),
+            "r1 += r2"
+        );
+        assert_eq!(
+            fmt(Insn::new(BPF_ALU | BPF_ARSH | BPF_K, 3, 0, 0, 4)),
+            "w3 s>>= 4"
+        );
+        assert_eq!(fmt(Insn::new(BPF_ALU64 | BPF_NEG, 1, 0, 0, 0)), "r1 = -r1");
+        assert_eq!(
+            fmt(Insn::new(BPF_ALU | BPF_END | BPF_TO_BE, 1, 0, 0, 16)),
+            "w1 = be16 w1"
+        );
+        assert_eq!(
+            fmt(Insn::new(BPF_ALU64 | BPF_MOV | BPF_X, 1, 2, 8, 0)),
+            "r1 = (s8)r2"
+        );
+    }
+
+    #[test]
+    fn test_format_mem() {
+        assert_eq!(
+            fmt(Insn::new(BPF_LDX | BPF_MEM | BPF_W, 0, 1, 4, 0)),
+            "r0 = *(u32 *)(r1 +4)"
+        );
+        assert_eq!(
+            fmt(Insn::new(BPF_STX | BPF_MEM | BPF_DW, 10, 1, -8, 0)),
+            "*(u64 *)(r10 -8) = r1"
+        );
+        assert_eq!(
+            fmt(Insn::new(BPF_ST | BPF_MEM | BPF_B, 10, 0, -1, 7)),
+            "*(u8 *)(r10 -1) = 7"
+        );
+        assert_eq!(
+            fmt(Insn::new(BPF_STX | BPF_ATOMIC | BPF_DW, 0, 1, 0, 0)),
+            "lock *(u64 *)(r0 +0) += r1"
+        );
+        assert_eq!(
+            fmt(Insn::new(BPF_STX | BPF_ATOMIC | BPF_W, 0, 1, 0, BPF_XCHG)),
+            "w1 = atomic_xchg((u32 *)(r0 +0), w1)"
+        );
+        assert_eq!(
+            fmt(Insn::new(BPF_LD | BPF_ABS | BPF_H, 0, 0, 0, 12)),
+            "r0 = *(u16 *)skb[12]"
+        );
+    }
+
+    #[test]
+    fn test_format_jmp() {
+        assert_eq!(
+            fmt(Insn::new(BPF_JMP | BPF_JEQ | BPF_K, 0, 0, 3, 0)),
+            "if r0 == 0x0 goto pc+3"
+        );
+        assert_eq!(
+            fmt(Insn::new(BPF_JMP32 | BPF_JSGT | BPF_X, 1, 2, -2, 0)),
+            "if w1 s> w2 goto pc-2"
+        );
+        assert_eq!(
+            fmt(Insn::new(BPF_JMP | BPF_CALL, 0, 0, 0, 1)),
+            "call bpf_map_lookup_elem#1"
+        );
+        assert_eq!(
+            fmt(Insn::new(BPF_JMP | BPF_CALL, 0, BPF_PSEUDO_CALL, 0, 4)),
+            "call pc+4"
+        );
+        assert_eq!(fmt(Insn::new(BPF_JMP | BPF_EXIT, 0, 0, 0, 0)), "exit");
+    }
+
+    #[test]
+    fn test_disassemble() {
+        let insns = vec![
+            Insn::new(BPF_LD | BPF_IMM | BPF_DW, 1, 0, 0, 0),
+            Insn::new(0, 0, 0, 0, 0),
+            Insn::new(BPF_LD | BPF_IMM | BPF_DW, 2, 0, 0, 1),
+            Insn::new(0, 0, 0, 0, 1),
+            Insn::new(BPF_JMP | BPF_EXIT, 0, 0, 0, 0),
+        ];
+        let relocs = vec![MapReloc {
+            insn: 0,
+            map: "counts".to_string(),
+        }];
+        assert_eq!(
+            disassemble_range(&insns, &relocs, 0..insns.len(), Some(4)),
+            "    0: r1 = map[counts]\n    2: r2 = 0x100000001 ll\n>   4: exit\n"
+        );
+        assert_eq!(
+            disassemble_range(&insns, &relocs, 3..5, None),
+            "    2: r2 = 0x100000001 ll\n    4: exit\n"
+        );
+        assert_eq!(disassemble_range(&insns, &relocs, 5..8, None), "");
+    }
+}
@@ -0,0 +1,190 @@
+//! Instructions of a program as the kernel runs it, like `bpftool prog
+//! dump xlated` and `bpftool prog dump jited`.
+
+use crate::error::{Error, Result};
+use crate::module::btf::Btf;
+use crate::module::btf_ext::SourceLine;
+use crate::module::cfg::Cfg;
+use crate::module::disasm::format_insn;
+use crate::module::insn::{parse_insns, Insn};
+use crate::module::program::{obj_name_to_string, Program};
+use crate::sys::syscall::{self, LineInfo, BPF_TAG_SIZE};
+use std::fmt::{self, Display};
+use std::mem;
+use std::os::unix::io::AsRawFd;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProgramDump {
+    pub id: u32,
+    pub name: String,
+    pub tag: [u8; BPF_TAG_SIZE],
+    /// Instructions after verifier rewrites. `ld_imm64` map references
+    /// carry the map id instead of a file descriptor.
+    pub xlated: Vec<Insn>,
+    /// Native code, empty when the JIT is disabled.
+    pub jited: Vec<u8>,
+    /// Kernel addresses of the JITed main program and its subprograms.
+    pub jited_ksyms: Vec<u64>,
+    /// Length in bytes of each JITed function in `jited`.
+    pub jited_func_lens: Vec<u32>,
+    pub line_info: Vec<SourceLine>,
+    /// Kernel address of the JITed code of each `line_info` record.
+    pub jited_line_info: Vec<u64>,
+}
+
+/// Dumps the program with kernel id `id`.
+pub fn dump_by_id(id: u32) -> Result<ProgramDump> {
+    dump(&Program::from_id(id)?)
+}
+
+pub fn dump(program: &Program) -> Result<ProgramDump> {
+    let fd = program.as_raw_fd();
+    let lens = program.info()?;
+    let mut xlated = vec![0u8; lens.xlated_prog_len as usize];
+    let mut jited = vec![0u8; lens.jited_prog_len as usize];
+    let mut jited_ksyms = vec![0u64; lens.nr_jited_ksyms as usize];
+    let mut jited_func_lens = vec![0u32; lens.nr_jited_func_lens as usize];
+    let mut line_info = vec![LineInfo::default(); lens.nr_line_info as usize];
+    let mut jited_line_info = vec![0u64; lens.nr_jited_line_info as usize];
+    let mut info = syscall::ProgInfo {
+        xlated_prog_len: xlated.len() as u32,
+        xlated_prog_insns: xlated.as_mut_ptr() as u64,
+        jited_prog_len: jited.len() as u32,
+        jited_prog_insns: jited.as_mut_ptr() as u64,
+        nr_jited_ksyms: jited_ksyms.len() as u32,
+        jited_ksyms: jited_ksyms.as_mut_ptr() as u64,
+        nr_jited_func_lens: jited_func_lens.len() as u32,
+        jited_func_lens: jited_func_lens.as_mut_ptr() as u64,
+        nr_line_info: line_info.len() as u32,
+        line_info_rec_size: mem::size_of::<LineInfo>() as u32,
+        line_info: line_info.as_mut_ptr() as u64,
+        nr_jited_line_info: jited_line_info.len() as u32,
+        jited_line_info_rec_size: mem::size_of::<u64>() as u32,
+        jited_line_info: jited_line_info.as_mut_ptr() as u64,
+        ..Default::default()
+    };
+    syscall::obj_get_info(fd, &mut info)
+        .map_err(|e| Error::Syscall("BPF_OBJ_GET_INFO_BY_FD", e))?;
+    if info.xlated_prog_len as usize > xlated.len() || info.jited_prog_len as usize > jited.len() {
+        return Err(Error::Invalid(format!(
+            "program {} changed size while being dumped",
+            info.id
+        )));
+    }
+    // the kernel zeroes the lengths when kptr_restrict hides the code
+    xlated.truncate(info.xlated_prog_len as usize);
+    jited.truncate(info.jited_prog_len as usize);
+
+    let line_info = if line_info.is_empty() || info.btf_id == 0 {
+        Vec::new()
+    } else {
+        let btf = Btf::from_id(info.btf_id)?;
+        line_info
+            .iter()
+            .map(|rec| {
+                SourceLine::resolve(
+                    &btf,
+                    rec.insn_off,
+                    rec.file_name_off,
+                    rec.line_off,
+                    rec.line_col,
+                )
+            })
+            .collect::<Result<_>>()?
+    };
+    Ok(ProgramDump {
+        id: info.id,
+        name: obj_name_to_string(&info.name),
+        tag: info.tag,
+        xlated: parse_insns(&xlated),
+        jited,
+        jited_ksyms,
+        jited_func_lens,
+        line_info,
+        jited_line_info,
+    })
+}
+
+impl ProgramDump {
+    /// Returns the line record starting at xlated instruction `idx`.
+    pub fn line_at(&self, idx: usize) -> Option<&SourceLine> {
+        self.line_info.iter().find(|l| l.insn_off as usize == idx)
+    }
+
+    /// The control-flow graph of the xlated instructions as Graphviz DOT.
+    pub fn to_dot(&self) -> String {
+        Cfg::build(&self.xlated).to_dot(&self.name, &self.xlated, &[])
+    }
+
+    /// Renders the JITed code as a hex dump, one block per function.
+    pub fn jited_hex(&self) -> String {
+        let mut out = String::new();
+        let lens = if self.jited_func_lens.is_empty() {
+            vec![self.jited.len() as u32]
+        } else {
+            self.jited_func_lens.clone()
+        };
+        let mut start = 0;
+        for (i, len) in lens.iter().enumerate() {
+            let end = (start + *len as usize).min(self.jited.len());
+            if let Some(addr) = self.jited_ksyms.get(i) {
+                out.push_str(&format!("{:#x}:\n", addr));
+            }
+            for (row, chunk) in self.jited[start..end].chunks(16).enumerate() {
+                let bytes: Vec<_> = chunk.iter().map(|b| format!("{:02x}", b)).collect();
+                out.push_str(&format!("{:6x}:\t{}\n", row * 16, bytes.join(" ")));
+            }
+            start = end;
+        }
+        out
+    }
+}
+
+impl Display for ProgramDump {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (idx, insn) in self.xlated.iter().enumerate() {
+            if let Some(line) = self.line_at(idx) {
+                writeln!(f, "{}", line)?;
+            }
+            // the second half of an ld_imm64 is printed with the first
+            if idx > 0 && self.xlated[idx - 1].is_ld_imm64() {
+                continue;
+            }
+            let text = format_insn(insn, self.xlated.get(idx + 1), None);
+            writeln!(f, "{:4}: {}", idx, text)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_display_with_lines() {
+        let dump = ProgramDump {
+            id: 1,
+            name: "prog".to_string(),
+            tag: [0; BPF_TAG_SIZE],
+            xlated: vec![Insn::new(0xb7, 0, 0, 0, 2), Insn::new(0x95, 0, 0, 0, 0)],
+            jited: Vec::new(),
+            jited_ksyms: Vec::new(),
+            jited_func_lens: Vec::new(),
+            line_info: vec![SourceLine {
+                insn_off: 0,
+                file: "prog.c".to_string(),
+                line: 7,
+                col: 5,
+                source: "return XDP_PASS;".to_string(),
+            }],
+            jited_line_info: Vec::new(),
+        };
+        assert_eq!(
+            dump.to_string(),
+            "; return XDP_PASS; [prog.c:7:5]\n\
+             \x20  0: r0 = 2\n\
+             \x20  1: exit\n"
+        );
+    }
+}
@@ -0,0 +1,425 @@
+//! Which program types, map types and helpers the running kernel supports.
+//!
+//! Release numbers (see `compat`) only tell what upstream had; distribution
+//! kernels backport features and leave others out of their config. This
+//! asks the kernel itself the way libbpf's `libbpf_probes.c` does: load a
+//! minimal program of each type, create a minimal map of each type, and
+//! load a program calling each helper and look for the verifier's "unknown
+//! helper" message.
+
+use crate::error::{Error, Result};
+use crate::module::helpers::Helper;
+use crate::module::insn::*;
+use crate::module::map::MapType;
+use crate::module::program::ProgramType;
+use crate::sys::syscall;
+use kernel_version::KernelVersion;
+use std::fmt::{self, Display};
+use std::io;
+use std::os::unix::io::RawFd;
+use std::sync::OnceLock;
+
+const LICENSE: &[u8] = b"GPL\0";
+
+/// Enough for the first lines of a rejected probe, which is all we look at.
+const LOG_SIZE: usize = 4096;
+
+/// Verifier messages for a helper the program type can't call, across
+/// kernel versions.
+const UNKNOWN_HELPER: [&str; 3] = [
+    "invalid func ",
+    "unknown func ",
+    "program of this type cannot use helper ",
+];
+
+const BPF_F_NO_PREALLOC: u32 = 1;
+/// `ENOTSUPP`, returned for a struct_ops map with an invalid BTF type.
+const ENOTSUPP: i32 = 524;
+
+// enum bpf_attach_type
+const BPF_CGROUP_INET4_CONNECT: u32 = 10;
+const BPF_CGROUP_GETSOCKOPT: u32 = 21;
+const BPF_TRACE_FENTRY: u32 = 24;
+const BPF_LSM_MAC: u32 = 27;
+const BPF_SK_LOOKUP: u32 = 36;
+
+/// What the running kernel supports.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Features {
+    pub program_types: Vec<(ProgramType, bool)>,
+    pub map_types: Vec<(MapType, bool)>,
+    /// helpers callable from each supported program type; types that can
+    /// only load with an attach target (tracing, ext, lsm, struct_ops) are
+    /// not probed
+    pub helpers: Vec<(ProgramType, Vec<Helper>)>,
+}
+
+static FEATURES: OnceLock<Features> = OnceLock::new();
+
+/// Probes the running kernel once and returns the cached result after.
+///
+/// Probing needs the privileges to load programs; without them this fails
+/// instead of reporting that nothing is supported.
+pub fn probe() -> Result<&'static Features> {
+    if let Some(features) = FEATURES.get() {
+        return Ok(features);
+    }
+    let features = Features::probe()?;
+    Ok(FEATURES.get_or_init(|| features))
+}
+
+impl Features {
+    /// Probes the running kernel without caching.
+    pub fn probe() -> Result<Features> {
+        // socket filters exist everywhere: failing to load one means we
+        // lack the privileges, not the feature
+        if let Err(e) = load(ProgramType::SocketFilter, &return_zero(), None) {
+            return Err(Error::Syscall("BPF_PROG_LOAD", e));
+        }
+        let program_types: Vec<_> = (1..)
+            .map_while(ProgramType::from_u32)
+            .map(|t| (t, probe_program_type(t)))
+            .collect();
+        let map_types = (1..)
+            .map_while(MapType::from_u32)
+            .map(|t| (t, probe_map_type(t)))
+            .collect();
+        let helpers = program_types
+            .iter()
+            .filter(|(t, supported)| *supported && needs_attach_target(*t).is_none())
+            .map(|(t, _)| {
+                let helpers = (1..)
+                    .map_while(Helper::from_i32)
+                    .filter(|h| probe_helper(*t, *h))
+                    .collect();
+                (*t, helpers)
+            })
+            .collect();
+        Ok(Features {
+            program_types,
+            map_types,
+            helpers,
+        })
+    }
+
+    pub fn program_type(&self, prog_type: ProgramType) -> bool {
+        self.program_types.contains(&(prog_type, true))
+    }
+
+    pub fn map_type(&self, map_type: MapType) -> bool {
+        self.map_types.contains(&(map_type, true))
+    }
+
+    /// Whether programs of type `prog_type` can call `helper`.
+    pub fn helper(&self, prog_type: ProgramType, helper: Helper) -> bool {
+        self.helpers(prog_type).contains(&helper)
+    }
+
+    pub fn helpers(&self, prog_type: ProgramType) -> &[Helper] {
+        self.helpers
+            .iter()
+            .find(|(t, _)| *t == prog_type)
+            .map_or(&[], |(_, helpers)| helpers)
+    }
+
+    pub fn to_json(&self) -> String {
+        fn flags<T: Copy>(items: &[(T, bool)], name: impl Fn(T) -> &'static str) -> String {
+            let items: Vec<_> = items
+                .iter()
+                .map(|(t, supported)| format!("\"{}\":{}", name(*t), supported))
+                .collect();
+            format!("{{{}}}", items.join(","))
+        }
+        let helpers: Vec<_> = self
+            .helpers
+            .iter()
+            .map(|(t, helpers)| {
+                let names: Vec<_> = helpers
+                    .iter()
+                    .map(|h| format!("\"bpf_{}\"", h.name()))
+                    .collect();
+                format!("\"{}\":[{}]", t.name(), names.join(","))
+            })
+            .collect();
+        format!(
+            "{{\"program_types\":{},\"map_types\":{},\"helpers\":{{{}}}}}",
+            flags(&self.program_types, ProgramType::name),
+            flags(&self.map_types, MapType::name),
+            helpers.join(",")
+        )
+    }
+}
+
+impl Display for Features {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let yes_no = |supported: bool| if supported { "yes" } else { "no" };
+        writeln!(f, "program types:")?;
+        for (t, supported) in &self.program_types {
+            writeln!(f, "  {}: {}", t.name(), yes_no(*supported))?;
+        }
+        writeln!(f, "map types:")?;
+        for (t, supported) in &self.map_types {
+            writeln!(f, "  {}: {}", t.name(), yes_no(*supported))?;
+        }
+        writeln!(f, "helpers:")?;
+        for (t, helpers) in &self.helpers {
+            let names: Vec<_> = helpers.iter().map(|h| h.name()).collect();
+            writeln!(f, "  {}: {}", t.name(), names.join(" "))?;
+        }
+        Ok(())
+    }
+}
+
+/// `r0 = 0; exit`
+fn return_zero() -> [Insn; 2] {
+    [
+        Insn::new(BPF_ALU64 | BPF_MOV | BPF_K, 0, 0, 0, 0),
+        Insn::new(BPF_JMP | BPF_EXIT, 0, 0, 0, 0),
+    ]
+}
+
+/// The expected attach type of program types that can only load with
+/// an attach target. The probe passes BTF type 1, which is never a
+/// function, so a kernel that knows the type rejects it in the verifier.
+fn needs_attach_target(prog_type: ProgramType) -> Option<u32> {
+    match prog_type {
+        ProgramType::Tracing => Some(BPF_TRACE_FENTRY),
+        ProgramType::Lsm => Some(BPF_LSM_MAC),
+        ProgramType::StructOps | ProgramType::Ext => Some(0),
+        _ => None,
+    }
+}
+
+fn expected_attach_type(prog_type: ProgramType) -> u32 {
+    match prog_type {
+        ProgramType::CgroupSockAddr => BPF_CGROUP_INET4_CONNECT,
+        ProgramType::CgroupSockopt => BPF_CGROUP_GETSOCKOPT,
+        ProgramType::SkLookup => BPF_SK_LOOKUP,
+        _ => needs_attach_target(prog_type).unwrap_or(0),
+    }
+}
+
+/// `LINUX_VERSION_CODE` of the running kernel, which kprobes had to match
+/// before 5.0.
+fn kernel_version_code() -> u32 {
+    KernelVersion::running().map_or(0, |version| version.code())
+}
+
+fn close(fd: RawFd) {
+    unsafe {
+        libc::close(fd);
+    }
+}
+
+/// Loads `insns` as a GPL program of type `prog_type` and closes it again,
+/// filling `log` with the verifier log.
+fn load(prog_type: ProgramType, insns: &[Insn], log: Option<&mut [u8]>) -> io::Result<()> {
+    let mut attr = syscall::ProgLoadAttr {
+        prog_type: prog_type as u32,
+        insn_cnt: insns.len() as u32,
+        insns: insns.as_ptr() as u64,
+        license: LICENSE.as_ptr() as u64,
+        expected_attach_type: expected_attach_type(prog_type),
+        attach_btf_id: needs_attach_target(prog_type).map_or(0, |_| 1),
+        ..Default::default()
+    };
+    if prog_type == ProgramType::Kprobe {
+        attr.kern_version = kernel_version_code();
+    }
+    if let Some(log) = log {
+        attr.log_level = 1;
+        attr.log_size = log.len() as u32;
+        attr.log_buf = log.as_mut_ptr() as u64;
+    }
+    syscall::prog_load(&mut attr).map(close)
+}
+
+fn log_text(log: &[u8]) -> String {
+    let len = log.iter().position(|&b| b == 0).unwrap_or(log.len());
+    String::from_utf8_lossy(&log[..len]).into_owned()
+}
+
+/// Whether the kernel knows program type `prog_type`. Unknown types are
+/// rejected with `EINVAL` before the verifier runs, so an empty log.
+pub fn probe_program_type(prog_type: ProgramType) -> bool {
+    let mut log = [0u8; LOG_SIZE];
+    match load(prog_type, &return_zero(), Some(&mut log)) {
+        Ok(()) => true,
+        Err(e) => match e.raw_os_error() {
+            Some(libc::EINVAL) | Some(libc::EOPNOTSUPP) => {
+                needs_attach_target(prog_type).is_some() && !log_text(&log).is_empty()
+            }
+            _ => true,
+        },
+    }
+}
+
+/// Whether programs of type `prog_type` can call `helper`. Any failure
+/// other than the verifier not knowing the helper, such as bad arguments,
+/// means it can.
+pub fn probe_helper(prog_type: ProgramType, helper: Helper) -> bool {
+    let insns = [
+        Insn::new(BPF_JMP | BPF_CALL, 0, 0, 0, helper as i32),
+        Insn::new(BPF_JMP | BPF_EXIT, 0, 0, 0, 0),
+    ];
+    let mut log = [0u8; LOG_SIZE];
+    if load(prog_type, &insns, Some(&mut log)).is_ok() {
+        return true;
+    }
+    let log = log_text(&log);
+    !UNKNOWN_HELPER.iter().any(|message| log.contains(message))
+}
+
+/// `int`, `struct bpf_spin_lock { int val; }` and
+/// `struct val { int cnt; struct bpf_spin_lock l; }`, the key and value
+/// types of the local storage map probes.
+fn storage_btf() -> Vec<u8> {
+    const STRINGS: &[u8] = b"\0bpf_spin_lock\0val\0cnt\0l\0";
+    const BTF_KIND_INT: u32 = 1;
+    const BTF_KIND_STRUCT: u32 = 4;
+    const BTF_INT_SIGNED: u32 = 1;
+    let types: [u32; 19] = [
+        // [1] int
+        0,
+        BTF_KIND_INT << 24,
+        4,
+        (BTF_INT_SIGNED << 24) | 32,
+        // [2] struct bpf_spin_lock
+        1,
+        (BTF_KIND_STRUCT << 24) | 1,
+        4,
+        15,
+        1,
+        0,
+        // [3] struct val
+        15,
+        (BTF_KIND_STRUCT << 24) | 2,
+        8,
+        19,
+        1,
+        0,
+        23,
+        2,
+        32,
+    ];
+    let type_len = types.len() as u32 * 4;
+    let mut btf = Vec::new();
+    btf.extend_from_slice(&0xeb9fu16.to_ne_bytes());
+    btf.extend_from_slice(&[1, 0]);
+    for field in &[24, 0, type_len, type_len, STRINGS.len() as u32] {
+        btf.extend_from_slice(&field.to_ne_bytes());
+    }
+    for word in &types {
+        btf.extend_from_slice(&word.to_ne_bytes());
+    }
+    btf.extend_from_slice(STRINGS);
+    btf
+}
+
+/// Whether the kernel can create maps of type `map_type`.
+pub fn probe_map_type(map_type: MapType) -> bool {
+    let mut attr = syscall::MapCreateAttr {
+        map_type: map_type as u32,
+        key_size: 4,
+        value_size: 4,
+        max_entries: 1,
+        ..Default::default()
+    };
+    let mut btf_fd = None;
+    let mut inner_fd = None;
+    match map_type {
+        MapType::StackTrace => attr.value_size = 8,
+        MapType::LpmTrie => {
+            attr.key_size = 8;
+            attr.value_size = 8;
+            attr.map_flags = BPF_F_NO_PREALLOC;
+        }
+        MapType::CgroupStorage | MapType::PercpuCgroupStorage => {
+            // struct bpf_cgroup_storage_key
+            attr.key_size = 16;
+            attr.value_size = 8;
+            attr.max_entries = 0;
+        }
+        MapType::Queue | MapType::Stack | MapType::BloomFilter => attr.key_size = 0,
+        MapType::Ringbuf => {
+            attr.key_size = 0;
+            attr.value_size = 0;
+            attr.max_entries = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as u32;
+        }
+        MapType::SkStorage | MapType::InodeStorage | MapType::TaskStorage => {
+            let fd = match syscall::btf_load(&storage_btf()) {
+                Ok(fd) => fd,
+                Err(_) => return false,
+            };
+            btf_fd = Some(fd);
+            attr.btf_fd = fd as u32;
+            attr.btf_key_type_id = 1;
+            attr.btf_value_type_id = 3;
+            attr.value_size = 8;
+            attr.max_entries = 0;
+            attr.map_flags = BPF_F_NO_PREALLOC;
+        }
+        MapType::StructOps => attr.btf_vmlinux_value_type_id = 1,
+        MapType::ArrayOfMaps | MapType::HashOfMaps => {
+            let mut inner = syscall::MapCreateAttr {
+                map_type: MapType::Hash as u32,
+                key_size: 4,
+                value_size: 4,
+                max_entries: 1,
+                ..Default::default()
+            };
+            let fd = match syscall::map_create(&mut inner) {
+                Ok(fd) => fd,
+                Err(_) => return false,
+            };
+            inner_fd = Some(fd);
+            attr.inner_map_fd = fd as u32;
+        }
+        _ => {}
+    }
+    let result = syscall::map_create(&mut attr);
+    btf_fd.into_iter().chain(inner_fd).for_each(close);
+    match result {
+        Ok(fd) => {
+            close(fd);
+            true
+        }
+        // the kernel knows struct_ops maps if it got as far as looking
+        // the value type up
+        Err(e) => map_type == MapType::StructOps && e.raw_os_error() == Some(ENOTSUPP),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_storage_btf() {
+        let btf = storage_btf();
+        assert_eq!(&btf[..2], &0xeb9fu16.to_ne_bytes());
+        assert_eq!(btf.len(), 24 + 19 * 4 + 25);
+        assert!(btf.ends_with(b"\0cnt\0l\0"));
+    }
+
+    #[test]
+    fn test_queries() {
+        let features = Features {
+            program_types: vec![(ProgramType::Xdp, true), (ProgramType::Lsm, false)],
+            map_types: vec![(MapType::Ringbuf, false), (MapType::PerfEventArray, true)],
+            helpers: vec![(ProgramType::Xdp, vec![Helper::MapLookupElem])],
+        };
+        assert!(features.program_type(ProgramType::Xdp));
+        assert!(!features.program_type(ProgramType::Lsm));
+        assert!(!features.program_type(ProgramType::Kprobe));
+        assert!(!features.map_type(MapType::Ringbuf));
+        assert!(features.helper(ProgramType::Xdp, Helper::MapLookupElem));
+        assert!(!features.helper(ProgramType::Kprobe, Helper::MapLookupElem));
+        assert_eq!(
+            features.to_json(),
+            "{\"program_types\":{\"xdp\":true,\"lsm\":false},\
+             \"map_types\":{\"ringbuf\":false,\"perf_event_array\":true},\
+             \"helpers\":{\"xdp\":[\"bpf_map_lookup_elem\"]}}"
+        );
+    }
+}
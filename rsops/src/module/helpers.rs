@@ -0,0 +1,488 @@
+//! The BPF helper functions, `enum bpf_func_id`.
+
+#[repr(i32)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Helper {
+    Unspec = 0,
+    MapLookupElem,
+    MapUpdateElem,
+    MapDeleteElem,
+    ProbeRead,
+    KtimeGetNs,
+    TracePrintk,
+    GetPrandomU32,
+    GetSmpProcessorId,
+    SkbStoreBytes,
+    L3CsumReplace,
+    L4CsumReplace,
+    TailCall,
+    CloneRedirect,
+    GetCurrentPidTgid,
+    GetCurrentUidGid,
+    GetCurrentComm,
+    GetCgroupClassid,
+    SkbVlanPush,
+    SkbVlanPop,
+    SkbGetTunnelKey,
+    SkbSetTunnelKey,
+    PerfEventRead,
+    Redirect,
+    GetRouteRealm,
+    PerfEventOutput,
+    SkbLoadBytes,
+    GetStackid,
+    CsumDiff,
+    SkbGetTunnelOpt,
+    SkbSetTunnelOpt,
+    SkbChangeProto,
+    SkbChangeType,
+    SkbUnderCgroup,
+    GetHashRecalc,
+    GetCurrentTask,
+    ProbeWriteUser,
+    CurrentTaskUnderCgroup,
+    SkbChangeTail,
+    SkbPullData,
+    CsumUpdate,
+    SetHashInvalid,
+    GetNumaNodeId,
+    SkbChangeHead,
+    XdpAdjustHead,
+    ProbeReadStr,
+    GetSocketCookie,
+    GetSocketUid,
+    SetHash,
+    Setsockopt,
+    SkbAdjustRoom,
+    RedirectMap,
+    SkRedirectMap,
+    SockMapUpdate,
+    XdpAdjustMeta,
+    PerfEventReadValue,
+    PerfProgReadValue,
+    Getsockopt,
+    OverrideReturn,
+    SockOpsCbFlagsSet,
+    MsgRedirectMap,
+    MsgApplyBytes,
+    MsgCorkBytes,
+    MsgPullData,
+    Bind,
+    XdpAdjustTail,
+    SkbGetXfrmState,
+    GetStack,
+    SkbLoadBytesRelative,
+    FibLookup,
+    SockHashUpdate,
+    MsgRedirectHash,
+    SkRedirectHash,
+    LwtPushEncap,
+    LwtSeg6StoreBytes,
+    LwtSeg6AdjustSrh,
+    LwtSeg6Action,
+    RcRepeat,
+    RcKeydown,
+    SkbCgroupId,
+    GetCurrentCgroupId,
+    GetLocalStorage,
+    SkSelectReuseport,
+    SkbAncestorCgroupId,
+    SkLookupTcp,
+    SkLookupUdp,
+    SkRelease,
+    MapPushElem,
+    MapPopElem,
+    MapPeekElem,
+    MsgPushData,
+    MsgPopData,
+    RcPointerRel,
+    SpinLock,
+    SpinUnlock,
+    SkFullsock,
+    TcpSock,
+    SkbEcnSetCe,
+    GetListenerSock,
+    SkcLookupTcp,
+    TcpCheckSyncookie,
+    SysctlGetName,
+    SysctlGetCurrentValue,
+    SysctlGetNewValue,
+    SysctlSetNewValue,
+    Strtol,
+    Strtoul,
+    SkStorageGet,
+    SkStorageDelete,
+    SendSignal,
+    TcpGenSyncookie,
+    SkbOutput,
+    ProbeReadUser,
+    ProbeReadKernel,
+    ProbeReadUserStr,
+    ProbeReadKernelStr,
+    TcpSendAck,
+    SendSignalThread,
+    Jiffies64,
+    ReadBranchRecords,
+    GetNsCurrentPidTgid,
+    XdpOutput,
+    GetNetnsCookie,
+    GetCurrentAncestorCgroupId,
+    SkAssign,
+    KtimeGetBootNs,
+    SeqPrintf,
+    SeqWrite,
+    SkCgroupId,
+    SkAncestorCgroupId,
+    RingbufOutput,
+    RingbufReserve,
+    RingbufSubmit,
+    RingbufDiscard,
+    RingbufQuery,
+    CsumLevel,
+    SkcToTcp6Sock,
+    SkcToTcpSock,
+    SkcToTcpTimewaitSock,
+    SkcToTcpRequestSock,
+    SkcToUdp6Sock,
+    GetTaskStack,
+    LoadHdrOpt,
+    StoreHdrOpt,
+    ReserveHdrOpt,
+    InodeStorageGet,
+    InodeStorageDelete,
+    DPath,
+    CopyFromUser,
+    SnprintfBtf,
+    SeqPrintfBtf,
+    SkbCgroupClassid,
+    RedirectNeigh,
+    PerCpuPtr,
+    ThisCpuPtr,
+    RedirectPeer,
+    TaskStorageGet,
+    TaskStorageDelete,
+    GetCurrentTaskBtf,
+    BprmOptsSet,
+    KtimeGetCoarseNs,
+    ImaInodeHash,
+    SockFromFile,
+    CheckMtu,
+    ForEachMapElem,
+    Snprintf,
+    SysBpf,
+    BtfFindByNameKind,
+    SysClose,
+    TimerInit,
+    TimerSetCallback,
+    TimerStart,
+    TimerCancel,
+    GetFuncIp,
+    GetAttachCookie,
+    TaskPtRegs,
+    GetBranchSnapshot,
+    TraceVprintk,
+    SkcToUnixSock,
+    KallsymsLookupName,
+    FindVma,
+    Loop,
+    Strncmp,
+    GetFuncArg,
+    GetFuncRet,
+    GetFuncArgCnt,
+    GetRetval,
+    SetRetval,
+    XdpGetBuffLen,
+    XdpLoadBytes,
+    XdpStoreBytes,
+    CopyFromUserTask,
+    SkbSetTstamp,
+    ImaFileHash,
+    KptrXchg,
+    MapLookupPercpuElem,
+    SkcToMptcpSock,
+    DynptrFromMem,
+    RingbufReserveDynptr,
+    RingbufSubmitDynptr,
+    RingbufDiscardDynptr,
+    DynptrRead,
+    DynptrWrite,
+    DynptrData,
+    TcpRawGenSyncookieIpv4,
+    TcpRawGenSyncookieIpv6,
+    TcpRawCheckSyncookieIpv4,
+    TcpRawCheckSyncookieIpv6,
+    KtimeGetTaiNs,
+    UserRingbufDrain,
+    CgrpStorageGet,
+    CgrpStorageDelete,
+}
+
+const HELPERS: [(Helper, &str); 212] = [
+    (Helper::Unspec, "unspec"),
+    (Helper::MapLookupElem, "map_lookup_elem"),
+    (Helper::MapUpdateElem, "map_update_elem"),
+    (Helper::MapDeleteElem, "map_delete_elem"),
+    (Helper::ProbeRead, "probe_read"),
+    (Helper::KtimeGetNs, "ktime_get_ns"),
+    (Helper::TracePrintk, "trace_printk"),
+    (Helper::GetPrandomU32, "get_prandom_u32"),
+    (Helper::GetSmpProcessorId, "get_smp_processor_id"),
+    (Helper::SkbStoreBytes, "skb_store_bytes"),
+    (Helper::L3CsumReplace, "l3_csum_replace"),
+    (Helper::L4CsumReplace, "l4_csum_replace"),
+    (Helper::TailCall, "tail_call"),
+    (Helper::CloneRedirect, "clone_redirect"),
+    (Helper::GetCurrentPidTgid, "get_current_pid_tgid"),
+    (Helper::GetCurrentUidGid, "get_current_uid_gid"),
+    (Helper::GetCurrentComm, "get_current_comm"),
+    (Helper::GetCgroupClassid, "get_cgroup_classid"),
+    (Helper::SkbVlanPush, "skb_vlan_push"),
+    (Helper::SkbVlanPop, "skb_vlan_pop"),
+    (Helper::SkbGetTunnelKey, "skb_get_tunnel_key"),
+    (Helper::SkbSetTunnelKey, "skb_set_tunnel_key"),
+    (Helper::PerfEventRead, "perf_event_read"),
+    (Helper::Redirect, "redirect"),
+    (Helper::GetRouteRealm, "get_route_realm"),
+    (Helper::PerfEventOutput, "perf_event_output"),
+    (Helper::SkbLoadBytes, "skb_load_bytes"),
+    (Helper::GetStackid, "get_stackid"),
+    (Helper::CsumDiff, "csum_diff"),
+    (Helper::SkbGetTunnelOpt, "skb_get_tunnel_opt"),
+    (Helper::SkbSetTunnelOpt, "skb_set_tunnel_opt"),
+    (Helper::SkbChangeProto, "skb_change_proto"),
+    (Helper::SkbChangeType, "skb_change_type"),
+    (Helper::SkbUnderCgroup, "skb_under_cgroup"),
+    (Helper::GetHashRecalc, "get_hash_recalc"),
+    (Helper::GetCurrentTask, "get_current_task"),
+    (Helper::ProbeWriteUser, "probe_write_user"),
+    (Helper::CurrentTaskUnderCgroup, "current_task_under_cgroup"),
+    (Helper::SkbChangeTail, "skb_change_tail"),
+    (Helper::SkbPullData, "skb_pull_data"),
+    (Helper::CsumUpdate, "csum_update"),
+    (Helper::SetHashInvalid, "set_hash_invalid"),
+    (Helper::GetNumaNodeId, "get_numa_node_id"),
+    (Helper::SkbChangeHead, "skb_change_head"),
+    (Helper::XdpAdjustHead, "xdp_adjust_head"),
+    (Helper::ProbeReadStr, "probe_read_str"),
+    (Helper::GetSocketCookie, "get_socket_cookie"),
+    (Helper::GetSocketUid, "get_socket_uid"),
+    (Helper::SetHash, "set_hash"),
+    (Helper::Setsockopt, "setsockopt"),
+    (Helper::SkbAdjustRoom, "skb_adjust_room"),
+    (Helper::RedirectMap, "redirect_map"),
+    (Helper::SkRedirectMap, "sk_redirect_map"),
+    (Helper::SockMapUpdate, "sock_map_update"),
+    (Helper::XdpAdjustMeta, "xdp_adjust_meta"),
+    (Helper::PerfEventReadValue, "perf_event_read_value"),
+    (Helper::PerfProgReadValue, "perf_prog_read_value"),
+    (Helper::Getsockopt, "getsockopt"),
+    (Helper::OverrideReturn, "override_return"),
+    (Helper::SockOpsCbFlagsSet, "sock_ops_cb_flags_set"),
+    (Helper::MsgRedirectMap, "msg_redirect_map"),
+    (Helper::MsgApplyBytes, "msg_apply_bytes"),
+    (Helper::MsgCorkBytes, "msg_cork_bytes"),
+    (Helper::MsgPullData, "msg_pull_data"),
+    (Helper::Bind, "bind"),
+    (Helper::XdpAdjustTail, "xdp_adjust_tail"),
+    (Helper::SkbGetXfrmState, "skb_get_xfrm_state"),
+    (Helper::GetStack, "get_stack"),
+    (Helper::SkbLoadBytesRelative, "skb_load_bytes_relative"),
+    (Helper::FibLookup, "fib_lookup"),
+    (Helper::SockHashUpdate, "sock_hash_update"),
+    (Helper::MsgRedirectHash, "msg_redirect_hash"),
+    (Helper::SkRedirectHash, "sk_redirect_hash"),
+    (Helper::LwtPushEncap, "lwt_push_encap"),
+    (Helper::LwtSeg6StoreBytes, "lwt_seg6_store_bytes"),
+    (Helper::LwtSeg6AdjustSrh, "lwt_seg6_adjust_srh"),
+    (Helper::LwtSeg6Action, "lwt_seg6_action"),
+    (Helper::RcRepeat, "rc_repeat"),
+    (Helper::RcKeydown, "rc_keydown"),
+    (Helper::SkbCgroupId, "skb_cgroup_id"),
+    (Helper::GetCurrentCgroupId, "get_current_cgroup_id"),
+    (Helper::GetLocalStorage, "get_local_storage"),
+    (Helper::SkSelectReuseport, "sk_select_reuseport"),
+    (Helper::SkbAncestorCgroupId, "skb_ancestor_cgroup_id"),
+    (Helper::SkLookupTcp, "sk_lookup_tcp"),
+    (Helper::SkLookupUdp, "sk_lookup_udp"),
+    (Helper::SkRelease, "sk_release"),
+    (Helper::MapPushElem, "map_push_elem"),
+    (Helper::MapPopElem, "map_pop_elem"),
+    (Helper::MapPeekElem, "map_peek_elem"),
+    (Helper::MsgPushData, "msg_push_data"),
+    (Helper::MsgPopData, "msg_pop_data"),
+    (Helper::RcPointerRel, "rc_pointer_rel"),
+    (Helper::SpinLock, "spin_lock"),
+    (Helper::SpinUnlock, "spin_unlock"),
+    (Helper::SkFullsock, "sk_fullsock"),
+    (Helper::TcpSock, "tcp_sock"),
+    (Helper::SkbEcnSetCe, "skb_ecn_set_ce"),
+    (Helper::GetListenerSock, "get_listener_sock"),
+    (Helper::SkcLookupTcp, "skc_lookup_tcp"),
+    (Helper::TcpCheckSyncookie, "tcp_check_syncookie"),
+    (Helper::SysctlGetName, "sysctl_get_name"),
+    (Helper::SysctlGetCurrentValue, "sysctl_get_current_value"),
+    (Helper::SysctlGetNewValue, "sysctl_get_new_value"),
+    (Helper::SysctlSetNewValue, "sysctl_set_new_value"),
+    (Helper::Strtol, "strtol"),
+    (Helper::Strtoul, "strtoul"),
+    (Helper::SkStorageGet, "sk_storage_get"),
+    (Helper::SkStorageDelete, "sk_storage_delete"),
+    (Helper::SendSignal, "send_signal"),
+    (Helper::TcpGenSyncookie, "tcp_gen_syncookie"),
+    (Helper::SkbOutput, "skb_output"),
+    (Helper::ProbeReadUser, "probe_read_user"),
+    (Helper::ProbeReadKernel, "probe_read_kernel"),
+    (Helper::ProbeReadUserStr, "probe_read_user_str"),
+    (Helper::ProbeReadKernelStr, "probe_read_kernel_str"),
+    (Helper::TcpSendAck, "tcp_send_ack"),
+    (Helper::SendSignalThread, "send_signal_thread"),
+    (Helper::Jiffies64, "jiffies64"),
+    (Helper::ReadBranchRecords, "read_branch_records"),
+    (Helper::GetNsCurrentPidTgid, "get_ns_current_pid_tgid"),
+    (Helper::XdpOutput, "xdp_output"),
+    (Helper::GetNetnsCookie, "get_netns_cookie"),
+    (
+        Helper::GetCurrentAncestorCgroupId,
+        "get_current_ancestor_cgroup_id",
+    ),
+    (Helper::SkAssign, "sk_assign"),
+    (Helper::KtimeGetBootNs, "ktime_get_boot_ns"),
+    (Helper::SeqPrintf, "seq_printf"),
+    (Helper::SeqWrite, "seq_write"),
+    (Helper::SkCgroupId, "sk_cgroup_id"),
+    (Helper::SkAncestorCgroupId, "sk_ancestor_cgroup_id"),
+    (Helper::RingbufOutput, "ringbuf_output"),
+    (Helper::RingbufReserve, "ringbuf_reserve"),
+    (Helper::RingbufSubmit, "ringbuf_submit"),
+    (Helper::RingbufDiscard, "ringbuf_discard"),
+    (Helper::RingbufQuery, "ringbuf_query"),
+    (Helper::CsumLevel, "csum_level"),
+    (Helper::SkcToTcp6Sock, "skc_to_tcp6_sock"),
+    (Helper::SkcToTcpSock, "skc_to_tcp_sock"),
+    (Helper::SkcToTcpTimewaitSock, "skc_to_tcp_timewait_sock"),
+    (Helper::SkcToTcpRequestSock, "skc_to_tcp_request_sock"),
+    (Helper::SkcToUdp6Sock, "skc_to_udp6_sock"),
+    (Helper::GetTaskStack, "get_task_stack"),
+    (Helper::LoadHdrOpt, "load_hdr_opt"),
+    (Helper::StoreHdrOpt, "store_hdr_opt"),
+    (Helper::ReserveHdrOpt, "reserve_hdr_opt"),
+    (Helper::InodeStorageGet, "inode_storage_get"),
+    (Helper::InodeStorageDelete, "inode_storage_delete"),
+    (Helper::DPath, "d_path"),
+    (Helper::CopyFromUser, "copy_from_user"),
+    (Helper::SnprintfBtf, "snprintf_btf"),
+    (Helper::SeqPrintfBtf, "seq_printf_btf"),
+    (Helper::SkbCgroupClassid, "skb_cgroup_classid"),
+    (Helper::RedirectNeigh, "redirect_neigh"),
+    (Helper::PerCpuPtr, "per_cpu_ptr"),
+    (Helper::ThisCpuPtr, "this_cpu_ptr"),
+    (Helper::RedirectPeer, "redirect_peer"),
+    (Helper::TaskStorageGet, "task_storage_get"),
+    (Helper::TaskStorageDelete, "task_storage_delete"),
+    (Helper::GetCurrentTaskBtf, "get_current_task_btf"),
+    (Helper::BprmOptsSet, "bprm_opts_set"),
+    (Helper::KtimeGetCoarseNs, "ktime_get_coarse_ns"),
+    (Helper::ImaInodeHash, "ima_inode_hash"),
+    (Helper::SockFromFile, "sock_from_file"),
+    (Helper::CheckMtu, "check_mtu"),
+    (Helper::ForEachMapElem, "for_each_map_elem"),
+    (Helper::Snprintf, "snprintf"),
+    (Helper::SysBpf, "sys_bpf"),
+    (Helper::BtfFindByNameKind, "btf_find_by_name_kind"),
+    (Helper::SysClose, "sys_close"),
+    (Helper::TimerInit, "timer_init"),
+    (Helper::TimerSetCallback, "timer_set_callback"),
+    (Helper::TimerStart, "timer_start"),
+    (Helper::TimerCancel, "timer_cancel"),
+    (Helper::GetFuncIp, "get_func_ip"),
+    (Helper::GetAttachCookie, "get_attach_cookie"),
+    (Helper::TaskPtRegs, "task_pt_regs"),
+    (Helper::GetBranchSnapshot, "get_branch_snapshot"),
+    (Helper::TraceVprintk, "trace_vprintk"),
+    (Helper::SkcToUnixSock, "skc_to_unix_sock"),
+    (Helper::KallsymsLookupName, "kallsyms_lookup_name"),
+    (Helper::FindVma, "find_vma"),
+    (Helper::Loop, "loop"),
+    (Helper::Strncmp, "strncmp"),
+    (Helper::GetFuncArg, "get_func_arg"),
+    (Helper::GetFuncRet, "get_func_ret"),
+    (Helper::GetFuncArgCnt, "get_func_arg_cnt"),
+    (Helper::GetRetval, "get_retval"),
+    (Helper::SetRetval, "set_retval"),
+    (Helper::XdpGetBuffLen, "xdp_get_buff_len"),
+    (Helper::XdpLoadBytes, "xdp_load_bytes"),
+    (Helper::XdpStoreBytes, "xdp_store_bytes"),
+    (Helper::CopyFromUserTask, "copy_from_user_task"),
+    (Helper::SkbSetTstamp, "skb_set_tstamp"),
+    (Helper::ImaFileHash, "ima_file_hash"),
+    (Helper::KptrXchg, "kptr_xchg"),
+    (Helper::MapLookupPercpuElem, "map_lookup_percpu_elem"),
+    (Helper::SkcToMptcpSock, "skc_to_mptcp_sock"),
+    (Helper::DynptrFromMem, "dynptr_from_mem"),
+    (Helper::RingbufReserveDynptr, "ringbuf_reserve_dynptr"),
+    (Helper::RingbufSubmitDynptr, "ringbuf_submit_dynptr"),
+    (Helper::RingbufDiscardDynptr, "ringbuf_discard_dynptr"),
+    (Helper::DynptrRead, "dynptr_read"),
+    (Helper::DynptrWrite, "dynptr_write"),
+    (Helper::DynptrData, "dynptr_data"),
+    (Helper::TcpRawGenSyncookieIpv4, "tcp_raw_gen_syncookie_ipv4"),
+    (Helper::TcpRawGenSyncookieIpv6, "tcp_raw_gen_syncookie_ipv6"),
+    (
+        Helper::TcpRawCheckSyncookieIpv4,
+        "tcp_raw_check_syncookie_ipv4",
+    ),
+    (
+        Helper::TcpRawCheckSyncookieIpv6,
+        "tcp_raw_check_syncookie_ipv6",
+    ),
+    (Helper::KtimeGetTaiNs, "ktime_get_tai_ns"),
+    (Helper::UserRingbufDrain, "user_ringbuf_drain"),
+    (Helper::CgrpStorageGet, "cgrp_storage_get"),
+    (Helper::CgrpStorageDelete, "cgrp_storage_delete"),
+];
+
+impl Helper {
+    pub fn from_i32(id: i32) -> Option<Helper> {
+        match id {
+            1.. => HELPERS.get(id as usize).map(|(h, _)| *h),
+            _ => None,
+        }
+    }
+
+    /// The helper's name without the `bpf_` prefix.
+    pub fn name(self) -> &'static str {
+        HELPERS[self as usize].1
+    }
+
+    /// Looks a helper up by name, with or without the `bpf_` prefix.
+    pub fn from_name(name: &str) -> Option<Helper> {
+        let name = name.strip_prefix("bpf_").unwrap_or(name);
+        HELPERS
+            .iter()
+            .skip(1)
+            .find(|(_, h)| *h == name)
+            .map(|(h, _)| *h)
+    }
+}
+
+/// Returns the name of helper `id`, e.g. `bpf_map_lookup_elem` for 1.
+pub fn helper_name(id: i32) -> Option<String> {
+    Helper::from_i32(id).map(|h| format!("bpf_{}", h.name()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_helpers() {
+        assert_eq!(helper_name(1).as_deref(), Some("bpf_map_lookup_elem"));
+        assert_eq!(helper_name(130).as_deref(), Some("bpf_ringbuf_output"));
+        assert_eq!(helper_name(0), None);
+        assert_eq!(helper_name(-1), None);
+        assert_eq!(Helper::from_name("bpf_tail_call"), Some(Helper::TailCall));
+        assert_eq!(Helper::from_name("trace_printk"), Some(Helper::TracePrintk));
+        assert_eq!(Helper::from_name("unspec"), None);
+        assert_eq!(Helper::CgrpStorageDelete as i32, 211);
+    }
+}
@@ -0,0 +1,177 @@
+//! The `struct bpf_insn` instruction format and its opcode encoding.
+
+use std::convert::TryInto;
+
+// instruction classes
+pub const BPF_LD: u8 = 0x00;
+pub const BPF_LDX: u8 = 0x01;
+pub const BPF_ST: u8 = 0x02;
+pub const BPF_STX: u8 = 0x03;
+pub const BPF_ALU: u8 = 0x04;
+pub const BPF_JMP: u8 = 0x05;
+pub const BPF_JMP32: u8 = 0x06;
+pub const BPF_ALU64: u8 = 0x07;
+
+// ld/ldx/st/stx sizes
+pub const BPF_W: u8 = 0x00;
+pub const BPF_H: u8 = 0x08;
+pub const BPF_B: u8 = 0x10;
+pub const BPF_DW: u8 = 0x18;
+
+// ld/ldx/st/stx modes
+pub const BPF_IMM: u8 = 0x00;
+pub const BPF_ABS: u8 = 0x20;
+pub const BPF_IND: u8 = 0x40;
+pub const BPF_MEM: u8 = 0x60;
+/// sign extending load, kernels 6.6+
+pub const BPF_MEMSX: u8 = 0x80;
+pub const BPF_ATOMIC: u8 = 0xc0;
+
+// atomic operations beyond the alu ones, in imm of BPF_ATOMIC
+pub const BPF_FETCH: i32 = 0x01;
+pub const BPF_XCHG: i32 = 0xe0 | BPF_FETCH;
+pub const BPF_CMPXCHG: i32 = 0xf0 | BPF_FETCH;
+
+// alu/jmp operand source
+pub const BPF_K: u8 = 0x00;
+pub const BPF_X: u8 = 0x08;
+
+// alu operations
+pub const BPF_ADD: u8 = 0x00;
+pub const BPF_SUB: u8 = 0x10;
+pub const BPF_MUL: u8 = 0x20;
+pub const BPF_DIV: u8 = 0x30;
+pub const BPF_OR: u8 = 0x40;
+pub const BPF_AND: u8 = 0x50;
+pub const BPF_LSH: u8 = 0x60;
+pub const BPF_RSH: u8 = 0x70;
+pub const BPF_NEG: u8 = 0x80;
+pub const BPF_MOD: u8 = 0x90;
+pub const BPF_XOR: u8 = 0xa0;
+pub const BPF_MOV: u8 = 0xb0;
+pub const BPF_ARSH: u8 = 0xc0;
+pub const BPF_END: u8 = 0xd0;
+
+// byte swap direction of BPF_END
+pub const BPF_TO_LE: u8 = 0x00;
+pub const BPF_TO_BE: u8 = 0x08;
+
+// jmp operations
+pub const BPF_JA: u8 = 0x00;
+pub const BPF_JEQ: u8 = 0x10;
+pub const BPF_JGT: u8 = 0x20;
+pub const BPF_JGE: u8 = 0x30;
+pub const BPF_JSET: u8 = 0x40;
+pub const BPF_JNE: u8 = 0x50;
+pub const BPF_JSGT: u8 = 0x60;
+pub const BPF_JSGE: u8 = 0x70;
+pub const BPF_CALL: u8 = 0x80;
+pub const BPF_EXIT: u8 = 0x90;
+pub const BPF_JLT: u8 = 0xa0;
+pub const BPF_JLE: u8 = 0xb0;
+pub const BPF_JSLT: u8 = 0xc0;
+pub const BPF_JSLE: u8 = 0xd0;
+
+// src_reg of a ld_imm64 referring to a map, kernel symbol or function
+pub const BPF_PSEUDO_MAP_FD: u8 = 1;
+pub const BPF_PSEUDO_MAP_VALUE: u8 = 2;
+pub const BPF_PSEUDO_BTF_ID: u8 = 3;
+pub const BPF_PSEUDO_FUNC: u8 = 4;
+pub const BPF_PSEUDO_MAP_IDX: u8 = 5;
+pub const BPF_PSEUDO_MAP_IDX_VALUE: u8 = 6;
+// src_reg of a call to a bpf-to-bpf function or a kernel function
+pub const BPF_PSEUDO_CALL: u8 = 1;
+pub const BPF_PSEUDO_KFUNC_CALL: u8 = 2;
+
+/// Size in bytes of one encoded instruction.
+pub const INSN_SIZE: usize = 8;
+
+/// One eBPF instruction, laid out like the kernel's `struct bpf_insn`.
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Insn {
+    pub code: u8,
+    /// dst_reg in the low nibble, src_reg in the high nibble
+    pub regs: u8,
+    pub off: i16,
+    pub imm: i32,
+}
+
+impl Insn {
+    pub fn new(code: u8, dst: u8, src: u8, off: i16, imm: i32) -> Insn {
+        Insn {
+            code,
+            regs: (src << 4) | (dst & 0x0f),
+            off,
+            imm,
+        }
+    }
+
+    pub fn dst(&self) -> u8 {
+        self.regs & 0x0f
+    }
+
+    pub fn src(&self) -> u8 {
+        self.regs >> 4
+    }
+
+    pub fn set_src(&mut self, src: u8) {
+        self.regs = (src << 4) | self.dst();
+    }
+
+    pub fn class(&self) -> u8 {
+        self.code & 0x07
+    }
+
+    /// The operation of an alu or jmp instruction.
+    pub fn op(&self) -> u8 {
+        self.code & 0xf0
+    }
+
+    /// `BPF_K` or `BPF_X` for alu and jmp instructions.
+    pub fn source(&self) -> u8 {
+        self.code & 0x08
+    }
+
+    /// The access size of a load or store.
+    pub fn size(&self) -> u8 {
+        self.code & 0x18
+    }
+
+    /// The addressing mode of a load or store.
+    pub fn mode(&self) -> u8 {
+        self.code & 0xe0
+    }
+
+    /// Whether this is the first half of a 16 byte `ld_imm64`.
+    pub fn is_ld_imm64(&self) -> bool {
+        self.code == BPF_LD | BPF_IMM | BPF_DW
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Insn {
+        Insn {
+            code: bytes[0],
+            regs: bytes[1],
+            off: i16::from_le_bytes(bytes[2..4].try_into().unwrap()),
+            imm: i32::from_le_bytes(bytes[4..8].try_into().unwrap()),
+        }
+    }
+
+    pub fn to_bytes(&self) -> [u8; INSN_SIZE] {
+        let mut bytes = [0u8; INSN_SIZE];
+        bytes[0] = self.code;
+        bytes[1] = self.regs;
+        bytes[2..4].copy_from_slice(&self.off.to_le_bytes());
+        bytes[4..8].copy_from_slice(&self.imm.to_le_bytes());
+        bytes
+    }
+}
+
+/// Decodes a little endian instruction stream, ignoring trailing bytes.
+pub fn parse_insns(bytes: &[u8]) -> Vec<Insn> {
+    bytes.chunks_exact(INSN_SIZE).map(Insn::from_bytes).collect()
+}
+
+pub fn insns_to_bytes(insns: &[Insn]) -> Vec<u8> {
+    insns.iter().flat_map(|i| i.to_bytes().to_vec()).collect()
+}
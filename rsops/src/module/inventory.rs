@@ -0,0 +1,282 @@
+//! System wide listing of loaded programs and maps, like `bpftool prog
+//! show` and `bpftool map show`.
+
+use crate::error::{Error, Result};
+use crate::module::map::{Map, MapDef, MapType};
+use crate::module::program::{obj_name_to_string, Program, ProgramType};
+use crate::sys::syscall::{self, BPF_MAP_GET_NEXT_ID, BPF_PROG_GET_NEXT_ID};
+use std::fmt::{self, Display};
+use std::fs;
+use std::mem;
+use std::os::unix::io::AsRawFd;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProgramEntry {
+    pub id: u32,
+    pub prog_type: Option<ProgramType>,
+    pub raw_type: u32,
+    pub name: String,
+    pub tag: [u8; 8],
+    pub gpl_compatible: bool,
+    /// wall clock load time in seconds since the epoch
+    pub load_time: u64,
+    pub uid: u32,
+    pub xlated_size: u32,
+    pub jited_size: u32,
+    pub memlock: Option<u64>,
+    pub map_ids: Vec<u32>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MapEntry {
+    pub id: u32,
+    pub map_type: Option<MapType>,
+    pub raw_type: u32,
+    pub name: String,
+    pub flags: u32,
+    pub key_size: u32,
+    pub value_size: u32,
+    pub max_entries: u32,
+    pub memlock: Option<u64>,
+}
+
+/// Walks the ids enumerated by `cmd`.
+fn ids(cmd: u32) -> Result<Vec<u32>> {
+    let mut ids = Vec::new();
+    let mut id = 0;
+    while let Some(next) =
+        syscall::obj_get_next_id(cmd, id).map_err(|e| Error::Syscall("BPF_*_GET_NEXT_ID", e))?
+    {
+        ids.push(next);
+        id = next;
+    }
+    Ok(ids)
+}
+
+/// Lists every program loaded in the system. Programs unloaded while the
+/// list is being built are skipped.
+pub fn programs() -> Result<Vec<ProgramEntry>> {
+    let mut entries = Vec::new();
+    for id in ids(BPF_PROG_GET_NEXT_ID)? {
+        match program_entry(id) {
+            Ok(entry) => entries.push(entry),
+            Err(Error::Syscall(_, e)) if e.raw_os_error() == Some(libc::ENOENT) => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(entries)
+}
+
+pub fn program_entry(id: u32) -> Result<ProgramEntry> {
+    let program = Program::from_id(id)?;
+    let info = program.info()?;
+    let mut map_ids = vec![0u32; info.nr_map_ids as usize];
+    if !map_ids.is_empty() {
+        let mut info = syscall::ProgInfo {
+            nr_map_ids: map_ids.len() as u32,
+            map_ids: map_ids.as_mut_ptr() as u64,
+            ..Default::default()
+        };
+        syscall::obj_get_info(program.as_raw_fd(), &mut info)
+            .map_err(|e| Error::Syscall("BPF_OBJ_GET_INFO_BY_FD", e))?;
+        map_ids.truncate(info.nr_map_ids as usize);
+    }
+    Ok(ProgramEntry {
+        id: info.id,
+        prog_type: ProgramType::from_u32(info.prog_type),
+        raw_type: info.prog_type,
+        name: obj_name_to_string(&info.name),
+        tag: info.tag,
+        gpl_compatible: info.flags & 1 == 1,
+        load_time: boot_time_to_epoch(info.load_time),
+        uid: info.created_by_uid,
+        xlated_size: info.xlated_prog_len,
+        jited_size: info.jited_prog_len,
+        memlock: fdinfo_memlock(program.as_raw_fd()),
+        map_ids,
+    })
+}
+
+/// Lists every map in the system.
+pub fn maps() -> Result<Vec<MapEntry>> {
+    let mut entries = Vec::new();
+    for id in ids(BPF_MAP_GET_NEXT_ID)? {
+        match map_entry(id) {
+            Ok(entry) => entries.push(entry),
+            Err(Error::Syscall(_, e)) if e.raw_os_error() == Some(libc::ENOENT) => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(entries)
+}
+
+pub fn map_entry(id: u32) -> Result<MapEntry> {
+    let fd = syscall::obj_get_fd_by_id(syscall::BPF_MAP_GET_FD_BY_ID, id)
+        .map_err(|e| Error::Syscall("BPF_MAP_GET_FD_BY_ID", e))?;
+    // read the info through a raw Map so unknown map types still list
+    let def = MapDef {
+        map_type: MapType::Unspec,
+        key_size: 0,
+        value_size: 0,
+        max_entries: 0,
+        map_flags: 0,
+    };
+    let map = Map::from_raw_fd("", def, fd);
+    let info = map.info()?;
+    Ok(MapEntry {
+        id: info.id,
+        map_type: MapType::from_u32(info.map_type),
+        raw_type: info.map_type,
+        name: obj_name_to_string(&info.name),
+        flags: info.map_flags,
+        key_size: info.key_size,
+        value_size: info.value_size,
+        max_entries: info.max_entries,
+        memlock: fdinfo_memlock(map.as_raw_fd()),
+    })
+}
+
+/// Reads the `memlock:` line the kernel reports for bpf fds.
+fn fdinfo_memlock(fd: i32) -> Option<u64> {
+    let fdinfo = fs::read_to_string(format!("/proc/self/fdinfo/{}", fd)).ok()?;
+    fdinfo
+        .lines()
+        .find(|l| l.starts_with("memlock:"))
+        .and_then(|l| l["memlock:".len()..].trim().parse().ok())
+}
+
+fn clock_ns(clock: libc::clockid_t) -> u64 {
+    let mut ts: libc::timespec = unsafe { mem::zeroed() };
+    unsafe { libc::clock_gettime(clock, &mut ts) };
+    ts.tv_sec as u64 * 1_000_000_000 + ts.tv_nsec as u64
+}
+
+/// Converts a `CLOCK_BOOTTIME` timestamp in ns to seconds since the epoch.
+fn boot_time_to_epoch(ns: u64) -> u64 {
+    let realtime = clock_ns(libc::CLOCK_REALTIME);
+    let boottime = clock_ns(libc::CLOCK_BOOTTIME);
+    (realtime.saturating_sub(boottime) + ns) / 1_000_000_000
+}
+
+/// Formats seconds since the epoch as an ISO 8601 UTC timestamp.
+pub fn format_time(secs: u64) -> String {
+    let days = (secs / 86400) as i64;
+    let rem = secs % 86400;
+    // civil_from_days, Howard Hinnant's date algorithms
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        rem / 3600,
+        rem % 3600 / 60,
+        rem % 60
+    )
+}
+
+fn write_memlock(f: &mut fmt::Formatter<'_>, memlock: Option<u64>) -> fmt::Result {
+    match memlock {
+        Some(bytes) => write!(f, "  memlock {}B", bytes),
+        None => Ok(()),
+    }
+}
+
+impl Display for ProgramEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.prog_type {
+            Some(t) => write!(f, "{}: {}  ", self.id, t.name())?,
+            None => write!(f, "{}: type {}  ", self.id, self.raw_type)?,
+        }
+        if !self.name.is_empty() {
+            write!(f, "name {}  ", self.name)?;
+        }
+        write!(f, "tag ")?;
+        for b in &self.tag {
+            write!(f, "{:02x}", b)?;
+        }
+        if self.gpl_compatible {
+            write!(f, "  gpl")?;
+        }
+        write!(
+            f,
+            "\n\tloaded_at {}  uid {}\n\txlated {}B",
+            format_time(self.load_time),
+            self.uid,
+            self.xlated_size
+        )?;
+        if self.jited_size > 0 {
+            write!(f, "  jited {}B", self.jited_size)?;
+        } else {
+            write!(f, "  not jited")?;
+        }
+        write_memlock(f, self.memlock)?;
+        if !self.map_ids.is_empty() {
+            let ids: Vec<_> = self.map_ids.iter().map(u32::to_string).collect();
+            write!(f, "  map_ids {}", ids.join(","))?;
+        }
+        Ok(())
+    }
+}
+
+impl Display for MapEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.map_type {
+            Some(t) => write!(f, "{}: {}  ", self.id, t.name())?,
+            None => write!(f, "{}: type {}  ", self.id, self.raw_type)?,
+        }
+        if !self.name.is_empty() {
+            write!(f, "name {}  ", self.name)?;
+        }
+        write!(
+            f,
+            "flags {:#x}\n\tkey {}B  value {}B  max_entries {}",
+            self.flags, self.key_size, self.value_size, self.max_entries
+        )?;
+        write_memlock(f, self.memlock)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_format_time() {
+        assert_eq!(format_time(0), "1970-01-01T00:00:00Z");
+        assert_eq!(format_time(951_782_400), "2000-02-29T00:00:00Z");
+        assert_eq!(format_time(1_616_235_072), "2021-03-20T10:11:12Z");
+    }
+
+    #[test]
+    fn test_display_program() {
+        let entry = ProgramEntry {
+            id: 42,
+            prog_type: Some(ProgramType::Xdp),
+            raw_type: 6,
+            name: "xdp_prog".to_string(),
+            tag: [0x3b, 0x18, 0x51, 0x87, 0xf1, 0x85, 0x5c, 0x4c],
+            gpl_compatible: true,
+            load_time: 0,
+            uid: 0,
+            xlated_size: 16,
+            jited_size: 18,
+            memlock: Some(4096),
+            map_ids: vec![4, 5],
+        };
+        assert_eq!(
+            entry.to_string(),
+            "42: xdp  name xdp_prog  tag 3b185187f1855c4c  gpl\n\
+             \tloaded_at 1970-01-01T00:00:00Z  uid 0\n\
+             \txlated 16B  jited 18B  memlock 4096B  map_ids 4,5"
+        );
+    }
+}
@@ -0,0 +1,273 @@
+//! Attaching loaded programs to kprobes and tracepoints.
+//!
+//! Every attachment is a [`Link`] holding the perf event or raw tracepoint
+//! file descriptor; the program is detached once it is dropped, unless
+//! the link was pinned with [`Link::pin`].
+//!
+//! ```no_run
+//! use rsops::module::link;
+//! use rsops::module::object::ObjectFile;
+//!
+//! let object = ObjectFile::open("prog.o")?.load()?;
+//! let program = object.program("on_sleep").unwrap();
+//! let _link = link::attach_kprobe(program, "do_nanosleep", false)?;
+//! # Ok::<(), rsops::Error>(())
+//! ```
+
+use crate::error::{Error, Result};
+use crate::module::pin;
+use crate::module::program::Program;
+use crate::sys::perf::{self, PerfEventAttr, PERF_TYPE_TRACEPOINT};
+use crate::sys::syscall;
+use std::ffi::CString;
+use std::fs;
+use std::io;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::path::Path;
+
+const KPROBE_PMU: &str = "/sys/bus/event_source/devices/kprobe";
+
+const TRACEFS_ROOTS: [&str; 2] = ["/sys/kernel/tracing", "/sys/kernel/debug/tracing"];
+
+/// An attached program. Dropping it closes the file descriptor, which
+/// detaches the program.
+#[derive(Debug)]
+pub struct Link {
+    fd: RawFd,
+    /// whether `fd` is a BPF link, rather than a perf event, and so can be
+    /// pinned
+    bpf_link: bool,
+}
+
+impl Link {
+    /// Attaches `program` to the perf event opened for `attr`, through a
+    /// BPF link where the kernel supports it (Linux 5.15) and the
+    /// `PERF_EVENT_IOC_SET_BPF` ioctl otherwise.
+    fn from_perf_event(attr: &mut PerfEventAttr, program: &Program) -> Result<Link> {
+        let event = Link {
+            fd: perf::perf_event_open(attr).map_err(|e| Error::Syscall("perf_event_open", e))?,
+            bpf_link: false,
+        };
+        match syscall::link_create(program.as_raw_fd(), event.fd, syscall::BPF_PERF_EVENT) {
+            Ok(fd) => {
+                // the link keeps the event alive once `event` is closed
+                let link = Link { fd, bpf_link: true };
+                perf::enable(event.fd).map_err(|e| Error::Syscall("PERF_EVENT_IOC_ENABLE", e))?;
+                Ok(link)
+            }
+            Err(e) if e.raw_os_error() == Some(libc::EINVAL) => {
+                perf::set_bpf(event.fd, program.as_raw_fd())
+                    .map_err(|e| Error::Syscall("PERF_EVENT_IOC_SET_BPF", e))?;
+                Ok(event)
+            }
+            Err(e) => Err(Error::Syscall("BPF_LINK_CREATE", e)),
+        }
+    }
+
+    /// Opens the link pinned at `path`.
+    pub fn from_pinned(path: &Path) -> Result<Link> {
+        Ok(Link {
+            fd: pin::get_pinned(path)?,
+            bpf_link: true,
+        })
+    }
+
+    /// Pins the link at `path`, which keeps the program attached after the
+    /// link is dropped, until the pin is removed. Attachments made with
+    /// the perf event ioctl on kernels before 5.15 can't be pinned.
+    pub fn pin(&self, path: &Path) -> Result<()> {
+        if !self.bpf_link {
+            return Err(Error::Invalid(
+                "perf event attachments can only be pinned as BPF links (Linux 5.15)".to_string(),
+            ));
+        }
+        pin::pin_fd(self.fd, path)
+    }
+}
+
+impl AsRawFd for Link {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd
+    }
+}
+
+impl Drop for Link {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.fd);
+        }
+    }
+}
+
+fn read_sysfs(path: &Path) -> Result<String> {
+    match fs::read_to_string(path) {
+        Ok(s) => Ok(s.trim().to_string()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            Err(Error::NotFound(path.display().to_string()))
+        }
+        Err(e) => Err(Error::Io(e)),
+    }
+}
+
+fn parse_sysfs<T: std::str::FromStr>(path: &Path, s: &str) -> Result<T> {
+    s.parse()
+        .map_err(|_| Error::Invalid(format!("{}: unexpected {:?}", path.display(), s)))
+}
+
+/// Bit of `config` selecting a kretprobe, from a PMU format such as
+/// `config:0`.
+fn parse_config_bit(format: &str) -> Option<u32> {
+    format.strip_prefix("config:")?.parse().ok()
+}
+
+/// Attaches `program` to the entry of kernel function `func`, or to its
+/// return if `retprobe` is set, through the kprobe PMU (Linux 4.17).
+pub fn attach_kprobe(program: &Program, func: &str, retprobe: bool) -> Result<Link> {
+    let pmu = Path::new(KPROBE_PMU);
+    let type_path = pmu.join("type");
+    let mut attr = PerfEventAttr {
+        type_: parse_sysfs(&type_path, &read_sysfs(&type_path)?)?,
+        ..Default::default()
+    };
+    if retprobe {
+        let format_path = pmu.join("format/retprobe");
+        let format = read_sysfs(&format_path)?;
+        let bit = parse_config_bit(&format).ok_or_else(|| {
+            Error::Invalid(format!(
+                "{}: unexpected {:?}",
+                format_path.display(),
+                format
+            ))
+        })?;
+        attr.config |= 1 << bit;
+    }
+    let func = CString::new(func)
+        .map_err(|_| Error::Invalid(format!("invalid kprobe function {:?}", func)))?;
+    attr.config1 = func.as_ptr() as u64;
+    Link::from_perf_event(&mut attr, program)
+}
+
+/// Attaches `program` to tracepoint `category:name`, e.g.
+/// `syscalls:sys_enter_nanosleep`.
+pub fn attach_tracepoint(program: &Program, category: &str, name: &str) -> Result<Link> {
+    let relative = Path::new("events").join(category).join(name).join("id");
+    let path = TRACEFS_ROOTS
+        .iter()
+        .map(|root| Path::new(root).join(&relative))
+        .find(|path| path.exists())
+        .ok_or_else(|| Error::NotFound(format!("tracepoint {}:{}", category, name)))?;
+    let mut attr = PerfEventAttr {
+        type_: PERF_TYPE_TRACEPOINT,
+        config: parse_sysfs(&path, &read_sysfs(&path)?)?,
+        sample_period: 1,
+        wakeup_events: 1,
+        ..Default::default()
+    };
+    Link::from_perf_event(&mut attr, program)
+}
+
+/// Attaches `program` to raw tracepoint `name`, e.g. `sched_switch`.
+pub fn attach_raw_tracepoint(program: &Program, name: &str) -> Result<Link> {
+    let c_name = CString::new(name)
+        .map_err(|_| Error::Invalid(format!("invalid raw tracepoint {:?}", name)))?;
+    let fd = syscall::raw_tracepoint_open(&c_name, program.as_raw_fd())
+        .map_err(|e| Error::Syscall("BPF_RAW_TRACEPOINT_OPEN", e))?;
+    Ok(Link { fd, bpf_link: true })
+}
+
+/// Where a program should be attached, from its ELF section name.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AttachPoint<'a> {
+    Kprobe { func: &'a str, retprobe: bool },
+    Tracepoint { category: &'a str, name: &'a str },
+    RawTracepoint(&'a str),
+}
+
+impl<'a> AttachPoint<'a> {
+    /// Decodes sections such as `kprobe/do_nanosleep`,
+    /// `kretprobe/do_nanosleep`, `tracepoint/syscalls/sys_enter_read` (or
+    /// `tp/...`) and `raw_tracepoint/sched_switch` (or `raw_tp/...`). Other
+    /// sections, e.g. `xdp`, have to be attached by the caller.
+    pub fn from_section(section: &'a str) -> Option<AttachPoint<'a>> {
+        let (kind, target) = section.split_once('/')?;
+        if target.is_empty() {
+            return None;
+        }
+        match kind {
+            "kprobe" | "kretprobe" => Some(AttachPoint::Kprobe {
+                func: target,
+                retprobe: kind == "kretprobe",
+            }),
+            "tracepoint" | "tp" => {
+                let (category, name) = target.split_once('/')?;
+                if category.is_empty() || name.is_empty() || name.contains('/') {
+                    return None;
+                }
+                Some(AttachPoint::Tracepoint { category, name })
+            }
+            "raw_tracepoint" | "raw_tp" if !target.contains('/') => {
+                Some(AttachPoint::RawTracepoint(target))
+            }
+            _ => None,
+        }
+    }
+
+    pub fn attach(&self, program: &Program) -> Result<Link> {
+        match *self {
+            AttachPoint::Kprobe { func, retprobe } => attach_kprobe(program, func, retprobe),
+            AttachPoint::Tracepoint { category, name } => {
+                attach_tracepoint(program, category, name)
+            }
+            AttachPoint::RawTracepoint(name) => attach_raw_tracepoint(program, name),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_attach_point() {
+        assert_eq!(
+            AttachPoint::from_section("kprobe/do_nanosleep"),
+            Some(AttachPoint::Kprobe {
+                func: "do_nanosleep",
+                retprobe: false
+            })
+        );
+        assert_eq!(
+            AttachPoint::from_section("kretprobe/do_nanosleep"),
+            Some(AttachPoint::Kprobe {
+                func: "do_nanosleep",
+                retprobe: true
+            })
+        );
+        let tracepoint = Some(AttachPoint::Tracepoint {
+            category: "syscalls",
+            name: "sys_enter_read",
+        });
+        assert_eq!(
+            AttachPoint::from_section("tracepoint/syscalls/sys_enter_read"),
+            tracepoint
+        );
+        assert_eq!(
+            AttachPoint::from_section("tp/syscalls/sys_enter_read"),
+            tracepoint
+        );
+        assert_eq!(
+            AttachPoint::from_section("raw_tp/sched_switch"),
+            Some(AttachPoint::RawTracepoint("sched_switch"))
+        );
+        assert_eq!(AttachPoint::from_section("tracepoint/syscalls"), None);
+        assert_eq!(
+            AttachPoint::from_section("tp/syscalls/sys_enter_read/x"),
+            None
+        );
+        assert_eq!(AttachPoint::from_section("kprobe/"), None);
+        assert_eq!(AttachPoint::from_section("xdp"), None);
+        assert_eq!(AttachPoint::from_section("socket/filter"), None);
+        assert_eq!(parse_config_bit("config:0"), Some(0));
+        assert_eq!(parse_config_bit("config1:0-63"), None);
+    }
+}
@@ -0,0 +1,314 @@
+use crate::error::{Error, Result};
+use crate::module::pin;
+use crate::module::program::obj_name_to_string;
+use crate::sys::syscall;
+use std::mem;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::path::Path;
+use std::ptr;
+use std::slice;
+
+#[repr(u32)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum MapType {
+    Unspec = 0,
+    Hash,
+    Array,
+    ProgArray,
+    PerfEventArray,
+    PercpuHash,
+    PercpuArray,
+    StackTrace,
+    CgroupArray,
+    LruHash,
+    LruPercpuHash,
+    LpmTrie,
+    ArrayOfMaps,
+    HashOfMaps,
+    Devmap,
+    Sockmap,
+    Cpumap,
+    Xskmap,
+    Sockhash,
+    CgroupStorage,
+    ReuseportSockarray,
+    PercpuCgroupStorage,
+    Queue,
+    Stack,
+    SkStorage,
+    DevmapHash,
+    StructOps,
+    Ringbuf,
+    InodeStorage,
+    TaskStorage,
+    BloomFilter,
+}
+
+const MAP_TYPES: [(MapType, &str); 31] = [
+    (MapType::Unspec, "unspec"),
+    (MapType::Hash, "hash"),
+    (MapType::Array, "array"),
+    (MapType::ProgArray, "prog_array"),
+    (MapType::PerfEventArray, "perf_event_array"),
+    (MapType::PercpuHash, "percpu_hash"),
+    (MapType::PercpuArray, "percpu_array"),
+    (MapType::StackTrace, "stack_trace"),
+    (MapType::CgroupArray, "cgroup_array"),
+    (MapType::LruHash, "lru_hash"),
+    (MapType::LruPercpuHash, "lru_percpu_hash"),
+    (MapType::LpmTrie, "lpm_trie"),
+    (MapType::ArrayOfMaps, "array_of_maps"),
+    (MapType::HashOfMaps, "hash_of_maps"),
+    (MapType::Devmap, "devmap"),
+    (MapType::Sockmap, "sockmap"),
+    (MapType::Cpumap, "cpumap"),
+    (MapType::Xskmap, "xskmap"),
+    (MapType::Sockhash, "sockhash"),
+    (MapType::CgroupStorage, "cgroup_storage"),
+    (MapType::ReuseportSockarray, "reuseport_sockarray"),
+    (MapType::PercpuCgroupStorage, "percpu_cgroup_storage"),
+    (MapType::Queue, "queue"),
+    (MapType::Stack, "stack"),
+    (MapType::SkStorage, "sk_storage"),
+    (MapType::DevmapHash, "devmap_hash"),
+    (MapType::StructOps, "struct_ops"),
+    (MapType::Ringbuf, "ringbuf"),
+    (MapType::InodeStorage, "inode_storage"),
+    (MapType::TaskStorage, "task_storage"),
+    (MapType::BloomFilter, "bloom_filter"),
+];
+
+impl MapType {
+    pub fn from_u32(value: u32) -> Option<MapType> {
+        MAP_TYPES.get(value as usize).map(|(t, _)| *t)
+    }
+
+    pub fn name(self) -> &'static str {
+        MAP_TYPES[self as usize].1
+    }
+}
+
+/// Plain old data that can be copied to and from map keys and values
+/// byte by byte.
+///
+/// # Safety
+///
+/// Implementors must be `#[repr(C)]` (or primitive) types without padding
+/// bytes that are valid for any bit pattern.
+pub unsafe trait Pod: Copy + 'static {}
+
+macro_rules! impl_pod {
+    ($($t:ty),*) => { $(unsafe impl Pod for $t {})* };
+}
+impl_pod!(u8, u16, u32, u64, u128, i8, i16, i32, i64, i128);
+unsafe impl<T: Pod, const N: usize> Pod for [T; N] {}
+
+pub fn pod_bytes<T: Pod>(value: &T) -> &[u8] {
+    unsafe { slice::from_raw_parts(value as *const T as *const u8, mem::size_of::<T>()) }
+}
+
+/// Reads a `T` from the start of `bytes`, which must be at least
+/// `size_of::<T>()` long.
+pub fn pod_read<T: Pod>(bytes: &[u8]) -> T {
+    assert!(bytes.len() >= mem::size_of::<T>());
+    unsafe { ptr::read_unaligned(bytes.as_ptr() as *const T) }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MapDef {
+    pub map_type: MapType,
+    pub key_size: u32,
+    pub value_size: u32,
+    pub max_entries: u32,
+    pub map_flags: u32,
+}
+
+/// A BPF map owned by this process. The file descriptor is closed on drop.
+#[derive(Debug)]
+pub struct Map {
+    pub name: String,
+    pub def: MapDef,
+    fd: RawFd,
+}
+
+impl Map {
+    pub fn create(name: &str, def: &MapDef) -> Result<Map> {
+        Map::create_with_inner(name, def, None)
+    }
+
+    /// Creates a map, passing `inner_map_fd` as the template of the inner
+    /// maps of an array or hash of maps.
+    pub fn create_with_inner(
+        name: &str,
+        def: &MapDef,
+        inner_map_fd: Option<RawFd>,
+    ) -> Result<Map> {
+        let mut attr = syscall::MapCreateAttr {
+            map_type: def.map_type as u32,
+            key_size: def.key_size,
+            value_size: def.value_size,
+            max_entries: def.max_entries,
+            map_flags: def.map_flags,
+            inner_map_fd: inner_map_fd.unwrap_or(0) as u32,
+            map_name: syscall::obj_name(name),
+            ..Default::default()
+        };
+        let fd = syscall::map_create(&mut attr)
+            .map_err(|e| Error::Syscall("BPF_MAP_CREATE", e))?;
+        Ok(Map::from_raw_fd(name, *def, fd))
+    }
+
+    /// Takes ownership of an already open map file descriptor.
+    pub fn from_raw_fd(name: &str, def: MapDef, fd: RawFd) -> Map {
+        Map {
+            name: name.to_string(),
+            def,
+            fd,
+        }
+    }
+
+    /// Opens the map with kernel id `id`.
+    pub fn from_id(id: u32) -> Result<Map> {
+        let fd = syscall::obj_get_fd_by_id(syscall::BPF_MAP_GET_FD_BY_ID, id)
+            .map_err(|e| Error::Syscall("BPF_MAP_GET_FD_BY_ID", e))?;
+        Map::from_fd_info(fd)
+    }
+
+    /// Opens the map pinned at `path`, reading its definition back from
+    /// the kernel.
+    pub fn from_pinned(path: &Path) -> Result<Map> {
+        Map::from_fd_info(pin::get_pinned(path)?)
+    }
+
+    fn from_fd_info(fd: RawFd) -> Result<Map> {
+        let mut map = Map::from_raw_fd(
+            "",
+            MapDef {
+                map_type: MapType::Unspec,
+                key_size: 0,
+                value_size: 0,
+                max_entries: 0,
+                map_flags: 0,
+            },
+            fd,
+        );
+        let info = map.info()?;
+        map.name = obj_name_to_string(&info.name);
+        map.def = MapDef {
+            map_type: MapType::from_u32(info.map_type).ok_or_else(|| {
+                Error::Invalid(format!("map {} has unknown type {}", map.name, info.map_type))
+            })?,
+            key_size: info.key_size,
+            value_size: info.value_size,
+            max_entries: info.max_entries,
+            map_flags: info.map_flags,
+        };
+        Ok(map)
+    }
+
+    pub fn info(&self) -> Result<syscall::MapInfo> {
+        let mut info = syscall::MapInfo::default();
+        syscall::obj_get_info(self.fd, &mut info)
+            .map_err(|e| Error::Syscall("BPF_OBJ_GET_INFO_BY_FD", e))?;
+        Ok(info)
+    }
+
+    pub fn pin(&self, path: &Path) -> Result<()> {
+        pin::pin_fd(self.fd, path)
+    }
+
+    pub fn lookup(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.check_key(key)?;
+        let mut value = vec![0u8; self.def.value_size as usize];
+        match syscall::map_lookup_elem(self.fd, Some(key), &mut value) {
+            Ok(()) => Ok(Some(value)),
+            Err(e) if e.raw_os_error() == Some(libc::ENOENT) => Ok(None),
+            Err(e) => Err(Error::Syscall("BPF_MAP_LOOKUP_ELEM", e)),
+        }
+    }
+
+    pub fn update(&self, key: &[u8], value: &[u8], flags: u64) -> Result<()> {
+        self.check_key(key)?;
+        if value.len() != self.def.value_size as usize {
+            return Err(Error::Invalid(format!(
+                "map {}: value is {} bytes, expected {}",
+                self.name,
+                value.len(),
+                self.def.value_size
+            )));
+        }
+        syscall::map_update_elem(self.fd, Some(key), value, flags)
+            .map_err(|e| Error::Syscall("BPF_MAP_UPDATE_ELEM", e))
+    }
+
+    /// Removes `key`, returning whether it was present.
+    pub fn delete(&self, key: &[u8]) -> Result<bool> {
+        self.check_key(key)?;
+        match syscall::map_delete_elem(self.fd, key) {
+            Ok(()) => Ok(true),
+            Err(e) if e.raw_os_error() == Some(libc::ENOENT) => Ok(false),
+            Err(e) => Err(Error::Syscall("BPF_MAP_DELETE_ELEM", e)),
+        }
+    }
+
+    pub fn keys(&self) -> MapKeys<'_> {
+        MapKeys {
+            map: self,
+            key: None,
+        }
+    }
+
+    /// Makes the map read-only for user space; programs may still write
+    /// it unless it was created with `BPF_F_RDONLY_PROG`.
+    pub fn freeze(&self) -> Result<()> {
+        syscall::map_freeze(self.fd).map_err(|e| Error::Syscall("BPF_MAP_FREEZE", e))
+    }
+
+    fn check_key(&self, key: &[u8]) -> Result<()> {
+        if key.len() != self.def.key_size as usize {
+            return Err(Error::Invalid(format!(
+                "map {}: key is {} bytes, expected {}",
+                self.name,
+                key.len(),
+                self.def.key_size
+            )));
+        }
+        Ok(())
+    }
+}
+
+impl AsRawFd for Map {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd
+    }
+}
+
+impl Drop for Map {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.fd);
+        }
+    }
+}
+
+/// Iterator over the keys of a map, driven by `BPF_MAP_GET_NEXT_KEY`.
+pub struct MapKeys<'a> {
+    map: &'a Map,
+    key: Option<Vec<u8>>,
+}
+
+impl Iterator for MapKeys<'_> {
+    type Item = Result<Vec<u8>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut next = vec![0u8; self.map.def.key_size as usize];
+        match syscall::map_get_next_key(self.map.fd, self.key.as_deref(), &mut next) {
+            Ok(true) => {
+                self.key = Some(next.clone());
+                Some(Ok(next))
+            }
+            Ok(false) => None,
+            Err(e) => Some(Err(Error::Syscall("BPF_MAP_GET_NEXT_KEY", e))),
+        }
+    }
+}
@@ -0,0 +1,84 @@
+use crate::error::{Error, Result};
+use crate::module::map::{pod_bytes, pod_read, Map, MapType, Pod};
+use crate::sys::syscall::BPF_ANY;
+use std::marker::PhantomData;
+use std::mem;
+use std::os::unix::io::AsRawFd;
+
+/// View of a `BPF_MAP_TYPE_ARRAY_OF_MAPS` or `BPF_MAP_TYPE_HASH_OF_MAPS`
+/// keyed by `K`.
+///
+/// Storing a map replaces the previous one in a single update, so programs
+/// see either the old or the new inner map, never a missing one.
+pub struct MapOfMaps<'a, K: Pod> {
+    map: &'a Map,
+    _key: PhantomData<K>,
+}
+
+impl<'a, K: Pod> MapOfMaps<'a, K> {
+    pub fn new(map: &'a Map) -> Result<MapOfMaps<'a, K>> {
+        match map.def.map_type {
+            MapType::ArrayOfMaps | MapType::HashOfMaps => {}
+            other => {
+                return Err(Error::Invalid(format!(
+                    "map {} is a {} map, not a map of maps",
+                    map.name,
+                    other.name()
+                )))
+            }
+        }
+        if map.def.key_size as usize != mem::size_of::<K>() {
+            return Err(Error::Invalid(format!(
+                "map {}: key is {} bytes, expected {}",
+                map.name,
+                map.def.key_size,
+                mem::size_of::<K>()
+            )));
+        }
+        Ok(MapOfMaps {
+            map,
+            _key: PhantomData,
+        })
+    }
+
+    /// Stores `inner` at `key`. The kernel rejects maps that don't match
+    /// the outer map's inner template.
+    pub fn set(&self, key: &K, inner: &Map) -> Result<()> {
+        let fd = inner.as_raw_fd() as u32;
+        self.map.update(pod_bytes(key), &fd.to_ne_bytes(), BPF_ANY)
+    }
+
+    pub fn remove(&self, key: &K) -> Result<bool> {
+        self.map.delete(pod_bytes(key))
+    }
+
+    /// Returns the id of the inner map stored at `key`.
+    pub fn inner_id(&self, key: &K) -> Result<Option<u32>> {
+        Ok(self.map.lookup(pod_bytes(key))?.map(|v| pod_read(&v)))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::module::map::MapDef;
+
+    fn map(map_type: MapType, key_size: u32) -> Map {
+        let def = MapDef {
+            map_type,
+            key_size,
+            value_size: 4,
+            max_entries: 4,
+            map_flags: 0,
+        };
+        Map::from_raw_fd("test", def, -1)
+    }
+
+    #[test]
+    fn test_new() {
+        assert!(MapOfMaps::<u32>::new(&map(MapType::ArrayOfMaps, 4)).is_ok());
+        assert!(MapOfMaps::<u64>::new(&map(MapType::HashOfMaps, 8)).is_ok());
+        assert!(MapOfMaps::<u32>::new(&map(MapType::Array, 4)).is_err());
+        assert!(MapOfMaps::<u64>::new(&map(MapType::ArrayOfMaps, 4)).is_err());
+    }
+}
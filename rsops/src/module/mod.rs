@@ -1 +1,29 @@
-pub mod bpf;
\ No newline at end of file
+pub mod asm;
+pub mod bpf;
+pub mod btf;
+pub mod btf_dump;
+pub mod btf_ext;
+pub mod btf_rust;
+pub mod cfg;
+pub mod compat;
+pub mod core_reloc;
+pub mod disasm;
+pub mod dump;
+pub mod features;
+pub mod helpers;
+pub mod insn;
+pub mod link;
+pub mod inventory;
+pub mod map;
+pub mod map_of_maps;
+pub mod object;
+pub mod pin;
+pub mod prog_array;
+pub mod program;
+pub mod queue;
+pub mod skeleton;
+pub mod stack;
+pub mod symbols;
+pub mod tag;
+pub mod verifier;
+pub mod vm;
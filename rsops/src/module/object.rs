@@ -0,0 +1,1706 @@
+//! Parsing of compiled BPF ELF objects and loading them into the kernel.
+//!
+//! Maps are read from the legacy `maps` section (an array of
+//! `struct bpf_map_def`) and from BTF defined maps in `.maps`, programs
+//! from every executable section whose name implies a program type, see
+//! [`ProgramType::from_section`]. A section may hold several programs, one
+//! per global function symbol. Static functions and the functions in
+//! `.text` are subprograms: each program gets a copy of those it reaches
+//! through bpf-to-bpf calls appended to its instructions.
+//!
+//! Maps of maps need an inner map template: the map whose `id` matches the
+//! `inner_id` of an iproute2 style legacy definition, the
+//! `__array(values, struct { ... })` declaration of a BTF map, or one set
+//! with [`ObjectFile::set_inner_map`]. BTF maps may also be initialized
+//! with `.values = { [0] = &inner }`, which fills the outer map after load.
+//!
+//! Maps with a [`Pinning::ByName`] policy are pinned below the object's
+//! pin root, `/sys/fs/bpf` unless configured otherwise. A map already
+//! pinned there is reused when its definition matches, and for a map of
+//! maps when the inner maps it holds match its template.
+//!
+//! Programs compiled with CO-RE relocations, recorded in `.BTF.ext`, are
+//! relocated against the running kernel's BTF when loaded, or against the
+//! one given to [`ObjectFile::set_core_target`].
+//!
+//! Global variables live in the `.data`, `.rodata` and `.bss` sections,
+//! each backed by a single element array map of the same name that holds
+//! the section's initial contents, see [`ObjectFile::set_data`] and
+//! [`Object::data`]. `.rodata` is frozen after load and read-only for
+//! programs, which lets the verifier treat its values as constants.
+//!
+//! Programs that should be reachable through `bpf_tail_call` can declare
+//! their slot in the section name as `<type>/tail/<prog_array>/<slot>`,
+//! e.g. `xdp/tail/jmp_table/1`, or be registered with
+//! [`ObjectFile::set_tail_call`] before loading.
+
+use crate::error::{Error, Result};
+use crate::module::btf::{Btf, BtfKind};
+use crate::module::btf_ext::{BtfExt, CoreReloc, SourceLine};
+use crate::module::cfg::{self, Cfg, Limits, Problem};
+use crate::module::core_reloc;
+use crate::module::disasm;
+use crate::module::insn::{
+    parse_insns, Insn, BPF_CALL, BPF_JMP, BPF_PSEUDO_CALL, BPF_PSEUDO_MAP_FD, BPF_PSEUDO_MAP_VALUE,
+    INSN_SIZE,
+};
+use crate::module::link::Link;
+use crate::module::map::{pod_bytes, pod_read, Map, MapDef, MapType, Pod};
+use crate::module::map_of_maps::MapOfMaps;
+use crate::module::pin::{self, Pinning, DEFAULT_PIN_ROOT};
+use crate::module::prog_array::ProgArray;
+use crate::module::program::{Program, ProgramType};
+use crate::module::tag;
+use crate::module::verifier::VerifierLog;
+use crate::sys::syscall::{BPF_ANY, BPF_F_RDONLY_PROG, BPF_TAG_SIZE};
+use goblin::elf::{section_header, sym, Elf};
+use std::borrow::Cow;
+use std::convert::TryInto;
+use std::fs;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Clone)]
+pub struct MapSpec {
+    pub name: String,
+    pub def: MapDef,
+    pub pinning: Pinning,
+    /// definition of the inner maps of an array or hash of maps
+    pub inner: Option<MapDef>,
+    /// maps stored in the outer map right after loading, by slot
+    pub values: Vec<(u32, String)>,
+}
+
+/// A reference from the `ld_imm64` at `insn` to the map called `map`.
+/// References to a global variable already have the
+/// `BPF_PSEUDO_MAP_VALUE` source register and the variable's offset in
+/// the second half of the instruction.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MapReloc {
+    pub insn: usize,
+    pub map: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct ProgramSpec {
+    pub name: String,
+    pub section: String,
+    /// byte offset of the first instruction within `section`
+    pub offset: usize,
+    pub prog_type: ProgramType,
+    pub insns: Vec<Insn>,
+    pub map_relocs: Vec<MapReloc>,
+    /// source lines from `.BTF.ext`, `insn_off` as an instruction index
+    /// within this program
+    pub line_info: Vec<SourceLine>,
+    /// CO-RE relocations from `.BTF.ext`, `insn_off` as an instruction
+    /// index within this program
+    pub core_relocs: Vec<CoreReloc>,
+    /// problems [`cfg::check`] found in the linked program against the
+    /// limits of the newest kernels, see [`ProgramSpec::check`] for those
+    /// of a given one
+    pub problems: Vec<Problem>,
+}
+
+impl ProgramSpec {
+    /// Runs [`cfg::check`] against the limits of another kernel.
+    pub fn check(&self, limits: &Limits) -> Vec<Problem> {
+        cfg::check(&self.insns, limits)
+    }
+
+    /// The control-flow graph as Graphviz DOT, see [`Cfg::to_dot`].
+    pub fn to_dot(&self) -> String {
+        Cfg::build(&self.insns).to_dot(&self.name, &self.insns, &self.map_relocs)
+    }
+
+    pub fn disassemble(&self) -> String {
+        disasm::disassemble(&self.insns, &self.map_relocs)
+    }
+
+    /// The source line instruction `idx` was generated from.
+    pub fn source_line(&self, idx: usize) -> Option<&SourceLine> {
+        self.line_info
+            .iter()
+            .take_while(|l| l.insn_off as usize <= idx)
+            .last()
+    }
+
+    /// Explains why the verifier rejected this program, given the `log`
+    /// of an [`Error::Load`]: the error, the C source line and the
+    /// instructions around the failing one, and a hint for common
+    /// failures.
+    pub fn verifier_report(&self, log: &str) -> String {
+        let log = VerifierLog::parse(log);
+        let mut report = format!("error: {}\n", log);
+        let idx = match log.failing_insn() {
+            Some(idx) => idx,
+            None => return report,
+        };
+        if let Some(line) = self.source_line(idx) {
+            report.push_str(&format!(
+                "  --> {}:{}:{}\n   | {}\n",
+                line.file, line.line, line.col, line.source
+            ));
+        } else if let Some(source) = log.failing_record().and_then(|r| r.source.as_ref()) {
+            report.push_str(&format!("  --> {}\n", source));
+        }
+        let range = idx.saturating_sub(VERIFIER_REPORT_CONTEXT)..idx + VERIFIER_REPORT_CONTEXT + 1;
+        report.push_str(&disasm::disassemble_range(
+            &self.insns,
+            &self.map_relocs,
+            range,
+            Some(idx),
+        ));
+        if let Some(state) = log.failing_state() {
+            report.push_str(&format!("registers: {}\n", state));
+        }
+        if let Some(hint) = log.failure.hint() {
+            report.push_str(&format!("hint: {}\n", hint));
+        }
+        report
+    }
+}
+
+/// Instructions shown before and after the failing one by
+/// [`ProgramSpec::verifier_report`].
+const VERIFIER_REPORT_CONTEXT: usize = 5;
+
+/// Program `program` is installed at index `slot` of prog array `map`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TailCall {
+    pub map: String,
+    pub slot: u32,
+    pub program: String,
+}
+
+/// A parsed, not yet loaded, BPF object file.
+#[derive(Debug, Clone, Default)]
+pub struct ObjectFile {
+    pub license: String,
+    pub kern_version: u32,
+    pub maps: Vec<MapSpec>,
+    pub programs: Vec<ProgramSpec>,
+    pub tail_calls: Vec<TailCall>,
+    pub btf: Option<Btf>,
+    /// functions only reachable through bpf-to-bpf calls
+    subprograms: Vec<ProgramSpec>,
+    pin_root: Option<PathBuf>,
+    /// kernel BTF CO-RE relocations are resolved against
+    core_target: Option<Btf>,
+    /// initial contents of each global data section
+    data: Vec<(String, Vec<u8>)>,
+}
+
+impl ObjectFile {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<ObjectFile> {
+        ObjectFile::parse(&fs::read(path)?)
+    }
+
+    pub fn parse(bytes: &[u8]) -> Result<ObjectFile> {
+        let elf = Elf::parse(bytes)?;
+        let mut object = ObjectFile::default();
+
+        let section_name = |idx: usize| section_name(&elf, idx);
+        let section_data = |idx: usize| -> Result<&[u8]> {
+            let sh = &elf.section_headers[idx];
+            if sh.sh_type == section_header::SHT_NOBITS {
+                return Ok(&[]);
+            }
+            let start = sh.sh_offset as usize;
+            start
+                .checked_add(sh.sh_size as usize)
+                .and_then(|end| bytes.get(start..end))
+                .ok_or_else(|| Error::Elf(format!("section {} out of bounds", section_name(idx))))
+        };
+
+        let mut maps_idx = None;
+        let mut btf_maps_idx = None;
+        let mut btf_ext_idx = None;
+        let mut data_sections = Vec::new();
+        for (idx, sh) in elf.section_headers.iter().enumerate() {
+            match section_name(idx) {
+                "license" => {
+                    let data = section_data(idx)?;
+                    let len = data.iter().position(|&b| b == 0).unwrap_or(data.len());
+                    object.license = String::from_utf8_lossy(&data[..len]).into_owned();
+                }
+                "version" => {
+                    let data = section_data(idx)?;
+                    if data.len() >= 4 {
+                        object.kern_version = u32::from_le_bytes(data[..4].try_into().unwrap());
+                    }
+                }
+                "maps" => maps_idx = Some(idx),
+                ".maps" => btf_maps_idx = Some(idx),
+                ".BTF" => object.btf = Some(Btf::parse(section_data(idx)?)?),
+                ".BTF.ext" => btf_ext_idx = Some(idx),
+                name if is_data_section(name)
+                    && sh.sh_flags & u64::from(section_header::SHF_ALLOC) != 0
+                    && sh.sh_size > 0 =>
+                {
+                    data_sections.push(idx);
+                }
+                name if sh.sh_flags & u64::from(section_header::SHF_EXECINSTR) != 0 => {
+                    let prog_type = match ProgramType::from_section(name) {
+                        Some(prog_type) => prog_type,
+                        None if name == ".text" => ProgramType::Unspec,
+                        None => continue,
+                    };
+                    object.parse_programs(&elf, idx, name, prog_type, section_data(idx)?);
+                }
+                _ => {}
+            }
+        }
+
+        if let Some(idx) = maps_idx {
+            object.parse_maps(&elf, idx, section_data(idx)?)?;
+        }
+        if let Some(idx) = btf_maps_idx {
+            let values_offsets = object.parse_btf_maps()?;
+            object.parse_btf_map_values(&elf, idx, &values_offsets)?;
+        }
+        for &idx in &data_sections {
+            let sh = &elf.section_headers[idx];
+            let data = match section_data(idx)? {
+                [] => vec![0; sh.sh_size as usize],
+                data => data.to_vec(),
+            };
+            object.add_data_section(section_name(idx), data);
+        }
+        object.fixup_datasecs(&elf);
+        if let (Some(idx), Some(btf)) = (btf_ext_idx, &object.btf) {
+            let ext = BtfExt::parse(section_data(idx)?, btf)?;
+            object.set_line_info(&ext);
+            object.set_core_relocs(&ext);
+        }
+        let map_sections: Vec<usize> = maps_idx.into_iter().chain(btf_maps_idx).collect();
+        object.parse_relocations(&elf, &map_sections, &data_sections)?;
+        object.link_subprograms(&elf)?;
+        Ok(object)
+    }
+
+    /// Splits section `section` into its functions: global ones become
+    /// programs, static ones and everything in `.text` subprograms.
+    fn parse_programs(
+        &mut self,
+        elf: &Elf<'_>,
+        shndx: usize,
+        section: &str,
+        prog_type: ProgramType,
+        data: &[u8],
+    ) {
+        let mut funcs: Vec<(String, usize, usize, bool)> = elf
+            .syms
+            .iter()
+            .filter(|s| s.st_shndx == shndx && s.st_type() == sym::STT_FUNC)
+            .filter_map(|s| {
+                let name = elf.strtab.get_unsafe(s.st_name)?;
+                let start = s.st_value as usize;
+                let end = if s.st_size == 0 {
+                    data.len()
+                } else {
+                    start + s.st_size as usize
+                };
+                let global = s.st_bind() == sym::STB_GLOBAL && section != ".text";
+                Some((name.to_string(), start.min(data.len()), end.min(data.len()), global))
+            })
+            .collect();
+        if funcs.is_empty() && section != ".text" {
+            funcs.push((section.replace('/', "_"), 0, data.len(), true));
+        }
+        funcs.sort_by_key(|(_, start, _, _)| *start);
+
+        let tail_call = parse_tail_call(section);
+        for (name, start, end, global) in funcs {
+            let spec = ProgramSpec {
+                name,
+                section: section.to_string(),
+                offset: start,
+                prog_type,
+                insns: parse_insns(&data[start..end]),
+                map_relocs: Vec::new(),
+                line_info: Vec::new(),
+                core_relocs: Vec::new(),
+                problems: Vec::new(),
+            };
+            if !global {
+                self.subprograms.push(spec);
+                continue;
+            }
+            if let Some((map, slot)) = &tail_call {
+                self.tail_calls.push(TailCall {
+                    map: map.clone(),
+                    slot: *slot,
+                    program: spec.name.clone(),
+                });
+            }
+            self.programs.push(spec);
+        }
+    }
+
+    /// Splits the line records of each section between its programs.
+    fn set_line_info(&mut self, ext: &BtfExt) {
+        for prog in self.programs.iter_mut().chain(&mut self.subprograms) {
+            let info = match ext.section(&prog.section) {
+                Some(info) => info,
+                None => continue,
+            };
+            let start = prog.offset;
+            let end = start + prog.insns.len() * INSN_SIZE;
+            prog.line_info = info
+                .line_info
+                .iter()
+                .filter(|l| (start..end).contains(&(l.insn_off as usize)))
+                .map(|l| SourceLine {
+                    insn_off: ((l.insn_off as usize - start) / INSN_SIZE) as u32,
+                    ..l.clone()
+                })
+                .collect();
+        }
+    }
+
+    /// Splits the CO-RE relocations of each section between its programs.
+    fn set_core_relocs(&mut self, ext: &BtfExt) {
+        for prog in self.programs.iter_mut().chain(&mut self.subprograms) {
+            let info = match ext.section(&prog.section) {
+                Some(info) => info,
+                None => continue,
+            };
+            let start = prog.offset;
+            let end = start + prog.insns.len() * INSN_SIZE;
+            prog.core_relocs = info
+                .core_relocs
+                .iter()
+                .filter(|r| (start..end).contains(&(r.insn_off as usize)))
+                .map(|r| CoreReloc {
+                    insn_off: ((r.insn_off as usize - start) / INSN_SIZE) as u32,
+                    ..r.clone()
+                })
+                .collect();
+        }
+    }
+
+    fn parse_maps(&mut self, elf: &Elf<'_>, shndx: usize, data: &[u8]) -> Result<()> {
+        let mut syms: Vec<_> = elf
+            .syms
+            .iter()
+            .filter(|s| s.st_shndx == shndx && s.st_type() != sym::STT_SECTION)
+            .filter_map(|s| Some((elf.strtab.get_unsafe(s.st_name)?, s.st_value, s.st_size)))
+            .filter(|(name, _, _)| !name.is_empty())
+            .collect();
+        syms.sort_by_key(|(_, offset, _)| *offset);
+        if syms.is_empty() {
+            return Ok(());
+        }
+
+        let stride = data.len() / syms.len();
+        // (id, inner_id) of every map, for resolving inner map templates
+        let mut ids = Vec::new();
+        for (name, offset, size) in syms {
+            let size = if size == 0 { stride } else { size as usize };
+            let offset = offset as usize;
+            let def = data
+                .get(offset..offset + size)
+                .filter(|d| d.len() >= 16)
+                .ok_or_else(|| Error::Elf(format!("map {} has a truncated definition", name)))?;
+            let field = |i: usize| {
+                def.get(i * 4..i * 4 + 4)
+                    .map_or(0, |b| u32::from_le_bytes(b.try_into().unwrap()))
+            };
+            let map_type = MapType::from_u32(field(0))
+                .ok_or_else(|| Error::Elf(format!("map {} has unknown type {}", name, field(0))))?;
+            // iproute2's struct bpf_elf_map carries a pinning field after
+            // the id; any of its namespaces maps to pinning by name here
+            let pinning = if field(6) != 0 {
+                Pinning::ByName
+            } else {
+                Pinning::None
+            };
+            ids.push((field(5), field(7)));
+            self.maps.push(MapSpec {
+                name: name.to_string(),
+                def: MapDef {
+                    map_type,
+                    key_size: field(1),
+                    value_size: field(2),
+                    max_entries: field(3),
+                    map_flags: field(4),
+                },
+                pinning,
+                inner: None,
+                values: Vec::new(),
+            });
+        }
+
+        let first = self.maps.len() - ids.len();
+        for (i, (_, inner_id)) in ids.iter().enumerate() {
+            if *inner_id == 0 {
+                continue;
+            }
+            let template = ids
+                .iter()
+                .position(|(id, _)| id == inner_id)
+                .ok_or_else(|| {
+                    Error::Elf(format!(
+                        "map {} refers to unknown inner map id {}",
+                        self.maps[first + i].name, inner_id
+                    ))
+                })?;
+            self.maps[first + i].inner = Some(self.maps[first + template].def);
+        }
+        Ok(())
+    }
+
+    /// Reads map definitions from the variables of the `.maps` data
+    /// section. Returns, for every map with initial `values`, the byte
+    /// offset of that array within the map's definition.
+    fn parse_btf_maps(&mut self) -> Result<Vec<(String, u32)>> {
+        let btf = match &self.btf {
+            Some(btf) => btf,
+            None => return Err(Error::Elf("found .maps but no .BTF section".to_string())),
+        };
+        let vars = match btf.find_by_name(".maps").map(|id| &btf.types[id as usize].kind) {
+            Some(BtfKind::Datasec { vars, .. }) => vars.clone(),
+            _ => return Err(Error::Elf("no BTF data section for .maps".to_string())),
+        };
+
+        let mut specs = Vec::new();
+        let mut values_offsets = Vec::new();
+        for var in vars {
+            let var = btf.type_by_id(var.type_id)?;
+            let def_id = var.referenced().unwrap_or(0);
+            let (spec, values_offset) = parse_btf_map_def(btf, &var.name, def_id)?;
+            if let Some(offset) = values_offset {
+                values_offsets.push((spec.name.clone(), offset));
+            }
+            specs.push(spec);
+        }
+        self.maps.extend(specs);
+        Ok(values_offsets)
+    }
+
+    /// Resolves the `.values` initializers of BTF maps from the relocations
+    /// of the `.maps` section. Entries of prog arrays become tail calls.
+    fn parse_btf_map_values(
+        &mut self,
+        elf: &Elf<'_>,
+        shndx: usize,
+        values_offsets: &[(String, u32)],
+    ) -> Result<()> {
+        let relocs = match elf
+            .shdr_relocs
+            .iter()
+            .find(|(idx, _)| elf.section_headers[*idx].sh_info as usize == shndx)
+        {
+            Some((_, relocs)) => relocs,
+            None => return Ok(()),
+        };
+        for reloc in relocs.iter() {
+            let offset = reloc.r_offset;
+            let outer = elf.syms.iter().find(|s| {
+                s.st_shndx == shndx
+                    && s.st_type() != sym::STT_SECTION
+                    && offset >= s.st_value
+                    && offset < s.st_value + s.st_size
+            });
+            let (outer, start) = match outer {
+                Some(s) => (elf.strtab.get_unsafe(s.st_name).unwrap_or(""), s.st_value),
+                None => continue,
+            };
+            let values_offset = match values_offsets.iter().find(|(name, _)| name == outer) {
+                Some((_, values_offset)) => u64::from(*values_offset),
+                None => continue,
+            };
+            let slot = (offset - start)
+                .checked_sub(values_offset)
+                .map(|off| (off / 8) as u32)
+                .ok_or_else(|| {
+                    Error::Invalid(format!(
+                        "map {}: relocation at {:#x} is before its values",
+                        outer, offset
+                    ))
+                })?;
+            let target = elf
+                .syms
+                .get(reloc.r_sym)
+                .and_then(|s| elf.strtab.get_unsafe(s.st_name))
+                .unwrap_or("")
+                .to_string();
+            let spec = self.maps.iter_mut().find(|m| m.name == outer).unwrap();
+            if spec.def.map_type == MapType::ProgArray {
+                self.tail_calls.push(TailCall {
+                    map: outer.to_string(),
+                    slot,
+                    program: target,
+                });
+            } else {
+                spec.values.push((slot, target));
+            }
+        }
+        Ok(())
+    }
+
+    /// Backs global data section `name` with a single element array.
+    fn add_data_section(&mut self, name: &str, data: Vec<u8>) {
+        self.maps.push(MapSpec {
+            name: name.to_string(),
+            def: MapDef {
+                map_type: MapType::Array,
+                key_size: 4,
+                value_size: data.len() as u32,
+                max_entries: 1,
+                map_flags: if name.starts_with(".rodata") {
+                    BPF_F_RDONLY_PROG
+                } else {
+                    0
+                },
+            },
+            pinning: Pinning::None,
+            inner: None,
+            values: Vec::new(),
+        });
+        self.data.push((name.to_string(), data));
+    }
+
+    /// clang leaves the size of BTF data sections and the offsets of their
+    /// variables to the loader; fills them in from the ELF section headers
+    /// and symbols the way libbpf does.
+    fn fixup_datasecs(&mut self, elf: &Elf<'_>) {
+        let btf = match &mut self.btf {
+            Some(btf) => btf,
+            None => return,
+        };
+        for id in 0..btf.types.len() {
+            let vars = match &btf.types[id].kind {
+                BtfKind::Datasec { size: 0, vars } => vars,
+                _ => continue,
+            };
+            let shndx = match (0..elf.section_headers.len())
+                .find(|&idx| section_name(elf, idx) == btf.types[id].name)
+            {
+                Some(shndx) => shndx,
+                None => continue,
+            };
+            let offsets: Vec<Option<u32>> = vars
+                .iter()
+                .map(|var| {
+                    let name = &btf.types.get(var.type_id as usize)?.name;
+                    elf.syms
+                        .iter()
+                        .find(|s| {
+                            s.st_shndx == shndx
+                                && elf.strtab.get_unsafe(s.st_name) == Some(name.as_str())
+                        })
+                        .map(|s| s.st_value as u32)
+                })
+                .collect();
+            if let BtfKind::Datasec { size, vars } = &mut btf.types[id].kind {
+                *size = elf.section_headers[shndx].sh_size as u32;
+                for (var, offset) in vars.iter_mut().zip(offsets) {
+                    if let Some(offset) = offset {
+                        var.offset = offset;
+                    }
+                }
+            }
+        }
+    }
+
+    fn parse_relocations(
+        &mut self,
+        elf: &Elf<'_>,
+        map_sections: &[usize],
+        data_sections: &[usize],
+    ) -> Result<()> {
+        for (rel_idx, relocs) in &elf.shdr_relocs {
+            let target = section_name(elf, elf.section_headers[*rel_idx].sh_info as usize);
+            for reloc in relocs.iter() {
+                let sym = match elf.syms.get(reloc.r_sym) {
+                    Some(sym) => sym,
+                    None => continue,
+                };
+                let data = data_sections.contains(&sym.st_shndx);
+                if !data && !map_sections.contains(&sym.st_shndx) {
+                    continue;
+                }
+                let map = if data {
+                    section_name(elf, sym.st_shndx).to_string()
+                } else {
+                    elf.strtab.get_unsafe(sym.st_name).unwrap_or("").to_string()
+                };
+                let offset = reloc.r_offset as usize;
+                let program = self.programs.iter_mut().chain(&mut self.subprograms).find(|p| {
+                    p.section == target
+                        && offset >= p.offset
+                        && offset < p.offset + p.insns.len() * INSN_SIZE
+                });
+                if let Some(program) = program {
+                    let insn = (offset - program.offset) / INSN_SIZE;
+                    // an ld_imm64 takes two slots, the second must be there
+                    if !program.insns[insn].is_ld_imm64() || insn + 1 >= program.insns.len() {
+                        return Err(Error::Elf(format!(
+                            "{}: relocation of map {} at insn {} is not a ld_imm64",
+                            program.name, map, insn
+                        )));
+                    }
+                    if data {
+                        // the variable's offset, from its symbol or, for
+                        // static ones, the instruction
+                        let offset = sym.st_value as i32 + program.insns[insn].imm;
+                        program.insns[insn].imm = 0;
+                        program.insns[insn].set_src(BPF_PSEUDO_MAP_VALUE);
+                        program.insns[insn + 1].imm = offset;
+                    }
+                    program.map_relocs.push(MapReloc { insn, map });
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Appends to every program the subprograms it calls, directly or
+    /// not, and points each call at the appended copy, then checks the
+    /// linked program. Calls within a section are already pc-relative,
+    /// calls into `.text` carry a relocation against the callee.
+    fn link_subprograms(&mut self, elf: &Elf<'_>) -> Result<()> {
+        let mut calls = Vec::new();
+        for (rel_idx, relocs) in &elf.shdr_relocs {
+            let section = section_name(elf, elf.section_headers[*rel_idx].sh_info as usize);
+            for reloc in relocs.iter() {
+                let sym = match elf.syms.get(reloc.r_sym) {
+                    Some(sym) => sym,
+                    None => continue,
+                };
+                let exec = elf.section_headers.get(sym.st_shndx).is_some_and(|sh| {
+                    sh.sh_flags & u64::from(section_header::SHF_EXECINSTR) != 0
+                });
+                if exec {
+                    let callee = section_name(elf, sym.st_shndx);
+                    calls.push((section, reloc.r_offset as usize, callee, sym.st_value as usize));
+                }
+            }
+        }
+
+        let funcs: Vec<ProgramSpec> = self
+            .subprograms
+            .iter()
+            .chain(&self.programs)
+            .cloned()
+            .collect();
+        let limits = Limits::newest();
+        for prog in &mut self.programs {
+            link_calls(prog, &funcs, &calls)?;
+            prog.problems = cfg::check(&prog.insns, &limits);
+        }
+        Ok(())
+    }
+
+    pub fn program(&self, name: &str) -> Option<&ProgramSpec> {
+        self.programs.iter().find(|p| p.name == name)
+    }
+
+    pub fn map(&self, name: &str) -> Option<&MapSpec> {
+        self.maps.iter().find(|m| m.name == name)
+    }
+
+    /// Declares that `program` must be stored in slot `slot` of prog array
+    /// `map` once the object is loaded.
+    pub fn set_tail_call(&mut self, map: &str, slot: u32, program: &str) -> Result<()> {
+        match self.map(map) {
+            Some(spec) if spec.def.map_type == MapType::ProgArray => {}
+            Some(_) => return Err(Error::Invalid(format!("map {} is not a prog_array", map))),
+            None => return Err(Error::NotFound(format!("map {}", map))),
+        }
+        if self.program(program).is_none() {
+            return Err(Error::NotFound(format!("program {}", program)));
+        }
+        self.tail_calls.retain(|t| !(t.map == map && t.slot == slot));
+        self.tail_calls.push(TailCall {
+            map: map.to_string(),
+            slot,
+            program: program.to_string(),
+        });
+        Ok(())
+    }
+
+    /// Uses the definition of map `template` for the inner maps of the
+    /// array or hash of maps `outer`.
+    pub fn set_inner_map(&mut self, outer: &str, template: &str) -> Result<()> {
+        let def = self
+            .map(template)
+            .ok_or_else(|| Error::NotFound(format!("map {}", template)))?
+            .def;
+        let spec = self
+            .maps
+            .iter_mut()
+            .find(|m| m.name == outer)
+            .ok_or_else(|| Error::NotFound(format!("map {}", outer)))?;
+        if !is_map_of_maps(spec.def.map_type) {
+            return Err(Error::Invalid(format!("map {} is not a map of maps", outer)));
+        }
+        spec.inner = Some(def);
+        Ok(())
+    }
+
+    pub fn set_pinning(&mut self, map: &str, pinning: Pinning) -> Result<()> {
+        let spec = self
+            .maps
+            .iter_mut()
+            .find(|m| m.name == map)
+            .ok_or_else(|| Error::NotFound(format!("map {}", map)))?;
+        spec.pinning = pinning;
+        Ok(())
+    }
+
+    /// Sets the bpffs directory maps and programs are pinned below.
+    pub fn set_pin_root<P: AsRef<Path>>(&mut self, root: P) {
+        self.pin_root = Some(root.as_ref().to_path_buf());
+    }
+
+    pub fn pin_root(&self) -> &Path {
+        self.pin_root
+            .as_deref()
+            .unwrap_or_else(|| Path::new(DEFAULT_PIN_ROOT))
+    }
+
+    /// Initial contents of global data section `section`, e.g. `.rodata`.
+    pub fn data(&self, section: &str) -> Option<&[u8]> {
+        self.data
+            .iter()
+            .find(|(name, _)| name == section)
+            .map(|(_, data)| data.as_slice())
+    }
+
+    /// Replaces the initial contents of global data section `section`,
+    /// e.g. to configure the `const volatile` variables of `.rodata`
+    /// before loading.
+    pub fn set_data(&mut self, section: &str, data: &[u8]) -> Result<()> {
+        let current = self
+            .data
+            .iter_mut()
+            .find(|(name, _)| name == section)
+            .map(|(_, data)| data)
+            .ok_or_else(|| Error::NotFound(format!("data section {}", section)))?;
+        if current.len() != data.len() {
+            return Err(Error::Invalid(format!(
+                "data section {} is {} bytes, got {}",
+                section,
+                current.len(),
+                data.len()
+            )));
+        }
+        current.copy_from_slice(data);
+        Ok(())
+    }
+
+    /// Resolves CO-RE relocations against `btf` instead of the running
+    /// kernel's, e.g. the BTF of a kernel without `/sys/kernel/btf`.
+    pub fn set_core_target(&mut self, btf: Btf) {
+        self.core_target = Some(btf);
+    }
+
+    /// The kernel BTF CO-RE relocations are resolved against, `None` if no
+    /// program has any.
+    fn core_target(&self) -> Result<Option<Cow<'_, Btf>>> {
+        if self.programs.iter().all(|p| p.core_relocs.is_empty()) {
+            return Ok(None);
+        }
+        Ok(Some(match &self.core_target {
+            Some(btf) => Cow::Borrowed(btf),
+            None => Cow::Owned(Btf::vmlinux()?),
+        }))
+    }
+
+    /// Creates every map, loads every program and fills the prog arrays.
+    pub fn load(&self) -> Result<Object> {
+        let mut object = Object {
+            pin_root: self.pin_root().to_path_buf(),
+            ..Default::default()
+        };
+        if self.maps.iter().any(|m| m.pinning != Pinning::None) {
+            pin::ensure_bpffs(&object.pin_root)?;
+        }
+        for spec in &self.maps {
+            let map = self.create_map(spec, &object.pin_root)?;
+            if let Some(inner) = spec.inner {
+                object.inner_templates.push((spec.name.clone(), inner));
+            }
+            object.maps.push(map);
+        }
+        for (section, data) in &self.data {
+            let map = object.map(section).unwrap();
+            map.update(&0u32.to_le_bytes(), data, BPF_ANY)?;
+            if map.def.map_flags & BPF_F_RDONLY_PROG != 0 {
+                map.freeze()?;
+            }
+        }
+        for spec in self.maps.iter().filter(|m| !m.values.is_empty()) {
+            let outer = object.map_of_maps::<u32>(&spec.name)?;
+            for (slot, inner) in &spec.values {
+                let inner = object
+                    .map(inner)
+                    .ok_or_else(|| Error::NotFound(format!("map {}", inner)))?;
+                outer.set(slot, inner)?;
+            }
+        }
+        let target = self.core_target()?;
+        for spec in &self.programs {
+            let program = self.load_program_with(spec, &object, target.as_deref())?;
+            object.programs.push(program);
+        }
+        for tail_call in &self.tail_calls {
+            let program = object
+                .program(&tail_call.program)
+                .ok_or_else(|| Error::NotFound(format!("program {}", tail_call.program)))?;
+            object.prog_array(&tail_call.map)?.set(tail_call.slot, program)?;
+            let fd = program.as_raw_fd();
+            object.tail_calls.retain(|(map, slot, _)| {
+                !(*map == tail_call.map && *slot == tail_call.slot)
+            });
+            object.tail_calls.push((tail_call.map.clone(), tail_call.slot, fd));
+        }
+        Ok(object)
+    }
+
+    fn create_map(&self, spec: &MapSpec, root: &Path) -> Result<Map> {
+        // the kernel only copies the inner map's definition, so a throwaway
+        // map serves as template
+        let template = match (spec.inner, is_map_of_maps(spec.def.map_type)) {
+            (Some(inner), true) => Some(Map::create(&format!("{}.inner", spec.name), &inner)?),
+            (None, true) => {
+                return Err(Error::Invalid(format!(
+                    "map of maps {} has no inner map template",
+                    spec.name
+                )))
+            }
+            _ => None,
+        };
+        let inner_fd = template.as_ref().map(|m| m.as_raw_fd());
+        let path = root.join(&spec.name);
+        if spec.pinning == Pinning::None || !path.exists() {
+            let map = Map::create_with_inner(&spec.name, &spec.def, inner_fd)?;
+            if spec.pinning == Pinning::ByName {
+                map.pin(&path)?;
+            }
+            return Ok(map);
+        }
+        let mut map = Map::from_pinned(&path)?;
+        map.name = spec.name.clone();
+        if map.def != spec.def {
+            return Err(Error::Invalid(format!(
+                "map {} pinned at {} is incompatible: found {}, expected {}",
+                spec.name,
+                path.display(),
+                describe_def(&map.def),
+                describe_def(&spec.def)
+            )));
+        }
+        if let (Some(inner), true) = (spec.inner, is_map_of_maps(spec.def.map_type)) {
+            check_pinned_inner(&map, &inner, &path)?;
+        }
+        Ok(map)
+    }
+
+    /// Loads the single program `name` against the maps of an already
+    /// loaded `object`, e.g. to hot-swap one stage of a tail call pipeline
+    /// with [`Object::replace_tail_call`].
+    pub fn load_program(&self, name: &str, object: &Object) -> Result<Program> {
+        let spec = self
+            .program(name)
+            .ok_or_else(|| Error::NotFound(format!("program {}", name)))?;
+        self.load_program_with(spec, object, self.core_target()?.as_deref())
+    }
+
+    /// The instructions of `spec` as the kernel sees them: CO-RE
+    /// relocated like [`ObjectFile::load`] would, map references marked
+    /// but without their file descriptors.
+    pub fn relocated_insns(&self, spec: &ProgramSpec) -> Result<Vec<Insn>> {
+        self.relocate(spec, self.core_target()?.as_deref())
+    }
+
+    /// The tag the running kernel will report for `spec` once loaded, see
+    /// [`tag::prog_tag`].
+    pub fn tag(&self, spec: &ProgramSpec) -> Result<[u8; BPF_TAG_SIZE]> {
+        Ok(tag::prog_tag(&self.relocated_insns(spec)?, tag::TagHash::running()))
+    }
+
+    fn relocate(&self, spec: &ProgramSpec, target: Option<&Btf>) -> Result<Vec<Insn>> {
+        let mut insns = spec.insns.clone();
+        if let (Some(local), Some(target)) = (&self.btf, target) {
+            core_reloc::relocate(&mut insns, &spec.core_relocs, local, target)
+                .map_err(|e| Error::Invalid(format!("{}: {}", spec.name, e)))?;
+        }
+        for reloc in &spec.map_relocs {
+            if insns[reloc.insn].src() != BPF_PSEUDO_MAP_VALUE {
+                insns[reloc.insn].set_src(BPF_PSEUDO_MAP_FD);
+            }
+        }
+        Ok(insns)
+    }
+
+    fn load_program_with(
+        &self,
+        spec: &ProgramSpec,
+        object: &Object,
+        target: Option<&Btf>,
+    ) -> Result<Program> {
+        let mut insns = self.relocate(spec, target)?;
+        for reloc in &spec.map_relocs {
+            let map = object
+                .map(&reloc.map)
+                .ok_or_else(|| Error::NotFound(format!("map {}", reloc.map)))?;
+            insns[reloc.insn].imm = map.as_raw_fd();
+        }
+        Program::load(
+            &spec.name,
+            spec.prog_type,
+            &insns,
+            &self.license,
+            self.kern_version,
+        )
+        .map_err(|e| explain_load_error(spec, e))
+    }
+}
+
+/// Links the callees of `prog` from `funcs`. `calls` holds the section
+/// and byte offset of each relocated call with the section and offset of
+/// the symbol it refers to.
+fn link_calls(
+    prog: &mut ProgramSpec,
+    funcs: &[ProgramSpec],
+    calls: &[(&str, usize, &str, usize)],
+) -> Result<()> {
+    // section, byte offset, length and index within `prog.insns` of the
+    // program and of every subprogram appended so far
+    let mut pieces = vec![(prog.section.clone(), prog.offset, prog.insns.len(), 0)];
+    let mut piece = 0;
+    while piece < pieces.len() {
+        let (section, offset, len, base) = pieces[piece].clone();
+        for i in base..base + len {
+            let insn = prog.insns[i];
+            if insn.code != BPF_JMP | BPF_CALL || insn.src() != BPF_PSEUDO_CALL {
+                continue;
+            }
+            let at = offset + (i - base) * INSN_SIZE;
+            let (callee, start) = match calls.iter().find(|c| c.0 == section && c.1 == at) {
+                Some(&(_, _, callee, value)) => (callee, value as i64),
+                None => (section.as_str(), at as i64),
+            };
+            let start = start + (i64::from(insn.imm) + 1) * INSN_SIZE as i64;
+            let linked = pieces.iter().find(|(s, o, l, _)| {
+                s == callee && start >= *o as i64 && start < (o + l * INSN_SIZE) as i64
+            });
+            let target = match linked {
+                Some((_, o, _, b)) => b + (start as usize - o) / INSN_SIZE,
+                None => {
+                    let func = funcs
+                        .iter()
+                        .find(|f| f.section == callee && f.offset as i64 == start)
+                        .ok_or_else(|| {
+                            Error::Elf(format!(
+                                "{}: call at insn {} to unknown function at {}+{:#x}",
+                                prog.name, i, callee, start
+                            ))
+                        })?;
+                    let b = prog.insns.len();
+                    prog.insns.extend_from_slice(&func.insns);
+                    prog.map_relocs.extend(func.map_relocs.iter().map(|r| MapReloc {
+                        insn: r.insn + b,
+                        map: r.map.clone(),
+                    }));
+                    prog.line_info.extend(func.line_info.iter().map(|l| SourceLine {
+                        insn_off: l.insn_off + b as u32,
+                        ..l.clone()
+                    }));
+                    prog.core_relocs.extend(func.core_relocs.iter().map(|r| CoreReloc {
+                        insn_off: r.insn_off + b as u32,
+                        ..r.clone()
+                    }));
+                    pieces.push((func.section.clone(), func.offset, func.insns.len(), b));
+                    b
+                }
+            };
+            prog.insns[i].imm = (target as i64 - i as i64 - 1) as i32;
+        }
+        piece += 1;
+    }
+    Ok(())
+}
+
+/// Attaches the [`ProgramSpec::verifier_report`] of `spec` to a load
+/// failure.
+fn explain_load_error(spec: &ProgramSpec, error: Error) -> Error {
+    match error {
+        Error::Load {
+            program,
+            error,
+            log,
+            report: None,
+        } if !log.is_empty() => Error::Load {
+            program,
+            error,
+            report: Some(spec.verifier_report(&log)),
+            log,
+        },
+        error => error,
+    }
+}
+
+fn is_map_of_maps(map_type: MapType) -> bool {
+    map_type == MapType::ArrayOfMaps || map_type == MapType::HashOfMaps
+}
+
+fn describe_def(def: &MapDef) -> String {
+    format!(
+        "{} key {} value {} max_entries {} flags {:#x}",
+        def.map_type.name(),
+        def.key_size,
+        def.value_size,
+        def.max_entries,
+        def.map_flags
+    )
+}
+
+/// Checks the inner maps of the pinned map of maps `map` against
+/// `template`. The kernel holds them all to the template the map was
+/// created with, so the first one tells; an empty map can't be checked.
+fn check_pinned_inner(map: &Map, template: &MapDef, path: &Path) -> Result<()> {
+    for key in map.keys() {
+        let id = match map.lookup(&key?)? {
+            Some(value) => pod_read::<u32>(&value),
+            None => continue,
+        };
+        let inner = Map::from_id(id)?;
+        if inner.def != *template {
+            return Err(Error::Invalid(format!(
+                "map {} pinned at {} holds incompatible inner maps: found {}, expected {}",
+                map.name,
+                path.display(),
+                describe_def(&inner.def),
+                describe_def(template)
+            )));
+        }
+        break;
+    }
+    Ok(())
+}
+
+/// Whether section `name` holds global variables, e.g. `.rodata` or
+/// `.data.counters`.
+fn is_data_section(name: &str) -> bool {
+    [".data", ".rodata", ".bss"]
+        .iter()
+        .any(|prefix| name == *prefix || name.starts_with(&format!("{}.", prefix)))
+}
+
+/// Decodes the BTF struct `def_id` describing a map. Each member encodes an
+/// attribute: `__uint(name, val)` is a pointer to an array of `val`
+/// elements, `__type(name, T)` a pointer to `T` and
+/// `__array(values, struct { ... })` a zero sized array of pointers to the
+/// inner map definition (or to a function prototype for prog arrays).
+/// Returns the byte offset of `values` when present.
+fn parse_btf_map_def(btf: &Btf, name: &str, def_id: u32) -> Result<(MapSpec, Option<u32>)> {
+    let def_id = btf.skip_mods_and_typedefs(def_id)?;
+    let members = match &btf.type_by_id(def_id)?.kind {
+        BtfKind::Struct { members, .. } => members,
+        _ => return Err(Error::Elf(format!("map {} is not a struct", name))),
+    };
+
+    let mut map_type = None;
+    let mut spec = MapSpec {
+        name: name.to_string(),
+        def: MapDef {
+            map_type: MapType::Unspec,
+            key_size: 0,
+            value_size: 0,
+            max_entries: 0,
+            map_flags: 0,
+        },
+        pinning: Pinning::None,
+        inner: None,
+        values: Vec::new(),
+    };
+    let mut values_offset = None;
+    for member in members {
+        let attr = btf.type_by_id(btf.skip_mods_and_typedefs(member.type_id)?)?;
+        if member.name == "values" {
+            let elem = match attr.kind {
+                BtfKind::Array { elem, .. } => elem,
+                _ => return Err(Error::Elf(format!("map {}: values is not an array", name))),
+            };
+            let pointee = match btf.type_by_id(btf.skip_mods_and_typedefs(elem)?)?.kind {
+                BtfKind::Ptr(t) => btf.skip_mods_and_typedefs(t)?,
+                _ => return Err(Error::Elf(format!("map {}: values are not pointers", name))),
+            };
+            if let BtfKind::Struct { .. } = btf.type_by_id(pointee)?.kind {
+                let (inner, _) = parse_btf_map_def(btf, &format!("{}.inner", name), pointee)?;
+                spec.inner = Some(inner.def);
+            }
+            values_offset = Some(member.bit_offset / 8);
+            continue;
+        }
+        let pointee = match attr.kind {
+            BtfKind::Ptr(t) => t,
+            _ => {
+                return Err(Error::Elf(format!(
+                    "map {}: attribute {} is not a pointer",
+                    name, member.name
+                )))
+            }
+        };
+        let uint = || -> Result<u32> {
+            match btf.type_by_id(btf.skip_mods_and_typedefs(pointee)?)?.kind {
+                BtfKind::Array { nelems, .. } => Ok(nelems),
+                _ => Err(Error::Elf(format!(
+                    "map {}: attribute {} is not an __uint",
+                    name, member.name
+                ))),
+            }
+        };
+        match member.name.as_str() {
+            "type" => {
+                let value = uint()?;
+                map_type = Some(MapType::from_u32(value).ok_or_else(|| {
+                    Error::Elf(format!("map {} has unknown type {}", name, value))
+                })?);
+            }
+            "key_size" => spec.def.key_size = uint()?,
+            "value_size" => spec.def.value_size = uint()?,
+            "max_entries" => spec.def.max_entries = uint()?,
+            "map_flags" => spec.def.map_flags = uint()?,
+            "key" => spec.def.key_size = btf.type_size(pointee)?,
+            "value" => spec.def.value_size = btf.type_size(pointee)?,
+            "pinning" => {
+                let value = uint()?;
+                spec.pinning = Pinning::from_u32(value).ok_or_else(|| {
+                    Error::Elf(format!("map {} has unknown pinning {}", name, value))
+                })?;
+            }
+            _ => {}
+        }
+    }
+    spec.def.map_type = map_type.ok_or_else(|| Error::Elf(format!("map {} has no type", name)))?;
+    // the value of maps of maps and prog arrays is an fd, implied by values
+    if values_offset.is_some() && spec.def.value_size == 0 {
+        spec.def.value_size = 4;
+    }
+    Ok((spec, values_offset))
+}
+
+fn section_name<'a>(elf: &Elf<'a>, idx: usize) -> &'a str {
+    elf.section_headers
+        .get(idx)
+        .and_then(|sh| elf.shdr_strtab.get_unsafe(sh.sh_name))
+        .unwrap_or("")
+}
+
+/// Parses the `<type>/tail/<prog_array>/<slot>` section name convention.
+fn parse_tail_call(section: &str) -> Option<(String, u32)> {
+    let parts: Vec<_> = section.split('/').collect();
+    let pos = parts.iter().skip(1).position(|p| *p == "tail")? + 1;
+    match &parts[pos + 1..] {
+        [map, slot] => Some((map.to_string(), slot.parse().ok()?)),
+        _ => None,
+    }
+}
+
+/// The maps and programs of a loaded object.
+#[derive(Debug, Default)]
+pub struct Object {
+    maps: Vec<Map>,
+    programs: Vec<Program>,
+    pin_root: PathBuf,
+    inner_templates: Vec<(String, MapDef)>,
+    /// prog array, slot and fd of the program stored there
+    tail_calls: Vec<(String, u32, RawFd)>,
+}
+
+impl Object {
+    pub fn maps(&self) -> &[Map] {
+        &self.maps
+    }
+
+    pub fn programs(&self) -> &[Program] {
+        &self.programs
+    }
+
+    pub fn map(&self, name: &str) -> Option<&Map> {
+        self.maps.iter().find(|m| m.name == name)
+    }
+
+    pub fn program(&self, name: &str) -> Option<&Program> {
+        self.programs.iter().find(|p| p.name == name)
+    }
+
+    /// Pins program `name` at `<pin root>/<name>`, returning the path.
+    pub fn pin_program(&self, name: &str) -> Result<PathBuf> {
+        let program = self
+            .program(name)
+            .ok_or_else(|| Error::NotFound(format!("program {}", name)))?;
+        pin::ensure_bpffs(&self.pin_root)?;
+        let path = self.pin_root.join(name);
+        program.pin(&path)?;
+        Ok(path)
+    }
+
+    /// Pins `link` at `<pin root>/<name>`, returning the path, see
+    /// [`Link::pin`].
+    pub fn pin_link(&self, link: &Link, name: &str) -> Result<PathBuf> {
+        pin::ensure_bpffs(&self.pin_root)?;
+        let path = self.pin_root.join(name);
+        link.pin(&path)?;
+        Ok(path)
+    }
+
+    /// Current contents of global data section `section`, as a `T` of the
+    /// section's size such as the bindings of its BTF.
+    pub fn data<T: Pod>(&self, section: &str) -> Result<T> {
+        let map = self.data_map::<T>(section)?;
+        let value = map
+            .lookup(&0u32.to_le_bytes())?
+            .ok_or_else(|| Error::NotFound(format!("data section {} contents", section)))?;
+        Ok(pod_read(&value))
+    }
+
+    /// Overwrites global data section `section` of the running programs.
+    /// `.rodata` is frozen, so this fails for it.
+    pub fn set_data<T: Pod>(&self, section: &str, value: &T) -> Result<()> {
+        self.data_map::<T>(section)?
+            .update(&0u32.to_le_bytes(), pod_bytes(value), BPF_ANY)
+    }
+
+    fn data_map<T: Pod>(&self, section: &str) -> Result<&Map> {
+        let map = self
+            .map(section)
+            .filter(|_| is_data_section(section))
+            .ok_or_else(|| Error::NotFound(format!("data section {}", section)))?;
+        if map.def.value_size as usize != std::mem::size_of::<T>() {
+            return Err(Error::Invalid(format!(
+                "data section {} is {} bytes, not {}",
+                section,
+                map.def.value_size,
+                std::mem::size_of::<T>()
+            )));
+        }
+        Ok(map)
+    }
+
+    /// Typed view of the array or hash of maps `name`.
+    pub fn map_of_maps<K: Pod>(&self, name: &str) -> Result<MapOfMaps<'_, K>> {
+        let map = self
+            .map(name)
+            .ok_or_else(|| Error::NotFound(format!("map {}", name)))?;
+        MapOfMaps::new(map)
+    }
+
+    /// Creates a new map matching the inner map template of `outer`, ready
+    /// to be stored in it with [`MapOfMaps::set`].
+    pub fn create_inner_map(&self, outer: &str, name: &str) -> Result<Map> {
+        let def = self
+            .inner_templates
+            .iter()
+            .find(|(map, _)| map == outer)
+            .map(|(_, def)| def)
+            .ok_or_else(|| Error::NotFound(format!("inner map template of {}", outer)))?;
+        Map::create(name, def)
+    }
+
+    pub fn prog_array(&self, name: &str) -> Result<ProgArray<'_>> {
+        let map = self
+            .map(name)
+            .ok_or_else(|| Error::NotFound(format!("map {}", name)))?;
+        ProgArray::new(map)
+    }
+
+    /// Atomically points slot `slot` of prog array `map` at `program`.
+    ///
+    /// Tail calls already in flight finish on the old program, every later
+    /// call enters the new one. The object keeps `program` alive; the
+    /// program previously in the slot is returned so the caller decides
+    /// when to release it, unless another slot still holds it.
+    pub fn replace_tail_call(
+        &mut self,
+        map: &str,
+        slot: u32,
+        program: Program,
+    ) -> Result<Option<Program>> {
+        self.prog_array(map)?.set(slot, &program)?;
+        let fd = program.as_raw_fd();
+        self.programs.push(program);
+        let old = match self.tail_calls.iter_mut().find(|(m, s, _)| m == map && *s == slot) {
+            Some(entry) => std::mem::replace(&mut entry.2, fd),
+            None => {
+                self.tail_calls.push((map.to_string(), slot, fd));
+                return Ok(None);
+            }
+        };
+        if self.tail_calls.iter().any(|(_, _, fd)| *fd == old) {
+            return Ok(None);
+        }
+        Ok(self
+            .programs
+            .iter()
+            .position(|p| p.as_raw_fd() == old)
+            .map(|idx| self.programs.remove(idx)))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::module::btf::fixture::{array, btf, int, member, ty};
+    use crate::module::btf_ext::CoreRelocKind;
+    use crate::module::vm::Vm;
+
+    #[test]
+    fn test_parse_tail_call() {
+        assert_eq!(parse_tail_call("xdp/tail/jmp_table/1"), Some(("jmp_table".into(), 1)));
+        assert_eq!(parse_tail_call("socket/tail/progs/12"), Some(("progs".into(), 12)));
+        assert_eq!(parse_tail_call("xdp/tail/jmp_table"), None);
+        assert_eq!(parse_tail_call("xdp/tail/jmp_table/x"), None);
+        assert_eq!(parse_tail_call("kprobe/tail"), None);
+        assert_eq!(parse_tail_call("tail/jmp_table/1"), None);
+        assert_eq!(parse_tail_call("xdp"), None);
+    }
+
+    #[test]
+    fn test_tail_call_object() {
+        let object =
+            ObjectFile::parse(include_bytes!("../../tests/fixtures/tail_call/prog.o")).unwrap();
+        let names: Vec<_> = object.programs.iter().map(|p| p.name.as_str()).collect();
+        assert_eq!(names, vec!["entry", "small", "large"]);
+        assert_eq!(object.map("jmp_table").unwrap().def.map_type, MapType::ProgArray);
+        let tail_call = |slot, program: &str| TailCall {
+            map: "jmp_table".to_string(),
+            slot,
+            program: program.to_string(),
+        };
+        assert_eq!(object.tail_calls, vec![tail_call(1, "small"), tail_call(2, "large")]);
+
+        // classify from .text, then the static verdict, are appended
+        let entry = object.program("entry").unwrap();
+        assert_eq!(entry.insns.len(), 12 + 6 + 6);
+        assert_eq!(entry.insns[2].imm, 12 - 2 - 1);
+        assert_eq!(entry.insns[10].imm, 18 - 10 - 1);
+        assert_eq!(
+            entry.map_relocs,
+            vec![MapReloc {
+                insn: 5,
+                map: "jmp_table".to_string(),
+            }]
+        );
+        assert!(entry.problems.is_empty(), "{:?}", entry.problems);
+        let large = object.program("large").unwrap();
+        assert_eq!(large.insns.len(), 3 + 6);
+        assert_eq!(large.insns[1].imm, 1);
+
+        // the tail call falls through, so entry returns verdict(2)
+        let mut vm = Vm::from_object(&object, 1).unwrap();
+        assert_eq!(vm.run(entry, &mut []).unwrap(), 1);
+        assert_eq!(vm.run(large, &mut []).unwrap(), 1);
+        assert_eq!(vm.run(object.program("small").unwrap(), &mut []).unwrap(), 2);
+    }
+
+    #[test]
+    fn test_parse_btf_map_def() {
+        let def = |members| ty("", BtfKind::Struct { size: 32, members });
+        let btf = btf(vec![
+            // 1
+            int("int", 4, true),
+            array(1, MapType::Hash as u32),
+            ty("", BtfKind::Ptr(2)),
+            array(1, 16),
+            ty("", BtfKind::Ptr(4)),
+            // 6
+            ty("", BtfKind::Ptr(1)),
+            def(vec![
+                member("type", 3, 0),
+                member("max_entries", 5, 64),
+                member("key", 6, 128),
+                member("value", 6, 192),
+            ]),
+            def(vec![member("type", 1, 0)]),
+            def(vec![member("type", 6, 0)]),
+            def(vec![member("max_entries", 5, 0)]),
+            // 11
+            ty("", BtfKind::Ptr(12)),
+            array(1, 999),
+            def(vec![member("type", 11, 0)]),
+            def(vec![member("type", 3, 0), member("values", 6, 64)]),
+            ty("loop", BtfKind::Typedef(15)),
+        ]);
+        let (spec, values) = parse_btf_map_def(&btf, "m", 7).unwrap();
+        assert_eq!(
+            spec.def,
+            MapDef {
+                map_type: MapType::Hash,
+                key_size: 4,
+                value_size: 4,
+                max_entries: 16,
+                map_flags: 0,
+            }
+        );
+        assert_eq!(values, None);
+
+        // not a struct, or a missing type
+        assert!(parse_btf_map_def(&btf, "m", 1).is_err());
+        assert!(parse_btf_map_def(&btf, "m", 99).is_err());
+        assert!(parse_btf_map_def(&btf, "m", 15).is_err());
+        // an attribute that is not a pointer, an __uint that is not an
+        // array, no type, an unknown type and values that are not an array
+        for id in [8, 9, 10, 13, 14] {
+            assert!(parse_btf_map_def(&btf, "m", id).is_err(), "{}", id);
+        }
+    }
+
+    #[test]
+    fn test_map_of_maps() {
+        let object =
+            ObjectFile::parse(include_bytes!("../../tests/fixtures/map_of_maps/prog.o")).unwrap();
+        let def = |map_type, value_size, max_entries| MapDef {
+            map_type,
+            key_size: 4,
+            value_size,
+            max_entries,
+            map_flags: 0,
+        };
+        let names: Vec<_> = object.maps.iter().map(|m| m.name.as_str()).collect();
+        assert_eq!(names, vec!["legacy_inner", "legacy_outer", "inner", "outer"]);
+
+        // iproute2 style, inner_id 7 naming legacy_inner
+        let outer = object.map("legacy_outer").unwrap();
+        assert_eq!(outer.def, def(MapType::ArrayOfMaps, 4, 2));
+        assert_eq!(outer.inner, Some(def(MapType::Array, 4, 1)));
+        assert_eq!(object.map("legacy_inner").unwrap().inner, None);
+
+        // __array(values, struct inner_map) with inner in slot 2
+        let outer = object.map("outer").unwrap();
+        assert_eq!(outer.def, def(MapType::ArrayOfMaps, 4, 4));
+        assert_eq!(outer.inner, Some(def(MapType::Array, 8, 1)));
+        assert_eq!(outer.values, vec![(2, "inner".to_string())]);
+        assert!(object.map("inner").unwrap().values.is_empty());
+    }
+
+    #[test]
+    fn test_load_error_report() {
+        use crate::module::insn::{BPF_B, BPF_EXIT, BPF_LDX, BPF_MEM, BPF_W};
+        let spec = ProgramSpec {
+            name: "prog".to_string(),
+            section: "xdp".to_string(),
+            offset: 0,
+            prog_type: ProgramType::Xdp,
+            insns: vec![
+                Insn::new(BPF_LDX | BPF_MEM | BPF_W, 2, 1, 0, 0),
+                Insn::new(BPF_LDX | BPF_MEM | BPF_B, 0, 2, 0, 0),
+                Insn::new(BPF_JMP | BPF_EXIT, 0, 0, 0, 0),
+            ],
+            map_relocs: Vec::new(),
+            line_info: vec![SourceLine {
+                insn_off: 0,
+                file: "prog.c".to_string(),
+                line: 12,
+                col: 2,
+                source: "return *(char *)(long)ctx->data;".to_string(),
+            }],
+            core_relocs: Vec::new(),
+            problems: Vec::new(),
+        };
+        let log = "0: R1=ctx() R10=fp0\n\
+                   0: (61) r2 = *(u32 *)(r1 +0)          ; R1=ctx() R2=pkt(r=0)\n\
+                   1: (71) r0 = *(u8 *)(r2 +0)\n\
+                   invalid access to packet, off=0 size=1, R2(id=0,off=0,r=0)\n\
+                   R2 offset is outside of the packet\n\
+                   processed 2 insns (limit 1000000) max_states_per_insn 0 total_states 0\n";
+        let error = explain_load_error(
+            &spec,
+            Error::Load {
+                program: "prog".to_string(),
+                error: std::io::Error::from_raw_os_error(libc::EACCES),
+                log: log.to_string(),
+                report: None,
+            },
+        );
+        match &error {
+            Error::Load { log: raw, .. } => assert_eq!(raw, log),
+            error => panic!("{:?}", error),
+        }
+        let text = error.to_string();
+        assert!(text.starts_with("loading program prog failed: "), "{}", text);
+        assert!(text.contains("error: invalid memory access at insn 1: "), "{}", text);
+        assert!(text.contains("  --> prog.c:12:2\n"), "{}", text);
+        assert!(text.contains(">   1: r0 = *(u8 *)(r2 +0)\n"), "{}", text);
+        assert!(text.contains("registers: R1=ctx() R2=pkt(r=0)\n"), "{}", text);
+        assert!(text.contains("hint: check map lookup results"), "{}", text);
+        assert!(!text.contains("processed 2 insns"), "{}", text);
+    }
+
+    #[test]
+    fn test_core_relocs() {
+        let object = ObjectFile::parse(include_bytes!("../../tests/fixtures/core/prog.o")).unwrap();
+        let local = object.btf.as_ref().unwrap();
+        let prog = object.program("prog").unwrap();
+        let task_struct = local.find_by_name("task_struct").unwrap();
+        let reloc = |insn_off, kind| CoreReloc {
+            insn_off,
+            type_id: task_struct,
+            access: "0:1".to_string(),
+            kind,
+        };
+        assert_eq!(
+            prog.core_relocs,
+            vec![
+                reloc(1, CoreRelocKind::FieldByteOffset),
+                reloc(8, CoreRelocKind::FieldExists)
+            ]
+        );
+
+        // a kernel where tgid moved behind a long
+        let target = btf(vec![
+            int("int", 4, true),
+            int("long", 8, true),
+            ty(
+                "task_struct",
+                BtfKind::Struct {
+                    size: 16,
+                    members: vec![
+                        member("pid", 1, 0),
+                        member("state", 2, 64),
+                        member("tgid", 1, 128),
+                    ],
+                },
+            ),
+        ]);
+        let mut insns = prog.insns.clone();
+        core_reloc::relocate(&mut insns, &prog.core_relocs, local, &target).unwrap();
+        assert_eq!(prog.insns[1].imm, 4);
+        assert_eq!(insns[1].imm, 16);
+        assert_eq!(insns[8].imm, 1);
+
+        // tags are computed over the relocated instructions
+        let mut object = object.clone();
+        object.set_core_target(target);
+        let prog = object.program("prog").unwrap();
+        assert_eq!(object.relocated_insns(prog).unwrap(), insns);
+        assert_eq!(
+            object.tag(prog).unwrap(),
+            tag::prog_tag(&insns, tag::TagHash::running())
+        );
+        assert_ne!(
+            object.tag(prog).unwrap(),
+            tag::prog_tag(&prog.insns, tag::TagHash::running())
+        );
+    }
+
+    #[test]
+    fn test_section_out_of_bounds() {
+        let mut bytes = include_bytes!("../../tests/fixtures/skeleton/prog.o").to_vec();
+        let sh_offset = {
+            let elf = Elf::parse(&bytes).unwrap();
+            let idx = (0..elf.section_headers.len())
+                .find(|&idx| section_name(&elf, idx) == "license")
+                .unwrap();
+            // sh_offset follows sh_name, sh_type, sh_flags and sh_addr
+            elf.header.e_shoff as usize + idx * elf.header.e_shentsize as usize + 24
+        };
+        bytes[sh_offset..sh_offset + 8].copy_from_slice(&(u64::MAX - 1).to_le_bytes());
+        match ObjectFile::parse(&bytes) {
+            Err(Error::Elf(msg)) => assert_eq!(msg, "section license out of bounds"),
+            other => panic!("{:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn test_truncated_ld_imm64() {
+        let mut bytes = include_bytes!("../../tests/fixtures/skeleton/prog.o").to_vec();
+        let (text, rel) = {
+            let elf = Elf::parse(&bytes).unwrap();
+            let offset = |name: &str| {
+                let idx = (0..elf.section_headers.len())
+                    .find(|&idx| section_name(&elf, idx) == name)
+                    .unwrap();
+                elf.section_headers[idx].sh_offset as usize
+            };
+            (
+                offset("tracepoint/syscalls/sys_enter_nanosleep"),
+                offset(".reltracepoint/syscalls/sys_enter_nanosleep"),
+            )
+        };
+        // move the relocation of `calls` onto the last instruction, made
+        // to look like the first half of an ld_imm64
+        let last = 5 * INSN_SIZE;
+        bytes[text + last] = 0x18;
+        bytes[rel..rel + 8].copy_from_slice(&(last as u64).to_le_bytes());
+        match ObjectFile::parse(&bytes) {
+            Err(Error::Elf(msg)) => assert!(msg.contains("not a ld_imm64"), "{}", msg),
+            other => panic!("{:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn test_global_data() {
+        let mut object =
+            ObjectFile::parse(include_bytes!("../../tests/fixtures/skeleton/prog.o")).unwrap();
+        let maps: Vec<_> = object.maps.iter().map(|m| (m.name.as_str(), m.def)).collect();
+        let array = |value_size, map_flags| MapDef {
+            map_type: MapType::Array,
+            key_size: 4,
+            value_size,
+            max_entries: 1,
+            map_flags,
+        };
+        assert_eq!(
+            maps,
+            vec![
+                ("counts", array(8, 0)),
+                (".rodata", array(4, BPF_F_RDONLY_PROG)),
+                (".bss", array(12, 0)),
+                (".data", array(4, 0)),
+            ]
+        );
+        assert_eq!(object.data(".data"), Some(&1i32.to_le_bytes()[..]));
+        assert_eq!(object.data(".bss"), Some(&[0; 12][..]));
+
+        let prog = object.program("on_sleep").unwrap();
+        let reloc = |insn, map: &str| MapReloc {
+            insn,
+            map: map.to_string(),
+        };
+        assert_eq!(
+            prog.map_relocs,
+            vec![
+                reloc(0, ".data"),
+                reloc(6, ".rodata"),
+                reloc(12, ".bss"),
+                reloc(16, ".bss"),
+                reloc(24, "counts"),
+            ]
+        );
+        assert_eq!(prog.insns[12].src(), BPF_PSEUDO_MAP_VALUE);
+        assert_eq!(prog.insns[13].imm, 8);
+        assert_eq!(prog.insns[24].src(), 0);
+
+        // clang leaves the sizes and offsets of the data sections to the loader
+        let btf = object.btf.as_ref().unwrap();
+        let bss = btf.type_by_id(btf.find_by_name(".bss").unwrap()).unwrap();
+        let vars = match &bss.kind {
+            BtfKind::Datasec { size, vars } => {
+                assert_eq!(*size, 12);
+                vars.iter().map(|v| (v.offset, v.size)).collect::<Vec<_>>()
+            }
+            kind => panic!("{:?}", kind),
+        };
+        assert_eq!(vars, vec![(0, 8), (8, 4)]);
+
+        assert!(object.set_data(".rodata", &[1; 3]).is_err());
+        assert!(object.set_data(".text", &[1; 4]).is_err());
+        object.set_data(".rodata", &42i32.to_le_bytes()).unwrap();
+        let mut vm = Vm::from_object(&object, 1).unwrap();
+        vm.set_pid_tgid(7, 7);
+        vm.run(object.program("on_sleep").unwrap(), &mut []).unwrap();
+        assert_eq!(vm.map(".bss").unwrap().lookup(&[0; 4]), Some(vec![0; 12]));
+        vm.set_pid_tgid(42, 42);
+        vm.run(object.program("on_sleep").unwrap(), &mut []).unwrap();
+        let mut bss = 1i64.to_le_bytes().to_vec();
+        bss.extend_from_slice(&42i32.to_le_bytes());
+        assert_eq!(vm.map(".bss").unwrap().lookup(&[0; 4]), Some(bss));
+    }
+}
@@ -0,0 +1,90 @@
+//! Pinning of maps, programs and links in the BPF filesystem.
+
+use crate::error::{Error, Result};
+use crate::sys::syscall;
+use std::ffi::CString;
+use std::fs;
+use std::io;
+use std::mem;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::RawFd;
+use std::path::Path;
+
+pub const DEFAULT_PIN_ROOT: &str = "/sys/fs/bpf";
+
+const BPF_FS_MAGIC: i64 = 0xcafe_4a11;
+
+/// Pinning policy of a map, the `pinning` attribute of a BTF map
+/// definition.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Pinning {
+    None,
+    /// Pin at `<root>/<map name>`, reusing a compatible map already there.
+    ByName,
+}
+
+impl Pinning {
+    /// Decodes `LIBBPF_PIN_NONE` (0) and `LIBBPF_PIN_BY_NAME` (1).
+    pub fn from_u32(value: u32) -> Option<Pinning> {
+        match value {
+            0 => Some(Pinning::None),
+            1 => Some(Pinning::ByName),
+            _ => None,
+        }
+    }
+}
+
+fn c_path(path: &Path) -> Result<CString> {
+    CString::new(path.as_os_str().as_bytes())
+        .map_err(|_| Error::Invalid(format!("invalid path {}", path.display())))
+}
+
+pub fn is_bpffs(path: &Path) -> Result<bool> {
+    let mut stat: libc::statfs = unsafe { mem::zeroed() };
+    let res = unsafe { libc::statfs(c_path(path)?.as_ptr(), &mut stat) };
+    if res < 0 {
+        return Err(Error::Syscall("statfs", io::Error::last_os_error()));
+    }
+    Ok(stat.f_type as i64 == BPF_FS_MAGIC)
+}
+
+/// Makes sure `root` is a directory on a BPF filesystem, mounting one at
+/// `/sys/fs/bpf` first if `root` lives below it and nothing is mounted
+/// there yet.
+pub fn ensure_bpffs(root: &Path) -> Result<()> {
+    let default = Path::new(DEFAULT_PIN_ROOT);
+    if root.starts_with(default) && !is_bpffs(default).unwrap_or(false) {
+        fs::create_dir_all(default)?;
+        let source = CString::new("bpf").unwrap();
+        let res = unsafe {
+            libc::mount(
+                source.as_ptr(),
+                c_path(default)?.as_ptr(),
+                source.as_ptr(),
+                0,
+                std::ptr::null(),
+            )
+        };
+        if res < 0 {
+            return Err(Error::Syscall("mount bpffs", io::Error::last_os_error()));
+        }
+    }
+    fs::create_dir_all(root)?;
+    if !is_bpffs(root)? {
+        return Err(Error::Invalid(format!(
+            "{} is not on a BPF filesystem",
+            root.display()
+        )));
+    }
+    Ok(())
+}
+
+/// Pins the map, program or link behind `fd` at `path`.
+pub fn pin_fd(fd: RawFd, path: &Path) -> Result<()> {
+    syscall::obj_pin(fd, &c_path(path)?).map_err(|e| Error::Syscall("BPF_OBJ_PIN", e))
+}
+
+/// Opens the object pinned at `path`, returning a new file descriptor.
+pub fn get_pinned(path: &Path) -> Result<RawFd> {
+    syscall::obj_get(&c_path(path)?).map_err(|e| Error::Syscall("BPF_OBJ_GET", e))
+}
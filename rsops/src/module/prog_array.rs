@@ -0,0 +1,49 @@
+use crate::error::{Error, Result};
+use crate::module::map::{Map, MapType};
+use crate::module::program::Program;
+use crate::sys::syscall::BPF_ANY;
+use std::os::unix::io::AsRawFd;
+
+/// View of a `BPF_MAP_TYPE_PROG_ARRAY`, the jump table of `bpf_tail_call`.
+///
+/// Updating a slot swaps the program pointer in one step, so a running
+/// pipeline never observes a half installed stage.
+pub struct ProgArray<'a> {
+    map: &'a Map,
+}
+
+impl<'a> ProgArray<'a> {
+    pub fn new(map: &'a Map) -> Result<ProgArray<'a>> {
+        if map.def.map_type != MapType::ProgArray {
+            return Err(Error::Invalid(format!(
+                "map {} is a {} map, not prog_array",
+                map.name,
+                map.def.map_type.name()
+            )));
+        }
+        Ok(ProgArray { map })
+    }
+
+    pub fn set(&self, slot: u32, program: &Program) -> Result<()> {
+        self.check_slot(slot)?;
+        let fd = program.as_raw_fd() as u32;
+        self.map
+            .update(&slot.to_ne_bytes(), &fd.to_ne_bytes(), BPF_ANY)
+    }
+
+    /// Empties `slot`; tail calls to it fall through afterwards.
+    pub fn clear(&self, slot: u32) -> Result<bool> {
+        self.check_slot(slot)?;
+        self.map.delete(&slot.to_ne_bytes())
+    }
+
+    fn check_slot(&self, slot: u32) -> Result<()> {
+        if slot >= self.map.def.max_entries {
+            return Err(Error::Invalid(format!(
+                "slot {} out of range for prog array {} with {} entries",
+                slot, self.map.name, self.map.def.max_entries
+            )));
+        }
+        Ok(())
+    }
+}
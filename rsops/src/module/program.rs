@@ -0,0 +1,254 @@
+use crate::error::{Error, Result};
+use crate::module::insn::Insn;
+use crate::module::pin;
+use crate::sys::syscall;
+use std::ffi::CString;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::path::Path;
+
+#[repr(u32)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ProgramType {
+    Unspec = 0,
+    SocketFilter,
+    Kprobe,
+    SchedCls,
+    SchedAct,
+    Tracepoint,
+    Xdp,
+    PerfEvent,
+    CgroupSkb,
+    CgroupSock,
+    LwtIn,
+    LwtOut,
+    LwtXmit,
+    SockOps,
+    SkSkb,
+    CgroupDevice,
+    SkMsg,
+    RawTracepoint,
+    CgroupSockAddr,
+    LwtSeg6local,
+    LircMode2,
+    SkReuseport,
+    FlowDissector,
+    CgroupSysctl,
+    RawTracepointWritable,
+    CgroupSockopt,
+    Tracing,
+    StructOps,
+    Ext,
+    Lsm,
+    SkLookup,
+}
+
+const PROGRAM_TYPES: [(ProgramType, &str); 31] = [
+    (ProgramType::Unspec, "unspec"),
+    (ProgramType::SocketFilter, "socket_filter"),
+    (ProgramType::Kprobe, "kprobe"),
+    (ProgramType::SchedCls, "sched_cls"),
+    (ProgramType::SchedAct, "sched_act"),
+    (ProgramType::Tracepoint, "tracepoint"),
+    (ProgramType::Xdp, "xdp"),
+    (ProgramType::PerfEvent, "perf_event"),
+    (ProgramType::CgroupSkb, "cgroup_skb"),
+    (ProgramType::CgroupSock, "cgroup_sock"),
+    (ProgramType::LwtIn, "lwt_in"),
+    (ProgramType::LwtOut, "lwt_out"),
+    (ProgramType::LwtXmit, "lwt_xmit"),
+    (ProgramType::SockOps, "sock_ops"),
+    (ProgramType::SkSkb, "sk_skb"),
+    (ProgramType::CgroupDevice, "cgroup_device"),
+    (ProgramType::SkMsg, "sk_msg"),
+    (ProgramType::RawTracepoint, "raw_tracepoint"),
+    (ProgramType::CgroupSockAddr, "cgroup_sock_addr"),
+    (ProgramType::LwtSeg6local, "lwt_seg6local"),
+    (ProgramType::LircMode2, "lirc_mode2"),
+    (ProgramType::SkReuseport, "sk_reuseport"),
+    (ProgramType::FlowDissector, "flow_dissector"),
+    (ProgramType::CgroupSysctl, "cgroup_sysctl"),
+    (ProgramType::RawTracepointWritable, "raw_tracepoint_writable"),
+    (ProgramType::CgroupSockopt, "cgroup_sockopt"),
+    (ProgramType::Tracing, "tracing"),
+    (ProgramType::StructOps, "struct_ops"),
+    (ProgramType::Ext, "ext"),
+    (ProgramType::Lsm, "lsm"),
+    (ProgramType::SkLookup, "sk_lookup"),
+];
+
+/// ELF section name prefixes and the program type they imply.
+const SECTION_PREFIXES: [(&str, ProgramType); 28] = [
+    ("socket", ProgramType::SocketFilter),
+    ("kprobe/", ProgramType::Kprobe),
+    ("kretprobe/", ProgramType::Kprobe),
+    ("uprobe/", ProgramType::Kprobe),
+    ("uretprobe/", ProgramType::Kprobe),
+    ("classifier", ProgramType::SchedCls),
+    ("tc", ProgramType::SchedCls),
+    ("action", ProgramType::SchedAct),
+    ("tracepoint/", ProgramType::Tracepoint),
+    ("tp/", ProgramType::Tracepoint),
+    ("raw_tracepoint/", ProgramType::RawTracepoint),
+    ("raw_tp/", ProgramType::RawTracepoint),
+    ("xdp", ProgramType::Xdp),
+    ("perf_event", ProgramType::PerfEvent),
+    ("cgroup/skb", ProgramType::CgroupSkb),
+    ("cgroup_skb/", ProgramType::CgroupSkb),
+    ("cgroup/sock", ProgramType::CgroupSock),
+    ("cgroup/sock_addr", ProgramType::CgroupSockAddr),
+    ("cgroup/sockopt", ProgramType::CgroupSockopt),
+    ("cgroup/dev", ProgramType::CgroupDevice),
+    ("lwt_in", ProgramType::LwtIn),
+    ("lwt_out", ProgramType::LwtOut),
+    ("lwt_xmit", ProgramType::LwtXmit),
+    ("sockops", ProgramType::SockOps),
+    ("sk_skb", ProgramType::SkSkb),
+    ("sk_msg", ProgramType::SkMsg),
+    ("flow_dissector", ProgramType::FlowDissector),
+    ("sk_reuseport", ProgramType::SkReuseport),
+];
+
+impl ProgramType {
+    pub fn from_u32(value: u32) -> Option<ProgramType> {
+        PROGRAM_TYPES.get(value as usize).map(|(t, _)| *t)
+    }
+
+    pub fn name(self) -> &'static str {
+        PROGRAM_TYPES[self as usize].1
+    }
+
+    /// Infers the program type from an ELF section name such as
+    /// `kprobe/do_sys_open` or `xdp`. The longest matching prefix wins, so
+    /// `cgroup/sockopt` is not taken for `cgroup/sock`.
+    pub fn from_section(section: &str) -> Option<ProgramType> {
+        SECTION_PREFIXES
+            .iter()
+            .filter(|(prefix, _)| section.starts_with(prefix))
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map(|(_, t)| *t)
+    }
+}
+
+/// Size of the verifier log buffer used when a load fails.
+const LOG_SIZE: usize = 1 << 20;
+
+/// A program loaded into the kernel. The file descriptor is closed on drop.
+#[derive(Debug)]
+pub struct Program {
+    pub name: String,
+    pub prog_type: ProgramType,
+    fd: RawFd,
+}
+
+impl Program {
+    /// Loads `insns` into the kernel. The load is first attempted without a
+    /// verifier log; if it fails it is retried with one so the returned
+    /// error carries the verifier's explanation.
+    pub fn load(
+        name: &str,
+        prog_type: ProgramType,
+        insns: &[Insn],
+        license: &str,
+        kern_version: u32,
+    ) -> Result<Program> {
+        let license = CString::new(license)
+            .map_err(|_| Error::Invalid(format!("program {}: invalid license", name)))?;
+        let mut attr = syscall::ProgLoadAttr {
+            prog_type: prog_type as u32,
+            insn_cnt: insns.len() as u32,
+            insns: insns.as_ptr() as u64,
+            license: license.as_ptr() as u64,
+            kern_version,
+            prog_name: syscall::obj_name(name),
+            ..Default::default()
+        };
+        let error = match syscall::prog_load(&mut attr) {
+            Ok(fd) => return Ok(Program::from_raw_fd(name, prog_type, fd)),
+            Err(e) => e,
+        };
+
+        let mut log = vec![0u8; LOG_SIZE];
+        attr.log_level = 1;
+        attr.log_size = log.len() as u32;
+        attr.log_buf = log.as_mut_ptr() as u64;
+        match syscall::prog_load(&mut attr) {
+            Ok(fd) => Ok(Program::from_raw_fd(name, prog_type, fd)),
+            Err(_) => {
+                let len = log.iter().position(|&b| b == 0).unwrap_or(log.len());
+                Err(Error::Load {
+                    program: name.to_string(),
+                    error,
+                    log: String::from_utf8_lossy(&log[..len]).into_owned(),
+                    report: None,
+                })
+            }
+        }
+    }
+
+    /// Takes ownership of an already open program file descriptor.
+    pub fn from_raw_fd(name: &str, prog_type: ProgramType, fd: RawFd) -> Program {
+        Program {
+            name: name.to_string(),
+            prog_type,
+            fd,
+        }
+    }
+
+    /// Opens the loaded program with kernel id `id`.
+    pub fn from_id(id: u32) -> Result<Program> {
+        let fd = syscall::obj_get_fd_by_id(syscall::BPF_PROG_GET_FD_BY_ID, id)
+            .map_err(|e| Error::Syscall("BPF_PROG_GET_FD_BY_ID", e))?;
+        let mut program = Program::from_raw_fd("", ProgramType::Unspec, fd);
+        let info = program.info()?;
+        program.name = obj_name_to_string(&info.name);
+        program.prog_type = ProgramType::from_u32(info.prog_type).unwrap_or(ProgramType::Unspec);
+        Ok(program)
+    }
+
+    pub fn info(&self) -> Result<syscall::ProgInfo> {
+        let mut info = syscall::ProgInfo::default();
+        syscall::obj_get_info(self.fd, &mut info)
+            .map_err(|e| Error::Syscall("BPF_OBJ_GET_INFO_BY_FD", e))?;
+        Ok(info)
+    }
+
+    pub fn pin(&self, path: &Path) -> Result<()> {
+        pin::pin_fd(self.fd, path)
+    }
+}
+
+/// Decodes the NUL padded name of a `bpf_*_info` struct.
+pub fn obj_name_to_string(name: &[u8]) -> String {
+    let len = name.iter().position(|&b| b == 0).unwrap_or(name.len());
+    String::from_utf8_lossy(&name[..len]).into_owned()
+}
+
+impl AsRawFd for Program {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd
+    }
+}
+
+impl Drop for Program {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.fd);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_from_section() {
+        let from = ProgramType::from_section;
+        assert_eq!(from("xdp/tail/jmp_table/1"), Some(ProgramType::Xdp));
+        assert_eq!(from("cgroup/sock"), Some(ProgramType::CgroupSock));
+        assert_eq!(from("cgroup/sock_addr/connect4"), Some(ProgramType::CgroupSockAddr));
+        assert_eq!(from("cgroup/sockopt"), Some(ProgramType::CgroupSockopt));
+        assert_eq!(from("cgroup/skb"), Some(ProgramType::CgroupSkb));
+        assert_eq!(from(".text"), None);
+    }
+}
@@ -0,0 +1,201 @@
+//! Typed access to the keyless map types: `BPF_MAP_TYPE_QUEUE`,
+//! `BPF_MAP_TYPE_STACK` and `BPF_MAP_TYPE_BLOOM_FILTER`.
+//!
+//! These maps have a key size of zero and are driven with a null key:
+//! update pushes, `BPF_MAP_LOOKUP_ELEM` peeks and
+//! `BPF_MAP_LOOKUP_AND_DELETE_ELEM` pops.
+
+use crate::error::{Error, Result};
+use crate::module::map::{pod_bytes, pod_read, Map, MapType, Pod};
+use crate::sys::syscall::{self, BPF_ANY, BPF_EXIST};
+use std::io;
+use std::marker::PhantomData;
+use std::mem;
+use std::os::unix::io::AsRawFd;
+
+/// Checks that `map` is a keyless map of `map_type` holding `V` values.
+fn check_map<V: Pod>(map: &Map, map_type: MapType) -> Result<()> {
+    if map.def.map_type != map_type {
+        return Err(Error::Invalid(format!(
+            "map {} is a {} map, not {}",
+            map.name,
+            map.def.map_type.name(),
+            map_type.name()
+        )));
+    }
+    if map.def.key_size != 0 || map.def.value_size as usize != mem::size_of::<V>() {
+        return Err(Error::Invalid(format!(
+            "map {}: value is {} bytes, expected {}",
+            map.name,
+            map.def.value_size,
+            mem::size_of::<V>()
+        )));
+    }
+    Ok(())
+}
+
+/// Update flags of a push: `BPF_EXIST` makes a full queue or stack evict
+/// its oldest element instead of failing.
+fn push_flags(overwrite: bool) -> u64 {
+    if overwrite {
+        BPF_EXIST
+    } else {
+        BPF_ANY
+    }
+}
+
+fn push<V: Pod>(map: &Map, value: &V, overwrite: bool) -> Result<()> {
+    let flags = push_flags(overwrite);
+    syscall::map_update_elem(map.as_raw_fd(), None, pod_bytes(value), flags)
+        .map_err(|e| Error::Syscall("BPF_MAP_UPDATE_ELEM", e))
+}
+
+/// Maps `ENOENT`, an empty queue or a missing bloom filter entry, to `None`.
+fn not_found<T>(op: &'static str, result: io::Result<T>) -> Result<Option<T>> {
+    match result {
+        Ok(value) => Ok(Some(value)),
+        Err(e) if e.raw_os_error() == Some(libc::ENOENT) => Ok(None),
+        Err(e) => Err(Error::Syscall(op, e)),
+    }
+}
+
+fn peek<V: Pod>(map: &Map) -> Result<Option<V>> {
+    let mut value = vec![0u8; mem::size_of::<V>()];
+    let found = not_found(
+        "BPF_MAP_LOOKUP_ELEM",
+        syscall::map_lookup_elem(map.as_raw_fd(), None, &mut value),
+    )?;
+    Ok(found.map(|_| pod_read(&value)))
+}
+
+fn pop<V: Pod>(map: &Map) -> Result<Option<V>> {
+    let mut value = vec![0u8; mem::size_of::<V>()];
+    let found = not_found(
+        "BPF_MAP_LOOKUP_AND_DELETE_ELEM",
+        syscall::map_lookup_and_delete_elem(map.as_raw_fd(), None, &mut value),
+    )?;
+    Ok(found.map(|_| pod_read(&value)))
+}
+
+macro_rules! keyless_map {
+    ($(#[$doc:meta])* $name:ident, $map_type:expr) => {
+        $(#[$doc])*
+        pub struct $name<'a, V: Pod> {
+            map: &'a Map,
+            _value: PhantomData<V>,
+        }
+
+        impl<'a, V: Pod> $name<'a, V> {
+            pub fn new(map: &'a Map) -> Result<$name<'a, V>> {
+                check_map::<V>(map, $map_type)?;
+                Ok($name {
+                    map,
+                    _value: PhantomData,
+                })
+            }
+
+            /// Adds `value`. Fails with `E2BIG` when the map is full.
+            pub fn push(&self, value: &V) -> Result<()> {
+                push(self.map, value, false)
+            }
+
+            /// Adds `value`, evicting the oldest element when the map is
+            /// full.
+            pub fn push_overwrite(&self, value: &V) -> Result<()> {
+                push(self.map, value, true)
+            }
+
+            /// Removes and returns the next element, `None` when empty.
+            pub fn pop(&self) -> Result<Option<V>> {
+                pop(self.map)
+            }
+
+            /// Returns the next element without removing it.
+            pub fn peek(&self) -> Result<Option<V>> {
+                peek(self.map)
+            }
+        }
+    };
+}
+
+keyless_map!(
+    /// FIFO shared between user space and programs, `BPF_MAP_TYPE_QUEUE`.
+    BpfQueue,
+    MapType::Queue
+);
+
+keyless_map!(
+    /// LIFO shared between user space and programs, `BPF_MAP_TYPE_STACK`.
+    BpfStack,
+    MapType::Stack
+);
+
+/// Probabilistic set, `BPF_MAP_TYPE_BLOOM_FILTER`. Lookups may report false
+/// positives but never false negatives, and elements cannot be removed.
+pub struct BloomFilter<'a, V: Pod> {
+    map: &'a Map,
+    _value: PhantomData<V>,
+}
+
+impl<'a, V: Pod> BloomFilter<'a, V> {
+    pub fn new(map: &'a Map) -> Result<BloomFilter<'a, V>> {
+        check_map::<V>(map, MapType::BloomFilter)?;
+        Ok(BloomFilter {
+            map,
+            _value: PhantomData,
+        })
+    }
+
+    pub fn insert(&self, value: &V) -> Result<()> {
+        push(self.map, value, false)
+    }
+
+    pub fn contains(&self, value: &V) -> Result<bool> {
+        // the kernel reads the value to test from the value pointer
+        let mut probe = pod_bytes(value).to_vec();
+        let found = not_found(
+            "BPF_MAP_LOOKUP_ELEM",
+            syscall::map_lookup_elem(self.map.as_raw_fd(), None, &mut probe),
+        )?;
+        Ok(found.is_some())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::module::map::MapDef;
+
+    fn map(map_type: MapType, key_size: u32, value_size: u32) -> Map {
+        let def = MapDef {
+            map_type,
+            key_size,
+            value_size,
+            max_entries: 16,
+            map_flags: 0,
+        };
+        Map::from_raw_fd("test", def, -1)
+    }
+
+    #[test]
+    fn test_check_map() {
+        assert!(BpfQueue::<u64>::new(&map(MapType::Queue, 0, 8)).is_ok());
+        assert!(BpfStack::<u32>::new(&map(MapType::Stack, 0, 4)).is_ok());
+        assert!(BloomFilter::<u64>::new(&map(MapType::BloomFilter, 0, 8)).is_ok());
+
+        // wrong map type
+        assert!(BpfQueue::<u64>::new(&map(MapType::Stack, 0, 8)).is_err());
+        assert!(BpfStack::<u64>::new(&map(MapType::Queue, 0, 8)).is_err());
+        assert!(BloomFilter::<u64>::new(&map(MapType::Hash, 0, 8)).is_err());
+        // keyed or of the wrong value size
+        assert!(BpfQueue::<u64>::new(&map(MapType::Queue, 4, 8)).is_err());
+        assert!(BpfStack::<u32>::new(&map(MapType::Stack, 0, 8)).is_err());
+        assert!(BloomFilter::<u64>::new(&map(MapType::BloomFilter, 0, 4)).is_err());
+    }
+
+    #[test]
+    fn test_push_flags() {
+        assert_eq!(push_flags(false), BPF_ANY);
+        assert_eq!(push_flags(true), BPF_EXIST);
+    }
+}
@@ -0,0 +1,393 @@
+//! Generates a Rust skeleton for a compiled BPF object, so tools use
+//! typed handles instead of looking maps and programs up by name.
+//!
+//! The skeleton embeds the object with `include_bytes!` and declares
+//!
+//! * `OpenSkeleton`, the parsed object with the initial contents of each
+//!   global data section (`.rodata`, `.data`, `.bss`) as a struct field,
+//!   e.g. to set `const volatile` configuration before `load()`;
+//! * `Skeleton`, the loaded object, with `attach()` for every kprobe and
+//!   tracepoint program, `maps()` and `progs()` returning a struct of
+//!   handles with one field per map or program, and getters and setters
+//!   for the global data sections;
+//! * a `types` module with the bindings of the data sections, one struct
+//!   per section named after it (`types::bss`), and the types they use.
+//!
+//! From a build script:
+//!
+//! ```ignore
+//! let out = Path::new(&env::var("OUT_DIR")?).join("probe.skel.rs");
+//! skeleton::write("probe.o", &out)?;
+//! ```
+//!
+//! and in the crate:
+//!
+//! ```ignore
+//! mod probe {
+//!     include!(concat!(env!("OUT_DIR"), "/probe.skel.rs"));
+//! }
+//!
+//! let mut open = probe::OpenSkeleton::new()?;
+//! open.rodata.target_pid = pid;
+//! let mut skel = open.load()?;
+//! skel.attach()?;
+//! let calls = skel.bss()?.calls;
+//! ```
+
+use crate::error::{Error, Result};
+use crate::module::btf::{Btf, BtfKind, BtfType, Member, BTF_VAR_GLOBAL_ALLOCATED};
+use crate::module::btf_rust::{self, ident, type_ident};
+use crate::module::object::ObjectFile;
+use std::collections::HashSet;
+use std::fmt::Write;
+use std::fs;
+use std::path::Path;
+
+/// A global data section with BTF, and the struct generated for it.
+struct DataSection {
+    section: String,
+    ident: String,
+    ty: String,
+}
+
+/// Rust identifiers for `names`, made unique by appending `_`.
+fn idents<'a, I: IntoIterator<Item = &'a str>>(names: I) -> Vec<String> {
+    let mut used = HashSet::new();
+    names
+        .into_iter()
+        .map(|name| {
+            let mut ident = ident(&name.replace(|c: char| !c.is_ascii_alphanumeric(), "_"));
+            while !used.insert(ident.clone()) {
+                ident.push('_');
+            }
+            ident
+        })
+        .collect()
+}
+
+/// Section `name` without its leading dot and as an identifier, e.g.
+/// `data_counters` for `.data.counters`.
+fn section_ident(name: &str) -> String {
+    name.trim_start_matches('.')
+        .replace(|c: char| !c.is_ascii_alphanumeric(), "_")
+}
+
+/// Adds a struct with the global variables of each data section of
+/// `object` to a copy of its BTF, returning it with the sections.
+fn data_sections(object: &ObjectFile) -> Result<(Option<Btf>, Vec<DataSection>)> {
+    let btf = match &object.btf {
+        Some(btf) => btf,
+        None => return Ok((None, Vec::new())),
+    };
+    let mut btf = btf.clone();
+    let mut sections = Vec::new();
+    for map in &object.maps {
+        let data = match object.data(&map.name) {
+            Some(data) => data,
+            None => continue,
+        };
+        let vars = match btf
+            .find_by_name(&map.name)
+            .map(|id| &btf.types[id as usize].kind)
+        {
+            Some(BtfKind::Datasec { vars, .. }) => vars,
+            _ => continue,
+        };
+        let mut members = Vec::new();
+        for var in vars {
+            let ty = btf.type_by_id(var.type_id)?;
+            match ty.kind {
+                BtfKind::Var { type_id, linkage } if linkage == BTF_VAR_GLOBAL_ALLOCATED => members
+                    .push(Member {
+                        name: ty.name.clone(),
+                        type_id,
+                        bit_offset: var.offset * 8,
+                        bitfield_size: 0,
+                    }),
+                _ => {}
+            }
+        }
+        members.sort_by_key(|m| m.bit_offset);
+
+        let mut name = section_ident(&map.name);
+        while btf.find_by_name(&name).is_some() {
+            name.push('_');
+        }
+        sections.push(DataSection {
+            section: map.name.clone(),
+            ident: String::new(),
+            ty: type_ident(&name),
+        });
+        btf.types.push(BtfType {
+            name,
+            kind: BtfKind::Struct {
+                size: data.len() as u32,
+                members,
+            },
+        });
+    }
+    let names = idents(sections.iter().map(|s| s.section.trim_start_matches('.')));
+    for (section, ident) in sections.iter_mut().zip(names) {
+        section.ident = ident;
+    }
+    Ok((Some(btf), sections))
+}
+
+/// Renders the skeleton of the object file at `path`. The object is
+/// embedded by its absolute path.
+pub fn generate<P: AsRef<Path>>(path: P) -> Result<String> {
+    let path = fs::canonicalize(path.as_ref())?;
+    let object = ObjectFile::open(&path)?;
+    let (btf, sections) = data_sections(&object)?;
+    let maps = idents(object.maps.iter().map(|m| m.name.trim_start_matches('.')));
+    let progs = idents(object.programs.iter().map(|p| p.name.as_str()));
+
+    let mut out = String::new();
+    let w = &mut out;
+    writeln!(
+        w,
+        "// Generated by rsops from {}, do not edit.",
+        path.display()
+    )
+    .unwrap();
+    if let Some(btf) = &btf {
+        if !sections.is_empty() {
+            let names: Vec<_> = btf.types[btf.types.len() - sections.len()..]
+                .iter()
+                .map(|ty| ty.name.as_str())
+                .collect();
+            writeln!(w, "\n/// Global data sections and the types they use.").unwrap();
+            writeln!(w, "pub mod types {{").unwrap();
+            w.push_str(&btf_rust::rust_types(btf, &names)?);
+            writeln!(w, "}}").unwrap();
+        }
+    }
+
+    let object_path = path
+        .to_str()
+        .ok_or_else(|| Error::Invalid(format!("invalid path {}", path.display())))?;
+    writeln!(w, "\n/// The object file the skeleton was generated from.").unwrap();
+    writeln!(
+        w,
+        "pub const OBJECT: &[u8] = include_bytes!({:?});",
+        object_path
+    )
+    .unwrap();
+    writeln!(
+        w,
+        "\n/// Each program and the section it is attached through."
+    )
+    .unwrap();
+    writeln!(w, "#[allow(dead_code)]").unwrap();
+    writeln!(
+        w,
+        "pub const PROGRAMS: [(&str, &str); {}] = [",
+        object.programs.len()
+    )
+    .unwrap();
+    for program in &object.programs {
+        writeln!(w, "    ({:?}, {:?}),", program.name, program.section).unwrap();
+    }
+    writeln!(w, "];").unwrap();
+
+    w.push_str(
+        "\n/// The parsed object, with the initial contents of its global data\n\
+         /// sections.\n\
+         #[allow(dead_code)]\n\
+         pub struct OpenSkeleton {\n    \
+             pub object: ::rsops::module::object::ObjectFile,\n",
+    );
+    for s in &sections {
+        writeln!(w, "    pub {}: types::{},", s.ident, s.ty).unwrap();
+    }
+    w.push_str(
+        "}\n\n\
+         #[allow(dead_code)]\n\
+         impl OpenSkeleton {\n    \
+             pub fn new() -> ::rsops::Result<OpenSkeleton> {\n        \
+                 let object = ::rsops::module::object::ObjectFile::parse(OBJECT)?;\n        \
+                 Ok(OpenSkeleton {\n",
+    );
+    for s in &sections {
+        writeln!(
+            w,
+            "            {}: ::rsops::module::map::pod_read(object.data({:?}).unwrap()),",
+            s.ident, s.section
+        )
+        .unwrap();
+    }
+    w.push_str(
+        "            object,\n        \
+                 })\n    \
+             }\n\n    \
+             /// Creates the maps, with the global data sections holding the\n    \
+             /// contents of `self`, and loads the programs.\n    \
+             pub fn load(mut self) -> ::rsops::Result<Skeleton> {\n",
+    );
+    for s in &sections {
+        writeln!(
+            w,
+            "        self.object\n            \
+             .set_data({:?}, ::rsops::module::map::pod_bytes(&self.{}))?;",
+            s.section, s.ident
+        )
+        .unwrap();
+    }
+    w.push_str(
+        "        Ok(Skeleton {\n            \
+                 object: self.object.load()?,\n            \
+                 links: Vec::new(),\n        \
+             })\n    \
+         }\n\
+         }\n",
+    );
+
+    w.push_str(
+        "\n/// The loaded object.\n\
+         pub struct Skeleton {\n    \
+             object: ::rsops::module::object::Object,\n    \
+             links: Vec<::rsops::module::link::Link>,\n\
+         }\n\n\
+         #[allow(dead_code)]\n\
+         impl Skeleton {\n    \
+             /// Loads the object with the initial contents of its global data.\n    \
+             pub fn load() -> ::rsops::Result<Skeleton> {\n        \
+                 OpenSkeleton::new()?.load()\n    \
+             }\n\n    \
+             /// Attaches every kprobe and tracepoint program as its section\n    \
+             /// says, replacing earlier attachments.\n    \
+             pub fn attach(&mut self) -> ::rsops::Result<()> {\n        \
+                 self.links.clear();\n        \
+                 for (name, section) in PROGRAMS.iter() {\n            \
+                     if let Some(point) = ::rsops::module::link::AttachPoint::from_section(section) {\n                \
+                         let link = point.attach(self.object.program(name).unwrap())?;\n                \
+                         self.links.push(link);\n            \
+                     }\n        \
+                 }\n        \
+                 Ok(())\n    \
+             }\n\n    \
+             pub fn detach(&mut self) {\n        \
+                 self.links.clear();\n    \
+             }\n\n    \
+             pub fn object(&self) -> &::rsops::module::object::Object {\n        \
+                 &self.object\n    \
+             }\n\n    \
+             pub fn maps(&self) -> Maps<'_> {\n        \
+                 Maps {\n",
+    );
+    for (map, ident) in object.maps.iter().zip(&maps) {
+        writeln!(
+            w,
+            "            {}: self.object.map({:?}).unwrap(),",
+            ident, map.name
+        )
+        .unwrap();
+    }
+    w.push_str(
+        "        }\n    \
+             }\n\n    \
+             pub fn progs(&self) -> Progs<'_> {\n        \
+                 Progs {\n",
+    );
+    for (program, ident) in object.programs.iter().zip(&progs) {
+        writeln!(
+            w,
+            "            {}: self.object.program({:?}).unwrap(),",
+            ident, program.name
+        )
+        .unwrap();
+    }
+    w.push_str("        }\n    }\n");
+    for s in &sections {
+        writeln!(
+            w,
+            "\n    pub fn {}(&self) -> ::rsops::Result<types::{}> {{\n        \
+             self.object.data({:?})\n    \
+             }}",
+            s.ident, s.ty, s.section
+        )
+        .unwrap();
+        if !s.section.starts_with(".rodata") {
+            writeln!(
+                w,
+                "\n    pub fn set_{}(&self, value: &types::{}) -> ::rsops::Result<()> {{\n        \
+                 self.object.set_data({:?}, value)\n    \
+                 }}",
+                s.ident, s.ty, s.section
+            )
+            .unwrap();
+        }
+    }
+    w.push_str("}\n");
+
+    w.push_str("\n#[allow(dead_code)]\npub struct Maps<'a> {\n");
+    for ident in &maps {
+        writeln!(w, "    pub {}: &'a ::rsops::module::map::Map,", ident).unwrap();
+    }
+    w.push_str("}\n\n#[allow(dead_code)]\npub struct Progs<'a> {\n");
+    for ident in &progs {
+        writeln!(
+            w,
+            "    pub {}: &'a ::rsops::module::program::Program,",
+            ident
+        )
+        .unwrap();
+    }
+    w.push_str("}\n");
+    Ok(out)
+}
+
+/// Writes the skeleton of the object file at `object` to `out`, e.g. from
+/// a build script.
+pub fn write<P: AsRef<Path>, Q: AsRef<Path>>(object: P, out: Q) -> Result<()> {
+    fs::write(out, generate(object)?)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_idents() {
+        assert_eq!(section_ident(".data.counters"), "data_counters");
+        assert_eq!(
+            idents(vec!["rodata", "data.x", "data_x", "type"]),
+            vec!["rodata", "data_x", "data_x_", "r#type"]
+        );
+    }
+
+    #[test]
+    fn test_data_sections() {
+        let object =
+            ObjectFile::parse(include_bytes!("../../tests/fixtures/skeleton/prog.o")).unwrap();
+        let (btf, sections) = data_sections(&object).unwrap();
+        let sections: Vec<_> = sections
+            .iter()
+            .map(|s| (s.section.as_str(), s.ident.as_str(), s.ty.as_str()))
+            .collect();
+        assert_eq!(
+            sections,
+            vec![
+                (".rodata", "rodata", "rodata"),
+                (".bss", "bss", "bss"),
+                (".data", "data", "data"),
+            ]
+        );
+        let types = btf_rust::rust_types(&btf.unwrap(), &["rodata", "bss", "data"]).unwrap();
+        assert!(types.contains("pub struct bss {\n    pub calls: i64,\n    pub last_pid: i32,\n"));
+        assert!(types.contains("pub struct rodata {\n    pub target_pid: i32,\n}"));
+    }
+
+    #[test]
+    fn test_generate() {
+        // the output of `rsops skeleton` with the object's path replaced
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/skeleton/prog.o");
+        let absolute = fs::canonicalize(&path).unwrap();
+        let out = generate(&path).unwrap();
+        assert_eq!(
+            out.replace(absolute.to_str().unwrap(), "<path>"),
+            include_str!("../../tests/fixtures/skeleton/skeleton.rs")
+        );
+    }
+}
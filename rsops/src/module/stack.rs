@@ -0,0 +1,91 @@
+use crate::error::{Error, Result};
+use crate::module::map::{Map, MapType};
+use crate::module::symbols::{Frame, Symbolizer};
+use std::convert::TryInto;
+
+/// Default depth of a stack trace map value, `PERF_MAX_STACK_DEPTH`.
+pub const PERF_MAX_STACK_DEPTH: u32 = 127;
+
+/// Reader for `BPF_MAP_TYPE_STACK_TRACE` maps filled by `bpf_get_stackid`.
+///
+/// Keys are the stack ids handed out by the helper, values are arrays of
+/// instruction pointers terminated by the first zero entry.
+pub struct StackTraceMap<'a> {
+    map: &'a Map,
+}
+
+impl<'a> StackTraceMap<'a> {
+    pub fn new(map: &'a Map) -> Result<StackTraceMap<'a>> {
+        if map.def.map_type != MapType::StackTrace {
+            return Err(Error::Invalid(format!(
+                "map {} is a {} map, not stack_trace",
+                map.name,
+                map.def.map_type.name()
+            )));
+        }
+        Ok(StackTraceMap { map })
+    }
+
+    /// Returns the frames of `stack_id`, innermost first.
+    ///
+    /// Negative ids are the error codes `bpf_get_stackid` returns when a
+    /// stack could not be collected and never have an entry.
+    pub fn get(&self, stack_id: i64) -> Result<Option<Vec<u64>>> {
+        if stack_id < 0 {
+            return Ok(None);
+        }
+        let key = (stack_id as u32).to_ne_bytes();
+        Ok(self.map.lookup(&key)?.map(|value| parse_frames(&value)))
+    }
+
+    pub fn remove(&self, stack_id: i64) -> Result<bool> {
+        if stack_id < 0 {
+            return Ok(false);
+        }
+        self.map.delete(&(stack_id as u32).to_ne_bytes())
+    }
+
+    /// Returns every stack id currently stored in the map.
+    pub fn stack_ids(&self) -> Result<Vec<u32>> {
+        self.map
+            .keys()
+            .map(|key| key.map(|k| u32::from_ne_bytes(k[..4].try_into().unwrap())))
+            .collect()
+    }
+
+    /// Fetches a kernel stack and resolves it against `/proc/kallsyms`.
+    pub fn kernel_frames(
+        &self,
+        stack_id: i64,
+        symbolizer: &mut Symbolizer,
+    ) -> Result<Option<Vec<Frame>>> {
+        Ok(self.get(stack_id)?.map(|ips| {
+            ips.iter()
+                .map(|&ip| symbolizer.symbolize_kernel(ip))
+                .collect()
+        }))
+    }
+
+    /// Fetches a user stack of process `pid` and resolves it against the
+    /// ELF symbol tables of the mapped binaries.
+    pub fn user_frames(
+        &self,
+        stack_id: i64,
+        pid: u32,
+        symbolizer: &mut Symbolizer,
+    ) -> Result<Option<Vec<Frame>>> {
+        Ok(self.get(stack_id)?.map(|ips| {
+            ips.iter()
+                .map(|&ip| symbolizer.symbolize_user(pid, ip))
+                .collect()
+        }))
+    }
+}
+
+fn parse_frames(value: &[u8]) -> Vec<u64> {
+    value
+        .chunks_exact(8)
+        .map(|ip| u64::from_ne_bytes(ip.try_into().unwrap()))
+        .take_while(|&ip| ip != 0)
+        .collect()
+}
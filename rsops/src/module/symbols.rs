@@ -0,0 +1,412 @@
+//! Address to symbol resolution for stack traces.
+//!
+//! Kernel addresses are looked up in `/proc/kallsyms`. User addresses are
+//! translated through `/proc/<pid>/maps` to an offset in the mapped binary
+//! and looked up in its ELF symbol tables. Parsed binaries are cached by
+//! their GNU build-id so the same library mapped into many processes, or
+//! reached through different paths, is only read once.
+
+use crate::error::Result;
+use goblin::elf::{note, program_header, sym, Elf};
+use std::collections::HashMap;
+use std::fmt::{self, Display};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Symbol {
+    pub name: String,
+    pub addr: u64,
+    pub size: u64,
+    pub module: Option<String>,
+}
+
+/// A resolved stack frame.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Frame {
+    pub addr: u64,
+    pub symbol: Option<String>,
+    pub offset: u64,
+    pub module: Option<String>,
+}
+
+impl Frame {
+    fn unknown(addr: u64, module: Option<String>) -> Frame {
+        Frame {
+            addr,
+            symbol: None,
+            offset: 0,
+            module,
+        }
+    }
+}
+
+impl Display for Frame {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.symbol {
+            Some(name) => write!(f, "{}+{:#x}", name, self.offset)?,
+            None => write!(f, "{:#x}", self.addr)?,
+        }
+        if let Some(module) = &self.module {
+            write!(f, " [{}]", module)?;
+        }
+        Ok(())
+    }
+}
+
+/// Symbols sorted by address, answering "which symbol covers this address".
+#[derive(Debug, Default)]
+struct SymbolTable {
+    syms: Vec<Symbol>,
+}
+
+impl SymbolTable {
+    fn new(mut syms: Vec<Symbol>) -> SymbolTable {
+        syms.sort_by_key(|s| s.addr);
+        SymbolTable { syms }
+    }
+
+    /// Finds the closest symbol at or below `addr`. Symbols with a known size
+    /// must contain the address; zero sized ones (kallsyms) extend up to the
+    /// next symbol.
+    fn resolve(&self, addr: u64) -> Option<(&Symbol, u64)> {
+        let idx = match self.syms.binary_search_by_key(&addr, |s| s.addr) {
+            Ok(mut idx) => {
+                while idx + 1 < self.syms.len() && self.syms[idx + 1].addr == addr {
+                    idx += 1;
+                }
+                idx
+            }
+            Err(0) => return None,
+            Err(idx) => idx - 1,
+        };
+        let sym = &self.syms[idx];
+        let offset = addr - sym.addr;
+        if sym.size != 0 && offset >= sym.size {
+            return None;
+        }
+        Some((sym, offset))
+    }
+}
+
+/// Parsed `/proc/kallsyms`.
+#[derive(Debug)]
+pub struct KernelSymbols {
+    table: SymbolTable,
+}
+
+impl KernelSymbols {
+    pub fn load() -> Result<KernelSymbols> {
+        Ok(KernelSymbols::parse(&fs::read_to_string("/proc/kallsyms")?))
+    }
+
+    /// Parses lines of the form `ffffffff81000000 T _stext [module]`. Only
+    /// text symbols are kept. With `kptr_restrict` every address reads as
+    /// zero, which leaves an empty table.
+    pub fn parse(kallsyms: &str) -> KernelSymbols {
+        let syms = kallsyms
+            .lines()
+            .filter_map(|line| {
+                let mut fields = line.split_whitespace();
+                let addr = u64::from_str_radix(fields.next()?, 16).ok()?;
+                let kind = fields.next()?;
+                let name = fields.next()?;
+                let module = fields
+                    .next()
+                    .map(|m| m.trim_start_matches('[').trim_end_matches(']').to_string());
+                if addr == 0 || !matches!(kind, "t" | "T" | "w" | "W") {
+                    return None;
+                }
+                Some(Symbol {
+                    name: name.to_string(),
+                    addr,
+                    size: 0,
+                    module,
+                })
+            })
+            .collect();
+        KernelSymbols {
+            table: SymbolTable::new(syms),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.table.syms.is_empty()
+    }
+
+    pub fn resolve(&self, addr: u64) -> Frame {
+        match self.table.resolve(addr) {
+            Some((sym, offset)) => Frame {
+                addr,
+                symbol: Some(sym.name.clone()),
+                offset,
+                module: sym.module.clone(),
+            },
+            None => Frame::unknown(addr, None),
+        }
+    }
+}
+
+/// One line of `/proc/<pid>/maps`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MapsEntry {
+    pub start: u64,
+    pub end: u64,
+    pub perms: String,
+    pub offset: u64,
+    pub path: Option<String>,
+}
+
+pub fn parse_proc_maps(maps: &str) -> Vec<MapsEntry> {
+    maps.lines()
+        .filter_map(|line| {
+            let mut fields = line.splitn(6, ' ');
+            let mut range = fields.next()?.splitn(2, '-');
+            let start = u64::from_str_radix(range.next()?, 16).ok()?;
+            let end = u64::from_str_radix(range.next()?, 16).ok()?;
+            let perms = fields.next()?.to_string();
+            let offset = u64::from_str_radix(fields.next()?, 16).ok()?;
+            let _dev = fields.next()?;
+            let _inode = fields.next()?;
+            let path = fields
+                .next()
+                .map(str::trim)
+                .filter(|p| !p.is_empty())
+                .map(String::from);
+            Some(MapsEntry {
+                start,
+                end,
+                perms,
+                offset,
+                path,
+            })
+        })
+        .collect()
+}
+
+/// Function symbols and load segments of one ELF binary.
+#[derive(Debug)]
+pub struct ElfSymbols {
+    pub build_id: Option<Vec<u8>>,
+    table: SymbolTable,
+    // (p_offset, p_vaddr, p_filesz) of every PT_LOAD segment
+    segments: Vec<(u64, u64, u64)>,
+}
+
+impl ElfSymbols {
+    pub fn parse(bytes: &[u8]) -> Result<ElfSymbols> {
+        let elf = Elf::parse(bytes)?;
+        let mut syms = Vec::new();
+        for (symtab, strtab) in &[(&elf.syms, &elf.strtab), (&elf.dynsyms, &elf.dynstrtab)] {
+            for s in symtab.iter() {
+                if s.st_type() != sym::STT_FUNC || s.st_value == 0 {
+                    continue;
+                }
+                if let Some(Ok(name)) = strtab.get(s.st_name) {
+                    syms.push(Symbol {
+                        name: name.to_string(),
+                        addr: s.st_value,
+                        size: s.st_size,
+                        module: None,
+                    });
+                }
+            }
+        }
+        syms.sort_by(|a, b| a.addr.cmp(&b.addr).then_with(|| a.name.cmp(&b.name)));
+        syms.dedup_by(|a, b| a.addr == b.addr && a.name == b.name);
+
+        let segments = elf
+            .program_headers
+            .iter()
+            .filter(|ph| ph.p_type == program_header::PT_LOAD)
+            .map(|ph| (ph.p_offset, ph.p_vaddr, ph.p_filesz))
+            .collect();
+
+        Ok(ElfSymbols {
+            build_id: build_id(&elf, bytes),
+            table: SymbolTable::new(syms),
+            segments,
+        })
+    }
+
+    /// Converts an offset into the file to the virtual address it is
+    /// linked at.
+    pub fn file_offset_to_vaddr(&self, offset: u64) -> Option<u64> {
+        self.segments
+            .iter()
+            .find(|(off, _, size)| offset >= *off && offset < off + size)
+            .map(|(off, vaddr, _)| offset - off + vaddr)
+    }
+
+    pub fn resolve(&self, vaddr: u64) -> Option<(&Symbol, u64)> {
+        self.table.resolve(vaddr)
+    }
+}
+
+fn build_id(elf: &Elf<'_>, bytes: &[u8]) -> Option<Vec<u8>> {
+    let notes = elf
+        .iter_note_sections(bytes, Some(".note.gnu.build-id"))
+        .or_else(|| elf.iter_note_headers(bytes))?;
+    notes
+        .filter_map(|n| n.ok())
+        .find(|n| n.n_type == note::NT_GNU_BUILD_ID && n.name.starts_with("GNU"))
+        .map(|n| n.desc.to_vec())
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum BinaryKey {
+    BuildId(Vec<u8>),
+    Path(PathBuf),
+}
+
+/// Resolves kernel and user addresses, caching everything it reads.
+#[derive(Default)]
+pub struct Symbolizer {
+    kernel: Option<KernelSymbols>,
+    processes: HashMap<u32, Vec<MapsEntry>>,
+    paths: HashMap<PathBuf, Option<BinaryKey>>,
+    binaries: HashMap<BinaryKey, Rc<ElfSymbols>>,
+}
+
+impl Symbolizer {
+    pub fn new() -> Symbolizer {
+        Symbolizer::default()
+    }
+
+    pub fn symbolize_kernel(&mut self, addr: u64) -> Frame {
+        if self.kernel.is_none() {
+            self.kernel = Some(KernelSymbols::load().unwrap_or_else(|_| KernelSymbols::parse("")));
+        }
+        self.kernel.as_ref().unwrap().resolve(addr)
+    }
+
+    pub fn symbolize_user(&mut self, pid: u32, addr: u64) -> Frame {
+        let maps = self.processes.entry(pid).or_insert_with(|| {
+            fs::read_to_string(format!("/proc/{}/maps", pid))
+                .map(|m| parse_proc_maps(&m))
+                .unwrap_or_default()
+        });
+        let entry = match maps
+            .iter()
+            .find(|e| addr >= e.start && addr < e.end)
+        {
+            Some(entry) => entry.clone(),
+            None => return Frame::unknown(addr, None),
+        };
+        let path = match &entry.path {
+            Some(path) if path.starts_with('/') => path,
+            // [vdso], [heap] and anonymous mappings
+            path => return Frame::unknown(addr, path.clone()),
+        };
+        let module = Path::new(path)
+            .file_name()
+            .map(|n| n.to_string_lossy().into_owned());
+
+        // Read through the process' root so binaries inside containers
+        // resolve to the right file.
+        let root_path = PathBuf::from(format!("/proc/{}/root{}", pid, path));
+        let binary = self
+            .binary(&root_path)
+            .or_else(|| self.binary(Path::new(path)));
+        let binary = match binary {
+            Some(binary) => binary,
+            None => return Frame::unknown(addr, module),
+        };
+        let file_offset = addr - entry.start + entry.offset;
+        match binary
+            .file_offset_to_vaddr(file_offset)
+            .and_then(|vaddr| binary.resolve(vaddr))
+        {
+            Some((sym, offset)) => Frame {
+                addr,
+                symbol: Some(sym.name.clone()),
+                offset,
+                module,
+            },
+            None => Frame::unknown(addr, module),
+        }
+    }
+
+    /// Drops the cached memory map of `pid`, e.g. after it exec'd or exited.
+    pub fn forget_process(&mut self, pid: u32) {
+        self.processes.remove(&pid);
+    }
+
+    fn binary(&mut self, path: &Path) -> Option<Rc<ElfSymbols>> {
+        if let Some(key) = self.paths.get(path) {
+            return key.as_ref().and_then(|k| self.binaries.get(k)).cloned();
+        }
+        let parsed = fs::read(path)
+            .ok()
+            .and_then(|bytes| ElfSymbols::parse(&bytes).ok());
+        let parsed = match parsed {
+            Some(parsed) => parsed,
+            None => {
+                self.paths.insert(path.to_path_buf(), None);
+                return None;
+            }
+        };
+        let key = match &parsed.build_id {
+            Some(id) => BinaryKey::BuildId(id.clone()),
+            None => BinaryKey::Path(path.to_path_buf()),
+        };
+        let binary = self
+            .binaries
+            .entry(key.clone())
+            .or_insert_with(|| Rc::new(parsed))
+            .clone();
+        self.paths.insert(path.to_path_buf(), Some(key));
+        Some(binary)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_kallsyms() {
+        let syms = KernelSymbols::parse(
+            "ffffffff81000000 T _stext\n\
+             ffffffff81000100 t do_one_initcall\n\
+             ffffffff81000200 D some_data\n\
+             ffffffffc0000000 t ext4_sync_fs\t[ext4]\n",
+        );
+        assert_eq!(syms.resolve(0xffffffff81000110).to_string(), "do_one_initcall+0x10");
+        assert_eq!(syms.resolve(0xffffffff81000000).to_string(), "_stext+0x0");
+        assert_eq!(syms.resolve(0xffffffffc0000004).to_string(), "ext4_sync_fs+0x4 [ext4]");
+        assert_eq!(syms.resolve(0x1000).to_string(), "0x1000");
+        assert!(KernelSymbols::parse("0000000000000000 T _stext\n").is_empty());
+    }
+
+    #[test]
+    fn test_parse_proc_maps() {
+        let maps = parse_proc_maps(
+            "55d0c3a00000-55d0c3a29000 r-xp 00002000 fd:01 1234    /usr/bin/true\n\
+             7ffd4b7f2000-7ffd4b7f4000 r-xp 00000000 00:00 0                  [vdso]\n\
+             7f0000000000-7f0000001000 rw-p 00000000 00:00 0\n",
+        );
+        assert_eq!(maps.len(), 3);
+        assert_eq!(maps[0].start, 0x55d0c3a00000);
+        assert_eq!(maps[0].offset, 0x2000);
+        assert_eq!(maps[0].path.as_deref(), Some("/usr/bin/true"));
+        assert_eq!(maps[1].path.as_deref(), Some("[vdso]"));
+        assert_eq!(maps[2].path, None);
+    }
+
+    #[test]
+    fn test_symbolize_own_binary() {
+        let exe = std::env::current_exe().unwrap();
+        let elf = ElfSymbols::parse(&fs::read(exe).unwrap()).unwrap();
+        let sym = elf
+            .table
+            .syms
+            .iter()
+            .find(|s| s.size > 1 && s.name.contains("test_symbolize_own_binary"))
+            .unwrap();
+        let (found, offset) = elf.resolve(sym.addr + 1).unwrap();
+        assert_eq!(found.addr, sym.addr);
+        assert_eq!(offset, 1);
+    }
+}
@@ -0,0 +1,279 @@
+//! Program tags computed like the kernel's `bpf_prog_calc_tag`, so a
+//! program in an ELF object can be matched against the programs already
+//! loaded without loading it.
+//!
+//! The tag is the first 8 bytes of a hash of the instructions, with the
+//! immediates of `ld_imm64` map references zeroed since they hold file
+//! descriptors that differ between loads. The hash is SHA-1 up to Linux
+//! 6.17 and SHA-256 from 6.18 on, which also uses the digest to verify
+//! signed programs.
+
+use crate::error::Result;
+use crate::module::insn::{
+    insns_to_bytes, Insn, BPF_DW, BPF_IMM, BPF_LD, BPF_PSEUDO_MAP_FD, BPF_PSEUDO_MAP_VALUE,
+};
+use crate::module::inventory::{self, ProgramEntry};
+use crate::sys::syscall::BPF_TAG_SIZE;
+use kernel_version::KernelVersion;
+use std::convert::TryInto;
+
+/// Hash function a kernel uses for program tags.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TagHash {
+    Sha1,
+    Sha256,
+}
+
+impl TagHash {
+    pub fn for_kernel(kernel: KernelVersion) -> TagHash {
+        if kernel >= KernelVersion::new(6, 18, 0) {
+            TagHash::Sha256
+        } else {
+            TagHash::Sha1
+        }
+    }
+
+    /// The hash used by the running kernel, SHA-1 if its release can't be
+    /// parsed.
+    pub fn running() -> TagHash {
+        match KernelVersion::running() {
+            Some(kernel) => TagHash::for_kernel(kernel),
+            None => TagHash::Sha1,
+        }
+    }
+}
+
+/// SHA-1 digest of `data`.
+pub fn sha1(data: &[u8]) -> [u8; 20] {
+    let mut h: [u32; 5] = [
+        0x6745_2301,
+        0xefcd_ab89,
+        0x98ba_dcfe,
+        0x1032_5476,
+        0xc3d2_e1f0,
+    ];
+    for block in pad(data).chunks_exact(64) {
+        let mut w = [0u32; 80];
+        for (i, word) in block.chunks_exact(4).enumerate() {
+            w[i] = u32::from_be_bytes(word.try_into().unwrap());
+        }
+        for i in 16..80 {
+            w[i] = (w[i - 3] ^ w[i - 8] ^ w[i - 14] ^ w[i - 16]).rotate_left(1);
+        }
+        let [mut a, mut b, mut c, mut d, mut e] = h;
+        for (i, wi) in w.iter().enumerate() {
+            let (f, k) = match i {
+                0..=19 => ((b & c) | (!b & d), 0x5a82_7999),
+                20..=39 => (b ^ c ^ d, 0x6ed9_eba1),
+                40..=59 => ((b & c) | (b & d) | (c & d), 0x8f1b_bcdc),
+                _ => (b ^ c ^ d, 0xca62_c1d6),
+            };
+            let temp = a
+                .rotate_left(5)
+                .wrapping_add(f)
+                .wrapping_add(e)
+                .wrapping_add(k)
+                .wrapping_add(*wi);
+            e = d;
+            d = c;
+            c = b.rotate_left(30);
+            b = a;
+            a = temp;
+        }
+        for (h, v) in h.iter_mut().zip([a, b, c, d, e].iter()) {
+            *h = h.wrapping_add(*v);
+        }
+    }
+
+    let mut digest = [0u8; 20];
+    for (out, word) in digest.chunks_exact_mut(4).zip(h.iter()) {
+        out.copy_from_slice(&word.to_be_bytes());
+    }
+    digest
+}
+
+/// SHA-256 digest of `data`.
+pub fn sha256(data: &[u8]) -> [u8; 32] {
+    const K: [u32; 64] = [
+        0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4,
+        0xab1c5ed5, 0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe,
+        0x9bdc06a7, 0xc19bf174, 0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f,
+        0x4a7484aa, 0x5cb0a9dc, 0x76f988da, 0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7,
+        0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967, 0x27b70a85, 0x2e1b2138, 0x4d2c6dfc,
+        0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85, 0xa2bfe8a1, 0xa81a664b,
+        0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070, 0x19a4c116,
+        0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+        0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7,
+        0xc67178f2,
+    ];
+    let mut h: [u32; 8] = [
+        0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab,
+        0x5be0cd19,
+    ];
+    for block in pad(data).chunks_exact(64) {
+        let mut w = [0u32; 64];
+        for (i, word) in block.chunks_exact(4).enumerate() {
+            w[i] = u32::from_be_bytes(word.try_into().unwrap());
+        }
+        for i in 16..64 {
+            let s0 = w[i - 15].rotate_right(7) ^ w[i - 15].rotate_right(18) ^ (w[i - 15] >> 3);
+            let s1 = w[i - 2].rotate_right(17) ^ w[i - 2].rotate_right(19) ^ (w[i - 2] >> 10);
+            w[i] = w[i - 16]
+                .wrapping_add(s0)
+                .wrapping_add(w[i - 7])
+                .wrapping_add(s1);
+        }
+        let mut v = h;
+        for i in 0..64 {
+            let [a, b, c, d, e, f, g, hh] = v;
+            let s1 = e.rotate_right(6) ^ e.rotate_right(11) ^ e.rotate_right(25);
+            let ch = (e & f) ^ (!e & g);
+            let t1 = hh
+                .wrapping_add(s1)
+                .wrapping_add(ch)
+                .wrapping_add(K[i])
+                .wrapping_add(w[i]);
+            let s0 = a.rotate_right(2) ^ a.rotate_right(13) ^ a.rotate_right(22);
+            let maj = (a & b) ^ (a & c) ^ (b & c);
+            let t2 = s0.wrapping_add(maj);
+            v = [t1.wrapping_add(t2), a, b, c, d.wrapping_add(t1), e, f, g];
+        }
+        for (h, v) in h.iter_mut().zip(v.iter()) {
+            *h = h.wrapping_add(*v);
+        }
+    }
+    let mut digest = [0u8; 32];
+    for (out, word) in digest.chunks_exact_mut(4).zip(h.iter()) {
+        out.copy_from_slice(&word.to_be_bytes());
+    }
+    digest
+}
+
+/// Merkle-Damgard padding shared by SHA-1 and SHA-256.
+fn pad(data: &[u8]) -> Vec<u8> {
+    let mut msg = data.to_vec();
+    msg.push(0x80);
+    while msg.len() % 64 != 56 {
+        msg.push(0);
+    }
+    msg.extend_from_slice(&((data.len() as u64) * 8).to_be_bytes());
+    msg
+}
+
+/// Computes the tag a kernel using `hash` assigns to `insns`. Map
+/// references must already carry their `BPF_PSEUDO_MAP_FD` or
+/// `BPF_PSEUDO_MAP_VALUE` source register, as they do once relocated.
+pub fn prog_tag(insns: &[Insn], hash: TagHash) -> [u8; BPF_TAG_SIZE] {
+    let mut insns = insns.to_vec();
+    let mut was_ld_map = false;
+    for insn in insns.iter_mut() {
+        if !was_ld_map
+            && insn.code == BPF_LD | BPF_IMM | BPF_DW
+            && (insn.src() == BPF_PSEUDO_MAP_FD || insn.src() == BPF_PSEUDO_MAP_VALUE)
+        {
+            was_ld_map = true;
+            insn.imm = 0;
+        } else if was_ld_map && insn.code == 0 && insn.regs == 0 && insn.off == 0 {
+            was_ld_map = false;
+            insn.imm = 0;
+        } else {
+            was_ld_map = false;
+        }
+    }
+    let bytes = insns_to_bytes(&insns);
+    let digest = match hash {
+        TagHash::Sha1 => sha1(&bytes).to_vec(),
+        TagHash::Sha256 => sha256(&bytes).to_vec(),
+    };
+    let mut tag = [0u8; BPF_TAG_SIZE];
+    tag.copy_from_slice(&digest[..BPF_TAG_SIZE]);
+    tag
+}
+
+pub fn tag_to_string(tag: &[u8; BPF_TAG_SIZE]) -> String {
+    tag.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Returns the loaded programs running exactly `insns`. Both tag hashes
+/// are tried so the answer doesn't depend on guessing the kernel version.
+pub fn find_loaded(insns: &[Insn]) -> Result<Vec<ProgramEntry>> {
+    let tags = [
+        prog_tag(insns, TagHash::Sha1),
+        prog_tag(insns, TagHash::Sha256),
+    ];
+    Ok(inventory::programs()?
+        .into_iter()
+        .filter(|p| tags.contains(&p.tag))
+        .collect())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_sha1() {
+        let hex = |d: [u8; 20]| d.iter().map(|b| format!("{:02x}", b)).collect::<String>();
+        assert_eq!(hex(sha1(b"")), "da39a3ee5e6b4b0d3255bfef95601890afd80709");
+        assert_eq!(
+            hex(sha1(b"abc")),
+            "a9993e364706816aba3e25717850c26c9cd0d89d"
+        );
+        assert_eq!(
+            hex(sha1(
+                b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq"
+            )),
+            "84983e441c3bd26ebaae4aa1f95129e5e54670f1"
+        );
+    }
+
+    #[test]
+    fn test_sha256() {
+        let hex = |d: [u8; 32]| d.iter().map(|b| format!("{:02x}", b)).collect::<String>();
+        assert_eq!(
+            hex(sha256(b"abc")),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        assert_eq!(
+            hex(sha256(
+                b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq"
+            )),
+            "248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1"
+        );
+    }
+
+    #[test]
+    fn test_prog_tag() {
+        // `r0 = 2; exit`, tags as reported by 5.x and 6.18 kernels
+        let insns = [Insn::new(0xb7, 0, 0, 0, 2), Insn::new(0x95, 0, 0, 0, 0)];
+        assert_eq!(
+            tag_to_string(&prog_tag(&insns, TagHash::Sha1)),
+            "3b185187f1855c4c"
+        );
+        assert_eq!(
+            tag_to_string(&prog_tag(&insns, TagHash::Sha256)),
+            "614b434cd8324ecc"
+        );
+        assert_eq!(
+            TagHash::for_kernel(KernelVersion::new(5, 15, 0)),
+            TagHash::Sha1
+        );
+        assert_eq!(
+            TagHash::for_kernel(KernelVersion::new(6, 18, 0)),
+            TagHash::Sha256
+        );
+
+        // map fds don't change the tag
+        let with_fd = |fd| {
+            [
+                Insn::new(BPF_LD | BPF_IMM | BPF_DW, 1, BPF_PSEUDO_MAP_FD, 0, fd),
+                Insn::new(0, 0, 0, 0, 0),
+                Insn::new(0x95, 0, 0, 0, 0),
+            ]
+        };
+        assert_eq!(
+            prog_tag(&with_fd(3), TagHash::Sha1),
+            prog_tag(&with_fd(42), TagHash::Sha1)
+        );
+    }
+}
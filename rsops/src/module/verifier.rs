@@ -0,0 +1,357 @@
+//! Parser for the verifier log attached to a failed program load.
+//!
+//! The log interleaves instruction lines such as
+//! `1: (61) r1 = *(u32 *)(r2 +0)   ; R1_w=scalar()`, register state lines
+//! (`0: R1=ctx() R10=fp0`, `from 4 to 6: ...`), source annotations
+//! (`; int x = *p; @ prog.c:12`) and ends with the reason of the rejection
+//! followed by a `processed N insns` summary.
+
+use std::fmt::{self, Display};
+
+/// One instruction the verifier walked through.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InsnRecord {
+    pub idx: usize,
+    pub code: u8,
+    pub text: String,
+    /// Register state after the instruction, or before it when the log
+    /// printed a separate state line.
+    pub state: Option<String>,
+    /// Source annotation printed before the instruction, for programs
+    /// loaded with line info.
+    pub source: Option<String>,
+}
+
+/// Broad reason a program was rejected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Failure {
+    UnboundedLoop,
+    TooManyInsns,
+    InvalidMemAccess,
+    UninitRegister,
+    InvalidHelperArg,
+    UnknownHelper,
+    GplOnly,
+    InvalidControlFlow,
+    Other,
+}
+
+const PATTERNS: [(&str, Failure); 24] = [
+    ("back-edge from insn", Failure::UnboundedLoop),
+    ("infinite loop detected", Failure::UnboundedLoop),
+    ("loop is not bounded", Failure::UnboundedLoop),
+    ("program is too large", Failure::TooManyInsns),
+    ("too complex", Failure::TooManyInsns),
+    ("too many states", Failure::TooManyInsns),
+    ("is too long", Failure::TooManyInsns),
+    ("!read_ok", Failure::UninitRegister),
+    ("invalid mem access", Failure::InvalidMemAccess),
+    ("invalid access to", Failure::InvalidMemAccess),
+    ("outside of the packet", Failure::InvalidMemAccess),
+    ("invalid read from stack", Failure::InvalidMemAccess),
+    ("invalid write to stack", Failure::InvalidMemAccess),
+    (
+        "invalid indirect read from stack",
+        Failure::InvalidMemAccess,
+    ),
+    ("invalid stack off", Failure::InvalidMemAccess),
+    ("min value is negative", Failure::InvalidMemAccess),
+    ("unbounded memory access", Failure::InvalidMemAccess),
+    (" expected=", Failure::InvalidHelperArg),
+    ("invalid func", Failure::UnknownHelper),
+    ("unknown func", Failure::UnknownHelper),
+    ("GPL", Failure::GplOnly),
+    ("unreachable insn", Failure::InvalidControlFlow),
+    ("jump out of range", Failure::InvalidControlFlow),
+    (
+        "last insn is not an exit or jmp",
+        Failure::InvalidControlFlow,
+    ),
+];
+
+impl Failure {
+    pub fn classify(error: &str) -> Failure {
+        PATTERNS
+            .iter()
+            .find(|(pattern, _)| error.contains(pattern))
+            .map_or(Failure::Other, |(_, failure)| *failure)
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Failure::UnboundedLoop => "unbounded loop",
+            Failure::TooManyInsns => "too many instructions",
+            Failure::InvalidMemAccess => "invalid memory access",
+            Failure::UninitRegister => "uninitialized register",
+            Failure::InvalidHelperArg => "invalid helper argument",
+            Failure::UnknownHelper => "unknown helper",
+            Failure::GplOnly => "GPL-only helper",
+            Failure::InvalidControlFlow => "invalid control flow",
+            Failure::Other => "rejected",
+        }
+    }
+
+    /// What usually fixes this kind of failure.
+    pub fn hint(self) -> Option<&'static str> {
+        Some(match self {
+            Failure::UnboundedLoop => {
+                "the verifier can't prove the loop terminates: bound it by a constant, \
+                 unroll it with `#pragma unroll` or use bpf_loop() on 5.17+; kernels \
+                 before 5.3 reject every back-edge"
+            }
+            Failure::TooManyInsns => {
+                "the verifier hit its complexity limit (4096 instructions before 5.2, \
+                 1M processed instructions since): tighten loop bounds, reduce branches \
+                 on values the verifier can't track, or split the program with tail \
+                 calls or bpf-to-bpf calls"
+            }
+            Failure::InvalidMemAccess => {
+                "check map lookup results against NULL, compare packet pointers with \
+                 data_end before reading, and keep stack and map value offsets in bounds"
+            }
+            Failure::UninitRegister => {
+                "a register is read before it is written: helper calls clobber r1-r5, \
+                 keep live values in r6-r9 and set r0 before exit"
+            }
+            Failure::InvalidHelperArg => {
+                "a helper got an argument of the wrong type, e.g. a scalar where a map \
+                 or stack pointer is expected; check the helper's signature"
+            }
+            Failure::UnknownHelper => {
+                "the helper doesn't exist on this kernel or isn't allowed for this \
+                 program type"
+            }
+            Failure::GplOnly => {
+                "the helper is GPL-only: declare a GPL compatible license in the \
+                 `license` section"
+            }
+            Failure::InvalidControlFlow => {
+                "every instruction must be reachable and the program must end with \
+                 exit or a jump; check the jump offsets"
+            }
+            Failure::Other => return None,
+        })
+    }
+}
+
+/// A parsed verifier log.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VerifierLog {
+    pub records: Vec<InsnRecord>,
+    /// Lines following the last instruction, explaining the rejection.
+    pub error: String,
+    pub failure: Failure,
+    /// The `processed N insns ...` summary.
+    pub stats: Option<String>,
+    pub processed: Option<u64>,
+}
+
+/// Parses `N: (xx) text` into the index, opcode and text.
+fn parse_insn_line(line: &str) -> Option<(usize, u8, &str)> {
+    let (idx, rest) = line.split_once(": (")?;
+    let idx = idx.parse().ok()?;
+    let (code, text) = rest.split_once(") ")?;
+    Some((idx, u8::from_str_radix(code, 16).ok()?, text))
+}
+
+/// Extracts the instruction named by messages like `back-edge from insn
+/// 3 to 1` or `unreachable insn 4`.
+fn insn_in_message(error: &str) -> Option<usize> {
+    let (_, rest) = error.split_once("insn ")?;
+    let digits: String = rest.chars().take_while(char::is_ascii_digit).collect();
+    digits.parse().ok()
+}
+
+impl VerifierLog {
+    pub fn parse(log: &str) -> VerifierLog {
+        let mut records: Vec<InsnRecord> = Vec::new();
+        let mut error = Vec::new();
+        let mut stats = None;
+        let mut pending_state = None;
+        let mut pending_source = None;
+        for line in log.lines() {
+            let line = line.trim_end();
+            if let Some((idx, code, text)) = parse_insn_line(line) {
+                let (text, state) = match text.split_once(';') {
+                    Some((text, state)) => (text.trim(), Some(state.trim().to_string())),
+                    None => (text.trim(), pending_state.take()),
+                };
+                records.push(InsnRecord {
+                    idx,
+                    code,
+                    text: text.to_string(),
+                    state,
+                    source: pending_source.take(),
+                });
+                error.clear();
+            } else if line.starts_with("processed ") {
+                stats = Some(line.to_string());
+            } else if let Some(source) = line.strip_prefix("; ") {
+                pending_source = Some(source.to_string());
+            } else if let Some((prefix, state)) = line.split_once(": ") {
+                let is_state = prefix.chars().all(|c| c.is_ascii_digit())
+                    || (prefix.starts_with("from ") && prefix.contains(" to "));
+                if is_state && !prefix.is_empty() {
+                    // `N: state` lines of older kernels describe the state
+                    // after the previous instruction
+                    match records.last_mut() {
+                        Some(last) if last.state.is_none() && !prefix.starts_with("from ") => {
+                            last.state = Some(state.to_string())
+                        }
+                        _ => pending_state = Some(state.to_string()),
+                    }
+                } else {
+                    error.push(line);
+                }
+            } else if !line.is_empty() && !is_summary(line) {
+                error.push(line);
+            }
+        }
+        let error = error.join("\n");
+        let processed = stats
+            .as_ref()
+            .and_then(|s: &String| s.split_whitespace().nth(1).and_then(|n| n.parse().ok()));
+        VerifierLog {
+            records,
+            failure: Failure::classify(&error),
+            error,
+            stats,
+            processed,
+        }
+    }
+
+    /// Index of the instruction the program was rejected at.
+    pub fn failing_insn(&self) -> Option<usize> {
+        insn_in_message(&self.error).or_else(|| self.records.last().map(|r| r.idx))
+    }
+
+    /// The record of the rejected instruction, when it was reached.
+    pub fn failing_record(&self) -> Option<&InsnRecord> {
+        let idx = self.failing_insn()?;
+        self.records.iter().rev().find(|r| r.idx == idx)
+    }
+
+    /// Register state right before the rejected instruction, as printed
+    /// after the one walked before it.
+    pub fn failing_state(&self) -> Option<&str> {
+        let idx = self.failing_insn()?;
+        let pos = self.records.iter().rposition(|r| r.idx == idx)?;
+        self.records[..pos]
+            .iter()
+            .rev()
+            .find_map(|r| r.state.as_deref())
+    }
+}
+
+/// Lines the verifier prints after the summary.
+fn is_summary(line: &str) -> bool {
+    line.starts_with("verification time")
+        || line.starts_with("stack depth")
+        || line.starts_with("Func#")
+}
+
+impl Display for VerifierLog {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.failure.name())?;
+        if let Some(idx) = self.failing_insn() {
+            write!(f, " at insn {}", idx)?;
+        }
+        if !self.error.is_empty() {
+            write!(f, ": {}", self.error.replace('\n', "; "))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const PACKET: &str = "0: R1=ctx() R10=fp0
+0: (61) r2 = *(u32 *)(r1 +0)          ; R1=ctx() R2=pkt(r=0)
+1: (71) r0 = *(u8 *)(r2 +0)
+invalid access to packet, off=0 size=1, R2(id=0,off=0,r=0)
+R2 offset is outside of the packet
+processed 2 insns (limit 1000000) max_states_per_insn 0 total_states 0 peak_states 0 mark_read 0
+";
+
+    #[test]
+    fn test_parse_packet_access() {
+        let log = VerifierLog::parse(PACKET);
+        assert_eq!(log.records.len(), 2);
+        assert_eq!(
+            log.records[0].state.as_deref(),
+            Some("R1=ctx() R2=pkt(r=0)")
+        );
+        assert_eq!(log.records[1].code, 0x71);
+        assert_eq!(log.records[1].text, "r0 = *(u8 *)(r2 +0)");
+        assert_eq!(log.failing_insn(), Some(1));
+        assert_eq!(log.failing_state(), Some("R1=ctx() R2=pkt(r=0)"));
+        assert_eq!(log.failure, Failure::InvalidMemAccess);
+        assert_eq!(log.processed, Some(2));
+        assert_eq!(
+            log.error,
+            "invalid access to packet, off=0 size=1, R2(id=0,off=0,r=0)\n\
+             R2 offset is outside of the packet"
+        );
+    }
+
+    #[test]
+    fn test_parse_old_format() {
+        // 4.x kernels print the state on its own line before each insn
+        let log = VerifierLog::parse(
+            "0: (b7) r0 = 0\n\
+             1: R0=inv0 R1=ctx R10=fp0\n\
+             1: (61) r1 = *(u32 *)(r2 +0)\n\
+             R2 !read_ok\n",
+        );
+        assert_eq!(
+            log.records[0].state.as_deref(),
+            Some("R0=inv0 R1=ctx R10=fp0")
+        );
+        assert_eq!(log.failing_state(), Some("R0=inv0 R1=ctx R10=fp0"));
+        assert_eq!(log.failure, Failure::UninitRegister);
+        assert_eq!(log.failing_insn(), Some(1));
+    }
+
+    #[test]
+    fn test_classify() {
+        let log = VerifierLog::parse("back-edge from insn 7 to 2\n");
+        assert_eq!(log.failure, Failure::UnboundedLoop);
+        assert_eq!(log.failing_insn(), Some(7));
+        assert_eq!(log.failing_state(), None);
+
+        // the message names an instruction before the last one walked
+        let log = VerifierLog::parse(
+            "0: (b7) r0 = 0                        ; R0_w=0\n\
+             1: (bf) r1 = r0                       ; R1_w=0\n\
+             2: (95) exit\n\
+             back-edge from insn 1 to 0\n",
+        );
+        assert_eq!(log.failing_insn(), Some(1));
+        assert_eq!(log.failing_state(), Some("R0_w=0"));
+
+        // a truncated log starting mid line
+        let log = VerifierLog::parse(
+            "pc-2        ; R0=0x7a11f\n\
+             1: (07) r0 += 1\n\
+             BPF program is too large. Processed 1000001 insn\n\
+             processed 1000001 insns (limit 1000000) max_states_per_insn 4\n",
+        );
+        assert_eq!(log.failure, Failure::TooManyInsns);
+        assert_eq!(log.failing_insn(), Some(1));
+        assert_eq!(log.processed, Some(1_000_001));
+
+        assert_eq!(
+            Failure::classify(
+                "cannot call GPL-restricted function from non-GPL compatible program"
+            ),
+            Failure::GplOnly
+        );
+        assert_eq!(
+            Failure::classify("R1 type=scalar expected=map_ptr"),
+            Failure::InvalidHelperArg
+        );
+        assert_eq!(Failure::classify("something new"), Failure::Other);
+    }
+}
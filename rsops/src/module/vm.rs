@@ -0,0 +1,1138 @@
+//! A userspace eBPF interpreter, for testing program logic from `cargo
+//! test` without a kernel or BPF privileges.
+//!
+//! Maps are emulated in memory: hash and LRU hash (evicting an arbitrary
+//! entry when full), array, their per-CPU variants and ringbuf. Prog
+//! arrays are always empty, `bpf_tail_call` falls through. Programs
+//! may call the map helpers, `bpf_ktime_get_ns`, `bpf_ktime_get_boot_ns`,
+//! `bpf_get_smp_processor_id`, `bpf_get_current_pid_tgid`,
+//! `bpf_trace_printk` and the ringbuf helpers; the values returned by the
+//! clock, cpu and pid helpers can be injected. Programs receive a pointer
+//! to a copy of the supplied context buffer in `r1`, changes to it are
+//! copied back when the program exits.
+//!
+//! There is no verifier: out of bounds accesses, NULL dereferences and
+//! unsupported instructions or helpers fail the run with [`Error::Vm`].
+//!
+//! ```no_run
+//! use rsops::module::object::ObjectFile;
+//! use rsops::module::map::pod_read;
+//! use rsops::module::vm::Vm;
+//! # fn main() -> rsops::Result<()> {
+//! let object = ObjectFile::open("prog.o")?;
+//! let mut vm = Vm::from_object(&object, 1)?;
+//! vm.set_pid_tgid(42, 42);
+//! let mut ctx = [0u8; 64];
+//! let ret = vm.run(object.program("count").unwrap(), &mut ctx)?;
+//! let count: u64 = pod_read(&vm.map("counts").unwrap().lookup(&0u32.to_le_bytes()).unwrap());
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::error::{Error, Result};
+use crate::module::helpers::Helper;
+use crate::module::insn::*;
+use crate::module::map::{MapDef, MapType};
+use crate::module::object::{ObjectFile, ProgramSpec};
+use crate::sys::syscall::{BPF_ANY, BPF_EXIST, BPF_NOEXIST};
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::mem;
+
+/// Stack size of each call frame, as in the kernel.
+const STACK_SIZE: usize = 512;
+const MAX_CALL_DEPTH: usize = 8;
+/// Default bound on executed instructions, the verifier's limit.
+const DEFAULT_INSN_LIMIT: u64 = 1_000_000;
+/// Longest string read for a `%s` of `bpf_trace_printk`.
+const MAX_STR_LEN: usize = 256;
+
+const ENOENT: i64 = 2;
+const E2BIG: i64 = 7;
+const EEXIST: i64 = 17;
+const EINVAL: i64 = 22;
+
+/// An emulated map.
+#[derive(Debug, Clone)]
+pub struct VmMap {
+    pub name: String,
+    pub def: MapDef,
+    nr_cpus: usize,
+    /// values by key, all CPUs' values concatenated for per-CPU maps;
+    /// array slots are created when first used
+    entries: HashMap<Vec<u8>, Vec<u8>>,
+    /// submitted ringbuf records, oldest first
+    records: Vec<Vec<u8>>,
+}
+
+impl VmMap {
+    fn new(name: &str, def: MapDef, nr_cpus: usize) -> Result<VmMap> {
+        match def.map_type {
+            MapType::Hash
+            | MapType::LruHash
+            | MapType::Array
+            | MapType::PercpuHash
+            | MapType::LruPercpuHash
+            | MapType::PercpuArray
+            | MapType::Ringbuf
+            | MapType::ProgArray => {}
+            other => {
+                return Err(Error::Invalid(format!(
+                    "map {}: {} maps are not supported by the vm",
+                    name,
+                    other.name()
+                )))
+            }
+        }
+        Ok(VmMap {
+            name: name.to_string(),
+            def,
+            nr_cpus,
+            entries: HashMap::new(),
+            records: Vec::new(),
+        })
+    }
+
+    fn is_array(&self) -> bool {
+        matches!(self.def.map_type, MapType::Array | MapType::PercpuArray)
+    }
+
+    fn is_percpu(&self) -> bool {
+        matches!(
+            self.def.map_type,
+            MapType::PercpuHash | MapType::LruPercpuHash | MapType::PercpuArray
+        )
+    }
+
+    fn is_lru(&self) -> bool {
+        matches!(self.def.map_type, MapType::LruHash | MapType::LruPercpuHash)
+    }
+
+    /// Size of the stored value: one per CPU for per-CPU maps.
+    fn stored_size(&self) -> usize {
+        let size = self.def.value_size as usize;
+        if self.is_percpu() {
+            size * self.nr_cpus
+        } else {
+            size
+        }
+    }
+
+    fn array_index(&self, key: &[u8]) -> Option<u32> {
+        let index = u32::from_le_bytes(key.get(..4)?.try_into().unwrap());
+        if index < self.def.max_entries {
+            Some(index)
+        } else {
+            None
+        }
+    }
+
+    /// Returns the value for `key`, all CPUs' values concatenated for
+    /// per-CPU maps.
+    pub fn lookup(&self, key: &[u8]) -> Option<Vec<u8>> {
+        match self.entries.get(key) {
+            Some(value) => Some(value.clone()),
+            None if self.is_array() => self.array_index(key).map(|_| vec![0; self.stored_size()]),
+            None => None,
+        }
+    }
+
+    /// Returns the value `cpu` sees for `key` in a per-CPU map.
+    pub fn lookup_percpu(&self, key: &[u8], cpu: usize) -> Option<Vec<u8>> {
+        let size = self.def.value_size as usize;
+        let value = self.lookup(key)?;
+        value.get(cpu * size..(cpu + 1) * size).map(<[u8]>::to_vec)
+    }
+
+    /// Stores `value` under `key`. Per-CPU maps take either one value per
+    /// CPU or a single value that is copied to every CPU.
+    pub fn update(&mut self, key: &[u8], value: &[u8], flags: u64) -> Result<()> {
+        let value = if self.is_percpu() && value.len() == self.def.value_size as usize {
+            value.repeat(self.nr_cpus)
+        } else {
+            value.to_vec()
+        };
+        match self.insert(key, value, flags) {
+            0 => Ok(()),
+            err => Err(Error::Invalid(format!(
+                "map {}: update failed: {}",
+                self.name,
+                std::io::Error::from_raw_os_error(-err as i32)
+            ))),
+        }
+    }
+
+    /// Inserts a full stored value, returning 0 or a negative errno like
+    /// `bpf_map_update_elem`.
+    fn insert(&mut self, key: &[u8], value: Vec<u8>, flags: u64) -> i64 {
+        if key.len() != self.def.key_size as usize || value.len() != self.stored_size() {
+            return -EINVAL;
+        }
+        let exists = self.entries.contains_key(key);
+        if self.is_array() {
+            if self.array_index(key).is_none() {
+                return -E2BIG;
+            }
+            if flags == BPF_NOEXIST {
+                return -EEXIST;
+            }
+        } else {
+            if flags == BPF_NOEXIST && exists {
+                return -EEXIST;
+            }
+            if flags == BPF_EXIST && !exists {
+                return -ENOENT;
+            }
+            if !exists && self.entries.len() >= self.def.max_entries as usize {
+                if !self.is_lru() {
+                    return -E2BIG;
+                }
+                let victim = self.entries.keys().next().cloned().unwrap();
+                self.entries.remove(&victim);
+            }
+        }
+        self.entries.insert(key.to_vec(), value);
+        0
+    }
+
+    /// Removes `key`, returning whether it was present.
+    pub fn delete(&mut self, key: &[u8]) -> bool {
+        !self.is_array() && self.entries.remove(key).is_some()
+    }
+
+    pub fn keys(&self) -> Vec<Vec<u8>> {
+        if self.is_array() {
+            (0..self.def.max_entries)
+                .map(|i| i.to_le_bytes().to_vec())
+                .collect()
+        } else {
+            self.entries.keys().cloned().collect()
+        }
+    }
+
+    /// Records submitted to a ringbuf map, oldest first.
+    pub fn records(&self) -> &[Vec<u8>] {
+        &self.records
+    }
+
+    /// Removes and returns the submitted ringbuf records.
+    pub fn take_records(&mut self) -> Vec<Vec<u8>> {
+        mem::take(&mut self.records)
+    }
+
+    /// The value of `key` seen by `cpu`, creating array slots on demand.
+    fn value_mut(&mut self, key: &[u8], cpu: usize) -> Option<&mut [u8]> {
+        if self.is_array() && !self.entries.contains_key(key) {
+            self.array_index(key)?;
+            let zeros = vec![0; self.stored_size()];
+            self.entries.insert(key.to_vec(), zeros);
+        }
+        let size = self.def.value_size as usize;
+        let start = if self.is_percpu() { cpu * size } else { 0 };
+        self.entries
+            .get_mut(key)
+            .map(|value| &mut value[start..start + size])
+    }
+}
+
+/// A block of memory a program can address. Addresses carry the index of
+/// their region plus one in the upper 32 bits and the offset within it in
+/// the lower 32, so that 0 stays NULL.
+#[derive(Debug)]
+enum Region {
+    /// a map pointer loaded by `ld_imm64`, not dereferenceable
+    Map(usize),
+    /// context and stack frames
+    Buf(Vec<u8>),
+    /// a map value returned by `bpf_map_lookup_elem`
+    Value {
+        map: usize,
+        key: Vec<u8>,
+        cpu: usize,
+    },
+    /// space from `bpf_ringbuf_reserve`, not yet submitted
+    Reserved {
+        map: usize,
+        data: Vec<u8>,
+    },
+    Released,
+}
+
+fn region_addr(idx: usize) -> u64 {
+    (idx as u64 + 1) << 32
+}
+
+/// Saved state of the caller of a bpf-to-bpf call.
+struct Frame {
+    ret: usize,
+    callee_saved: [u64; 4],
+    fp: u64,
+}
+
+/// The userspace interpreter and its emulated maps.
+pub struct Vm {
+    maps: Vec<VmMap>,
+    nr_cpus: usize,
+    cpu: u32,
+    pid_tgid: u64,
+    clock: Box<dyn FnMut() -> u64>,
+    insn_limit: u64,
+    trace: Vec<String>,
+    regions: Vec<Region>,
+    /// instruction being executed, for errors
+    pc: usize,
+}
+
+fn monotonic_ns() -> u64 {
+    let mut ts: libc::timespec = unsafe { mem::zeroed() };
+    unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut ts) };
+    ts.tv_sec as u64 * 1_000_000_000 + ts.tv_nsec as u64
+}
+
+impl Vm {
+    /// Creates a vm emulating `nr_cpus` CPUs for per-CPU maps, without
+    /// maps. The pid and tgid default to this process, the clock to
+    /// `CLOCK_MONOTONIC`.
+    pub fn new(nr_cpus: usize) -> Vm {
+        let pid = u64::from(std::process::id());
+        Vm {
+            maps: Vec::new(),
+            nr_cpus: nr_cpus.max(1),
+            cpu: 0,
+            pid_tgid: pid << 32 | pid,
+            clock: Box::new(monotonic_ns),
+            insn_limit: DEFAULT_INSN_LIMIT,
+            trace: Vec::new(),
+            regions: Vec::new(),
+            pc: 0,
+        }
+    }
+
+    /// Creates a vm with the maps of `object`, global data sections holding
+    /// their initial contents.
+    pub fn from_object(object: &ObjectFile, nr_cpus: usize) -> Result<Vm> {
+        let mut vm = Vm::new(nr_cpus);
+        for spec in &object.maps {
+            let map = vm.create_map(&spec.name, spec.def)?;
+            if let Some(data) = object.data(&spec.name) {
+                vm.maps[map].update(&0u32.to_le_bytes(), data, BPF_ANY)?;
+            }
+        }
+        Ok(vm)
+    }
+
+    /// Adds an empty map, returning its index for `BPF_PSEUDO_MAP_IDX`
+    /// references.
+    pub fn create_map(&mut self, name: &str, def: MapDef) -> Result<usize> {
+        if self.map(name).is_some() {
+            return Err(Error::Invalid(format!("map {} already exists", name)));
+        }
+        self.maps.push(VmMap::new(name, def, self.nr_cpus)?);
+        Ok(self.maps.len() - 1)
+    }
+
+    pub fn map(&self, name: &str) -> Option<&VmMap> {
+        self.maps.iter().find(|m| m.name == name)
+    }
+
+    pub fn map_mut(&mut self, name: &str) -> Option<&mut VmMap> {
+        self.maps.iter_mut().find(|m| m.name == name)
+    }
+
+    /// Sets the CPU reported by `bpf_get_smp_processor_id` and used for
+    /// per-CPU maps.
+    pub fn set_cpu(&mut self, cpu: u32) {
+        self.cpu = cpu.min(self.nr_cpus as u32 - 1);
+    }
+
+    /// Sets the value returned by `bpf_get_current_pid_tgid`.
+    pub fn set_pid_tgid(&mut self, tgid: u32, pid: u32) {
+        self.pid_tgid = u64::from(tgid) << 32 | u64::from(pid);
+    }
+
+    /// Replaces the clock behind the `bpf_ktime_*` helpers.
+    pub fn set_clock<F: FnMut() -> u64 + 'static>(&mut self, clock: F) {
+        self.clock = Box::new(clock);
+    }
+
+    /// Bounds the instructions executed by one run.
+    pub fn set_insn_limit(&mut self, limit: u64) {
+        self.insn_limit = limit;
+    }
+
+    /// Lines printed by `bpf_trace_printk`, oldest first.
+    pub fn trace(&self) -> &[String] {
+        &self.trace
+    }
+
+    pub fn take_trace(&mut self) -> Vec<String> {
+        mem::take(&mut self.trace)
+    }
+
+    /// Runs `spec` with `ctx` as its context, returning `r0`. Map
+    /// references are resolved by name against the vm's maps.
+    pub fn run(&mut self, spec: &ProgramSpec, ctx: &mut [u8]) -> Result<u64> {
+        let mut refs = HashMap::new();
+        for reloc in &spec.map_relocs {
+            let idx = self
+                .maps
+                .iter()
+                .position(|m| m.name == reloc.map)
+                .ok_or_else(|| Error::NotFound(format!("map {}", reloc.map)))?;
+            refs.insert(reloc.insn, idx);
+        }
+        self.exec(&spec.insns, &refs, ctx)
+    }
+
+    /// Runs raw instructions, which may only refer to maps by index with
+    /// `BPF_PSEUDO_MAP_IDX` and `BPF_PSEUDO_MAP_IDX_VALUE`.
+    pub fn run_insns(&mut self, insns: &[Insn], ctx: &mut [u8]) -> Result<u64> {
+        self.exec(insns, &HashMap::new(), ctx)
+    }
+
+    fn fault(&self, reason: String) -> Error {
+        Error::Vm {
+            insn: self.pc,
+            reason,
+        }
+    }
+
+    fn push_region(&mut self, region: Region) -> u64 {
+        self.regions.push(region);
+        region_addr(self.regions.len() - 1)
+    }
+
+    /// Resolves `len` bytes at `addr`.
+    fn mem(&mut self, addr: u64, len: usize) -> Result<&mut [u8]> {
+        let idx = ((addr >> 32) as usize).wrapping_sub(1);
+        let off = (addr & 0xffff_ffff) as usize;
+        let pc = self.pc;
+        let fault = move || Error::Vm {
+            insn: pc,
+            reason: format!("invalid access of {} bytes at {:#x}", len, addr),
+        };
+        let buf: &mut [u8] = match self.regions.get_mut(idx) {
+            Some(Region::Buf(data)) | Some(Region::Reserved { data, .. }) => data,
+            Some(Region::Value { map, key, cpu }) => self.maps[*map]
+                .value_mut(key, *cpu)
+                .ok_or_else(|| Error::Vm {
+                    insn: pc,
+                    reason: format!("access to deleted map element at {:#x}", addr),
+                })?,
+            _ => return Err(fault()),
+        };
+        buf.get_mut(off..off.checked_add(len).ok_or_else(fault)?)
+            .ok_or_else(fault)
+    }
+
+    fn load(&mut self, addr: u64, size: usize) -> Result<u64> {
+        let bytes = self.mem(addr, size)?;
+        let mut buf = [0u8; 8];
+        buf[..size].copy_from_slice(bytes);
+        Ok(u64::from_le_bytes(buf))
+    }
+
+    fn store(&mut self, addr: u64, size: usize, value: u64) -> Result<()> {
+        self.mem(addr, size)?
+            .copy_from_slice(&value.to_le_bytes()[..size]);
+        Ok(())
+    }
+
+    fn read_bytes(&mut self, addr: u64, len: usize) -> Result<Vec<u8>> {
+        Ok(self.mem(addr, len)?.to_vec())
+    }
+
+    /// Reads a NUL terminated string of at most `max` bytes.
+    fn read_str(&mut self, addr: u64, max: usize) -> Result<String> {
+        let mut out = Vec::new();
+        while out.len() < max {
+            match self.load(addr + out.len() as u64, 1)? as u8 {
+                0 => break,
+                b => out.push(b),
+            }
+        }
+        Ok(String::from_utf8_lossy(&out).into_owned())
+    }
+
+    fn map_of(&self, addr: u64) -> Result<usize> {
+        let idx = ((addr >> 32) as usize).wrapping_sub(1);
+        match self.regions.get(idx) {
+            Some(Region::Map(map)) if addr & 0xffff_ffff == 0 => Ok(*map),
+            _ => Err(self.fault(format!("{:#x} is not a map pointer", addr))),
+        }
+    }
+
+    fn exec(
+        &mut self,
+        insns: &[Insn],
+        refs: &HashMap<usize, usize>,
+        ctx: &mut [u8],
+    ) -> Result<u64> {
+        self.regions = self
+            .maps
+            .iter()
+            .enumerate()
+            .map(|(i, _)| Region::Map(i))
+            .collect();
+        self.pc = 0;
+        let ctx_addr = self.push_region(Region::Buf(ctx.to_vec()));
+        let mut regs = [0u64; 11];
+        regs[1] = ctx_addr;
+        regs[10] = self.push_region(Region::Buf(vec![0; STACK_SIZE])) + STACK_SIZE as u64;
+        let mut frames: Vec<Frame> = Vec::new();
+        let mut executed = 0u64;
+
+        let ret = loop {
+            let pc = self.pc;
+            let insn = *insns
+                .get(pc)
+                .ok_or_else(|| self.fault("jump out of the program".to_string()))?;
+            executed += 1;
+            if executed > self.insn_limit {
+                return Err(self.fault(format!(
+                    "more than {} instructions executed",
+                    self.insn_limit
+                )));
+            }
+            let (dst, src) = (insn.dst() as usize, insn.src() as usize);
+            if dst > 10 || src > 10 {
+                return Err(self.fault("invalid register".to_string()));
+            }
+            let mut next = pc + 1;
+            match insn.class() {
+                BPF_ALU64 | BPF_ALU => {
+                    regs[dst] = self.alu(insn, regs[dst], regs[src])?;
+                }
+                BPF_JMP | BPF_JMP32 => match insn.op() {
+                    BPF_CALL => {
+                        if insn.src() == BPF_PSEUDO_CALL {
+                            if frames.len() == MAX_CALL_DEPTH {
+                                return Err(self.fault("call stack too deep".to_string()));
+                            }
+                            frames.push(Frame {
+                                ret: next,
+                                callee_saved: [regs[6], regs[7], regs[8], regs[9]],
+                                fp: regs[10],
+                            });
+                            regs[10] = self.push_region(Region::Buf(vec![0; STACK_SIZE]))
+                                + STACK_SIZE as u64;
+                            next = (next as i64 + i64::from(insn.imm)) as usize;
+                        } else if insn.src() == 0 {
+                            regs[0] = self.call(insn.imm, &regs)?;
+                        } else {
+                            return Err(self.fault("kfunc calls are not supported".to_string()));
+                        }
+                    }
+                    BPF_EXIT => match frames.pop() {
+                        Some(frame) => {
+                            regs[6..10].copy_from_slice(&frame.callee_saved);
+                            regs[10] = frame.fp;
+                            next = frame.ret;
+                        }
+                        None => break regs[0],
+                    },
+                    BPF_JA => {
+                        let off = if insn.class() == BPF_JMP32 {
+                            i64::from(insn.imm)
+                        } else {
+                            i64::from(insn.off)
+                        };
+                        next = (next as i64 + off) as usize;
+                    }
+                    op => {
+                        let rhs = if insn.source() == BPF_X {
+                            regs[src]
+                        } else {
+                            i64::from(insn.imm) as u64
+                        };
+                        let taken = if insn.class() == BPF_JMP32 {
+                            compare(op, u64::from(regs[dst] as u32), u64::from(rhs as u32), true)
+                        } else {
+                            compare(op, regs[dst], rhs, false)
+                        }
+                        .ok_or_else(|| self.fault(format!("invalid jump op {:#x}", op)))?;
+                        if taken {
+                            next = (next as i64 + i64::from(insn.off)) as usize;
+                        }
+                    }
+                },
+                BPF_LDX => {
+                    let size = access_size(insn);
+                    let addr = regs[src].wrapping_add(i64::from(insn.off) as u64);
+                    let value = self.load(addr, size)?;
+                    regs[dst] = match insn.mode() {
+                        BPF_MEM => value,
+                        BPF_MEMSX => sign_extend(value, size * 8),
+                        _ => return Err(self.fault("invalid load mode".to_string())),
+                    };
+                }
+                BPF_ST | BPF_STX => {
+                    let size = access_size(insn);
+                    let addr = regs[dst].wrapping_add(i64::from(insn.off) as u64);
+                    match (insn.class(), insn.mode()) {
+                        (BPF_ST, BPF_MEM) => self.store(addr, size, i64::from(insn.imm) as u64)?,
+                        (BPF_STX, BPF_MEM) => self.store(addr, size, regs[src])?,
+                        (BPF_STX, BPF_ATOMIC) => self.atomic(insn, addr, size, &mut regs)?,
+                        _ => return Err(self.fault("invalid store mode".to_string())),
+                    }
+                }
+                BPF_LD if insn.is_ld_imm64() => {
+                    let hi = insns
+                        .get(pc + 1)
+                        .ok_or_else(|| self.fault("truncated ld_imm64".to_string()))?
+                        .imm;
+                    regs[dst] = self.ld_imm64(insn, hi, refs.get(&pc).copied())?;
+                    next = pc + 2;
+                }
+                _ => return Err(self.fault(format!("unsupported instruction {:#04x}", insn.code))),
+            }
+            self.pc = next;
+        };
+
+        ctx.copy_from_slice(match &self.regions[self.maps.len()] {
+            Region::Buf(data) => data,
+            _ => unreachable!(),
+        });
+        self.regions.clear();
+        Ok(ret)
+    }
+
+    fn ld_imm64(&mut self, insn: Insn, hi: i32, reloc: Option<usize>) -> Result<u64> {
+        let imm = u64::from(hi as u32) << 32 | u64::from(insn.imm as u32);
+        let map = match (reloc, insn.src()) {
+            (Some(map), BPF_PSEUDO_MAP_VALUE) => map,
+            (Some(map), _) => return Ok(region_addr(map)),
+            (None, 0) => return Ok(imm),
+            (None, BPF_PSEUDO_MAP_IDX) | (None, BPF_PSEUDO_MAP_IDX_VALUE) => insn.imm as usize,
+            (None, src) => {
+                return Err(self.fault(format!(
+                    "unresolved ld_imm64 with src {}, map references need a relocation",
+                    src
+                )))
+            }
+        };
+        if map >= self.maps.len() {
+            return Err(self.fault(format!("no map at index {}", map)));
+        }
+        if insn.src() == BPF_PSEUDO_MAP_IDX {
+            return Ok(region_addr(map));
+        }
+        // direct value access, e.g. to .data or .bss, into the first slot
+        let cpu = self.cpu as usize;
+        let value = self.push_region(Region::Value {
+            map,
+            key: 0u32.to_le_bytes().to_vec(),
+            cpu,
+        });
+        Ok(value + u64::from(hi as u32))
+    }
+
+    fn alu(&self, insn: Insn, dst: u64, src: u64) -> Result<u64> {
+        let is64 = insn.class() == BPF_ALU64;
+        let rhs = if insn.source() == BPF_X {
+            src
+        } else {
+            i64::from(insn.imm) as u64
+        };
+        let signed = insn.off == 1;
+        let op = insn.op();
+        if op == BPF_END {
+            let width = insn.imm as u32;
+            let v = match width {
+                16 => u64::from(dst as u16),
+                32 => u64::from(dst as u32),
+                64 => dst,
+                _ => return Err(self.fault(format!("invalid byte swap width {}", width))),
+            };
+            // ALU64 | END is the unconditional bswap of cpu v4
+            return Ok(if is64 || insn.source() == BPF_TO_BE {
+                v.swap_bytes() >> (64 - width)
+            } else {
+                v
+            });
+        }
+        if is64 {
+            Ok(match op {
+                BPF_ADD => dst.wrapping_add(rhs),
+                BPF_SUB => dst.wrapping_sub(rhs),
+                BPF_MUL => dst.wrapping_mul(rhs),
+                BPF_DIV if rhs == 0 => 0,
+                BPF_DIV if signed => (dst as i64).wrapping_div(rhs as i64) as u64,
+                BPF_DIV => dst / rhs,
+                BPF_MOD if rhs == 0 => dst,
+                BPF_MOD if signed => (dst as i64).wrapping_rem(rhs as i64) as u64,
+                BPF_MOD => dst % rhs,
+                BPF_OR => dst | rhs,
+                BPF_AND => dst & rhs,
+                BPF_XOR => dst ^ rhs,
+                BPF_LSH => dst << (rhs & 63),
+                BPF_RSH => dst >> (rhs & 63),
+                BPF_ARSH => ((dst as i64) >> (rhs & 63)) as u64,
+                BPF_NEG => (dst as i64).wrapping_neg() as u64,
+                BPF_MOV if insn.off == 0 => rhs,
+                BPF_MOV => sign_extend(rhs, insn.off as usize),
+                _ => return Err(self.fault(format!("invalid alu op {:#x}", op))),
+            })
+        } else {
+            let (dst, rhs) = (dst as u32, rhs as u32);
+            let v = match op {
+                BPF_ADD => dst.wrapping_add(rhs),
+                BPF_SUB => dst.wrapping_sub(rhs),
+                BPF_MUL => dst.wrapping_mul(rhs),
+                BPF_DIV if rhs == 0 => 0,
+                BPF_DIV if signed => (dst as i32).wrapping_div(rhs as i32) as u32,
+                BPF_DIV => dst / rhs,
+                BPF_MOD if rhs == 0 => dst,
+                BPF_MOD if signed => (dst as i32).wrapping_rem(rhs as i32) as u32,
+                BPF_MOD => dst % rhs,
+                BPF_OR => dst | rhs,
+                BPF_AND => dst & rhs,
+                BPF_XOR => dst ^ rhs,
+                BPF_LSH => dst << (rhs & 31),
+                BPF_RSH => dst >> (rhs & 31),
+                BPF_ARSH => ((dst as i32) >> (rhs & 31)) as u32,
+                BPF_NEG => (dst as i32).wrapping_neg() as u32,
+                BPF_MOV if insn.off == 0 => rhs,
+                BPF_MOV => sign_extend(u64::from(rhs), insn.off as usize) as u32,
+                _ => return Err(self.fault(format!("invalid alu op {:#x}", op))),
+            };
+            Ok(u64::from(v))
+        }
+    }
+
+    fn atomic(&mut self, insn: Insn, addr: u64, size: usize, regs: &mut [u64; 11]) -> Result<()> {
+        let src = insn.src() as usize;
+        let truncate = |v: u64| if size == 4 { u64::from(v as u32) } else { v };
+        let old = self.load(addr, size)?;
+        let operand = truncate(regs[src]);
+        let new = match insn.imm & !BPF_FETCH {
+            op if op == i32::from(BPF_ADD) => old.wrapping_add(operand),
+            op if op == i32::from(BPF_OR) => old | operand,
+            op if op == i32::from(BPF_AND) => old & operand,
+            op if op == i32::from(BPF_XOR) => old ^ operand,
+            _ if insn.imm == BPF_XCHG => operand,
+            _ if insn.imm == BPF_CMPXCHG => {
+                let expected = truncate(regs[0]);
+                regs[0] = old;
+                if old == expected {
+                    operand
+                } else {
+                    old
+                }
+            }
+            _ => return Err(self.fault(format!("invalid atomic op {:#x}", insn.imm))),
+        };
+        self.store(addr, size, truncate(new))?;
+        if insn.imm & BPF_FETCH != 0 && insn.imm != BPF_CMPXCHG {
+            regs[src] = old;
+        }
+        Ok(())
+    }
+
+    fn call(&mut self, id: i32, regs: &[u64; 11]) -> Result<u64> {
+        let helper =
+            Helper::from_i32(id).ok_or_else(|| self.fault(format!("unknown helper {}", id)))?;
+        let errno = |e: i64| (-e) as u64;
+        Ok(match helper {
+            Helper::MapLookupElem => {
+                let map = self.map_of(regs[1])?;
+                let key = self.read_bytes(regs[2], self.maps[map].def.key_size as usize)?;
+                let cpu = self.cpu as usize;
+                let map_type = self.maps[map].def.map_type;
+                if map_type == MapType::Ringbuf || map_type == MapType::ProgArray {
+                    return Err(self.fault(format!("lookup in a {} map", map_type.name())));
+                }
+                if self.maps[map].value_mut(&key, cpu).is_some() {
+                    self.push_region(Region::Value { map, key, cpu })
+                } else {
+                    0
+                }
+            }
+            Helper::MapUpdateElem => {
+                let map = self.map_of(regs[1])?;
+                let key = self.read_bytes(regs[2], self.maps[map].def.key_size as usize)?;
+                let size = self.maps[map].def.value_size as usize;
+                let value = self.read_bytes(regs[3], size)?;
+                let cpu = self.cpu as usize;
+                let m = &mut self.maps[map];
+                if m.is_percpu() {
+                    // other CPUs keep their values
+                    let mut stored = m.lookup(&key).unwrap_or_else(|| vec![0; m.stored_size()]);
+                    stored[cpu * size..(cpu + 1) * size].copy_from_slice(&value);
+                    m.insert(&key, stored, regs[4]) as u64
+                } else {
+                    m.insert(&key, value, regs[4]) as u64
+                }
+            }
+            Helper::MapDeleteElem => {
+                let map = self.map_of(regs[1])?;
+                let key = self.read_bytes(regs[2], self.maps[map].def.key_size as usize)?;
+                let m = &mut self.maps[map];
+                if m.is_array() {
+                    errno(EINVAL)
+                } else if m.delete(&key) {
+                    0
+                } else {
+                    errno(ENOENT)
+                }
+            }
+            // no programs are installed, as for an empty slot the
+            // caller continues
+            Helper::TailCall => {
+                self.map_of(regs[2])?;
+                errno(ENOENT)
+            }
+            Helper::KtimeGetNs | Helper::KtimeGetBootNs | Helper::KtimeGetCoarseNs => {
+                (self.clock)()
+            }
+            Helper::GetSmpProcessorId => u64::from(self.cpu),
+            Helper::GetCurrentPidTgid => self.pid_tgid,
+            Helper::TracePrintk => {
+                let fmt = self.read_bytes(regs[1], regs[2] as usize)?;
+                let line = self.printk(&fmt, &regs[3..6])?;
+                let len = line.len() as u64;
+                self.trace.push(line);
+                len
+            }
+            Helper::RingbufOutput => {
+                let map = self.ringbuf(regs[1])?;
+                let data = self.read_bytes(regs[2], regs[3] as usize)?;
+                self.maps[map].records.push(data);
+                0
+            }
+            Helper::RingbufReserve => {
+                let map = self.ringbuf(regs[1])?;
+                if regs[2] > u64::from(self.maps[map].def.max_entries) {
+                    0
+                } else {
+                    let data = vec![0; regs[2] as usize];
+                    self.push_region(Region::Reserved { map, data })
+                }
+            }
+            Helper::RingbufSubmit | Helper::RingbufDiscard => {
+                let idx = ((regs[1] >> 32) as usize).wrapping_sub(1);
+                match self
+                    .regions
+                    .get_mut(idx)
+                    .map(|r| mem::replace(r, Region::Released))
+                {
+                    Some(Region::Reserved { map, data }) if regs[1] & 0xffff_ffff == 0 => {
+                        if helper == Helper::RingbufSubmit {
+                            self.maps[map].records.push(data);
+                        }
+                        0
+                    }
+                    _ => {
+                        return Err(
+                            self.fault(format!("{:#x} is not a ringbuf reservation", regs[1]))
+                        )
+                    }
+                }
+            }
+            other => {
+                return Err(self.fault(format!(
+                    "helper bpf_{} is not supported by the vm",
+                    other.name()
+                )))
+            }
+        })
+    }
+
+    fn ringbuf(&self, addr: u64) -> Result<usize> {
+        let map = self.map_of(addr)?;
+        if self.maps[map].def.map_type != MapType::Ringbuf {
+            return Err(self.fault(format!("map {} is not a ringbuf", self.maps[map].name)));
+        }
+        Ok(map)
+    }
+
+    /// Formats like the kernel's `bpf_trace_printk`: up to three
+    /// arguments, `%d %i %u %x %X %p %s %c` with `l`/`ll` modifiers.
+    fn printk(&mut self, fmt: &[u8], args: &[u64]) -> Result<String> {
+        let len = fmt.iter().position(|&b| b == 0).unwrap_or(fmt.len());
+        let fmt = String::from_utf8_lossy(&fmt[..len]).into_owned();
+        let mut out = String::new();
+        let mut args = args.iter();
+        let mut chars = fmt.chars().peekable();
+        while let Some(c) = chars.next() {
+            if c != '%' {
+                out.push(c);
+                continue;
+            }
+            let mut longs = 0;
+            while chars.peek() == Some(&'l') {
+                chars.next();
+                longs += 1;
+            }
+            let conv = match chars.next() {
+                Some('%') => {
+                    out.push('%');
+                    continue;
+                }
+                Some(conv) => conv,
+                None => break,
+            };
+            let arg = *args
+                .next()
+                .ok_or_else(|| self.fault("too few bpf_trace_printk arguments".to_string()))?;
+            let arg = if longs == 0 && conv != 'p' && conv != 's' {
+                u64::from(arg as u32)
+            } else {
+                arg
+            };
+            match conv {
+                'd' | 'i' if longs == 0 => out.push_str(&(arg as u32 as i32).to_string()),
+                'd' | 'i' => out.push_str(&(arg as i64).to_string()),
+                'u' => out.push_str(&arg.to_string()),
+                'x' => out.push_str(&format!("{:x}", arg)),
+                'X' => out.push_str(&format!("{:X}", arg)),
+                'p' => out.push_str(&format!("{:#x}", arg)),
+                'c' => out.push(arg as u8 as char),
+                's' => {
+                    let s = self.read_str(arg, MAX_STR_LEN)?;
+                    out.push_str(&s);
+                }
+                other => {
+                    return Err(
+                        self.fault(format!("invalid bpf_trace_printk conversion %{}", other))
+                    )
+                }
+            }
+        }
+        Ok(out)
+    }
+}
+
+fn access_size(insn: Insn) -> usize {
+    match insn.size() {
+        BPF_B => 1,
+        BPF_H => 2,
+        BPF_W => 4,
+        _ => 8,
+    }
+}
+
+fn sign_extend(value: u64, bits: usize) -> u64 {
+    let shift = 64 - bits;
+    (((value << shift) as i64) >> shift) as u64
+}
+
+/// Evaluates a conditional jump, `None` for an invalid op.
+fn compare(op: u8, lhs: u64, rhs: u64, is32: bool) -> Option<bool> {
+    let (slhs, srhs) = if is32 {
+        (i64::from(lhs as u32 as i32), i64::from(rhs as u32 as i32))
+    } else {
+        (lhs as i64, rhs as i64)
+    };
+    Some(match op {
+        BPF_JEQ => lhs == rhs,
+        BPF_JNE => lhs != rhs,
+        BPF_JGT => lhs > rhs,
+        BPF_JGE => lhs >= rhs,
+        BPF_JLT => lhs < rhs,
+        BPF_JLE => lhs <= rhs,
+        BPF_JSET => lhs & rhs != 0,
+        BPF_JSGT => slhs > srhs,
+        BPF_JSGE => slhs >= srhs,
+        BPF_JSLT => slhs < srhs,
+        BPF_JSLE => slhs <= srhs,
+        _ => return None,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::module::asm::assemble;
+    use crate::module::map::pod_read;
+    use crate::module::program::ProgramType;
+
+    fn spec(text: &str) -> ProgramSpec {
+        assemble(text)
+            .unwrap()
+            .into_spec("test", ProgramType::Xdp)
+            .unwrap()
+    }
+
+    fn def(map_type: MapType, key_size: u32, value_size: u32, max_entries: u32) -> MapDef {
+        MapDef {
+            map_type,
+            key_size,
+            value_size,
+            max_entries,
+            map_flags: 0,
+        }
+    }
+
+    const COUNT: &str = "
+        r6 = r1
+        *(u32 *)(r10 -4) = 0
+        r2 = r10
+        r2 += -4
+        r1 = map[counts]
+        call bpf_map_lookup_elem
+        if r0 == 0x0 goto out
+        r1 = *(u32 *)(r6 +0)
+        lock *(u64 *)(r0 +0) += r1
+    out:
+        r0 = 2
+        exit
+    ";
+
+    #[test]
+    fn test_alu_and_jumps() {
+        let mut vm = Vm::new(1);
+        let prog = spec(
+            "r0 = 0
+             r1 = 10
+         loop:
+             r0 += r1
+             r1 -= 1
+             if r1 != 0x0 goto loop
+             w2 = -1
+             r0 <<= 32
+             r0 |= r2
+             r3 = 7
+             r3 /= 0
+             r0 += r3
+             exit",
+        );
+        assert_eq!(vm.run(&prog, &mut []).unwrap(), 55 << 32 | 0xffff_ffff);
+    }
+
+    #[test]
+    fn test_array_map() {
+        let mut vm = Vm::new(1);
+        vm.create_map("counts", def(MapType::Array, 4, 8, 1))
+            .unwrap();
+        let prog = spec(COUNT);
+        let mut ctx = 3u32.to_le_bytes();
+        assert_eq!(vm.run(&prog, &mut ctx).unwrap(), 2);
+        vm.run(&prog, &mut ctx).unwrap();
+        let value = vm
+            .map("counts")
+            .unwrap()
+            .lookup(&0u32.to_le_bytes())
+            .unwrap();
+        assert_eq!(pod_read::<u64>(&value), 6);
+    }
+
+    #[test]
+    fn test_percpu_and_hash() {
+        let mut vm = Vm::new(2);
+        vm.create_map("counts", def(MapType::PercpuArray, 4, 8, 1))
+            .unwrap();
+        let prog = spec(COUNT);
+        vm.set_cpu(1);
+        vm.run(&prog, &mut 5u32.to_le_bytes()).unwrap();
+        let counts = vm.map("counts").unwrap();
+        assert_eq!(
+            counts.lookup_percpu(&0u32.to_le_bytes(), 0),
+            Some(vec![0; 8])
+        );
+        assert_eq!(
+            counts.lookup_percpu(&0u32.to_le_bytes(), 1),
+            Some(5u64.to_le_bytes().to_vec())
+        );
+
+        // a miss in a hash map skips the update
+        let mut vm = Vm::new(1);
+        vm.create_map("counts", def(MapType::Hash, 4, 8, 1))
+            .unwrap();
+        vm.run(&prog, &mut 5u32.to_le_bytes()).unwrap();
+        assert_eq!(vm.map("counts").unwrap().lookup(&0u32.to_le_bytes()), None);
+        let counts = vm.map_mut("counts").unwrap();
+        counts.update(&0u32.to_le_bytes(), &[0; 8], 0).unwrap();
+        assert!(counts.update(&1u32.to_le_bytes(), &[0; 8], 0).is_err());
+        vm.run(&prog, &mut 5u32.to_le_bytes()).unwrap();
+        let value = vm
+            .map("counts")
+            .unwrap()
+            .lookup(&0u32.to_le_bytes())
+            .unwrap();
+        assert_eq!(pod_read::<u64>(&value), 5);
+    }
+
+    #[test]
+    fn test_helpers() {
+        let mut vm = Vm::new(1);
+        vm.create_map("events", def(MapType::Ringbuf, 0, 0, 4096))
+            .unwrap();
+        vm.set_pid_tgid(7, 9);
+        vm.set_clock(|| 1000);
+        let prog = spec(
+            "call bpf_get_current_pid_tgid
+             r6 = r0
+             call bpf_ktime_get_ns
+             *(u64 *)(r10 -8) = r0
+             r1 = map[events]
+             r2 = r10
+             r2 += -8
+             r3 = 8
+             r4 = 0
+             call bpf_ringbuf_output
+             r1 = map[events]
+             r2 = 4
+             r3 = 0
+             call bpf_ringbuf_reserve
+             if r0 == 0x0 goto out
+             *(u32 *)(r0 +0) = 42
+             r1 = r0
+             r2 = 0
+             call bpf_ringbuf_submit
+             *(u32 *)(r10 -16) = 25637
+             r1 = r10
+             r1 += -16
+             r2 = 4
+             r3 = r6
+             r3 >>= 32
+             r4 = r10
+             r4 += -16
+             call bpf_trace_printk
+         out:
+             r0 = r6
+             exit",
+        );
+        assert_eq!(vm.run(&prog, &mut []).unwrap(), 7 << 32 | 9);
+        assert_eq!(
+            vm.map("events").unwrap().records(),
+            &[1000u64.to_le_bytes().to_vec(), 42u32.to_le_bytes().to_vec()]
+        );
+        assert_eq!(vm.trace(), &["7".to_string()]);
+    }
+
+    #[test]
+    fn test_calls_and_faults() {
+        let mut vm = Vm::new(1);
+        let prog = spec(
+            "r6 = 3
+             r1 = 4
+             call pc+2
+             r0 += r6
+             exit
+             r6 = 100
+             r0 = r1
+             r0 *= 2
+             exit",
+        );
+        assert_eq!(vm.run(&prog, &mut []).unwrap(), 11);
+
+        let err = vm
+            .run(&spec("r0 = *(u64 *)(r10 +0)\nexit"), &mut [])
+            .unwrap_err();
+        assert!(matches!(err, Error::Vm { insn: 0, .. }), "{}", err);
+        vm.set_insn_limit(100);
+        let err = vm.run(&spec("l:\ngoto l\nexit"), &mut []).unwrap_err();
+        assert!(err.to_string().contains("more than 100"), "{}", err);
+    }
+
+    #[test]
+    fn test_ctx_written_back() {
+        let mut vm = Vm::new(1);
+        let mut ctx = [0u8; 8];
+        let prog = spec("*(u32 *)(r1 +4) = 99\nr0 = *(u8 *)(r1 +0)\nexit");
+        ctx[0] = 5;
+        assert_eq!(vm.run(&prog, &mut ctx).unwrap(), 5);
+        assert_eq!(&ctx[4..], &99u32.to_le_bytes());
+    }
+}
@@ -1,7 +1,7 @@
 extern crate elf;
 use std::path::PathBuf;
 
-struct Bpf {}
+pub struct Bpf {}
 
 pub fn new_bpf(path: &str) {
     println!("path is {}!", path);
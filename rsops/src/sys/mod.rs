@@ -0,0 +1,3 @@
+pub mod libbpf;
+pub mod perf;
+pub mod syscall;
@@ -0,0 +1,85 @@
+//! Thin wrappers around `perf_event_open(2)`, which kprobes and tracepoints
+//! are attached through.
+
+use std::io;
+use std::os::unix::io::RawFd;
+
+pub const PERF_TYPE_TRACEPOINT: u32 = 2;
+
+const PERF_FLAG_FD_CLOEXEC: libc::c_ulong = 1 << 3;
+
+const PERF_EVENT_IOC_ENABLE: libc::c_ulong = 0x2400;
+const PERF_EVENT_IOC_SET_BPF: libc::c_ulong = 0x4004_2408;
+
+/// `struct perf_event_attr` up to `sample_max_stack`, the size the kernel
+/// has accepted since 4.19.
+#[repr(C)]
+#[derive(Debug, Default)]
+pub struct PerfEventAttr {
+    pub type_: u32,
+    pub size: u32,
+    pub config: u64,
+    pub sample_period: u64,
+    pub sample_type: u64,
+    pub read_format: u64,
+    pub flags: u64,
+    pub wakeup_events: u32,
+    pub bp_type: u32,
+    pub config1: u64,
+    pub config2: u64,
+    pub branch_sample_type: u64,
+    pub sample_regs_user: u64,
+    pub sample_stack_user: u32,
+    pub clockid: i32,
+    pub sample_regs_intr: u64,
+    pub aux_watermark: u32,
+    pub sample_max_stack: u16,
+    pub __reserved_2: u16,
+}
+
+/// Opens a perf event for `attr` counting every process on CPU 0, which is
+/// all a kprobe or tracepoint needs to run its BPF program everywhere.
+pub fn perf_event_open(attr: &mut PerfEventAttr) -> io::Result<RawFd> {
+    attr.size = std::mem::size_of::<PerfEventAttr>() as u32;
+    let ret = unsafe {
+        libc::syscall(
+            libc::SYS_perf_event_open,
+            attr as *mut PerfEventAttr,
+            -1,
+            0,
+            -1,
+            PERF_FLAG_FD_CLOEXEC,
+        )
+    };
+    if ret < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(ret as RawFd)
+    }
+}
+
+/// Runs program `prog_fd` whenever perf event `fd` fires, and enables it.
+pub fn set_bpf(fd: RawFd, prog_fd: RawFd) -> io::Result<()> {
+    if unsafe { libc::ioctl(fd, PERF_EVENT_IOC_SET_BPF, prog_fd) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    enable(fd)
+}
+
+pub fn enable(fd: RawFd) -> io::Result<()> {
+    if unsafe { libc::ioctl(fd, PERF_EVENT_IOC_ENABLE, 0) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_attr_size() {
+        // PERF_ATTR_SIZE_VER5
+        assert_eq!(std::mem::size_of::<PerfEventAttr>(), 112);
+    }
+}
@@ -0,0 +1,442 @@
+//! Thin wrappers around the `bpf(2)` syscall.
+//!
+//! Every command has its own `#[repr(C)]` attribute struct laid out like the
+//! matching member of the kernel's `union bpf_attr`. The kernel accepts any
+//! attribute size up to the one it knows about, so passing the smaller
+//! struct is fine on both old and new kernels.
+
+use std::ffi::CStr;
+use std::io;
+use std::mem;
+use std::os::unix::io::RawFd;
+use std::ptr;
+
+pub const BPF_MAP_CREATE: u32 = 0;
+pub const BPF_MAP_LOOKUP_ELEM: u32 = 1;
+pub const BPF_MAP_UPDATE_ELEM: u32 = 2;
+pub const BPF_MAP_DELETE_ELEM: u32 = 3;
+pub const BPF_MAP_GET_NEXT_KEY: u32 = 4;
+pub const BPF_PROG_LOAD: u32 = 5;
+pub const BPF_OBJ_PIN: u32 = 6;
+pub const BPF_OBJ_GET: u32 = 7;
+pub const BPF_PROG_GET_NEXT_ID: u32 = 11;
+pub const BPF_MAP_GET_NEXT_ID: u32 = 12;
+pub const BPF_PROG_GET_FD_BY_ID: u32 = 13;
+pub const BPF_MAP_GET_FD_BY_ID: u32 = 14;
+pub const BPF_OBJ_GET_INFO_BY_FD: u32 = 15;
+pub const BPF_BTF_LOAD: u32 = 18;
+pub const BPF_RAW_TRACEPOINT_OPEN: u32 = 17;
+pub const BPF_BTF_GET_FD_BY_ID: u32 = 19;
+pub const BPF_MAP_LOOKUP_AND_DELETE_ELEM: u32 = 21;
+pub const BPF_MAP_FREEZE: u32 = 22;
+pub const BPF_LINK_CREATE: u32 = 28;
+
+/// Attach type of a link to a perf event, such as a kprobe or tracepoint.
+pub const BPF_PERF_EVENT: u32 = 41;
+
+pub const BPF_ANY: u64 = 0;
+pub const BPF_NOEXIST: u64 = 1;
+pub const BPF_EXIST: u64 = 2;
+
+pub const BPF_OBJ_NAME_LEN: usize = 16;
+
+/// Map flag making a map read-only for programs, e.g. `.rodata`.
+pub const BPF_F_RDONLY_PROG: u32 = 1 << 7;
+
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct MapCreateAttr {
+    pub map_type: u32,
+    pub key_size: u32,
+    pub value_size: u32,
+    pub max_entries: u32,
+    pub map_flags: u32,
+    pub inner_map_fd: u32,
+    pub numa_node: u32,
+    pub map_name: [u8; BPF_OBJ_NAME_LEN],
+    pub map_ifindex: u32,
+    pub btf_fd: u32,
+    pub btf_key_type_id: u32,
+    pub btf_value_type_id: u32,
+    pub btf_vmlinux_value_type_id: u32,
+}
+
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct ProgLoadAttr {
+    pub prog_type: u32,
+    pub insn_cnt: u32,
+    pub insns: u64,
+    pub license: u64,
+    pub log_level: u32,
+    pub log_size: u32,
+    pub log_buf: u64,
+    pub kern_version: u32,
+    pub prog_flags: u32,
+    pub prog_name: [u8; BPF_OBJ_NAME_LEN],
+    pub prog_ifindex: u32,
+    pub expected_attach_type: u32,
+    pub prog_btf_fd: u32,
+    pub func_info_rec_size: u32,
+    pub func_info: u64,
+    pub func_info_cnt: u32,
+    pub line_info_rec_size: u32,
+    pub line_info: u64,
+    pub line_info_cnt: u32,
+    pub attach_btf_id: u32,
+}
+
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+struct BtfLoadAttr {
+    btf: u64,
+    btf_log_buf: u64,
+    btf_size: u32,
+    btf_log_size: u32,
+    btf_log_level: u32,
+}
+
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+struct ObjAttr {
+    pathname: u64,
+    bpf_fd: u32,
+    file_flags: u32,
+}
+
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+struct InfoAttr {
+    bpf_fd: u32,
+    info_len: u32,
+    info: u64,
+}
+
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+struct IdAttr {
+    id: u32,
+    next_id: u32,
+    open_flags: u32,
+}
+
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+struct RawTracepointAttr {
+    name: u64,
+    prog_fd: u32,
+    _pad: u32,
+}
+
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+struct LinkCreateAttr {
+    prog_fd: u32,
+    target_fd: u32,
+    attach_type: u32,
+    flags: u32,
+    bpf_cookie: u64,
+}
+
+pub const BPF_TAG_SIZE: usize = 8;
+
+/// `struct bpf_prog_info`
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct ProgInfo {
+    pub prog_type: u32,
+    pub id: u32,
+    pub tag: [u8; BPF_TAG_SIZE],
+    pub jited_prog_len: u32,
+    pub xlated_prog_len: u32,
+    pub jited_prog_insns: u64,
+    pub xlated_prog_insns: u64,
+    /// nanoseconds since boot
+    pub load_time: u64,
+    pub created_by_uid: u32,
+    pub nr_map_ids: u32,
+    pub map_ids: u64,
+    pub name: [u8; BPF_OBJ_NAME_LEN],
+    pub ifindex: u32,
+    /// bit 0: gpl_compatible
+    pub flags: u32,
+    pub netns_dev: u64,
+    pub netns_ino: u64,
+    pub nr_jited_ksyms: u32,
+    pub nr_jited_func_lens: u32,
+    pub jited_ksyms: u64,
+    pub jited_func_lens: u64,
+    pub btf_id: u32,
+    pub func_info_rec_size: u32,
+    pub func_info: u64,
+    pub nr_func_info: u32,
+    pub nr_line_info: u32,
+    pub line_info: u64,
+    pub jited_line_info: u64,
+    pub nr_jited_line_info: u32,
+    pub line_info_rec_size: u32,
+    pub jited_line_info_rec_size: u32,
+    pub nr_prog_tags: u32,
+    pub prog_tags: u64,
+    pub run_time_ns: u64,
+    pub run_cnt: u64,
+}
+
+/// `struct bpf_btf_info`
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct BtfInfo {
+    pub btf: u64,
+    pub btf_size: u32,
+    pub id: u32,
+    pub name: u64,
+    pub name_len: u32,
+    pub kernel_btf: u32,
+}
+
+/// `struct bpf_line_info`
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct LineInfo {
+    pub insn_off: u32,
+    pub file_name_off: u32,
+    pub line_off: u32,
+    /// line number in the upper 22 bits, column in the lower 10
+    pub line_col: u32,
+}
+
+/// `struct bpf_map_info`
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct MapInfo {
+    pub map_type: u32,
+    pub id: u32,
+    pub key_size: u32,
+    pub value_size: u32,
+    pub max_entries: u32,
+    pub map_flags: u32,
+    pub name: [u8; BPF_OBJ_NAME_LEN],
+    pub ifindex: u32,
+    pub btf_vmlinux_value_type_id: u32,
+    pub netns_dev: u64,
+    pub netns_ino: u64,
+    pub btf_id: u32,
+    pub btf_key_type_id: u32,
+    pub btf_value_type_id: u32,
+    _pad: u32,
+    pub map_extra: u64,
+}
+
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+struct MapElemAttr {
+    map_fd: u32,
+    _pad: u32,
+    key: u64,
+    value: u64,
+    flags: u64,
+}
+
+pub fn bpf<T>(cmd: u32, attr: &mut T) -> io::Result<i32> {
+    let ret = unsafe {
+        libc::syscall(
+            libc::SYS_bpf,
+            cmd,
+            attr as *mut T as *mut libc::c_void,
+            mem::size_of::<T>() as u32,
+        )
+    };
+    if ret < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(ret as i32)
+    }
+}
+
+/// Copies `name` into a NUL terminated object name, truncating it the same
+/// way the kernel does.
+pub fn obj_name(name: &str) -> [u8; BPF_OBJ_NAME_LEN] {
+    let mut buf = [0u8; BPF_OBJ_NAME_LEN];
+    let valid = name
+        .bytes()
+        .take_while(|b| b.is_ascii_alphanumeric() || *b == b'_' || *b == b'.')
+        .take(BPF_OBJ_NAME_LEN - 1);
+    for (dst, src) in buf.iter_mut().zip(valid) {
+        *dst = src;
+    }
+    buf
+}
+
+pub fn map_create(attr: &mut MapCreateAttr) -> io::Result<RawFd> {
+    bpf(BPF_MAP_CREATE, attr)
+}
+
+pub fn prog_load(attr: &mut ProgLoadAttr) -> io::Result<RawFd> {
+    bpf(BPF_PROG_LOAD, attr)
+}
+
+/// Loads raw BTF, header included.
+pub fn btf_load(data: &[u8]) -> io::Result<RawFd> {
+    let mut attr = BtfLoadAttr {
+        btf: data.as_ptr() as u64,
+        btf_size: data.len() as u32,
+        ..Default::default()
+    };
+    bpf(BPF_BTF_LOAD, &mut attr)
+}
+
+pub fn obj_pin(fd: RawFd, path: &CStr) -> io::Result<()> {
+    let mut attr = ObjAttr {
+        pathname: path.as_ptr() as u64,
+        bpf_fd: fd as u32,
+        ..Default::default()
+    };
+    bpf(BPF_OBJ_PIN, &mut attr).map(|_| ())
+}
+
+pub fn obj_get(path: &CStr) -> io::Result<RawFd> {
+    let mut attr = ObjAttr {
+        pathname: path.as_ptr() as u64,
+        ..Default::default()
+    };
+    bpf(BPF_OBJ_GET, &mut attr)
+}
+
+/// Returns the id following `id` of the objects enumerated by `cmd`
+/// (`BPF_PROG_GET_NEXT_ID` or `BPF_MAP_GET_NEXT_ID`), `None` at the end.
+pub fn obj_get_next_id(cmd: u32, id: u32) -> io::Result<Option<u32>> {
+    let mut attr = IdAttr {
+        id,
+        ..Default::default()
+    };
+    match bpf(cmd, &mut attr) {
+        Ok(_) => Ok(Some(attr.next_id)),
+        Err(e) if e.raw_os_error() == Some(libc::ENOENT) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Opens the object with `id` using `cmd` (`BPF_PROG_GET_FD_BY_ID`,
+/// `BPF_MAP_GET_FD_BY_ID` or `BPF_BTF_GET_FD_BY_ID`).
+pub fn obj_get_fd_by_id(cmd: u32, id: u32) -> io::Result<RawFd> {
+    let mut attr = IdAttr {
+        id,
+        ..Default::default()
+    };
+    bpf(cmd, &mut attr)
+}
+
+/// Fills one of the `bpf_*_info` structs for the object behind `fd`.
+/// Kernels older than the struct only fill the fields they know about.
+pub fn obj_get_info<T>(fd: RawFd, info: &mut T) -> io::Result<()> {
+    let mut attr = InfoAttr {
+        bpf_fd: fd as u32,
+        info_len: mem::size_of::<T>() as u32,
+        info: info as *mut T as u64,
+    };
+    bpf(BPF_OBJ_GET_INFO_BY_FD, &mut attr).map(|_| ())
+}
+
+fn ptr_of(buf: Option<&[u8]>) -> u64 {
+    buf.map_or(ptr::null(), |b| b.as_ptr()) as u64
+}
+
+pub fn map_lookup_elem(fd: RawFd, key: Option<&[u8]>, value: &mut [u8]) -> io::Result<()> {
+    let mut attr = MapElemAttr {
+        map_fd: fd as u32,
+        key: ptr_of(key),
+        value: value.as_mut_ptr() as u64,
+        ..Default::default()
+    };
+    bpf(BPF_MAP_LOOKUP_ELEM, &mut attr).map(|_| ())
+}
+
+/// Pops an element from a queue or stack map, which take a null key.
+pub fn map_lookup_and_delete_elem(
+    fd: RawFd,
+    key: Option<&[u8]>,
+    value: &mut [u8],
+) -> io::Result<()> {
+    let mut attr = MapElemAttr {
+        map_fd: fd as u32,
+        key: ptr_of(key),
+        value: value.as_mut_ptr() as u64,
+        ..Default::default()
+    };
+    bpf(BPF_MAP_LOOKUP_AND_DELETE_ELEM, &mut attr).map(|_| ())
+}
+
+pub fn map_update_elem(
+    fd: RawFd,
+    key: Option<&[u8]>,
+    value: &[u8],
+    flags: u64,
+) -> io::Result<()> {
+    let mut attr = MapElemAttr {
+        map_fd: fd as u32,
+        key: ptr_of(key),
+        value: value.as_ptr() as u64,
+        flags,
+        ..Default::default()
+    };
+    bpf(BPF_MAP_UPDATE_ELEM, &mut attr).map(|_| ())
+}
+
+pub fn map_delete_elem(fd: RawFd, key: &[u8]) -> io::Result<()> {
+    let mut attr = MapElemAttr {
+        map_fd: fd as u32,
+        key: key.as_ptr() as u64,
+        ..Default::default()
+    };
+    bpf(BPF_MAP_DELETE_ELEM, &mut attr).map(|_| ())
+}
+
+/// Makes the map read-only for user space, once its initial contents are
+/// in place.
+pub fn map_freeze(fd: RawFd) -> io::Result<()> {
+    let mut attr = MapElemAttr {
+        map_fd: fd as u32,
+        ..Default::default()
+    };
+    bpf(BPF_MAP_FREEZE, &mut attr).map(|_| ())
+}
+
+/// Attaches program `prog_fd` to raw tracepoint `name`. The tracepoint
+/// stays attached until the returned file descriptor is closed.
+pub fn raw_tracepoint_open(name: &CStr, prog_fd: RawFd) -> io::Result<RawFd> {
+    let mut attr = RawTracepointAttr {
+        name: name.as_ptr() as u64,
+        prog_fd: prog_fd as u32,
+        ..Default::default()
+    };
+    bpf(BPF_RAW_TRACEPOINT_OPEN, &mut attr)
+}
+
+/// Attaches program `prog_fd` to `target_fd`, e.g. a perf event with
+/// `BPF_PERF_EVENT`, through a link that can be pinned (Linux 5.7, 5.15
+/// for perf events).
+pub fn link_create(prog_fd: RawFd, target_fd: RawFd, attach_type: u32) -> io::Result<RawFd> {
+    let mut attr = LinkCreateAttr {
+        prog_fd: prog_fd as u32,
+        target_fd: target_fd as u32,
+        attach_type,
+        ..Default::default()
+    };
+    bpf(BPF_LINK_CREATE, &mut attr)
+}
+
+/// Stores the key following `key` (or the first key when `key` is `None`)
+/// in `next_key`. Returns `false` once the end of the map is reached.
+pub fn map_get_next_key(fd: RawFd, key: Option<&[u8]>, next_key: &mut [u8]) -> io::Result<bool> {
+    let mut attr = MapElemAttr {
+        map_fd: fd as u32,
+        key: ptr_of(key),
+        value: next_key.as_mut_ptr() as u64,
+        ..Default::default()
+    };
+    match bpf(BPF_MAP_GET_NEXT_KEY, &mut attr) {
+        Ok(_) => Ok(true),
+        Err(e) if e.raw_os_error() == Some(libc::ENOENT) => Ok(false),
+        Err(e) => Err(e),
+    }
+}
@@ -0,0 +1,58 @@
+; A kprobe reading task->tgid with CO-RE, the IR clang emits for
+;
+;   struct task_struct { int pid; int tgid; } __attribute__((preserve_access_index));
+;
+; plus a bpf_core_field_exists(task->tgid). Rebuild prog.o with
+;
+;   opt -mtriple=bpfel -passes='default<O2>' prog.ll | llc -march=bpfel -filetype=obj -o prog.o
+
+target datalayout = "e-m:e-p:64:64-i64:64-i128:128-n32:64-S128"
+target triple = "bpf"
+
+%struct.task_struct = type { i32, i32 }
+
+@_license = dso_local global [4 x i8] c"GPL\00", section "license", align 1, !dbg !30
+@llvm.compiler.used = appending global [2 x i8*] [i8* getelementptr inbounds ([4 x i8], [4 x i8]* @_license, i32 0, i32 0), i8* bitcast (i32 (i8*)* @prog to i8*)], section "llvm.metadata"
+
+define dso_local i32 @prog(i8* %ctx) section "kprobe/do_nanosleep" !dbg !20 {
+entry:
+  %pid = alloca i32, align 4
+  %task = call i64 inttoptr (i64 35 to i64 ()*)(), !dbg !25
+  %t = inttoptr i64 %task to %struct.task_struct*, !dbg !25
+  %f = call i32* @llvm.preserve.struct.access.index.p0i32.p0s_struct.task_structs(%struct.task_struct* elementtype(%struct.task_struct) %t, i32 1, i32 1), !dbg !25, !llvm.preserve.access.index !10
+  %dst = bitcast i32* %pid to i8*, !dbg !25
+  %src = bitcast i32* %f to i8*, !dbg !25
+  %r = call i64 inttoptr (i64 113 to i64 (i8*, i32, i8*)*)(i8* %dst, i32 4, i8* %src), !dbg !25
+  %exists = call i32 @llvm.bpf.preserve.field.info.p0i32(i32* %f, i64 2), !dbg !25
+  %v = load i32, i32* %pid, align 4, !dbg !25
+  %sum = add i32 %v, %exists, !dbg !25
+  ret i32 %sum, !dbg !25
+}
+
+declare i32* @llvm.preserve.struct.access.index.p0i32.p0s_struct.task_structs(%struct.task_struct*, i32 immarg, i32 immarg)
+declare i32 @llvm.bpf.preserve.field.info.p0i32(i32*, i64 immarg)
+
+!llvm.dbg.cu = !{!0}
+!llvm.module.flags = !{!2, !3}
+
+!0 = distinct !DICompileUnit(language: DW_LANG_C99, file: !1, producer: "hand", isOptimized: true, runtimeVersion: 0, emissionKind: FullDebug, globals: !29)
+!1 = !DIFile(filename: "prog.c", directory: "/tmp/core")
+!2 = !{i32 7, !"Dwarf Version", i32 5}
+!3 = !{i32 2, !"Debug Info Version", i32 3}
+!10 = distinct !DICompositeType(tag: DW_TAG_structure_type, name: "task_struct", file: !1, line: 1, size: 64, elements: !11)
+!11 = !{!12, !13}
+!12 = !DIDerivedType(tag: DW_TAG_member, name: "pid", scope: !10, file: !1, line: 1, baseType: !14, size: 32)
+!13 = !DIDerivedType(tag: DW_TAG_member, name: "tgid", scope: !10, file: !1, line: 1, baseType: !14, size: 32, offset: 32)
+!14 = !DIBasicType(name: "int", size: 32, encoding: DW_ATE_signed)
+!20 = distinct !DISubprogram(name: "prog", scope: !1, file: !1, line: 3, type: !21, scopeLine: 3, flags: DIFlagPrototyped, spFlags: DISPFlagDefinition | DISPFlagOptimized, unit: !0)
+!21 = !DISubroutineType(types: !22)
+!22 = !{!14, !23}
+!23 = !DIDerivedType(tag: DW_TAG_pointer_type, baseType: null, size: 64)
+!25 = !DILocation(line: 4, column: 3, scope: !20)
+!29 = !{!30}
+!30 = !DIGlobalVariableExpression(var: !31, expr: !DIExpression())
+!31 = distinct !DIGlobalVariable(name: "_license", scope: !0, file: !1, line: 2, type: !32, isLocal: false, isDefinition: true)
+!32 = !DICompositeType(tag: DW_TAG_array_type, baseType: !33, size: 32, elements: !34)
+!33 = !DIBasicType(name: "char", size: 8, encoding: DW_ATE_signed_char)
+!34 = !{!35}
+!35 = !DISubrange(count: 4)
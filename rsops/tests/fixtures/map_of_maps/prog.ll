@@ -0,0 +1,101 @@
+; Maps of maps declared both ways, the IR clang emits for
+;
+;   struct bpf_elf_map SEC("maps") legacy_inner = {
+;   	.type = BPF_MAP_TYPE_ARRAY,
+;   	.size_key = 4,
+;   	.size_value = 4,
+;   	.max_elem = 1,
+;   	.id = 7,
+;   };
+;
+;   struct bpf_elf_map SEC("maps") legacy_outer = {
+;   	.type = BPF_MAP_TYPE_ARRAY_OF_MAPS,
+;   	.size_key = 4,
+;   	.size_value = 4,
+;   	.max_elem = 2,
+;   	.id = 8,
+;   	.inner_id = 7,
+;   };
+;
+;   struct inner_map {
+;   	__uint(type, BPF_MAP_TYPE_ARRAY);
+;   	__uint(max_entries, 1);
+;   	__type(key, int);
+;   	__type(value, long);
+;   } inner SEC(".maps");
+;
+;   struct {
+;   	__uint(type, BPF_MAP_TYPE_ARRAY_OF_MAPS);
+;   	__uint(max_entries, 4);
+;   	__type(key, int);
+;   	__array(values, struct inner_map);
+;   } outer SEC(".maps") = {
+;   	.values = { [2] = &inner },
+;   };
+;
+; Rebuild prog.o with
+;
+;   opt -mtriple=bpfel -passes='default<O2>' prog.ll | llc -march=bpfel -filetype=obj -o prog.o
+
+target datalayout = "e-m:e-p:64:64-i64:64-i128:128-n32:64-S128"
+target triple = "bpf"
+
+%struct.bpf_elf_map = type { i32, i32, i32, i32, i32, i32, i32, i32 }
+%struct.inner_map = type { [2 x i32]*, [1 x i32]*, i32*, i64* }
+%struct.outer = type { [12 x i32]*, [4 x i32]*, i32*, [3 x %struct.inner_map*] }
+
+@legacy_inner = dso_local global %struct.bpf_elf_map { i32 2, i32 4, i32 4, i32 1, i32 0, i32 7, i32 0, i32 0 }, section "maps", align 4
+@legacy_outer = dso_local global %struct.bpf_elf_map { i32 12, i32 4, i32 4, i32 2, i32 0, i32 8, i32 0, i32 7 }, section "maps", align 4
+@inner = dso_local global %struct.inner_map zeroinitializer, section ".maps", align 8, !dbg !40
+@outer = dso_local global %struct.outer { [12 x i32]* null, [4 x i32]* null, i32* null, [3 x %struct.inner_map*] [%struct.inner_map* null, %struct.inner_map* null, %struct.inner_map* @inner] }, section ".maps", align 8, !dbg !50
+@_license = dso_local global [4 x i8] c"GPL\00", section "license", align 1
+@llvm.compiler.used = appending global [5 x i8*] [i8* bitcast (%struct.bpf_elf_map* @legacy_inner to i8*), i8* bitcast (%struct.bpf_elf_map* @legacy_outer to i8*), i8* bitcast (%struct.inner_map* @inner to i8*), i8* bitcast (%struct.outer* @outer to i8*), i8* getelementptr inbounds ([4 x i8], [4 x i8]* @_license, i32 0, i32 0)], section "llvm.metadata"
+
+!llvm.dbg.cu = !{!0}
+!llvm.module.flags = !{!3, !4}
+
+!0 = distinct !DICompileUnit(language: DW_LANG_C99, file: !1, producer: "hand", isOptimized: true, runtimeVersion: 0, emissionKind: FullDebug, globals: !2)
+!1 = !DIFile(filename: "prog.c", directory: "/tmp/map_of_maps")
+!2 = !{!40, !50}
+!3 = !{i32 7, !"Dwarf Version", i32 5}
+!4 = !{i32 2, !"Debug Info Version", i32 3}
+!10 = !DIBasicType(name: "int", size: 32, encoding: DW_ATE_signed)
+!11 = !DIBasicType(name: "long", size: 64, encoding: DW_ATE_signed)
+!40 = !DIGlobalVariableExpression(var: !41, expr: !DIExpression())
+!41 = distinct !DIGlobalVariable(name: "inner", scope: !0, file: !1, line: 25, type: !42, isLocal: false, isDefinition: true)
+!42 = distinct !DICompositeType(tag: DW_TAG_structure_type, name: "inner_map", file: !1, line: 20, size: 256, elements: !43)
+!43 = !{!44, !45, !46, !47}
+!44 = !DIDerivedType(tag: DW_TAG_member, name: "type", scope: !42, file: !1, line: 21, baseType: !70, size: 64)
+!45 = !DIDerivedType(tag: DW_TAG_member, name: "max_entries", scope: !42, file: !1, line: 22, baseType: !72, size: 64, offset: 64)
+!46 = !DIDerivedType(tag: DW_TAG_member, name: "key", scope: !42, file: !1, line: 23, baseType: !74, size: 64, offset: 128)
+!47 = !DIDerivedType(tag: DW_TAG_member, name: "value", scope: !42, file: !1, line: 24, baseType: !75, size: 64, offset: 192)
+!50 = !DIGlobalVariableExpression(var: !51, expr: !DIExpression())
+!51 = distinct !DIGlobalVariable(name: "outer", scope: !0, file: !1, line: 32, type: !52, isLocal: false, isDefinition: true)
+!52 = distinct !DICompositeType(tag: DW_TAG_structure_type, file: !1, line: 27, size: 192, elements: !53)
+!53 = !{!54, !55, !56, !57}
+!54 = !DIDerivedType(tag: DW_TAG_member, name: "type", scope: !52, file: !1, line: 28, baseType: !80, size: 64)
+!55 = !DIDerivedType(tag: DW_TAG_member, name: "max_entries", scope: !52, file: !1, line: 29, baseType: !82, size: 64, offset: 64)
+!56 = !DIDerivedType(tag: DW_TAG_member, name: "key", scope: !52, file: !1, line: 30, baseType: !74, size: 64, offset: 128)
+!57 = !DIDerivedType(tag: DW_TAG_member, name: "values", scope: !52, file: !1, line: 31, baseType: !84, offset: 192)
+!70 = !DIDerivedType(tag: DW_TAG_pointer_type, baseType: !71, size: 64)
+!71 = !DICompositeType(tag: DW_TAG_array_type, baseType: !10, size: 64, elements: !76)
+!72 = !DIDerivedType(tag: DW_TAG_pointer_type, baseType: !73, size: 64)
+!73 = !DICompositeType(tag: DW_TAG_array_type, baseType: !10, size: 32, elements: !78)
+!74 = !DIDerivedType(tag: DW_TAG_pointer_type, baseType: !10, size: 64)
+!75 = !DIDerivedType(tag: DW_TAG_pointer_type, baseType: !11, size: 64)
+!76 = !{!77}
+!77 = !DISubrange(count: 2)
+!78 = !{!79}
+!79 = !DISubrange(count: 1)
+!80 = !DIDerivedType(tag: DW_TAG_pointer_type, baseType: !81, size: 64)
+!81 = !DICompositeType(tag: DW_TAG_array_type, baseType: !10, size: 384, elements: !86)
+!82 = !DIDerivedType(tag: DW_TAG_pointer_type, baseType: !83, size: 64)
+!83 = !DICompositeType(tag: DW_TAG_array_type, baseType: !10, size: 128, elements: !88)
+!84 = !DICompositeType(tag: DW_TAG_array_type, baseType: !85, elements: !90)
+!85 = !DIDerivedType(tag: DW_TAG_pointer_type, baseType: !42, size: 64)
+!86 = !{!87}
+!87 = !DISubrange(count: 12)
+!88 = !{!89}
+!89 = !DISubrange(count: 4)
+!90 = !{!91}
+!91 = !DISubrange(count: -1)
@@ -0,0 +1,159 @@
+; A program with a BTF map and global data, the IR clang emits for
+;
+;   struct {
+;   	__uint(type, BPF_MAP_TYPE_ARRAY);
+;   	__uint(max_entries, 1);
+;   	__type(key, int);
+;   	__type(value, long);
+;   } counts SEC(".maps");
+;
+;   const volatile int target_pid = 0;
+;   long calls = 0;
+;   int last_pid = 0;
+;   int enabled = 1;
+;
+;   SEC("kprobe/do_nanosleep") int on_sleep(void *ctx)
+;   {
+;   	int key = 0, pid;
+;   	long *value;
+;
+;   	if (!enabled)
+;   		return 0;
+;   	pid = bpf_get_current_pid_tgid() >> 32;
+;   	if (target_pid && pid != target_pid)
+;   		return 0;
+;   	last_pid = pid;
+;   	__sync_fetch_and_add(&calls, 1);
+;   	value = bpf_map_lookup_elem(&counts, &key);
+;   	if (value)
+;   		__sync_fetch_and_add(value, 1);
+;   	return 0;
+;   }
+;
+;   SEC("tracepoint/syscalls/sys_enter_nanosleep") int on_enter(void *ctx)
+;   {
+;   	__sync_fetch_and_add(&calls, 1);
+;   	return 0;
+;   }
+;
+;   SEC("xdp") int pass(void *ctx) { return XDP_PASS; }
+;
+; Rebuild prog.o with
+;
+;   opt -mtriple=bpfel -passes='default<O2>' prog.ll | llc -march=bpfel -filetype=obj -o prog.o
+
+target datalayout = "e-m:e-p:64:64-i64:64-i128:128-n32:64-S128"
+target triple = "bpf"
+
+%struct.anon = type { [2 x i32]*, [1 x i32]*, i32*, i64* }
+
+@counts = dso_local global %struct.anon zeroinitializer, section ".maps", align 8, !dbg !40
+@target_pid = dso_local constant i32 0, align 4, !dbg !50
+@calls = dso_local global i64 0, align 8, !dbg !55
+@last_pid = dso_local global i32 0, align 4, !dbg !57
+@enabled = dso_local global i32 1, align 4, !dbg !59
+@_license = dso_local global [4 x i8] c"GPL\00", section "license", align 1, !dbg !61
+@llvm.compiler.used = appending global [5 x i8*] [i8* bitcast (%struct.anon* @counts to i8*), i8* getelementptr inbounds ([4 x i8], [4 x i8]* @_license, i32 0, i32 0), i8* bitcast (i32 (i8*)* @on_sleep to i8*), i8* bitcast (i32 (i8*)* @on_enter to i8*), i8* bitcast (i32 (i8*)* @pass to i8*)], section "llvm.metadata"
+
+define dso_local i32 @on_sleep(i8* %ctx) section "kprobe/do_nanosleep" !dbg !20 {
+entry:
+  %key = alloca i32, align 4
+  %en = load i32, i32* @enabled, align 4, !dbg !25
+  %off = icmp eq i32 %en, 0, !dbg !25
+  br i1 %off, label %out, label %check, !dbg !25
+
+check:
+  %tg = call i64 inttoptr (i64 14 to i64 ()*)(), !dbg !25
+  %hi = lshr i64 %tg, 32, !dbg !25
+  %pid = trunc i64 %hi to i32, !dbg !25
+  %target = load volatile i32, i32* @target_pid, align 4, !dbg !25
+  %any = icmp eq i32 %target, 0, !dbg !25
+  br i1 %any, label %count, label %cmp, !dbg !25
+
+cmp:
+  %target2 = load volatile i32, i32* @target_pid, align 4, !dbg !25
+  %same = icmp eq i32 %pid, %target2, !dbg !25
+  br i1 %same, label %count, label %out, !dbg !25
+
+count:
+  store i32 %pid, i32* @last_pid, align 4, !dbg !25
+  %old = atomicrmw add i64* @calls, i64 1 seq_cst, !dbg !25
+  store i32 0, i32* %key, align 4, !dbg !25
+  %k = bitcast i32* %key to i8*, !dbg !25
+  %v = call i8* inttoptr (i64 1 to i8* (i8*, i8*)*)(i8* bitcast (%struct.anon* @counts to i8*), i8* %k), !dbg !25
+  %null = icmp eq i8* %v, null, !dbg !25
+  br i1 %null, label %out, label %inc, !dbg !25
+
+inc:
+  %vp = bitcast i8* %v to i64*, !dbg !25
+  %old2 = atomicrmw add i64* %vp, i64 1 seq_cst, !dbg !25
+  br label %out, !dbg !25
+
+out:
+  ret i32 0, !dbg !25
+}
+
+define dso_local i32 @on_enter(i8* %ctx) section "tracepoint/syscalls/sys_enter_nanosleep" !dbg !26 {
+entry:
+  %old = atomicrmw add i64* @calls, i64 1 seq_cst, !dbg !27
+  ret i32 0, !dbg !27
+}
+
+define dso_local i32 @pass(i8* %ctx) section "xdp" !dbg !28 {
+entry:
+  ret i32 2, !dbg !29
+}
+
+!llvm.dbg.cu = !{!0}
+!llvm.module.flags = !{!3, !4}
+
+!0 = distinct !DICompileUnit(language: DW_LANG_C99, file: !1, producer: "hand", isOptimized: true, runtimeVersion: 0, emissionKind: FullDebug, globals: !2)
+!1 = !DIFile(filename: "prog.c", directory: "/tmp/skeleton")
+!2 = !{!40, !50, !55, !57, !59, !61}
+!3 = !{i32 7, !"Dwarf Version", i32 5}
+!4 = !{i32 2, !"Debug Info Version", i32 3}
+!10 = !DIBasicType(name: "int", size: 32, encoding: DW_ATE_signed)
+!11 = !DIBasicType(name: "long", size: 64, encoding: DW_ATE_signed)
+!12 = !DIDerivedType(tag: DW_TAG_pointer_type, baseType: null, size: 64)
+!20 = distinct !DISubprogram(name: "on_sleep", scope: !1, file: !1, line: 15, type: !21, scopeLine: 15, flags: DIFlagPrototyped, spFlags: DISPFlagDefinition | DISPFlagOptimized, unit: !0)
+!21 = !DISubroutineType(types: !22)
+!22 = !{!10, !12}
+!25 = !DILocation(line: 16, column: 2, scope: !20)
+!26 = distinct !DISubprogram(name: "on_enter", scope: !1, file: !1, line: 33, type: !21, scopeLine: 33, flags: DIFlagPrototyped, spFlags: DISPFlagDefinition | DISPFlagOptimized, unit: !0)
+!27 = !DILocation(line: 35, column: 2, scope: !26)
+!28 = distinct !DISubprogram(name: "pass", scope: !1, file: !1, line: 39, type: !21, scopeLine: 39, flags: DIFlagPrototyped, spFlags: DISPFlagDefinition | DISPFlagOptimized, unit: !0)
+!29 = !DILocation(line: 39, column: 32, scope: !28)
+!40 = !DIGlobalVariableExpression(var: !41, expr: !DIExpression())
+!41 = distinct !DIGlobalVariable(name: "counts", scope: !0, file: !1, line: 6, type: !42, isLocal: false, isDefinition: true)
+!42 = distinct !DICompositeType(tag: DW_TAG_structure_type, file: !1, line: 1, size: 256, elements: !43)
+!43 = !{!44, !45, !46, !47}
+!44 = !DIDerivedType(tag: DW_TAG_member, name: "type", scope: !42, file: !1, line: 2, baseType: !70, size: 64)
+!45 = !DIDerivedType(tag: DW_TAG_member, name: "max_entries", scope: !42, file: !1, line: 3, baseType: !72, size: 64, offset: 64)
+!46 = !DIDerivedType(tag: DW_TAG_member, name: "key", scope: !42, file: !1, line: 4, baseType: !74, size: 64, offset: 128)
+!47 = !DIDerivedType(tag: DW_TAG_member, name: "value", scope: !42, file: !1, line: 5, baseType: !75, size: 64, offset: 192)
+!50 = !DIGlobalVariableExpression(var: !51, expr: !DIExpression())
+!51 = distinct !DIGlobalVariable(name: "target_pid", scope: !0, file: !1, line: 8, type: !52, isLocal: false, isDefinition: true)
+!52 = !DIDerivedType(tag: DW_TAG_const_type, baseType: !53)
+!53 = !DIDerivedType(tag: DW_TAG_volatile_type, baseType: !10)
+!55 = !DIGlobalVariableExpression(var: !56, expr: !DIExpression())
+!56 = distinct !DIGlobalVariable(name: "calls", scope: !0, file: !1, line: 9, type: !11, isLocal: false, isDefinition: true)
+!57 = !DIGlobalVariableExpression(var: !58, expr: !DIExpression())
+!58 = distinct !DIGlobalVariable(name: "last_pid", scope: !0, file: !1, line: 10, type: !10, isLocal: false, isDefinition: true)
+!59 = !DIGlobalVariableExpression(var: !60, expr: !DIExpression())
+!60 = distinct !DIGlobalVariable(name: "enabled", scope: !0, file: !1, line: 11, type: !10, isLocal: false, isDefinition: true)
+!61 = !DIGlobalVariableExpression(var: !62, expr: !DIExpression())
+!62 = distinct !DIGlobalVariable(name: "_license", scope: !0, file: !1, line: 41, type: !63, isLocal: false, isDefinition: true)
+!63 = !DICompositeType(tag: DW_TAG_array_type, baseType: !64, size: 32, elements: !65)
+!64 = !DIBasicType(name: "char", size: 8, encoding: DW_ATE_signed_char)
+!65 = !{!66}
+!66 = !DISubrange(count: 4)
+!70 = !DIDerivedType(tag: DW_TAG_pointer_type, baseType: !71, size: 64)
+!71 = !DICompositeType(tag: DW_TAG_array_type, baseType: !10, size: 64, elements: !76)
+!72 = !DIDerivedType(tag: DW_TAG_pointer_type, baseType: !73, size: 64)
+!73 = !DICompositeType(tag: DW_TAG_array_type, baseType: !10, size: 32, elements: !78)
+!74 = !DIDerivedType(tag: DW_TAG_pointer_type, baseType: !10, size: 64)
+!75 = !DIDerivedType(tag: DW_TAG_pointer_type, baseType: !11, size: 64)
+!76 = !{!77}
+!77 = !DISubrange(count: 2)
+!78 = !{!79}
+!79 = !DISubrange(count: 1)
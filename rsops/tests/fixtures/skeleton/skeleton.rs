@@ -0,0 +1,200 @@
+// Generated by rsops from <path>, do not edit.
+
+/// Global data sections and the types they use.
+pub mod types {
+// Generated by rsops from BTF, do not edit.
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+#[allow(non_camel_case_types, non_snake_case, dead_code)]
+pub struct rodata {
+    pub target_pid: i32,
+}
+
+impl ::std::default::Default for rodata {
+    fn default() -> Self {
+        unsafe { ::std::mem::zeroed() }
+    }
+}
+
+unsafe impl ::rsops::module::map::Pod for rodata {}
+
+const _: () = assert!(
+    ::std::mem::size_of::<rodata>() == 4 && ::std::mem::align_of::<rodata>() == 4
+);
+
+#[repr(C, packed)]
+#[derive(Debug, Clone, Copy)]
+#[allow(non_camel_case_types, non_snake_case, dead_code)]
+pub struct bss {
+    pub calls: i64,
+    pub last_pid: i32,
+}
+
+impl ::std::default::Default for bss {
+    fn default() -> Self {
+        unsafe { ::std::mem::zeroed() }
+    }
+}
+
+unsafe impl ::rsops::module::map::Pod for bss {}
+
+const _: () = assert!(
+    ::std::mem::size_of::<bss>() == 12 && ::std::mem::align_of::<bss>() == 1
+);
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+#[allow(non_camel_case_types, non_snake_case, dead_code)]
+pub struct data {
+    pub enabled: i32,
+}
+
+impl ::std::default::Default for data {
+    fn default() -> Self {
+        unsafe { ::std::mem::zeroed() }
+    }
+}
+
+unsafe impl ::rsops::module::map::Pod for data {}
+
+const _: () = assert!(
+    ::std::mem::size_of::<data>() == 4 && ::std::mem::align_of::<data>() == 4
+);
+}
+
+/// The object file the skeleton was generated from.
+pub const OBJECT: &[u8] = include_bytes!("<path>");
+
+/// Each program and the section it is attached through.
+#[allow(dead_code)]
+pub const PROGRAMS: [(&str, &str); 3] = [
+    ("on_sleep", "kprobe/do_nanosleep"),
+    ("on_enter", "tracepoint/syscalls/sys_enter_nanosleep"),
+    ("pass", "xdp"),
+];
+
+/// The parsed object, with the initial contents of its global data
+/// sections.
+#[allow(dead_code)]
+pub struct OpenSkeleton {
+    pub object: ::rsops::module::object::ObjectFile,
+    pub rodata: types::rodata,
+    pub bss: types::bss,
+    pub data: types::data,
+}
+
+#[allow(dead_code)]
+impl OpenSkeleton {
+    pub fn new() -> ::rsops::Result<OpenSkeleton> {
+        let object = ::rsops::module::object::ObjectFile::parse(OBJECT)?;
+        Ok(OpenSkeleton {
+            rodata: ::rsops::module::map::pod_read(object.data(".rodata").unwrap()),
+            bss: ::rsops::module::map::pod_read(object.data(".bss").unwrap()),
+            data: ::rsops::module::map::pod_read(object.data(".data").unwrap()),
+            object,
+        })
+    }
+
+    /// Creates the maps, with the global data sections holding the
+    /// contents of `self`, and loads the programs.
+    pub fn load(mut self) -> ::rsops::Result<Skeleton> {
+        self.object
+            .set_data(".rodata", ::rsops::module::map::pod_bytes(&self.rodata))?;
+        self.object
+            .set_data(".bss", ::rsops::module::map::pod_bytes(&self.bss))?;
+        self.object
+            .set_data(".data", ::rsops::module::map::pod_bytes(&self.data))?;
+        Ok(Skeleton {
+            object: self.object.load()?,
+            links: Vec::new(),
+        })
+    }
+}
+
+/// The loaded object.
+pub struct Skeleton {
+    object: ::rsops::module::object::Object,
+    links: Vec<::rsops::module::link::Link>,
+}
+
+#[allow(dead_code)]
+impl Skeleton {
+    /// Loads the object with the initial contents of its global data.
+    pub fn load() -> ::rsops::Result<Skeleton> {
+        OpenSkeleton::new()?.load()
+    }
+
+    /// Attaches every kprobe and tracepoint program as its section
+    /// says, replacing earlier attachments.
+    pub fn attach(&mut self) -> ::rsops::Result<()> {
+        self.links.clear();
+        for (name, section) in PROGRAMS.iter() {
+            if let Some(point) = ::rsops::module::link::AttachPoint::from_section(section) {
+                let link = point.attach(self.object.program(name).unwrap())?;
+                self.links.push(link);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn detach(&mut self) {
+        self.links.clear();
+    }
+
+    pub fn object(&self) -> &::rsops::module::object::Object {
+        &self.object
+    }
+
+    pub fn maps(&self) -> Maps<'_> {
+        Maps {
+            counts: self.object.map("counts").unwrap(),
+            rodata: self.object.map(".rodata").unwrap(),
+            bss: self.object.map(".bss").unwrap(),
+            data: self.object.map(".data").unwrap(),
+        }
+    }
+
+    pub fn progs(&self) -> Progs<'_> {
+        Progs {
+            on_sleep: self.object.program("on_sleep").unwrap(),
+            on_enter: self.object.program("on_enter").unwrap(),
+            pass: self.object.program("pass").unwrap(),
+        }
+    }
+
+    pub fn rodata(&self) -> ::rsops::Result<types::rodata> {
+        self.object.data(".rodata")
+    }
+
+    pub fn bss(&self) -> ::rsops::Result<types::bss> {
+        self.object.data(".bss")
+    }
+
+    pub fn set_bss(&self, value: &types::bss) -> ::rsops::Result<()> {
+        self.object.set_data(".bss", value)
+    }
+
+    pub fn data(&self) -> ::rsops::Result<types::data> {
+        self.object.data(".data")
+    }
+
+    pub fn set_data(&self, value: &types::data) -> ::rsops::Result<()> {
+        self.object.set_data(".data", value)
+    }
+}
+
+#[allow(dead_code)]
+pub struct Maps<'a> {
+    pub counts: &'a ::rsops::module::map::Map,
+    pub rodata: &'a ::rsops::module::map::Map,
+    pub bss: &'a ::rsops::module::map::Map,
+    pub data: &'a ::rsops::module::map::Map,
+}
+
+#[allow(dead_code)]
+pub struct Progs<'a> {
+    pub on_sleep: &'a ::rsops::module::program::Program,
+    pub on_enter: &'a ::rsops::module::program::Program,
+    pub pass: &'a ::rsops::module::program::Program,
+}
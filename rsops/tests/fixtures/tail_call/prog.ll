@@ -0,0 +1,72 @@
+; Tail call stages dispatched through a legacy prog array, with bpf-to-bpf
+; calls into a static function and into .text, the IR clang emits for
+;
+;   struct bpf_map_def SEC("maps") jmp_table = {
+;   	.type = BPF_MAP_TYPE_PROG_ARRAY,
+;   	.key_size = 4,
+;   	.value_size = 4,
+;   	.max_entries = 4,
+;   };
+;
+;   __noinline int classify(int len) { return len > 64 ? 2 : 1; }
+;
+;   static __noinline int verdict(int slot) { return slot == 2 ? XDP_DROP : XDP_PASS; }
+;
+;   SEC("xdp") int entry(void *ctx)
+;   {
+;   	int slot = classify(100);
+;
+;   	bpf_tail_call(ctx, &jmp_table, slot);
+;   	return verdict(slot);
+;   }
+;
+;   SEC("xdp/tail/jmp_table/1") int small(void *ctx) { return XDP_PASS; }
+;
+;   SEC("xdp/tail/jmp_table/2") int large(void *ctx) { return classify(10) + XDP_DROP - 1; }
+;
+; Rebuild prog.o with
+;
+;   opt -mtriple=bpfel -passes='default<O2>' prog.ll | llc -march=bpfel -filetype=obj -o prog.o
+
+target datalayout = "e-m:e-p:64:64-i64:64-i128:128-n32:64-S128"
+target triple = "bpf"
+
+%struct.bpf_map_def = type { i32, i32, i32, i32, i32 }
+
+@jmp_table = dso_local global %struct.bpf_map_def { i32 3, i32 4, i32 4, i32 4, i32 0 }, section "maps", align 4
+@_license = dso_local global [4 x i8] c"GPL\00", section "license", align 1
+@llvm.compiler.used = appending global [5 x i8*] [i8* bitcast (%struct.bpf_map_def* @jmp_table to i8*), i8* getelementptr inbounds ([4 x i8], [4 x i8]* @_license, i32 0, i32 0), i8* bitcast (i32 (i8*)* @entry to i8*), i8* bitcast (i32 (i8*)* @small to i8*), i8* bitcast (i32 (i8*)* @large to i8*)], section "llvm.metadata"
+
+define dso_local i32 @classify(i32 %len) noinline {
+entry:
+  %big = icmp sgt i32 %len, 64
+  %slot = select i1 %big, i32 2, i32 1
+  ret i32 %slot
+}
+
+define internal i32 @verdict(i32 %slot) noinline section "xdp" {
+entry:
+  %drop = icmp eq i32 %slot, 2
+  %v = select i1 %drop, i32 1, i32 2
+  ret i32 %v
+}
+
+define dso_local i32 @entry(i8* %ctx) section "xdp" {
+entry:
+  %slot = call i32 @classify(i32 100)
+  call void inttoptr (i64 12 to void (i8*, i8*, i32)*)(i8* %ctx, i8* bitcast (%struct.bpf_map_def* @jmp_table to i8*), i32 %slot)
+  %v = call i32 @verdict(i32 %slot)
+  ret i32 %v
+}
+
+define dso_local i32 @small(i8* %ctx) section "xdp/tail/jmp_table/1" {
+entry:
+  ret i32 2
+}
+
+define dso_local i32 @large(i8* %ctx) section "xdp/tail/jmp_table/2" {
+entry:
+  %c = call i32 @classify(i32 10)
+  %v = add i32 %c, 0
+  ret i32 %v
+}